@@ -9,13 +9,18 @@ use lapce_core::command::{
 };
 use lapce_core::syntax::Syntax;
 use lapce_rpc::{
-    buffer::BufferId, file::FileNodeItem, plugin::PluginDescription,
-    source_control::DiffInfo, style::Style, terminal::TermId,
+    buffer::BufferId,
+    file::FileNodeItem,
+    plugin::{CommandDefinition, PluginDescription},
+    source_control::DiffInfo,
+    style::Style,
+    terminal::TermId,
+    RequestId,
 };
 use lsp_types::{
-    CodeActionOrCommand, CodeActionResponse, CompletionItem, CompletionResponse,
-    InlayHint, Location, Position, ProgressParams, PublishDiagnosticsParams,
-    TextEdit,
+    CodeActionOrCommand, CodeActionResponse, CodeLens, CompletionItem,
+    CompletionResponse, InlayHint, Location, Position, ProgressParams,
+    PublishDiagnosticsParams, TextEdit, WorkDoneProgress,
 };
 use serde_json::Value;
 use strum::{self, EnumMessage, IntoEnumIterator};
@@ -444,6 +449,10 @@ pub enum LapceUICommand {
         rev: u64,
         content: Rope,
     },
+    /// Sends the proxy the whole text of the open file at `path`
+    SyncBuffer {
+        path: PathBuf,
+    },
     LoadBufferHead {
         path: PathBuf,
         version: String,
@@ -469,13 +478,40 @@ pub enum LapceUICommand {
     CancelCompletion(usize),
     ResolveCompletion(BufferId, u64, usize, Box<CompletionItem>),
     UpdateCompletion(usize, String, CompletionResponse),
+    /// The completion request can't be answered, for the reason given
+    CompletionUnavailable(usize, String),
+    UpdateCompletionPartial(usize, Position, Vec<CompletionItem>),
     UpdateHover(usize, Arc<Vec<RichText>>),
     UpdateInlayHints {
         path: PathBuf,
         rev: u64,
         hints: Spans<InlayHint>,
     },
+    /// The inlay hint at `offset`, resolved by the server
+    UpdateInlayHint {
+        path: PathBuf,
+        rev: u64,
+        offset: usize,
+        hint: InlayHint,
+    },
+    UpdateCodeLens {
+        path: PathBuf,
+        rev: u64,
+        lenses: Spans<CodeLens>,
+    },
+    /// Gets the code lenses of the document at `path` if it's still at `rev`
+    RequestCodeLens {
+        path: PathBuf,
+        rev: u64,
+    },
     UpdateCodeActions(PathBuf, u64, usize, CodeActionResponse),
+    /// Asks for the semantic styles of the open documents at the paths
+    /// again
+    RefreshSemanticStyles(Vec<PathBuf>),
+    /// Asks for the inlay hints of the open documents at the paths again
+    RefreshInlayHints(Vec<PathBuf>),
+    /// Asks for the code lenses of the open documents at the paths again
+    RefreshCodeLens(Vec<PathBuf>),
     CancelPalette,
     RunCodeAction(CodeActionOrCommand),
     ShowCodeActions(Option<Point>),
@@ -508,6 +544,8 @@ pub enum LapceUICommand {
     UpdateUninstalledPluginDescriptions(PluginLoadingStatus),
     UpdatePluginInstallationChange(HashMap<String, PluginDescription>),
     UpdateDisabledPlugins(HashMap<String, PluginDescription>),
+    UpdatePluginCommands(Vec<CommandDefinition>),
+    ExecutePluginCommand(String),
     DisablePlugin(PluginDescription),
     EnablePlugin(PluginDescription),
     RemovePlugin(PluginDescription),
@@ -532,7 +570,7 @@ pub enum LapceUICommand {
     ApplyEdits(usize, u64, Vec<TextEdit>),
     ApplyEditsAndSave(usize, u64, Result<Value>),
     DocumentFormat(PathBuf, u64, Result<Vec<TextEdit>>),
-    DocumentFormatAndSave(PathBuf, u64, Result<Vec<TextEdit>>, Option<WidgetId>),
+    DocumentFormatAndSave(PathBuf, u64, Result<Option<String>>, Option<WidgetId>),
     DocumentSave(PathBuf, Option<WidgetId>),
     BufferSave(PathBuf, u64, Option<WidgetId>),
     UpdateSemanticStyles(BufferId, PathBuf, u64, Arc<Spans<Style>>),
@@ -558,6 +596,8 @@ pub enum LapceUICommand {
     UpdateLineChanges(BufferId),
     PublishDiagnostics(PublishDiagnosticsParams),
     WorkDoneProgress(ProgressParams),
+    /// A language server's progress on the proxy request of the id
+    RequestProgress(RequestId, WorkDoneProgress),
     UpdateDiffInfo(DiffInfo),
     EnsureVisible((Rect, (f64, f64), Option<EnsureVisiblePosition>)),
     EnsureRectVisible(Rect),
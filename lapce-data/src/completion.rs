@@ -1,12 +1,12 @@
-use std::{fmt::Display, sync::Arc};
+use std::{borrow::Cow, fmt::Display, sync::Arc};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error, Result};
 use druid::{ExtEventSink, Size, Target, WidgetId};
 use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use itertools::Itertools;
-use lapce_core::movement::Movement;
+use lapce_core::{buffer::Buffer, movement::Movement, selection::Selection};
 use lapce_rpc::buffer::BufferId;
-use lsp_types::{CompletionItem, CompletionResponse, Position};
+use lsp_types::{CompletionItem, CompletionResponse, InsertTextMode, Position};
 use regex::Regex;
 use std::str::FromStr;
 
@@ -245,8 +245,17 @@ pub struct CompletionData {
     pub input: String,
     pub index: usize,
     pub input_items: im::HashMap<String, Arc<Vec<ScoredCompletionItem>>>,
+    /// The input each request was made for, by the line and character of
+    /// its position, to place the partial results of the request
+    request_inputs: im::HashMap<(u32, u32), String>,
     empty: Arc<Vec<ScoredCompletionItem>>,
     pub filtered_items: Arc<Vec<ScoredCompletionItem>>,
+    /// Whether the user asked for completion, rather than it coming up as
+    /// they typed
+    pub explicit: bool,
+    /// Why there's no completion to be had, shown in place of the items
+    /// when the user asked for it
+    pub unavailable: Option<String>,
     pub matcher: Arc<SkimMatcherV2>,
     /// The size of the completion list
     pub size: Size,
@@ -267,7 +276,10 @@ impl CompletionData {
             buffer_id: BufferId(0),
             input: "".to_string(),
             input_items: im::HashMap::new(),
+            request_inputs: im::HashMap::new(),
             filtered_items: Arc::new(Vec::new()),
+            explicit: false,
+            unavailable: None,
             matcher: Arc::new(SkimMatcherV2::default().ignore_case()),
             size: Size::new(400.0, 300.0),
             // TODO: Make this configurable
@@ -317,27 +329,44 @@ impl CompletionData {
 
     #[allow(clippy::too_many_arguments)]
     pub fn request(
-        &self,
+        &mut self,
         proxy: Arc<LapceProxy>,
         request_id: usize,
         buffer_id: BufferId,
         input: String,
         position: Position,
+        trigger_character: Option<String>,
         completion_widget_id: WidgetId,
         event_sink: ExtEventSink,
     ) {
+        self.request_inputs
+            .insert((position.line, position.character), input.clone());
         proxy.get_completion(
             request_id,
             buffer_id,
             position,
-            Box::new(move |result| {
-                if let Ok(resp) = result {
+            trigger_character,
+            None,
+            Box::new(move |result| match result {
+                Ok(resp) => {
                     let _ = event_sink.submit_command(
                         LAPCE_UI_COMMAND,
                         LapceUICommand::UpdateCompletion(request_id, input, resp),
                         Target::Widget(completion_widget_id),
                     );
                 }
+                Err(e) => {
+                    if let Some(reason) = e.no_language_server() {
+                        let _ = event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::CompletionUnavailable(
+                                request_id,
+                                reason.to_string(),
+                            ),
+                            Target::Widget(completion_widget_id),
+                        );
+                    }
+                }
             }),
         );
     }
@@ -349,6 +378,8 @@ impl CompletionData {
         self.status = CompletionStatus::Inactive;
         self.input = "".to_string();
         self.input_items.clear();
+        self.unavailable = None;
+        self.request_inputs.clear();
         self.index = 0;
     }
 
@@ -390,6 +421,41 @@ impl CompletionData {
         self.filter_items();
     }
 
+    /// Adds the items a server streamed ahead of its response to the items
+    /// of the request made at `position`.
+    pub fn receive_partial(
+        &mut self,
+        request_id: usize,
+        position: &Position,
+        items: &[CompletionItem],
+    ) {
+        if self.status == CompletionStatus::Inactive || self.request_id != request_id
+        {
+            return;
+        }
+        let input = match self
+            .request_inputs
+            .get(&(position.line, position.character))
+        {
+            Some(input) => input.clone(),
+            None => return,
+        };
+
+        let mut all_items = self
+            .input_items
+            .get(&input)
+            .map(|items| items.as_ref().clone())
+            .unwrap_or_default();
+        all_items.extend(items.iter().map(|i| ScoredCompletionItem {
+            item: i.to_owned(),
+            score: 0,
+            label_score: 0,
+            indices: Vec::new(),
+        }));
+        self.input_items.insert(input, Arc::new(all_items));
+        self.filter_items();
+    }
+
     pub fn filter_items(&mut self) {
         if self.input.is_empty() {
             return;
@@ -464,8 +530,76 @@ pub struct ScoredCompletionItem {
     pub indices: Vec<usize>,
 }
 
+/// The edits applying `item`: `text` replacing `start..end`, and the
+/// additional edits of the item, like the import of what's completed, sorted
+/// by offset. This fails rather than giving edits that overlap, which would
+/// corrupt the document when applied together.
+pub fn completion_edits<'a>(
+    buffer: &Buffer,
+    item: &'a CompletionItem,
+    (start, end): (usize, usize),
+    text: &'a str,
+) -> Result<Vec<(Selection, &'a str)>> {
+    let mut edits = vec![(start, end, text)];
+    for edit in item.additional_text_edits.iter().flatten() {
+        let (edit_start, edit_end) = buffer
+            .offset_of_position(&edit.range.start)
+            .zip(buffer.offset_of_position(&edit.range.end))
+            .ok_or_else(|| anyhow!("bad additional edit range {:?}", edit.range))?;
+        if edit_start < end && edit_end > start {
+            return Err(anyhow!(
+                "additional edit {:?} overlaps the completion",
+                edit.range
+            ));
+        }
+        edits.push((edit_start, edit_end, edit.new_text.as_str()));
+    }
+
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    for (a, b) in edits.iter().tuple_windows() {
+        if a.1 > b.0 {
+            return Err(anyhow!(
+                "additional edits of {} overlap each other",
+                item.label
+            ));
+        }
+    }
+    Ok(edits
+        .into_iter()
+        .map(|(start, end, text)| (Selection::region(start, end), text))
+        .collect())
+}
+
+/// `text` with the lines after its first indented like the line it's
+/// inserted on at `offset`, when `item` asks for its indentation to be
+/// adjusted. Blank lines are left as they are.
+pub fn adjust_indentation<'a>(
+    buffer: &Buffer,
+    item: &CompletionItem,
+    offset: usize,
+    text: &'a str,
+) -> Cow<'a, str> {
+    if item.insert_text_mode != Some(InsertTextMode::ADJUST_INDENTATION) {
+        return Cow::Borrowed(text);
+    }
+    let indent = buffer.indent_on_line(buffer.line_of_offset(offset));
+    if indent.is_empty() || !text.contains('\n') {
+        return Cow::Borrowed(text);
+    }
+    let lines = text.split('\n').enumerate().map(|(i, line)| {
+        if i == 0 || line.trim().is_empty() {
+            Cow::Borrowed(line)
+        } else {
+            Cow::Owned(format!("{indent}{line}"))
+        }
+    });
+    Cow::Owned(lines.collect::<Vec<_>>().join("\n"))
+}
+
 #[cfg(test)]
 mod tests {
+    use lsp_types::{Range, TextEdit};
+
     use super::*;
 
     #[test]
@@ -482,4 +616,59 @@ mod tests {
             parsed.tabs(0)
         );
     }
+
+    #[test]
+    fn test_completion_edits() {
+        let buffer = Buffer::new("fn main() {\n    Has\n}\n");
+        let import = |line, character| TextEdit {
+            range: Range {
+                start: Position { line, character },
+                end: Position { line, character },
+            },
+            new_text: "use std::collections::HashMap;\n".to_string(),
+        };
+        let mut item = CompletionItem {
+            label: "HashMap".to_string(),
+            additional_text_edits: Some(vec![import(0, 0)]),
+            ..Default::default()
+        };
+
+        // the import comes first, before the completed word
+        let edits = completion_edits(&buffer, &item, (16, 19), "HashMap").unwrap();
+        let edits: Vec<_> = edits
+            .iter()
+            .map(|(selection, text)| (selection.min_offset(), *text))
+            .collect();
+        assert_eq!(
+            edits,
+            vec![(0, "use std::collections::HashMap;\n"), (16, "HashMap")]
+        );
+
+        // an edit inside the completed word is refused
+        item.additional_text_edits = Some(vec![import(1, 5)]);
+        assert!(completion_edits(&buffer, &item, (16, 19), "HashMap").is_err());
+
+        // and so are additional edits overlapping each other
+        let mut overlapping = import(0, 0);
+        overlapping.range.end.character = 5;
+        item.additional_text_edits = Some(vec![import(0, 2), overlapping]);
+        assert!(completion_edits(&buffer, &item, (16, 19), "HashMap").is_err());
+    }
+
+    #[test]
+    fn test_adjust_indentation() {
+        let buffer = Buffer::new("fn main() {\n    ma\n}\n");
+        let mut item = CompletionItem {
+            label: "match".to_string(),
+            ..Default::default()
+        };
+        let text = "match $1 {\n    $0\n\n}";
+        assert_eq!(adjust_indentation(&buffer, &item, 16, text), text);
+
+        item.insert_text_mode = Some(InsertTextMode::ADJUST_INDENTATION);
+        assert_eq!(
+            adjust_indentation(&buffer, &item, 16, text),
+            "match $1 {\n        $0\n\n    }"
+        );
+    }
 }
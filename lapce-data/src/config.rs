@@ -12,6 +12,7 @@ use druid::{
 };
 use indexmap::IndexMap;
 use lapce_proxy::plugin::PluginCatalog;
+use lapce_rpc::proxy::{DocumentLimits, MemoryTrim, SaveStages, ServerLaunch};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -148,6 +149,62 @@ pub struct LapceConfig {
     pub modal: bool,
     #[field_names(desc = "Set the color theme of Lapce")]
     pub color_theme: String,
+    #[field_names(
+        desc = "Poll for file changes every this many milliseconds instead of using native notifications. 0 only polls on network filesystems"
+    )]
+    pub file_watcher_poll_interval: u64,
+    #[field_names(
+        desc = "How many language servers can run at once, the least recently used one is shut down to make room. 0 means no limit"
+    )]
+    pub max_language_servers: usize,
+    #[field_names(
+        desc = "Files larger than this many megabytes are opened without language servers. 0 means no limit"
+    )]
+    pub max_lsp_document_size: usize,
+    #[field_names(
+        desc = "Shut down language servers that weren't used for this many minutes and have no files open, starting them again when they're needed. 0 means never"
+    )]
+    pub language_server_idle_timeout: u64,
+    #[field_names(
+        desc = "The error codes of language servers that requests only reading from them are sent again once for, like -32801 when the file changed while they worked on it"
+    )]
+    pub language_server_retry_error_codes: Vec<i64>,
+    #[field_names(
+        desc = "The largest message in megabytes language servers and plugins may send, larger ones are dropped"
+    )]
+    pub max_message_size: usize,
+    #[field_names(
+        desc = "Filter and rank long lists of completion items before they're sent to the editor, for language servers giving very many of them"
+    )]
+    pub filter_completion_in_proxy: bool,
+    #[field_names(
+        desc = "Keep the symbols of the whole workspace to search them without asking the language servers every time"
+    )]
+    pub cache_workspace_symbols: bool,
+    #[field_names(
+        desc = "When the documentation of completion items is fetched from language servers that leave it out: \"eager\" for every item before the list is shown, \"lazy\" for the item that's picked, or \"off\" to go without"
+    )]
+    pub completion_documentation: String,
+    #[field_names(
+        desc = "Globs of the files never given to language servers, like \"vendor/**\" or \"*.generated.rs\", relative to the workspace"
+    )]
+    pub lsp_ignore: Vec<String>,
+    #[field_names(
+        desc = "The folders of the other projects in the workspace, like \"crates/server\", relative to it. Language servers take each file as part of the project it's in"
+    )]
+    pub workspace_roots: Vec<String>,
+    #[field_names(
+        desc = "The languages, like \"rust\", whose language servers run once for each project in the workspace rather than once for all of them"
+    )]
+    pub per_root_language_servers: Vec<String>,
+    #[field_names(
+        desc = "When the files opened as Lapce starts are given to language servers: \"plugins_first\" once the plugins have started theirs, or \"concurrent\" right away, leaving the servers of plugins without them until they're used"
+    )]
+    pub lsp_startup_order: String,
+    #[field_names(
+        desc = "How many milliseconds a file has to stay open before it's given to language servers, so that files only glanced at don't start them. 0 gives them right away"
+    )]
+    pub lsp_attach_delay: u32,
 }
 
 #[derive(FieldNames, Debug, Clone, Deserialize, Serialize, Default)]
@@ -171,6 +228,18 @@ pub struct EditorConfig {
         desc = "If the editor should show the documentation of the current completion item"
     )]
     pub completion_show_documentation: bool,
+    #[field_names(
+        desc = "Characters that should request completion as you type them, on top of the ones the language server asks for"
+    )]
+    pub completion_trigger_characters_add: String,
+    #[field_names(
+        desc = "Characters that shouldn't request completion as you type them, even if the language server asks for them"
+    )]
+    pub completion_trigger_characters_remove: String,
+    #[field_names(
+        desc = "How long (in ms) after accepting a completion item the completions its last character triggers are ignored, so that the list doesn't pop up again. 0 to never ignore them"
+    )]
+    pub completion_suppression_window: u64,
     #[field_names(
         desc = "How long (in ms) it should take before the hover information appears"
     )]
@@ -358,6 +427,10 @@ pub struct TerminalConfig {
     pub line_height: usize,
     #[field_names(desc = "Set the terminal Shell")]
     pub shell: String,
+    #[field_names(
+        desc = "How many MB of terminal output can wait to be displayed, older output is dropped beyond that"
+    )]
+    pub output_buffer_size: usize,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -879,6 +952,73 @@ impl Config {
         Some(table)
     }
 
+    /// The user's `[lsp.<language id>]` tables, merged by the proxy into the
+    /// initialization options of the language servers.
+    pub fn lsp_settings() -> HashMap<String, serde_json::Value> {
+        Self::get_file_table()
+            .and_then(|table| table.get("lsp")?.clone().try_into().ok())
+            .unwrap_or_default()
+    }
+
+    /// The user's `[lsp-document-limits.<server name>]` tables, limiting the
+    /// documents each language server is given.
+    pub fn lsp_document_limits() -> HashMap<String, DocumentLimits> {
+        Self::get_file_table()
+            .and_then(|table| {
+                table.get("lsp-document-limits")?.clone().try_into().ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The user's `[lsp-env.<server name>]` tables, the environment
+    /// variables each language server is started with. They win over the
+    /// ones the server's plugin sets, and an empty value unsets a variable.
+    pub fn lsp_env() -> HashMap<String, HashMap<String, String>> {
+        Self::get_file_table()
+            .and_then(|table| table.get("lsp-env")?.clone().try_into().ok())
+            .unwrap_or_default()
+    }
+
+    /// The user's `[lsp-launch.<server name>]` tables, the commands each
+    /// language server is launched through, like `wrapper = ["asdf",
+    /// "exec"]`, over the ones of the server's plugin.
+    pub fn lsp_launch() -> HashMap<String, ServerLaunch> {
+        Self::get_file_table()
+            .and_then(|table| table.get("lsp-launch")?.clone().try_into().ok())
+            .unwrap_or_default()
+    }
+
+    /// The user's `[lsp-max-in-flight-requests]` table, how many requests
+    /// each language server, by name, is given to answer at once.
+    pub fn lsp_max_in_flight_requests() -> HashMap<String, usize> {
+        Self::get_file_table()
+            .and_then(|table| {
+                table
+                    .get("lsp-max-in-flight-requests")?
+                    .clone()
+                    .try_into()
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The user's `[lsp-memory-trim.<language id>]` tables, the `command`
+    /// the language servers of each language free memory with, sent once
+    /// they've been idle for `idle-timeout` seconds.
+    pub fn lsp_memory_trim() -> HashMap<String, MemoryTrim> {
+        Self::get_file_table()
+            .and_then(|table| table.get("lsp-memory-trim")?.clone().try_into().ok())
+            .unwrap_or_default()
+    }
+
+    /// The user's `[save-stages]` table, which stages of getting ready to be
+    /// saved the documents of each language go through.
+    pub fn save_stages() -> HashMap<String, SaveStages> {
+        Self::get_file_table()
+            .and_then(|table| table.get("save-stages")?.clone().try_into().ok())
+            .unwrap_or_default()
+    }
+
     pub fn reset_setting(parent: &str, key: &str) -> Option<()> {
         let mut main_table = Self::get_file_table().unwrap_or_default();
 
@@ -11,7 +11,7 @@ use std::{
 #[cfg(target_os = "windows")]
 use std::env;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use directories::BaseDirs;
 use druid::{
@@ -31,13 +31,19 @@ use lapce_core::{
     selection::Selection,
 };
 use lapce_rpc::{
-    buffer::BufferId, plugin::PluginDescription, source_control::FileDiff,
+    buffer::BufferId,
+    plugin::{CommandDefinition, PluginDescription},
+    source_control::FileDiff,
     terminal::TermId,
 };
 
 use lapce_proxy::plugin::PluginCatalog;
+use lapce_proxy::registry;
 
-use lsp_types::{Diagnostic, DiagnosticSeverity, Position, ProgressToken, TextEdit};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, Position, ProgressToken, TextEdit,
+    WorkDoneProgress,
+};
 use notify::Watcher;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -273,8 +279,7 @@ impl LapceData {
 
     pub fn load_plugin_descriptions() -> Result<Vec<PluginDescription>> {
         let plugins: Vec<String> =
-            reqwest::blocking::get("https://lapce.github.io/plugins.json")?
-                .json()?;
+            registry::get("https://lapce.github.io/plugins.json")?.json()?;
         let plugins: Vec<PluginDescription> = plugins
             .iter()
             .filter_map(|plugin| LapceData::load_plugin_description(plugin).ok())
@@ -287,10 +292,19 @@ impl LapceData {
             "https://raw.githubusercontent.com/{}/master/plugin.toml",
             plugin
         );
-        let content = reqwest::blocking::get(url)?.text()?;
+        let content = registry::get(&url)?.text()?;
         let plugin: PluginDescription = toml::from_str(&content)?;
         Ok(plugin)
     }
+
+    /// Downloads the icon of a plugin found in the plugin index, which
+    /// isn't installed, when it's shown.
+    pub fn load_plugin_icon(plugin: &PluginDescription) -> Result<Vec<u8>> {
+        let url = plugin
+            .icon_url()
+            .ok_or_else(|| anyhow!("{} has no icon", plugin.name))?;
+        Ok(registry::get(&url)?.error_for_status()?.bytes()?.to_vec())
+    }
 }
 
 /// `LapceWindowData` is the application model for a top-level window.
@@ -522,6 +536,7 @@ pub struct LapceTabData {
     pub uninstalled_plugins_desc: Arc<PluginLoadingStatus>,
     pub installed_plugins: Arc<HashMap<String, PluginDescription>>,
     pub disabled_plugins: Arc<HashMap<String, PluginDescription>>,
+    pub plugin_commands: Arc<Vec<CommandDefinition>>,
     pub file_explorer: Arc<FileExplorerData>,
     pub proxy: Arc<LapceProxy>,
     pub proxy_status: Arc<ProxyStatus>,
@@ -569,6 +584,7 @@ impl Data for LapceTabData {
                 .same(&other.uninstalled_plugins_desc)
             && self.disabled_plugins.same(&other.disabled_plugins)
             && self.installed_plugins.same(&other.installed_plugins)
+            && self.plugin_commands.same(&other.plugin_commands)
             && self.picker.same(&other.picker)
             && self.drag.same(&other.drag)
             && self.keypress.same(&other.keypress)
@@ -604,6 +620,7 @@ impl LapceTabData {
         let proxy = Arc::new(LapceProxy::new(
             tab_id,
             workspace.clone(),
+            &config,
             term_sender.clone(),
             event_sink.clone(),
         ));
@@ -716,6 +733,7 @@ impl LapceTabData {
             installed_plugins_desc: Arc::new(PluginLoadingStatus::Ok(Vec::new())),
             uninstalled_plugins_desc: Arc::new(PluginLoadingStatus::Ok(Vec::new())),
             installed_plugins: Arc::new(HashMap::new()),
+            plugin_commands: Arc::new(Vec::new()),
             find: Arc::new(Find::new(0)),
             picker: file_picker,
             source_control,
@@ -1002,6 +1020,7 @@ impl LapceTabData {
             find: self.find.clone(),
             focus_area: self.focus_area.clone(),
             terminal: self.terminal.clone(),
+            plugin_commands: self.plugin_commands.clone(),
         }
     }
 
@@ -1660,6 +1679,35 @@ impl LapceTabData {
     pub fn handle_workspace_file_change(&self, _ctx: &mut EventCtx) {
         self.file_explorer.reload();
     }
+
+    /// Updates the work in progress reported with `token`.
+    pub fn update_progress(
+        &mut self,
+        token: &ProgressToken,
+        progress: &WorkDoneProgress,
+    ) {
+        match progress {
+            WorkDoneProgress::Begin(begin) => {
+                self.progresses.push_back(WorkProgress {
+                    token: token.clone(),
+                    title: begin.title.clone(),
+                    message: begin.message.clone(),
+                    percentage: begin.percentage,
+                });
+            }
+            WorkDoneProgress::Report(report) => {
+                for p in self.progresses.iter_mut() {
+                    if &p.token == token {
+                        p.message = report.message.clone();
+                        p.percentage = report.percentage;
+                    }
+                }
+            }
+            WorkDoneProgress::End(_end) => {
+                self.progresses.retain(|p| &p.token != token);
+            }
+        }
+    }
 }
 
 pub struct LapceTabLens(pub WidgetId);
@@ -1866,6 +1914,23 @@ impl LapceMainSplitData {
         }
     }
 
+    /// Removes the editor, closing the file it showed if no other editor
+    /// shows it, for the proxy and its language servers to let go of it.
+    pub fn remove_editor(&mut self, view_id: &WidgetId) {
+        let editor = match self.editors.remove(view_id) {
+            Some(editor) => editor,
+            None => return,
+        };
+        if let BufferContent::File(path) = &editor.content {
+            let shown = self.editors.values().any(|e| e.content == editor.content);
+            if !shown {
+                if let Some(doc) = self.open_docs.remove(path) {
+                    doc.proxy.close_buffer(doc.id());
+                }
+            }
+        }
+    }
+
     pub fn editor_doc(&self, editor_view_id: WidgetId) -> Arc<Document> {
         let editor = self.editors.get(&editor_view_id).unwrap();
         self.content_doc(&editor.content)
@@ -1877,7 +1942,11 @@ impl LapceMainSplitData {
         rev: u64,
         edits: &Result<Vec<TextEdit>>,
     ) {
-        let doc = self.open_docs.get(path).unwrap();
+        let doc = match self.open_docs.get(path) {
+            Some(doc) => doc,
+            // closed while it was being formatted
+            None => return,
+        };
         if doc.rev() != rev {
             return;
         }
@@ -1907,15 +1976,36 @@ impl LapceMainSplitData {
         }
     }
 
+    /// Saves the document after putting in the text the proxy prepared for
+    /// saving, if the document is still at the revision it was prepared at.
     pub fn document_format_and_save(
         &mut self,
         ctx: &mut EventCtx,
         path: &Path,
         rev: u64,
-        result: &Result<Vec<TextEdit>>,
+        result: &Result<Option<String>>,
         exit_widget_id: Option<WidgetId>,
     ) {
-        self.document_format(path, rev, result);
+        let doc = match self.open_docs.get(path) {
+            Some(doc) => doc,
+            None => return,
+        };
+        match result {
+            Ok(Some(text)) => {
+                if doc.rev() == rev {
+                    let old = doc.buffer().text().to_string();
+                    let (start, old_end, new_end) = changed_span(&old, text);
+                    let selection = Selection::region(start, old_end);
+                    self.edit(
+                        path,
+                        &[(selection, &text[start..new_end])],
+                        EditType::Other,
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("failed to prepare {path:?} for saving: {err}"),
+        }
         self.document_save(ctx, path, exit_widget_id);
     }
 
@@ -1925,7 +2015,10 @@ impl LapceMainSplitData {
         path: &Path,
         exit_widget_id: Option<WidgetId>,
     ) {
-        let doc = self.open_docs.get(path).unwrap();
+        let doc = match self.open_docs.get(path) {
+            Some(doc) => doc,
+            None => return,
+        };
         let rev = doc.rev();
         let buffer_id = doc.id();
         let event_sink = ctx.get_external_handle();
@@ -3543,3 +3636,23 @@ impl std::fmt::Display for LapceWorkspace {
         )
     }
 }
+
+/// Where `new` differs from `old`, as the start of the difference and where
+/// it ends in `old` and in `new`, leaving out the text they start and end
+/// with alike.
+fn changed_span(old: &str, new: &str) -> (usize, usize, usize) {
+    let start = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map(|((i, _), _)| i)
+        .unwrap_or_else(|| old.len().min(new.len()));
+    let suffix = old[start..]
+        .chars()
+        .rev()
+        .zip(new[start..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum::<usize>();
+    (start, old.len() - suffix, new.len() - suffix)
+}
@@ -8,6 +8,8 @@ use std::{
         atomic::{self},
         Arc,
     },
+    thread,
+    time::Duration,
 };
 
 use druid::{
@@ -35,9 +37,10 @@ use lapce_rpc::{
     style::{LineStyle, LineStyles, Style},
 };
 use lsp_types::{
-    CodeActionOrCommand, CodeActionResponse, DiagnosticSeverity, InlayHint,
-    InlayHintLabel,
+    CodeActionOrCommand, CodeActionResponse, CodeLens, DiagnosticSeverity,
+    InlayHint, InlayHintLabel,
 };
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use xi_rope::{
@@ -56,6 +59,10 @@ use crate::{
     settings::SettingsValueKind,
 };
 
+/// How long a document has to go without changing before its code lenses are
+/// asked for again
+const CODE_LENS_DELAY: Duration = Duration::from_millis(500);
+
 pub struct SystemClipboard {}
 
 impl Clipboard for SystemClipboard {
@@ -399,6 +406,8 @@ pub struct Document {
     pub scroll_offset: Vec2,
     pub code_actions: im::HashMap<usize, CodeActionResponse>,
     pub inlay_hints: Option<Spans<InlayHint>>,
+    /// The code lenses of the document, at the start of their range
+    pub code_lens: Option<Spans<CodeLens>>,
     pub diagnostics: Option<Arc<Vec<EditorDiagnostic>>>,
     pub find: Rc<RefCell<Find>>,
     find_progress: Rc<RefCell<FindProgress>>,
@@ -440,6 +449,7 @@ impl Document {
             scroll_offset: Vec2::ZERO,
             code_actions: im::HashMap::new(),
             inlay_hints: None,
+            code_lens: None,
             diagnostics: None,
             find: Rc::new(RefCell::new(Find::new(0))),
             find_progress: Rc::new(RefCell::new(FindProgress::Ready)),
@@ -549,6 +559,7 @@ impl Document {
     pub fn reload(&mut self, content: Rope, set_pristine: bool) {
         self.code_actions.clear();
         self.inlay_hints = None;
+        self.code_lens = None;
         let delta = self.buffer.reload(content, set_pristine);
         self.apply_deltas(&[delta]);
     }
@@ -740,7 +751,7 @@ impl Document {
         }
     }
 
-    fn get_semantic_styles(&self) {
+    pub fn get_semantic_styles(&self) {
         if !self.loaded() {
             return;
         }
@@ -829,10 +840,147 @@ impl Document {
         }
     }
 
+    /// Asks for the code lenses of the document once it has gone
+    /// `CODE_LENS_DELAY` without changing, so a burst of edits doesn't send
+    /// a `codeLens` request and its resolves for every keystroke.
+    fn request_code_lens(&self) {
+        if !self.loaded() {
+            return;
+        }
+
+        if let BufferContent::File(path) = self.content() {
+            let tab_id = self.tab_id;
+            let path = path.clone();
+            let rev = self.rev();
+            let event_sink = self.event_sink.clone();
+            thread::spawn(move || {
+                thread::sleep(CODE_LENS_DELAY);
+                let _ = event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RequestCodeLens { path, rev },
+                    Target::Widget(tab_id),
+                );
+            });
+        }
+    }
+
+    /// Gets the code lenses of the document, resolving the ones the server
+    /// left the command out of before they're shown.
+    pub fn get_code_lens(&self) {
+        if !self.loaded() {
+            return;
+        }
+
+        if let BufferContent::File(path) = self.content() {
+            let tab_id = self.tab_id;
+            let path = path.clone();
+            let buffer_id = self.id();
+            let rev = self.rev();
+            let buffer = self.buffer().clone();
+            let event_sink = self.event_sink.clone();
+            let proxy = self.proxy.clone();
+            self.proxy.get_code_lens(buffer_id, move |result| {
+                let lenses = match result {
+                    Ok(lenses) => lenses,
+                    Err(_) => return,
+                };
+                let update = move |mut lenses: Vec<CodeLens>| {
+                    lenses.sort_by(|left, right| {
+                        left.range.start.cmp(&right.range.start)
+                    });
+                    let len = buffer.len();
+                    let mut lens_span = SpansBuilder::new(len);
+                    for lens in lenses {
+                        if let Some(offset) =
+                            buffer.offset_of_position(&lens.range.start)
+                        {
+                            let offset = offset.min(len);
+                            lens_span.add_span(
+                                Interval::new(offset, (offset + 1).min(len)),
+                                lens,
+                            );
+                        }
+                    }
+                    let _ = event_sink.submit_command(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::UpdateCodeLens {
+                            path: path.clone(),
+                            rev,
+                            lenses: lens_span.build(),
+                        },
+                        Target::Widget(tab_id),
+                    );
+                };
+
+                let unresolved = lenses
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, lens)| lens.command.is_none())
+                    .map(|(i, _)| i)
+                    .collect::<Vec<_>>();
+                if unresolved.is_empty() {
+                    update(lenses);
+                    return;
+                }
+                // the lenses with the number of them still being resolved
+                let pending = Arc::new(Mutex::new((lenses, unresolved.len())));
+                for i in unresolved {
+                    let lens = pending.lock().0[i].clone();
+                    let pending = pending.clone();
+                    let update = update.clone();
+                    proxy.code_lens_resolve(buffer_id, lens, move |result| {
+                        let mut pending = pending.lock();
+                        if let Ok(lens) = result {
+                            pending.0[i] = lens;
+                        }
+                        pending.1 -= 1;
+                        if pending.1 == 0 {
+                            update(std::mem::take(&mut pending.0));
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    /// Asks the server to fill in the inlay hint at `offset`, like its
+    /// tooltip, if it hasn't been resolved yet.
+    pub fn resolve_inlay_hint(&self, offset: usize) {
+        let hint = match self.inlay_hints.as_ref().and_then(|hints| {
+            hints.iter().find(|(interval, _)| interval.start == offset)
+        }) {
+            Some((_, hint)) if hint.tooltip.is_none() => hint.clone(),
+            _ => return,
+        };
+
+        if let BufferContent::File(path) = self.content() {
+            let tab_id = self.tab_id;
+            let path = path.clone();
+            let rev = self.rev();
+            let event_sink = self.event_sink.clone();
+            self.proxy
+                .inlay_hint_resolve(self.id(), hint, move |result| {
+                    if let Ok(hint) = result {
+                        let _ = event_sink.submit_command(
+                            LAPCE_UI_COMMAND,
+                            LapceUICommand::UpdateInlayHint {
+                                path,
+                                rev,
+                                offset,
+                                hint,
+                            },
+                            Target::Widget(tab_id),
+                        );
+                    }
+                });
+        }
+    }
+
     fn on_update(&mut self, delta: Option<&RopeDelta>) {
         self.find.borrow_mut().unset();
         *self.find_progress.borrow_mut() = FindProgress::Started;
         self.get_inlay_hints();
+        self.request_code_lens();
         self.get_semantic_styles();
         self.clear_style_cache();
         self.trigger_syntax_change(delta);
@@ -951,6 +1099,27 @@ impl Document {
         self.clear_text_layout_cache();
     }
 
+    /// Puts `hint` in place of the inlay hint at `offset`
+    pub fn set_inlay_hint(&mut self, offset: usize, hint: InlayHint) {
+        let hints = match self.inlay_hints.as_mut() {
+            Some(hints) => hints,
+            None => return,
+        };
+        let interval =
+            match hints.iter().find(|(interval, _)| interval.start == offset) {
+                Some((interval, _)) => interval,
+                None => return,
+            };
+        let mut hint_span = SpansBuilder::new(interval.end - interval.start);
+        hint_span.add_span(Interval::new(0, interval.end - interval.start), hint);
+        hints.edit(interval, hint_span.build());
+        self.clear_text_layout_cache();
+    }
+
+    pub fn set_code_lens(&mut self, lenses: Spans<CodeLens>) {
+        self.code_lens = Some(lenses);
+    }
+
     pub fn buffer(&self) -> &Buffer {
         &self.buffer
     }
@@ -983,6 +1152,12 @@ impl Document {
         }
     }
 
+    fn update_code_lens(&mut self, delta: &RopeDelta) {
+        if let Some(lenses) = self.code_lens.as_mut() {
+            lenses.apply_shape(delta);
+        }
+    }
+
     pub fn line_phantom_text(
         &self,
         config: &Config,
@@ -1035,6 +1210,7 @@ impl Document {
         for (i, (delta, _)) in deltas.iter().enumerate() {
             self.update_styles(delta);
             self.update_inlay_hints(delta);
+            self.update_code_lens(delta);
             self.update_diagnostics(delta);
             if self.content.is_file() {
                 self.proxy.update(self.id, delta, rev + i as u64 + 1);
@@ -2,7 +2,9 @@ use crate::command::LapceCommand;
 use crate::command::LAPCE_COMMAND;
 use crate::command::LAPCE_SAVE_FILE_AS;
 use crate::command::{CommandExecuted, CommandKind};
-use crate::completion::{CompletionData, CompletionStatus, Snippet};
+use crate::completion::{
+    adjust_indentation, completion_edits, CompletionData, CompletionStatus, Snippet,
+};
 use crate::config::Config;
 use crate::data::EditorView;
 use crate::data::{
@@ -56,8 +58,8 @@ use lsp_types::TextEdit;
 use lsp_types::Url;
 use lsp_types::WorkspaceEdit;
 use lsp_types::{
-    CodeActionResponse, CompletionItem, DiagnosticSeverity, GotoDefinitionResponse,
-    Location, Position,
+    CodeActionResponse, CompletionItem, DiagnosticSeverity, FormattingOptions,
+    GotoDefinitionResponse, Location, Position,
 };
 use std::cmp::Ordering;
 use std::path::Path;
@@ -242,8 +244,12 @@ impl LapceEditorBufferData {
                 };
                 let rev = self.doc.rev();
                 let event_sink = ctx.get_external_handle();
-                self.proxy
-                    .get_code_actions(buffer_id, position, move |result| {
+                self.proxy.get_code_actions(
+                    buffer_id,
+                    position,
+                    None,
+                    &[],
+                    move |result| {
                         if let Ok(resp) = result {
                             let _ = event_sink.submit_command(
                                 LAPCE_UI_COMMAND,
@@ -256,7 +262,8 @@ impl LapceEditorBufferData {
                                 Target::Auto,
                             );
                         }
-                    });
+                    },
+                );
             }
         }
     }
@@ -346,39 +353,6 @@ impl LapceEditorBufferData {
     }
 
     pub fn apply_completion_item(&mut self, item: &CompletionItem) -> Result<()> {
-        let additional_edit: Option<Option<Vec<_>>> =
-            item.additional_text_edits.as_ref().map(|edits| {
-                edits
-                    .iter()
-                    .map(|edit| {
-                        let selection = lapce_core::selection::Selection::region(
-                            self.doc.buffer().offset_of_position(&edit.range.start)?,
-                            self.doc.buffer().offset_of_position(&edit.range.end)?,
-                        );
-                        Some((selection, edit.new_text.as_str()))
-                    })
-                    .collect::<Option<Vec<(lapce_core::selection::Selection, &str)>>>()
-            });
-
-        // If the inner option is empty
-        if additional_edit
-            .as_ref()
-            .map(Option::is_none)
-            .unwrap_or(false)
-        {
-            log::error!("Failed to convert completion item's additional edit Positions to offsets");
-            return Err(anyhow!("Bad additional edit position"));
-        }
-
-        let additional_edit = additional_edit.flatten();
-
-        let additional_edit: Vec<_> = additional_edit
-            .as_ref()
-            .map(|edits| {
-                edits.iter().map(|(selection, c)| (selection, *c)).collect()
-            })
-            .unwrap_or_default();
-
         let text_format = item
             .insert_text_format
             .unwrap_or(lsp_types::InsertTextFormat::PLAIN_TEXT);
@@ -405,21 +379,26 @@ impl LapceEditorBufferData {
                         return Err(anyhow!("bad edit end position"));
                     };
 
-                    let selection = lapce_core::selection::Selection::region(
-                        start_offset.min(edit_start),
-                        end_offset.max(edit_end),
+                    let range =
+                        (start_offset.min(edit_start), end_offset.max(edit_end));
+                    let selection =
+                        lapce_core::selection::Selection::region(range.0, range.1);
+                    let new_text = adjust_indentation(
+                        self.doc.buffer(),
+                        item,
+                        range.0,
+                        &edit.new_text,
                     );
                     match text_format {
                         lsp_types::InsertTextFormat::PLAIN_TEXT => {
+                            let edits = completion_edits(
+                                self.doc.buffer(),
+                                item,
+                                range,
+                                &new_text,
+                            )?;
                             let (delta, inval_lines) = Arc::make_mut(&mut self.doc)
-                                .do_raw_edit(
-                                    &[
-                                        &[(&selection, edit.new_text.as_str())][..],
-                                        &additional_edit[..],
-                                    ]
-                                    .concat(),
-                                    EditType::Completion,
-                                );
+                                .do_raw_edit(&edits, EditType::Completion);
                             let selection = selection.apply_delta(
                                 &delta,
                                 true,
@@ -432,17 +411,16 @@ impl LapceEditorBufferData {
                             return Ok(());
                         }
                         lsp_types::InsertTextFormat::SNIPPET => {
-                            let snippet = Snippet::from_str(&edit.new_text)?;
+                            let snippet = Snippet::from_str(&new_text)?;
                             let text = snippet.text();
+                            let edits = completion_edits(
+                                self.doc.buffer(),
+                                item,
+                                range,
+                                &text,
+                            )?;
                             let (delta, inval_lines) = Arc::make_mut(&mut self.doc)
-                                .do_raw_edit(
-                                    &[
-                                        &[(&selection, text.as_str())][..],
-                                        &additional_edit[..],
-                                    ]
-                                    .concat(),
-                                    EditType::Completion,
-                                );
+                                .do_raw_edit(&edits, EditType::Completion);
                             let selection = selection.apply_delta(
                                 &delta,
                                 true,
@@ -450,8 +428,7 @@ impl LapceEditorBufferData {
                             );
 
                             let mut transformer = Transformer::new(&delta);
-                            let offset = transformer
-                                .transform(start_offset.min(edit_start), false);
+                            let offset = transformer.transform(range.0, false);
                             let snippet_tabs = snippet.tabs(offset);
 
                             if snippet_tabs.is_empty() {
@@ -489,17 +466,20 @@ impl LapceEditorBufferData {
         let end_offset = self.doc.buffer().next_code_boundary(offset);
         let selection = Selection::region(start_offset, end_offset);
 
-        let (delta, inval_lines) = Arc::make_mut(&mut self.doc).do_raw_edit(
-            &[
-                &[(
-                    &selection,
-                    item.insert_text.as_deref().unwrap_or(item.label.as_str()),
-                )][..],
-                &additional_edit[..],
-            ]
-            .concat(),
-            EditType::Completion,
+        let text = adjust_indentation(
+            self.doc.buffer(),
+            item,
+            start_offset,
+            item.insert_text.as_deref().unwrap_or(item.label.as_str()),
         );
+        let edits = completion_edits(
+            self.doc.buffer(),
+            item,
+            (start_offset, end_offset),
+            &text,
+        )?;
+        let (delta, inval_lines) =
+            Arc::make_mut(&mut self.doc).do_raw_edit(&edits, EditType::Completion);
         let selection = selection.apply_delta(&delta, true, InsertDrift::Default);
         Arc::make_mut(&mut self.editor)
             .cursor
@@ -551,12 +531,15 @@ impl LapceEditorBufferData {
                 .slice_to_cow(start_offset - 1..start_offset)
                 .to_string()
         };
+        let is_trigger = self.proxy.is_completion_trigger(self.doc.id(), &char);
         let completion = Arc::make_mut(&mut self.completion);
-        if !display_if_empty_input && input.is_empty() && char != "." && char != ":"
-        {
+        if !display_if_empty_input && input.is_empty() && !is_trigger {
             completion.cancel();
             return;
         }
+        // the requests at the start of the input are triggered by the
+        // character before it
+        let trigger_character = if is_trigger { Some(char) } else { None };
 
         if completion.status != CompletionStatus::Inactive
             && completion.offset == start_offset
@@ -575,6 +558,7 @@ impl LapceEditorBufferData {
                         self.doc.id(),
                         "".to_string(),
                         start_pos,
+                        trigger_character.clone(),
                         completion.id,
                         event_sink,
                     );
@@ -593,6 +577,7 @@ impl LapceEditorBufferData {
                         self.doc.id(),
                         input,
                         position,
+                        None,
                         completion.id,
                         event_sink,
                     );
@@ -609,6 +594,8 @@ impl LapceEditorBufferData {
         completion.input = input.clone();
         completion.status = CompletionStatus::Started;
         completion.input_items.clear();
+        completion.explicit = display_if_empty_input;
+        completion.unavailable = None;
         completion.request_id += 1;
         let event_sink = ctx.get_external_handle();
         if let Some(start_pos) = self.doc.buffer().offset_to_position(start_offset) {
@@ -618,6 +605,7 @@ impl LapceEditorBufferData {
                 self.doc.id(),
                 "".to_string(),
                 start_pos,
+                trigger_character,
                 completion.id,
                 event_sink.clone(),
             );
@@ -630,6 +618,7 @@ impl LapceEditorBufferData {
                     self.doc.id(),
                     input,
                     position,
+                    None,
                     completion.id,
                     event_sink,
                 );
@@ -674,6 +663,8 @@ impl LapceEditorBufferData {
             return;
         }
 
+        self.doc.resolve_inlay_hint(offset);
+
         let start_offset = self.doc.buffer().prev_code_boundary(offset);
         let end_offset = self.doc.buffer().next_code_boundary(offset);
         let input = self.doc.buffer().slice_to_cow(start_offset..end_offset);
@@ -1168,26 +1159,20 @@ impl LapceEditorBufferData {
             let view_id = self.editor.view_id;
             let (sender, receiver) = bounded(1);
             thread::spawn(move || {
-                proxy.get_document_formatting(
-                    buffer_id,
-                    Box::new(move |result| {
-                        let _ = sender.send(result);
-                    }),
-                );
+                proxy.prepare_save(rev, buffer_id, format_on_save, move |result| {
+                    let _ = sender.send(result);
+                });
 
+                // the proxy gives each stage a second
                 let result =
-                    receiver.recv_timeout(Duration::from_secs(1)).map_or_else(
+                    receiver.recv_timeout(Duration::from_secs(5)).map_or_else(
                         |e| Err(anyhow!("{}", e)),
-                        |v| v.map_err(|e| anyhow!("{:?}", e)),
+                        |v| v.map(|r| r.text).map_err(|e| anyhow!("{:?}", e)),
                     );
 
                 let exit = if exit { Some(view_id) } else { None };
-                let cmd = if format_on_save {
-                    LapceUICommand::DocumentFormatAndSave(path, rev, result, exit)
-                } else {
-                    LapceUICommand::DocumentSave(path, exit)
-                };
-
+                let cmd =
+                    LapceUICommand::DocumentFormatAndSave(path, rev, result, exit);
                 let _ =
                     event_sink.submit_command(LAPCE_UI_COMMAND, cmd, Target::Auto);
             });
@@ -1535,6 +1520,7 @@ impl LapceEditorBufferData {
                 } else {
                     let item = self.completion.current_item().to_owned();
                     self.cancel_completion();
+                    self.proxy.completion_accepted(self.doc.id());
                     if item.data.is_some() {
                         let view_id = self.editor.view_id;
                         let buffer_id = self.doc.id();
@@ -1559,7 +1545,9 @@ impl LapceEditorBufferData {
                             },
                         );
                     } else {
-                        let _ = self.apply_completion_item(&item);
+                        if let Err(err) = self.apply_completion_item(&item) {
+                            log::error!("Failed to apply completion item: {err}");
+                        }
                     }
                 }
             }
@@ -1725,6 +1713,7 @@ impl LapceEditorBufferData {
                     offset,
                     buffer_id,
                     position,
+                    None,
                     move |result| {
                         if let Ok(resp) = result {
                             if let Some(location) = match resp {
@@ -1746,6 +1735,7 @@ impl LapceEditorBufferData {
                                     proxy.get_references(
                                         buffer_id,
                                         position,
+                                        None,
                                         move |result| {
                                             let _ = process_get_references(
                                                 offset, result, event_sink,
@@ -1790,6 +1780,7 @@ impl LapceEditorBufferData {
                     offset,
                     buffer_id,
                     position,
+                    None,
                     move |result| {
                         if let Ok(resp) = result {
                             match resp {
@@ -1883,6 +1874,7 @@ impl LapceEditorBufferData {
                     thread::spawn(move || {
                         proxy.get_document_formatting(
                             buffer_id,
+                            None,
                             Box::new(move |result| {
                                 let _ = sender.send(result);
                             }),
@@ -2074,6 +2066,9 @@ impl KeyPressFocus for LapceEditorBufferData {
             self.update_completion(ctx, false);
             self.cancel_hover();
             self.apply_deltas(&deltas);
+            if self.proxy.is_on_type_formatting_trigger(self.doc.id(), c) {
+                self.format_on_type(ctx, c);
+            }
         } else if let Some(direction) = self.editor.inline_find.clone() {
             self.inline_find(ctx, direction.clone(), c);
             let editor = Arc::make_mut(&mut self.editor);
@@ -2082,6 +2077,49 @@ impl KeyPressFocus for LapceEditorBufferData {
         }
     }
 
+    /// Has the language server format what was typed up to the cursor, now
+    /// that `c` was typed.
+    fn format_on_type(&mut self, ctx: &mut EventCtx, c: &str) {
+        let path = match self.doc.content() {
+            BufferContent::File(path) => path.clone(),
+            _ => return,
+        };
+        let position = match self
+            .doc
+            .buffer()
+            .offset_to_position(self.editor.cursor.offset())
+        {
+            Some(position) => position,
+            None => return,
+        };
+        let indent = self.doc.buffer().indent_unit();
+        let options = FormattingOptions {
+            tab_size: if indent.starts_with(' ') {
+                indent.len() as u32
+            } else {
+                self.config.editor.tab_width as u32
+            },
+            insert_spaces: indent.starts_with(' '),
+            ..Default::default()
+        };
+        let rev = self.doc.rev();
+        let event_sink = ctx.get_external_handle();
+        self.proxy.on_type_formatting(
+            self.doc.id(),
+            position,
+            c,
+            options,
+            move |result| {
+                let result = result.map_err(|e| anyhow!("{:?}", e));
+                let _ = event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::DocumentFormat(path, rev, result),
+                    Target::Auto,
+                );
+            },
+        );
+    }
+
     fn run_command(
         &mut self,
         ctx: &mut EventCtx,
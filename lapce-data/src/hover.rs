@@ -131,6 +131,7 @@ impl HoverData {
             request_id,
             buffer_id,
             position,
+            None,
             Box::new(move |result| {
                 if let Ok(resp) = result {
                     let items = parse_hover_resp(resp, &p_config);
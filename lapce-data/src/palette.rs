@@ -10,6 +10,7 @@ use lapce_core::command::{EditCommand, FocusCommand};
 use lapce_core::language::LapceLanguage;
 use lapce_core::mode::Mode;
 use lapce_core::movement::Movement;
+use lapce_rpc::plugin::CommandDefinition;
 use lsp_types::{DocumentSymbolResponse, Position, Range, SymbolKind};
 use std::cmp::Ordering;
 use std::collections::HashSet;
@@ -140,6 +141,7 @@ pub enum PaletteItemContent {
     Workspace(LapceWorkspace),
     SshHost(String, String),
     Command(LapceCommand),
+    PluginCommand(CommandDefinition),
     Theme(String),
     Language(String),
 }
@@ -245,6 +247,15 @@ impl PaletteItemContent {
                 }
                 return !command.is_palette_command();
             }
+            PaletteItemContent::PluginCommand(command) => {
+                if !preview {
+                    ctx.submit_command(Command::new(
+                        LAPCE_UI_COMMAND,
+                        LapceUICommand::ExecutePluginCommand(command.id.clone()),
+                        Target::Auto,
+                    ));
+                }
+            }
             PaletteItemContent::TerminalLine(line, _content) => {
                 if !preview {
                     ctx.submit_command(Command::new(
@@ -295,6 +306,7 @@ pub struct PaletteViewData {
     pub config: Arc<Config>,
     pub focus_area: FocusArea,
     pub terminal: Arc<TerminalSplitData>,
+    pub plugin_commands: Arc<Vec<CommandDefinition>>,
 }
 
 impl Lens<LapceTabData, PaletteViewData> for PaletteViewLens {
@@ -911,6 +923,12 @@ impl PaletteViewData {
                     indices: vec![],
                 })
             })
+            .chain(self.plugin_commands.iter().map(|c| PaletteItem {
+                content: PaletteItemContent::PluginCommand(c.clone()),
+                filter_text: c.palette_text(),
+                score: 0,
+                indices: vec![],
+            }))
             .collect();
     }
 
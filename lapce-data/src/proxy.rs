@@ -16,8 +16,15 @@ use flate2::read::GzDecoder;
 use lapce_proxy::dispatch::Dispatcher;
 use lapce_rpc::buffer::{BufferHeadResponse, BufferId, NewBufferResponse};
 use lapce_rpc::core::{CoreNotification, CoreRequest};
+use lapce_rpc::handshake::Handshake;
 use lapce_rpc::plugin::PluginDescription;
-use lapce_rpc::proxy::{ProxyRequest, ReadDirResponse};
+use lapce_rpc::proxy::{
+    DiagnosticsBundleResponse, LanguageServersResponse, PluginIconResponse,
+    PluginStatsResponse, PrepareSaveResponse, ProxyRequest, ReadDirResponse,
+    RefreshDiagnosticsResponse, RenamePreviewResponse, ServersForPathResponse,
+    TriggerCharacterOverride, VersionsResponse, WorkspaceEditResponse,
+    NO_LANGUAGE_SERVER,
+};
 use lapce_rpc::source_control::FileDiff;
 use lapce_rpc::style::SemanticStyles;
 use lapce_rpc::terminal::TermId;
@@ -26,8 +33,12 @@ use lapce_rpc::{stdio_transport, Callback};
 use lapce_rpc::{ControlFlow, Handler};
 use lsp_types::request::GotoTypeDefinitionResponse;
 use lsp_types::{
-    CodeActionResponse, CompletionItem, CompletionResponse, DocumentSymbolResponse,
-    GotoDefinitionResponse, InlayHint, SymbolInformation, TextEdit,
+    CallHierarchyIncomingCall, CallHierarchyItem, CallHierarchyOutgoingCall,
+    CodeActionKind, CodeActionResponse, CodeLens, CompletionItem,
+    CompletionResponse, CompletionTriggerKind, DocumentDiagnosticReport,
+    DocumentSymbolResponse, FormattingOptions, GotoDefinitionResponse, InlayHint,
+    LinkedEditingRanges, Moniker, SymbolInformation, TextEdit, TraceValue,
+    WorkspaceEdit,
 };
 use lsp_types::{Hover, Position};
 use lsp_types::{Location, Url};
@@ -38,6 +49,7 @@ use serde_json::Value;
 use thiserror::Error;
 use xi_rope::{Rope, RopeDelta};
 
+use crate::alert::AlertContentData;
 use crate::command::LapceUICommand;
 use crate::command::LAPCE_UI_COMMAND;
 use crate::config::Config;
@@ -68,6 +80,20 @@ pub enum RequestError {
     Rpc(Value),
 }
 
+impl RequestError {
+    /// Why there was nobody to ask, like `no language server for .xyz
+    /// files`, if the request failed for no language server taking the
+    /// document.
+    pub fn no_language_server(&self) -> Option<&str> {
+        match self {
+            RequestError::Rpc(error) if error["code"] == NO_LANGUAGE_SERVER => {
+                error["message"].as_str()
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct LapceProxy {
     pub tab_id: WidgetId,
@@ -75,6 +101,15 @@ pub struct LapceProxy {
     proxy_receiver: Arc<Receiver<Value>>,
     term_tx: Sender<(TermId, TermEvent)>,
     event_sink: ExtEventSink,
+    /// The completion trigger characters of each buffer, by the server
+    /// they are from
+    completion_triggers: Arc<Mutex<HashMap<BufferId, HashMap<String, Vec<String>>>>>,
+    /// The characters typing which formats what was typed, like the
+    /// completion triggers
+    on_type_formatting_triggers:
+        Arc<Mutex<HashMap<BufferId, HashMap<String, Vec<String>>>>>,
+    /// What the proxy told about itself once it started
+    proxy_handshake: Arc<Mutex<Option<Handshake>>>,
 }
 
 impl Handler for LapceProxy {
@@ -105,6 +140,14 @@ impl Handler for LapceProxy {
                     Target::Widget(self.tab_id),
                 );
             }
+            BufferOutOfSync { path, rev } => {
+                log::warn!("the proxy's text of {path:?} at {rev} is out of sync");
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::SyncBuffer { path },
+                    Target::Widget(self.tab_id),
+                );
+            }
             PublishDiagnostics { diagnostics } => {
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -112,6 +155,41 @@ impl Handler for LapceProxy {
                     Target::Widget(self.tab_id),
                 );
             }
+            CompletionTriggerCharacters {
+                buffer_id,
+                server,
+                trigger_characters,
+            } => {
+                self.completion_triggers
+                    .lock()
+                    .entry(buffer_id)
+                    .or_default()
+                    .insert(server, trigger_characters);
+            }
+            OnTypeFormattingTriggerCharacters {
+                buffer_id,
+                server,
+                trigger_characters,
+            } => {
+                self.on_type_formatting_triggers
+                    .lock()
+                    .entry(buffer_id)
+                    .or_default()
+                    .insert(server, trigger_characters);
+            }
+            CompletionPartialResult {
+                request_id,
+                position,
+                items,
+            } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdateCompletionPartial(
+                        request_id, position, items,
+                    ),
+                    Target::Widget(self.tab_id),
+                );
+            }
             WorkDoneProgress { progress } => {
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -119,6 +197,16 @@ impl Handler for LapceProxy {
                     Target::Widget(self.tab_id),
                 );
             }
+            RequestProgress {
+                request_id,
+                progress,
+            } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RequestProgress(request_id, progress),
+                    Target::Widget(self.tab_id),
+                );
+            }
             InstalledPlugins { plugins } => {
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -150,6 +238,17 @@ impl Handler for LapceProxy {
                     .term_tx
                     .send((term_id, TermEvent::UpdateContent(content)));
             }
+            TerminalExited { term_id, status } => {
+                log::info!("terminal {term_id:?} exited: {status:?}");
+            }
+            TerminalOutputTruncated {
+                term_id,
+                dropped_bytes,
+            } => {
+                log::warn!(
+                    "terminal {term_id:?} dropped {dropped_bytes} bytes of output"
+                );
+            }
             CloseTerminal { term_id } => {
                 let _ = self.term_tx.send((term_id, TermEvent::CloseTerminal));
                 let _ = self.event_sink.submit_command(
@@ -158,13 +257,28 @@ impl Handler for LapceProxy {
                     Target::Widget(self.tab_id),
                 );
             }
-            ProxyConnected {} => {
+            ProxyConnected { handshake } => {
+                let checked = match handshake.as_ref() {
+                    Some(proxy) => Handshake::new(VERSION).check(proxy, "the proxy"),
+                    None => Err(anyhow!(
+                        "the proxy is older than core v{VERSION}, update the proxy"
+                    )),
+                };
+                if let Err(err) = checked {
+                    self.stop();
+                    self.incompatible(&err.to_string());
+                    return ControlFlow::Continue;
+                }
+                *self.proxy_handshake.lock() = handshake;
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
                     LapceUICommand::ProxyUpdateStatus(ProxyStatus::Connected),
                     Target::Widget(self.tab_id),
                 );
             }
+            ProxyIncompatible { reason } => {
+                self.incompatible(&reason);
+            }
             HomeDir { path } => {
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -172,7 +286,111 @@ impl Handler for LapceProxy {
                     Target::Widget(self.tab_id),
                 );
             }
+            PluginError { plugin, error } => {
+                log::error!("plugin {plugin}: {error}");
+            }
+            PluginLog { plugin, line } => {
+                log::debug!(target: &format!("plugin::{plugin}"), "{line}");
+            }
+            PluginAutoDisabled { plugin, reason } => {
+                log::warn!(
+                    "plugin {plugin} was disabled because {reason}, enable it again once it's fixed"
+                );
+            }
+            PluginConfigReloaded { enabled, disabled } => {
+                log::info!(
+                    "plugins.toml was edited, enabled {enabled:?} and disabled {disabled:?}"
+                );
+            }
+            PluginInstallCancelled { plugin } => {
+                log::info!("installation of plugin {plugin} cancelled");
+            }
+            PluginShadowed {
+                plugin,
+                active,
+                shadowed,
+            } => {
+                log::warn!(
+                    "plugin {plugin} in {shadowed:?} is shadowed by the one in {active:?}"
+                );
+            }
+            DocumentTooLargeForLsp { path, size, limit } => {
+                log::warn!(
+                    "{path:?} is {size} bytes, over the {limit} bytes language servers are given, so they're not attached to it"
+                );
+            }
+            DocumentIgnoredForLsp { path } => {
+                log::info!("{path:?} is kept from language servers by lsp-ignore");
+            }
+            FormatterFailed {
+                path,
+                formatter,
+                error,
+            } => {
+                log::warn!("{formatter} couldn't format {path:?}: {error}");
+            }
+            DocumentTooLargeForServer {
+                path,
+                server,
+                reason,
+            } => {
+                log::warn!("{path:?} isn't given to {server}, it's {reason}");
+            }
+            UnexpectedServerProtocol { server, error } => {
+                log::error!(
+                    "{server} was shut down, check the command it's started with: {error}"
+                );
+            }
+            LanguageServerRequestFailed {
+                server,
+                method,
+                code,
+                message,
+            } => {
+                log::error!("{server} couldn't answer {method}: {message} ({code})");
+            }
+            LspTrace {
+                server,
+                direction,
+                method,
+                id,
+                message,
+            } => {
+                log::info!(
+                    "[LSP::{server}] {direction:?} {} #{}: {message}",
+                    method.as_deref().unwrap_or("response"),
+                    id.as_deref().unwrap_or("-"),
+                );
+            }
+            PluginCommands { commands } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::UpdatePluginCommands(commands),
+                    Target::Widget(self.tab_id),
+                );
+            }
             ListDir { .. } | DiffFiles { .. } => {}
+            SemanticTokensRefresh { paths } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RefreshSemanticStyles(paths),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            InlayHintsRefresh { paths } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RefreshInlayHints(paths),
+                    Target::Widget(self.tab_id),
+                );
+            }
+            CodeLensRefresh { paths } => {
+                let _ = self.event_sink.submit_command(
+                    LAPCE_UI_COMMAND,
+                    LapceUICommand::RefreshCodeLens(paths),
+                    Target::Widget(self.tab_id),
+                );
+            }
             WorkspaceFileChange {} => {
                 let _ = self.event_sink.submit_command(
                     LAPCE_UI_COMMAND,
@@ -193,6 +411,7 @@ impl LapceProxy {
     pub fn new(
         tab_id: WidgetId,
         workspace: LapceWorkspace,
+        config: &Config,
         term_tx: Sender<(TermId, TermEvent)>,
         event_sink: ExtEventSink,
     ) -> Self {
@@ -204,16 +423,20 @@ impl LapceProxy {
             proxy_receiver: Arc::new(proxy_receiver),
             term_tx,
             event_sink: event_sink.clone(),
+            completion_triggers: Arc::new(Mutex::new(HashMap::new())),
+            on_type_formatting_triggers: Arc::new(Mutex::new(HashMap::new())),
+            proxy_handshake: Arc::new(Mutex::new(None)),
         };
 
         let local_proxy = proxy.clone();
+        let config = config.clone();
         thread::spawn(move || {
             let _ = event_sink.submit_command(
                 LAPCE_UI_COMMAND,
                 LapceUICommand::ProxyUpdateStatus(ProxyStatus::Connecting),
                 Target::Widget(tab_id),
             );
-            let _ = local_proxy.start(workspace.clone());
+            let _ = local_proxy.start(workspace.clone(), &config);
             let _ = event_sink.submit_command(
                 LAPCE_UI_COMMAND,
                 LapceUICommand::ProxyUpdateStatus(ProxyStatus::Disconnected),
@@ -224,9 +447,16 @@ impl LapceProxy {
         proxy
     }
 
-    fn start(&self, workspace: LapceWorkspace) -> Result<()> {
+    fn start(&self, workspace: LapceWorkspace, config: &Config) -> Result<()> {
+        // the proxy takes nothing else before it
+        self.rpc.send_rpc_notification(
+            "handshake",
+            &json!({
+                "handshake": Handshake::new(VERSION),
+            }),
+        );
         if let Some(path) = workspace.path.as_ref() {
-            self.initialize(path.clone());
+            self.initialize(path.clone(), config);
         }
         let (core_sender, core_receiver) = crossbeam_channel::unbounded();
         match workspace.kind {
@@ -323,11 +553,76 @@ impl LapceProxy {
         Ok(())
     }
 
-    pub fn initialize(&self, workspace: PathBuf) {
+    /// Whether typing `c` in `buffer_id` should request completion. Until a
+    /// language server tells which characters it wants, `.` and `:` do.
+    pub fn is_completion_trigger(&self, buffer_id: BufferId, c: &str) -> bool {
+        match self.completion_triggers.lock().get(&buffer_id) {
+            Some(servers) => servers
+                .values()
+                .any(|characters| characters.iter().any(|t| t == c)),
+            None => c == "." || c == ":",
+        }
+    }
+
+    /// Whether typing `c` in `buffer_id` should have a language server
+    /// format what was typed.
+    pub fn is_on_type_formatting_trigger(
+        &self,
+        buffer_id: BufferId,
+        c: &str,
+    ) -> bool {
+        self.on_type_formatting_triggers
+            .lock()
+            .get(&buffer_id)
+            .map(|servers| {
+                servers
+                    .values()
+                    .any(|characters| characters.iter().any(|t| t == c))
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn initialize(&self, workspace: PathBuf, config: &Config) {
         self.rpc.send_rpc_notification(
             "initialize",
             &json!({
                 "workspace": workspace,
+                "file_watcher_poll_interval": config.lapce.file_watcher_poll_interval,
+                "max_language_servers": config.lapce.max_language_servers,
+                "completion_trigger_characters": TriggerCharacterOverride {
+                    add: config
+                        .editor
+                        .completion_trigger_characters_add
+                        .chars()
+                        .map(String::from)
+                        .collect(),
+                    remove: config
+                        .editor
+                        .completion_trigger_characters_remove
+                        .chars()
+                        .map(String::from)
+                        .collect(),
+                },
+                "lsp_settings": Config::lsp_settings(),
+                "max_lsp_document_size": config.lapce.max_lsp_document_size * 1024 * 1024,
+                "lsp_document_limits": Config::lsp_document_limits(),
+                "lsp_env": Config::lsp_env(),
+                "lsp_launch": Config::lsp_launch(),
+                "lsp_max_in_flight_requests": Config::lsp_max_in_flight_requests(),
+                "save_stages": Config::save_stages(),
+                "lsp_idle_timeout": config.lapce.language_server_idle_timeout * 60,
+                "lsp_memory_trim": Config::lsp_memory_trim(),
+                "lsp_retry_error_codes": config.lapce.language_server_retry_error_codes,
+                "max_message_size": config.lapce.max_message_size * 1024 * 1024,
+                "filter_completion": config.lapce.filter_completion_in_proxy,
+                "cache_workspace_symbols": config.lapce.cache_workspace_symbols,
+                "completion_documentation": config.lapce.completion_documentation,
+                "completion_suppression": config.editor.completion_suppression_window,
+                "lsp_ignore": config.lapce.lsp_ignore,
+                "workspace_roots": config.lapce.workspace_roots,
+                "per_root_language_servers": config.lapce.per_root_language_servers,
+                "lsp_startup_order": config.lapce.lsp_startup_order,
+                "lsp_attach_delay": config.lapce.lsp_attach_delay,
             }),
         )
     }
@@ -367,6 +662,7 @@ impl LapceProxy {
         term_id: TermId,
         cwd: Option<PathBuf>,
         shell: String,
+        output_limit: usize,
         raw: Arc<Mutex<RawTerminal>>,
     ) {
         let _ = self.term_tx.send((term_id, TermEvent::NewTerminal(raw)));
@@ -376,6 +672,7 @@ impl LapceProxy {
                 "term_id": term_id,
                 "cwd": cwd,
                 "shell": shell,
+                "output_limit": output_limit * 1024 * 1024,
             }),
         )
     }
@@ -431,6 +728,13 @@ impl LapceProxy {
             .send_rpc_notification("install_plugin", &json!({ "plugin": plugin }));
     }
 
+    pub fn cancel_plugin_install(&self, name: &str) {
+        self.rpc.send_rpc_notification(
+            "cancel_plugin_install",
+            &json!({ "name": name }),
+        );
+    }
+
     pub fn disable_plugin(&self, plugin: &PluginDescription) {
         self.rpc
             .send_rpc_notification("disable_plugin", &json!({ "plugin": plugin }))
@@ -446,6 +750,13 @@ impl LapceProxy {
             .send_rpc_notification("remove_plugin", &json!({ "plugin": plugin }));
     }
 
+    pub fn execute_plugin_command(&self, command: &str) {
+        self.rpc.send_rpc_notification(
+            "execute_plugin_command",
+            &json!({ "command": command }),
+        );
+    }
+
     pub fn get_buffer_head(
         &self,
         buffer_id: BufferId,
@@ -508,6 +819,17 @@ impl LapceProxy {
         self.rpc.send_rpc_request_value_async(request, f);
     }
 
+    pub fn close_buffer(&self, buffer_id: BufferId) {
+        self.completion_triggers.lock().remove(&buffer_id);
+        self.on_type_formatting_triggers.lock().remove(&buffer_id);
+        self.rpc.send_rpc_notification(
+            "close_buffer",
+            &json!({
+                "buffer_id": buffer_id,
+            }),
+        )
+    }
+
     pub fn update(&self, buffer_id: BufferId, delta: &RopeDelta, rev: u64) {
         self.rpc.send_rpc_notification(
             "update",
@@ -515,10 +837,126 @@ impl LapceProxy {
                 "buffer_id": buffer_id,
                 "delta": delta,
                 "rev": rev,
+                "base_rev": rev - 1,
+            }),
+        )
+    }
+
+    /// Gives the proxy the whole text of the buffer, once it told its text
+    /// is out of sync.
+    pub fn sync_buffer(&self, buffer_id: BufferId, content: String, rev: u64) {
+        self.rpc.send_rpc_notification(
+            "sync_buffer",
+            &json!({
+                "buffer_id": buffer_id,
+                "content": content,
+                "rev": rev,
             }),
         )
     }
 
+    /// Moves the open file at `path` over to the language servers of
+    /// `language_id`, e.g. when the user changes its file type.
+    pub fn change_document_language(&self, path: PathBuf, language_id: String) {
+        self.rpc.send_rpc_notification(
+            "change_document_language",
+            &json!({
+                "path": path,
+                "language_id": language_id,
+            }),
+        )
+    }
+
+    /// Restarts the language servers of `language_id` with `options` merged
+    /// into their initialization options.
+    pub fn restart_language_server_with_options(
+        &self,
+        language_id: &str,
+        options: Value,
+    ) {
+        self.rpc.send_rpc_notification(
+            "restart_language_server_with_options",
+            &json!({
+                "language_id": language_id,
+                "options": options,
+            }),
+        )
+    }
+
+    /// Traces the JSON-RPC traffic of the language servers of `language_id`
+    /// at `level`, to the trace files of the proxy or to the log of core.
+    pub fn set_lsp_trace(
+        &self,
+        language_id: &str,
+        level: TraceValue,
+        to_core: bool,
+    ) {
+        self.rpc.send_rpc_notification(
+            "set_lsp_trace",
+            &json!({
+                "language_id": language_id,
+                "level": level,
+                "to_core": to_core,
+            }),
+        )
+    }
+
+    /// Replaces the globs of the files kept from the language servers.
+    pub fn set_lsp_ignore(&self, patterns: &[String]) {
+        self.rpc.send_rpc_notification(
+            "set_lsp_ignore",
+            &json!({
+                "patterns": patterns,
+            }),
+        )
+    }
+
+    /// Sets how much the language servers of `language_id` log about
+    /// themselves: off, messages or verbose.
+    pub fn set_server_trace(&self, language_id: &str, value: TraceValue) {
+        self.rpc.send_rpc_notification(
+            "set_server_trace",
+            &json!({
+                "language_id": language_id,
+                "value": value,
+            }),
+        )
+    }
+
+    /// Gets the initialization options the language server of `language_id`
+    /// runs with, or null if there's none.
+    pub fn get_effective_server_config(
+        &self,
+        language_id: &str,
+        f: Box<dyn Callback>,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_effective_server_config",
+            &json!({
+                "language_id": language_id,
+            }),
+            f,
+        );
+    }
+
+    pub fn prepare_save(
+        &self,
+        rev: u64,
+        buffer_id: BufferId,
+        format: bool,
+        f: impl FnOnce(Result<PrepareSaveResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "prepare_save",
+            &json!({
+                "rev": rev,
+                "buffer_id": buffer_id,
+                "format": format,
+            }),
+            box_json_cb(f),
+        );
+    }
+
     pub fn save(&self, rev: u64, buffer_id: BufferId, f: Box<dyn Callback>) {
         self.rpc.send_rpc_request_async(
             "save",
@@ -581,19 +1019,38 @@ impl LapceProxy {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        trigger_character: Option<String>,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<CompletionResponse, RequestError>) + Send + 'static,
     ) {
+        let trigger_kind = if trigger_character.is_some() {
+            CompletionTriggerKind::TRIGGER_CHARACTER
+        } else {
+            CompletionTriggerKind::INVOKED
+        };
         self.rpc.send_rpc_request_async(
             "get_completion",
             &json!({
                 "request_id": request_id,
                 "buffer_id": buffer_id,
                 "position": position,
+                "trigger_kind": trigger_kind,
+                "trigger_character": trigger_character,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
     }
 
+    pub fn completion_accepted(&self, buffer_id: BufferId) {
+        self.rpc.send_rpc_notification(
+            "completion_accepted",
+            &json!({
+                "buffer_id": buffer_id,
+            }),
+        );
+    }
+
     pub fn completion_resolve(
         &self,
         buffer_id: BufferId,
@@ -615,6 +1072,7 @@ impl LapceProxy {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<Hover, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
@@ -623,6 +1081,7 @@ impl LapceProxy {
                 "request_id": request_id,
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
@@ -632,6 +1091,7 @@ impl LapceProxy {
         &self,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
         f: Box<dyn Callback>,
     ) {
         self.rpc.send_rpc_request_async(
@@ -639,6 +1099,7 @@ impl LapceProxy {
             &json!({
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
             }),
             f,
         );
@@ -648,6 +1109,7 @@ impl LapceProxy {
         &self,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<Vec<Location>, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
@@ -655,6 +1117,7 @@ impl LapceProxy {
             &json!({
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
@@ -687,11 +1150,160 @@ impl LapceProxy {
         );
     }
 
+    pub fn get_versions(
+        &self,
+        f: impl FnOnce(Result<VersionsResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc
+            .send_rpc_request_async("get_versions", &json!({}), box_json_cb(f));
+    }
+
+    /// The icon of the installed plugin `name`.
+    pub fn get_plugin_icon(
+        &self,
+        name: &str,
+        f: impl FnOnce(Result<PluginIconResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_plugin_icon",
+            &json!({
+                "name": name,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// The language servers running for `language_id`, whose ids can be
+    /// given to the requests to have one of them answer.
+    pub fn get_language_servers(
+        &self,
+        language_id: &str,
+        f: impl FnOnce(Result<LanguageServersResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_language_servers",
+            &json!({
+                "language_id": language_id,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// The language servers handling the open document at `path`, which is
+    /// an empty list when none does.
+    pub fn servers_for_path(
+        &self,
+        path: &Path,
+        f: impl FnOnce(Result<ServersForPathResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_servers_for_path",
+            &json!({
+                "path": path,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// Has the language servers of the open document at `path` compute its
+    /// diagnostics again, for when the ones shown are stuck.
+    pub fn refresh_diagnostics(
+        &self,
+        path: &Path,
+        f: impl FnOnce(Result<RefreshDiagnosticsResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "refresh_diagnostics",
+            &json!({
+                "path": path,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// What each running plugin has used, for finding the one slowing the
+    /// editor down.
+    pub fn get_plugin_stats(
+        &self,
+        f: impl FnOnce(Result<PluginStatsResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_plugin_stats",
+            &json!({}),
+            box_json_cb(f),
+        );
+    }
+
+    /// Asks the proxy what goes in a bug report: the versions, the plugins
+    /// and servers it runs, and the last lines it logged.
+    pub fn collect_diagnostics_bundle(
+        &self,
+        f: impl FnOnce(Result<DiagnosticsBundleResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "collect_diagnostics_bundle",
+            &json!({}),
+            box_json_cb(f),
+        );
+    }
+
+    /// Saves what goes in a bug report to `path`, as json.
+    pub fn save_diagnostics_bundle(
+        &self,
+        path: PathBuf,
+        f: impl FnOnce(Result<()>) + Send + 'static,
+    ) {
+        self.collect_diagnostics_bundle(move |result| {
+            f(result.map_err(|e| anyhow!(e)).and_then(|bundle| {
+                std::fs::write(&path, serde_json::to_vec_pretty(&bundle)?)?;
+                Ok(())
+            }))
+        });
+    }
+
+    /// Orders `edit` into the operations to apply it with, failing if it
+    /// was made to other versions of the open documents.
+    pub fn normalize_workspace_edit(
+        &self,
+        edit: &WorkspaceEdit,
+        f: impl FnOnce(Result<WorkspaceEditResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "normalize_workspace_edit",
+            &json!({
+                "edit": edit,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// The edit renaming the symbol at `position` in `path`, with the lines
+    /// it changes, to review before applying it with
+    /// `normalize_workspace_edit`.
+    pub fn rename_preview(
+        &self,
+        path: &Path,
+        position: Position,
+        new_name: &str,
+        f: impl FnOnce(Result<RenamePreviewResponse, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "rename_preview",
+            &json!({
+                "path": path,
+                "position": position,
+                "new_name": new_name,
+            }),
+            box_json_cb(f),
+        );
+    }
+
     pub fn get_definition(
         &self,
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<GotoDefinitionResponse, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
@@ -700,6 +1312,7 @@ impl LapceProxy {
                 "request_id": request_id,
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
@@ -710,6 +1323,7 @@ impl LapceProxy {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<GotoTypeDefinitionResponse, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
@@ -718,6 +1332,7 @@ impl LapceProxy {
                 "request_id": request_id,
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
@@ -769,6 +1384,52 @@ impl LapceProxy {
         );
     }
 
+    pub fn inlay_hint_resolve(
+        &self,
+        buffer_id: BufferId,
+        inlay_hint: InlayHint,
+        f: impl FnOnce(Result<InlayHint, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "inlay_hint_resolve",
+            &json!({
+                "buffer_id": buffer_id,
+                "inlay_hint": inlay_hint,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_code_lens(
+        &self,
+        buffer_id: BufferId,
+        f: impl FnOnce(Result<Vec<CodeLens>, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_code_lens",
+            &json!({
+                "buffer_id": buffer_id,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn code_lens_resolve(
+        &self,
+        buffer_id: BufferId,
+        code_lens: CodeLens,
+        f: impl FnOnce(Result<CodeLens, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "code_lens_resolve",
+            &json!({
+                "buffer_id": buffer_id,
+                "code_lens": code_lens,
+            }),
+            box_json_cb(f),
+        );
+    }
+
     pub fn get_semantic_tokens(
         &self,
         buffer_id: BufferId,
@@ -787,6 +1448,8 @@ impl LapceProxy {
         &self,
         buffer_id: BufferId,
         position: Position,
+        server_id: Option<&str>,
+        only: &[CodeActionKind],
         f: impl FnOnce(Result<CodeActionResponse, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
@@ -794,6 +1457,8 @@ impl LapceProxy {
             &json!({
                 "buffer_id": buffer_id,
                 "position": position,
+                "server_id": server_id,
+                "only": only,
             }),
             box_json_cb(f),
         );
@@ -802,17 +1467,173 @@ impl LapceProxy {
     pub fn get_document_formatting(
         &self,
         buffer_id: BufferId,
+        server_id: Option<&str>,
         f: impl FnOnce(Result<Vec<TextEdit>, RequestError>) + Send + 'static,
     ) {
         self.rpc.send_rpc_request_async(
             "get_document_formatting",
             &json!({
                 "buffer_id": buffer_id,
+                "server_id": server_id,
             }),
             box_json_cb(f),
         );
     }
 
+    pub fn on_type_formatting(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        ch: &str,
+        options: FormattingOptions,
+        f: impl FnOnce(Result<Vec<TextEdit>, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "on_type_formatting",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+                "ch": ch,
+                "options": options,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_linked_editing_range(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: impl FnOnce(Result<Option<LinkedEditingRanges>, RequestError>)
+            + Send
+            + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_linked_editing_range",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_moniker(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: impl FnOnce(Result<Option<Vec<Moniker>>, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_moniker",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn prepare_call_hierarchy(
+        &self,
+        buffer_id: BufferId,
+        position: Position,
+        f: impl FnOnce(Result<Option<Vec<CallHierarchyItem>>, RequestError>)
+            + Send
+            + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "prepare_call_hierarchy",
+            &json!({
+                "buffer_id": buffer_id,
+                "position": position,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_incoming_calls(
+        &self,
+        buffer_id: BufferId,
+        item: CallHierarchyItem,
+        f: impl FnOnce(Result<Option<Vec<CallHierarchyIncomingCall>>, RequestError>)
+            + Send
+            + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_incoming_calls",
+            &json!({
+                "buffer_id": buffer_id,
+                "item": item,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_outgoing_calls(
+        &self,
+        buffer_id: BufferId,
+        item: CallHierarchyItem,
+        f: impl FnOnce(Result<Option<Vec<CallHierarchyOutgoingCall>>, RequestError>)
+            + Send
+            + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_outgoing_calls",
+            &json!({
+                "buffer_id": buffer_id,
+                "item": item,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    pub fn get_document_diagnostic(
+        &self,
+        buffer_id: BufferId,
+        previous_result_id: Option<String>,
+        f: impl FnOnce(Result<DocumentDiagnosticReport, RequestError>) + Send + 'static,
+    ) {
+        self.rpc.send_rpc_request_async(
+            "get_document_diagnostic",
+            &json!({
+                "buffer_id": buffer_id,
+                "previous_result_id": previous_result_id,
+            }),
+            box_json_cb(f),
+        );
+    }
+
+    /// Whether the proxy takes the request `feature`, which proxies of the
+    /// same protocol version older than the request don't.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.proxy_handshake
+            .lock()
+            .as_ref()
+            .map(|handshake| handshake.supports(feature))
+            .unwrap_or(false)
+    }
+
+    /// Tells the user that core and the proxy can't work together.
+    fn incompatible(&self, reason: &str) {
+        log::error!("{reason}");
+        let _ = self.event_sink.submit_command(
+            LAPCE_UI_COMMAND,
+            LapceUICommand::ShowAlert(AlertContentData {
+                title: "Lapce can't work with the proxy".to_string(),
+                msg: reason.to_string(),
+                buttons: Vec::new(),
+            }),
+            Target::Widget(self.tab_id),
+        );
+    }
+
+    /// Has the proxy keep what it knows across a restart, before its window
+    /// or the editor is closed.
+    pub fn persist_state(&self) {
+        self.rpc.send_rpc_notification("persist_state", &json!({}));
+    }
+
     pub fn stop(&self) {
         self.rpc.send_rpc_notification("shutdown", &json!({}));
         // self.core_sender.send(json!({
@@ -536,8 +536,9 @@ impl LapceTerminalData {
         let local_proxy = proxy.clone();
         let local_raw = raw.clone();
         let shell = config.terminal.shell.clone();
+        let output_limit = config.terminal.output_buffer_size;
         std::thread::spawn(move || {
-            local_proxy.new_terminal(term_id, cwd, shell, local_raw);
+            local_proxy.new_terminal(term_id, cwd, shell, output_limit, local_raw);
         });
 
         Self {
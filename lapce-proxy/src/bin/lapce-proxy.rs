@@ -1,5 +1,18 @@
 use lapce_proxy::mainloop;
 
-fn main() {
-    mainloop();
+/// Serves core over stdio, or over a socket with `--tcp <addr>`, a loopback
+/// address, or, on Unix, `--unix <path>`.
+fn main() -> anyhow::Result<()> {
+    lapce_proxy::log_tail::init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>()[..] {
+        ["--tcp", addr] => lapce_proxy::mainloop_tcp(addr),
+        #[cfg(unix)]
+        ["--unix", path] => lapce_proxy::mainloop_unix(std::path::Path::new(path)),
+        [] => {
+            mainloop();
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!("unknown arguments {args:?}")),
+    }
 }
@@ -1,15 +1,23 @@
 use anyhow::{anyhow, Result};
 use lapce_core::buffer::CharIndicesJoin;
-use lapce_core::encoding::offset_utf8_to_utf16;
+use lapce_core::encoding::{offset_utf16_to_utf8, offset_utf8_to_utf16};
 use lapce_rpc::buffer::BufferId;
 use lsp_types::*;
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::path::PathBuf;
 use std::{borrow::Cow, path::Path, time::SystemTime};
-use xi_rope::{interval::IntervalBounds, rope::Rope, RopeDelta};
+use xi_rope::{
+    interval::IntervalBounds,
+    rope::{LinesMetric, Rope},
+    tree::TreeBuilder,
+    RopeDelta, RopeInfo,
+};
+
+/// The size of the chunks files are read into their rope in
+const READ_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Clone)]
 pub struct Buffer {
@@ -90,10 +98,42 @@ impl Buffer {
         )
     }
 
+    /// Replaces the whole text with `content` at `rev`, when it got out of
+    /// sync with core's.
+    pub fn replace(
+        &mut self,
+        content: String,
+        rev: u64,
+    ) -> TextDocumentContentChangeEvent {
+        self.rope = Rope::from(&content);
+        self.rev = rev;
+        TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: content,
+        }
+    }
+
     pub fn get_document(&self) -> String {
         self.rope.to_string()
     }
 
+    /// The index of the last line, which may be empty.
+    pub fn last_line(&self) -> usize {
+        self.rope.measure::<LinesMetric>()
+    }
+
+    /// How many lines the text has, not counting the empty one after a
+    /// final line break.
+    pub fn num_lines(&self) -> usize {
+        let last_line = self.last_line();
+        if self.offset_of_line(last_line) == self.len() {
+            last_line
+        } else {
+            last_line + 1
+        }
+    }
+
     pub fn offset_of_line(&self, offset: usize) -> usize {
         self.rope.offset_of_line(offset)
     }
@@ -123,6 +163,17 @@ impl Buffer {
         })
     }
 
+    /// Converts a UTF16 LSP position to a UTF8 offset
+    /// Returns `None` if it is not a valid UTF16 offset
+    pub fn offset_of_position(&self, position: &Position) -> Option<usize> {
+        let line_offset = self.offset_of_line(position.line as usize);
+        let col = offset_utf16_to_utf8(
+            self.char_indices_iter(line_offset..),
+            position.character as usize,
+        )?;
+        Some(line_offset + col)
+    }
+
     pub fn slice_to_cow<T: IntervalBounds>(&self, range: T) -> Cow<str> {
         self.rope.slice_to_cow(range)
     }
@@ -150,7 +201,59 @@ pub fn load_file(path: &Path) -> Result<String> {
 }
 
 fn load_rope(path: &Path) -> Result<Rope> {
-    Ok(Rope::from(read_path_to_string_lossy(path)?))
+    read_rope_lossy(File::open(path)?)
+}
+
+/// Reads `reader` into a rope a chunk at a time, so that large files are
+/// never held whole in a `String`. Like `read_path_to_string_lossy`, non-utf8
+/// data is replaced with the replacement character.
+fn read_rope_lossy(mut reader: impl Read) -> Result<Rope> {
+    let mut builder = TreeBuilder::<RopeInfo>::new();
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    // the start of a character cut off by the end of the last chunk
+    let mut pending = 0;
+    loop {
+        let read = match reader.read(&mut buf[pending..]) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let len = pending + read;
+        if read == 0 {
+            if len > 0 {
+                builder.push_str(&String::from_utf8_lossy(&buf[..len]));
+            }
+            break;
+        }
+
+        let mut chunk = &buf[..len];
+        while !chunk.is_empty() {
+            match std::str::from_utf8(chunk) {
+                Ok(text) => {
+                    builder.push_str(text);
+                    chunk = &[];
+                }
+                Err(e) => {
+                    let (valid, rest) = chunk.split_at(e.valid_up_to());
+                    builder.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(invalid) => {
+                            builder.push_str("\u{FFFD}");
+                            chunk = &rest[invalid..];
+                        }
+                        // finished by the next chunk
+                        None => {
+                            chunk = rest;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        pending = chunk.len();
+        buf.copy_within(len - pending..len, 0);
+    }
+    Ok(builder.build())
 }
 
 pub fn read_path_to_string_lossy<P: AsRef<Path>>(
@@ -259,3 +362,134 @@ pub fn get_mod_time<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
         .and_then(|meta| meta.modified())
         .ok()
 }
+
+/// Turns the paths of open files into the keys they're found by, so that a
+/// file is the same entry whatever path it's named by. On a filesystem that
+/// ignores case, `Foo.rs` and `foo.rs` are one file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathKeys {
+    pub case_insensitive: bool,
+}
+
+impl PathKeys {
+    /// The keys of the files on the filesystem of `dir`, found to ignore
+    /// case if `dir` can be reached with the case of its name swapped.
+    pub fn for_dir(dir: &Path) -> PathKeys {
+        let case_insensitive = dir
+            .ancestors()
+            .find_map(|dir| {
+                let name = dir.file_name()?.to_str()?;
+                let swapped = swap_case(name);
+                (swapped != name)
+                    .then(|| fs::metadata(dir.with_file_name(swapped)).is_ok())
+            })
+            .unwrap_or(cfg!(any(target_os = "macos", target_os = "windows")));
+        PathKeys { case_insensitive }
+    }
+
+    pub fn key(&self, path: &Path) -> String {
+        // a file that's gone can still be named by its directory
+        let path = fs::canonicalize(path)
+            .ok()
+            .or_else(|| {
+                let parent = fs::canonicalize(path.parent()?).ok()?;
+                Some(parent.join(path.file_name()?))
+            })
+            .unwrap_or_else(|| path.to_path_buf());
+        let key = path.to_string_lossy();
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        }
+    }
+}
+
+fn swap_case(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_uppercase() {
+                c.to_ascii_lowercase()
+            } else {
+                c.to_ascii_uppercase()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives out what it reads a few bytes at a time, cutting characters
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_read_rope_lossy() {
+        let mut content = "fn main() {\n    println!(\"héllo wörld 🦀\");\n}\n"
+            .as_bytes()
+            .to_vec();
+        content.extend_from_slice(b"\xff\xfe invalid \xe2\x82");
+        let expected = String::from_utf8_lossy(&content).to_string();
+
+        let rope = read_rope_lossy(Trickle(&content)).unwrap();
+        assert_eq!(rope.to_string(), expected);
+
+        let rope = read_rope_lossy(content.as_slice()).unwrap();
+        assert_eq!(rope.to_string(), expected);
+    }
+
+    #[test]
+    fn test_num_lines() {
+        let lines = |text: &str| {
+            Buffer {
+                language_id: String::new(),
+                id: BufferId(1),
+                rope: Rope::from(text),
+                path: PathBuf::from("/main.rs"),
+                rev: 0,
+                mod_time: None,
+            }
+            .num_lines()
+        };
+        assert_eq!(lines(""), 0);
+        assert_eq!(lines("fn main() {}"), 1);
+        assert_eq!(lines("fn main() {\n}\n"), 2);
+        assert_eq!(lines("fn main() {\n}\n\n"), 3);
+    }
+
+    #[test]
+    fn test_path_keys() {
+        let dir = std::env::temp_dir().join("lapce-test-path-keys");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Foo.rs"), "").unwrap();
+
+        let insensitive = PathKeys {
+            case_insensitive: true,
+        };
+        let key = insensitive.key(&dir.join("Foo.rs"));
+        assert_eq!(insensitive.key(&dir.join("foo.rs")), key);
+        assert_eq!(insensitive.key(&dir.join("./FOO.RS")), key);
+        // still the same key once the file is gone
+        fs::remove_file(dir.join("Foo.rs")).unwrap();
+        assert_eq!(insensitive.key(&dir.join("Foo.rs")), key);
+
+        let sensitive = PathKeys::default();
+        assert_ne!(
+            sensitive.key(&dir.join("Foo.rs")),
+            sensitive.key(&dir.join("foo.rs"))
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(PathKeys::for_dir(&dir), sensitive);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use lsp_types::{Diagnostic, PublishDiagnosticsParams, Range, Url};
+
+use crate::buffer::Buffer;
+
+/// What published a set of diagnostics. Each source's diagnostics of a
+/// document replace only its own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiagnosticSource {
+    /// A language server, by its id
+    Server(String),
+    /// A plugin linting documents itself, by its name
+    Plugin(String),
+}
+
+/// The diagnostics of each document by their source, which core is sent
+/// merged.
+#[derive(Default)]
+pub struct DiagnosticStore {
+    documents: HashMap<Url, HashMap<DiagnosticSource, Vec<Diagnostic>>>,
+}
+
+impl DiagnosticStore {
+    /// Replaces the diagnostics `source` has for the document of `params`,
+    /// and gives back all the diagnostics of the document.
+    pub fn publish(
+        &mut self,
+        source: DiagnosticSource,
+        params: PublishDiagnosticsParams,
+    ) -> PublishDiagnosticsParams {
+        let sources = self.documents.entry(params.uri.clone()).or_default();
+        if params.diagnostics.is_empty() {
+            sources.remove(&source);
+        } else {
+            sources.insert(source, params.diagnostics);
+        }
+
+        let diagnostics = sources.values().flatten().cloned().collect();
+        if sources.is_empty() {
+            self.documents.remove(&params.uri);
+        }
+        PublishDiagnosticsParams {
+            uri: params.uri,
+            diagnostics,
+            version: params.version,
+        }
+    }
+
+    /// Drops the diagnostics `source` has for every document, e.g. once it
+    /// stopped, and gives back all the diagnostics left of each document
+    /// it had some for.
+    pub fn clear_source(
+        &mut self,
+        source: &DiagnosticSource,
+    ) -> Vec<PublishDiagnosticsParams> {
+        let mut cleared = Vec::new();
+        self.documents.retain(|uri, sources| {
+            if sources.remove(source).is_some() {
+                cleared.push(PublishDiagnosticsParams {
+                    uri: uri.clone(),
+                    diagnostics: sources.values().flatten().cloned().collect(),
+                    version: None,
+                });
+            }
+            !sources.is_empty()
+        });
+        cleared
+    }
+}
+
+/// Whether `range` is within `buffer`. Positions past the end of a line
+/// stand for the end of the line, as the protocol has it.
+pub fn range_within(buffer: &Buffer, range: &Range) -> bool {
+    let last_line = buffer.line_of_offset(buffer.len()) as u32;
+    range.start <= range.end && range.end.line <= last_line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use lapce_rpc::buffer::BufferId;
+    use lsp_types::Position;
+    use xi_rope::Rope;
+
+    use super::*;
+
+    fn diagnostic(line: u32, message: &str) -> Diagnostic {
+        Diagnostic {
+            range: Range::new(Position::new(line, 0), Position::new(line, 1)),
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_publish_by_source() {
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        let publish = |diagnostics| PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics,
+            version: None,
+        };
+        let server = DiagnosticSource::Server("rust-analyzer".to_string());
+        let plugin = DiagnosticSource::Plugin("lint".to_string());
+        let mut store = DiagnosticStore::default();
+
+        store.publish(server.clone(), publish(vec![diagnostic(0, "unused")]));
+        let merged =
+            store.publish(plugin.clone(), publish(vec![diagnostic(1, "too long")]));
+        assert_eq!(merged.diagnostics.len(), 2);
+
+        // clearing the plugin's keeps the server's
+        let merged = store.publish(plugin, publish(Vec::new()));
+        assert_eq!(merged.diagnostics, vec![diagnostic(0, "unused")]);
+        let merged = store.publish(server, publish(Vec::new()));
+        assert!(merged.diagnostics.is_empty());
+        assert!(store.documents.is_empty());
+    }
+
+    #[test]
+    fn test_clear_source() {
+        let main = Url::parse("file:///src/main.rs").unwrap();
+        let lib = Url::parse("file:///src/lib.rs").unwrap();
+        let publish = |uri: &Url, diagnostics| PublishDiagnosticsParams {
+            uri: uri.clone(),
+            diagnostics,
+            version: None,
+        };
+        let server = DiagnosticSource::Server("rust-analyzer".to_string());
+        let plugin = DiagnosticSource::Plugin("lint".to_string());
+        let mut store = DiagnosticStore::default();
+
+        store.publish(
+            server.clone(),
+            publish(&main, vec![diagnostic(0, "unused")]),
+        );
+        store.publish(plugin.clone(), publish(&main, vec![diagnostic(1, "long")]));
+        store.publish(server.clone(), publish(&lib, vec![diagnostic(2, "dead")]));
+
+        let mut cleared = store.clear_source(&server);
+        cleared.sort_by(|a, b| a.uri.as_str().cmp(b.uri.as_str()));
+        assert_eq!(cleared.len(), 2);
+        assert_eq!(cleared[0].uri, lib);
+        assert!(cleared[0].diagnostics.is_empty());
+        assert_eq!(cleared[1].uri, main);
+        assert_eq!(cleared[1].diagnostics, vec![diagnostic(1, "long")]);
+
+        // nothing's left of it to clear
+        assert!(store.clear_source(&server).is_empty());
+        assert_eq!(store.clear_source(&plugin).len(), 1);
+        assert!(store.documents.is_empty());
+    }
+
+    #[test]
+    fn test_range_within() {
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {\n}\n"),
+            path: PathBuf::from("/main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+        let range = |start: (u32, u32), end: (u32, u32)| {
+            Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+        };
+
+        assert!(range_within(&buffer, &range((0, 3), (0, 7))));
+        // past the end of the line
+        assert!(range_within(&buffer, &range((1, 0), (1, 80))));
+        assert!(range_within(&buffer, &range((2, 0), (2, 0))));
+        assert!(!range_within(&buffer, &range((3, 0), (3, 1))));
+        assert!(!range_within(&buffer, &range((1, 0), (0, 3))));
+    }
+}
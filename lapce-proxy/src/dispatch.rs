@@ -1,8 +1,18 @@
-use crate::buffer::{get_mod_time, load_file, Buffer};
-use crate::lsp::LspCatalog;
-use crate::plugin::PluginCatalog;
+use crate::buffer::{get_mod_time, load_file, Buffer, PathKeys};
+use crate::diagnostics::{DiagnosticSource, DiagnosticStore};
+use crate::log_tail;
+use crate::lsp::{activation_path, load_lsp_settings, LspCatalog};
+use crate::plugin::{
+    plugin_config_path, read_plugin_icon, InstallCancelled, PluginCatalog,
+};
+use crate::save::SavePipeline;
+use crate::snapshot::{snapshot_path, ProxySnapshot};
 use crate::terminal::Terminal;
-use crate::watcher::{FileWatcher, Notify, WatchToken};
+use crate::trace::TraceSetting;
+use crate::watcher::{is_network_filesystem, FileWatcher, Notify, WatchToken};
+use crate::workspace::{
+    normalize_workspace_edit, preview_text_edits, workspace_file,
+};
 use alacritty_terminal::event_loop::Msg;
 use alacritty_terminal::term::SizeInfo;
 use anyhow::{anyhow, Context, Result};
@@ -17,24 +27,49 @@ use grep_searcher::SearcherBuilder;
 use lapce_rpc::buffer::{BufferHeadResponse, BufferId, NewBufferResponse};
 use lapce_rpc::core::CoreNotification;
 use lapce_rpc::file::FileNodeItem;
-use lapce_rpc::proxy::{ProxyNotification, ProxyRequest, ReadDirResponse};
+use lapce_rpc::handshake::Handshake;
+use lapce_rpc::proxy::{
+    DiagnosticsBundleResponse, FileEditPreview, LanguageServersResponse,
+    LspStartupOrder, PluginIconResponse, PluginStatsResponse, ProxyNotification,
+    ProxyRequest, QueueStatsResponse, ReadDirResponse, RefreshDiagnosticsResponse,
+    ServersForPathResponse, VersionsResponse, WorkspaceEditOperation,
+    WorkspaceEditResponse, NO_LANGUAGE_SERVER,
+};
 use lapce_rpc::source_control::{DiffInfo, FileDiff};
 use lapce_rpc::terminal::TermId;
 use lapce_rpc::{self, Call, RequestId, RpcObject};
+use lsp_types::{PublishDiagnosticsParams, Url, WorkspaceEdit};
 use parking_lot::Mutex;
 use serde_json::json;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use std::{collections::HashSet, io::BufRead};
 use xi_rope::Rope;
 
+/// The version of the proxy, which core is told in the handshake
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 const OPEN_FILE_EVENT_TOKEN: WatchToken = WatchToken(1);
 const WORKSPACE_EVENT_TOKEN: WatchToken = WatchToken(2);
+const PLUGIN_CONFIG_EVENT_TOKEN: WatchToken = WatchToken(3);
+/// How long `plugins.toml` has to be left alone after an edit before it's
+/// read again, so that it's read once the editor is done writing it
+const PLUGIN_CONFIG_DEBOUNCE: Duration = Duration::from_millis(500);
+const NETWORK_FS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The largest message a language server or plugin may send by default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+/// How often language servers are checked for being idle
+const LSP_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the plugins are checked for being stuck handling a message
+const PLUGIN_WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a plugin may take to handle a message before it's interrupted
+const PLUGIN_STUCK_TIMEOUT: Duration = Duration::from_secs(30);
+type WaitingRequests = Vec<(RequestId, ProxyRequest)>;
 
 #[derive(Clone)]
 pub struct Dispatcher {
@@ -45,12 +80,37 @@ pub struct Dispatcher {
     #[allow(deprecated)]
     pub terminals: Arc<Mutex<HashMap<TermId, mio::channel::Sender<Msg>>>>,
 
+    /// The open files by their `path_keys` key
     open_files: Arc<Mutex<HashMap<String, BufferId>>>,
-    plugins: Arc<Mutex<PluginCatalog>>,
+    path_keys: Arc<Mutex<PathKeys>>,
+    pub(crate) plugins: Arc<Mutex<PluginCatalog>>,
     pub lsp: Arc<Mutex<LspCatalog>>,
+    /// The diagnostics published by the servers and the plugins
+    diagnostics: Arc<Mutex<DiagnosticStore>>,
     pub file_watcher: Arc<Mutex<Option<FileWatcher>>>,
+    /// Set to cancel the installation of the plugin
+    plugin_installs: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    /// The largest message in bytes a language server or plugin may send,
+    /// larger ones are dropped
+    pub max_message_size: Arc<AtomicUsize>,
     workspace_fs_change_handler: Arc<Mutex<Option<Sender<bool>>>>,
+    /// Bumped by each edit of `plugins.toml`, which is only read again once
+    /// it stops changing
+    plugin_config_edits: Arc<AtomicUsize>,
     last_diff: Arc<Mutex<DiffInfo>>,
+    /// The buffers core was asked for the whole text of, as their edits
+    /// can't be applied
+    out_of_sync: Arc<Mutex<HashSet<BufferId>>>,
+    /// The buffers opened while the plugins start, handed to the language
+    /// servers once the plugins started theirs. `None` once they are.
+    held_opens: Arc<Mutex<Option<Vec<BufferId>>>>,
+    /// The buffers opened less than `lsp_attach_delay` ago, not given to the
+    /// language servers yet, with the requests about them that need the
+    /// servers waiting until they are. Their edits and saves aren't sent
+    /// meanwhile, and a buffer closed by then never is given.
+    unattached: Arc<Mutex<HashMap<BufferId, WaitingRequests>>>,
+    /// In milliseconds
+    lsp_attach_delay: Arc<AtomicU32>,
 }
 
 impl Notify for Dispatcher {
@@ -61,25 +121,28 @@ impl Notify for Dispatcher {
 
 impl Dispatcher {
     pub fn new(sender: Sender<Value>) -> Dispatcher {
-        let plugins = PluginCatalog::new();
-        let dispatcher = Dispatcher {
-            sender: Arc::new(sender),
-            workspace: Arc::new(Mutex::new(None)),
-            buffers: Arc::new(Mutex::new(HashMap::new())),
-            open_files: Arc::new(Mutex::new(HashMap::new())),
-            terminals: Arc::new(Mutex::new(HashMap::new())),
-            plugins: Arc::new(Mutex::new(plugins)),
-            lsp: Arc::new(Mutex::new(LspCatalog::new())),
-            file_watcher: Arc::new(Mutex::new(None)),
-            last_diff: Arc::new(Mutex::new(DiffInfo::default())),
-            workspace_fs_change_handler: Arc::new(Mutex::new(None)),
-        };
-        *dispatcher.file_watcher.lock() = Some(FileWatcher::new(dispatcher.clone()));
-        dispatcher.lsp.lock().dispatcher = Some(dispatcher.clone());
+        let dispatcher = Dispatcher::without_plugins(sender);
+        // the buffers wait for the servers the plugins start
+        *dispatcher.held_opens.lock() = Some(Vec::new());
+        dispatcher.watch_plugin_config();
+        dispatcher.watch_plugins();
 
         let local_dispatcher = dispatcher.clone();
         thread::spawn(move || {
-            local_dispatcher.plugins.lock().reload();
+            let shadowed = {
+                let mut plugins = local_dispatcher.plugins.lock();
+                plugins.reload();
+                std::mem::take(&mut plugins.shadowed)
+            };
+            for plugin in shadowed {
+                local_dispatcher.send_rpc_notification(
+                    CoreNotification::PluginShadowed {
+                        plugin: plugin.name,
+                        active: plugin.active,
+                        shadowed: plugin.shadowed,
+                    },
+                );
+            }
             let plugins = { local_dispatcher.plugins.lock().items.clone() };
             local_dispatcher.send_notification(
                 "installed_plugins",
@@ -91,6 +154,8 @@ impl Dispatcher {
                 .plugins
                 .lock()
                 .start_all(local_dispatcher.clone());
+            local_dispatcher.plugins_started();
+            local_dispatcher.send_plugin_commands();
         });
 
         let local_dispatcher = dispatcher.clone();
@@ -106,44 +171,111 @@ impl Dispatcher {
             }
         });
 
-        dispatcher.send_notification("proxy_connected", json!({}));
+        dispatcher.send_rpc_notification(CoreNotification::ProxyConnected {
+            handshake: Some(Handshake::new(VERSION)),
+        });
+
+        dispatcher
+    }
 
+    /// A dispatcher that loads no plugin, giving the buffers opened in it to
+    /// the language servers without waiting for the servers of plugins.
+    pub(crate) fn without_plugins(sender: Sender<Value>) -> Dispatcher {
+        let dispatcher = Dispatcher {
+            sender: Arc::new(sender),
+            workspace: Arc::new(Mutex::new(None)),
+            buffers: Arc::new(Mutex::new(HashMap::new())),
+            open_files: Arc::new(Mutex::new(HashMap::new())),
+            path_keys: Arc::new(Mutex::new(PathKeys::default())),
+            terminals: Arc::new(Mutex::new(HashMap::new())),
+            plugins: Arc::new(Mutex::new(PluginCatalog::new())),
+            lsp: Arc::new(Mutex::new(LspCatalog::new())),
+            diagnostics: Arc::new(Mutex::new(DiagnosticStore::default())),
+            file_watcher: Arc::new(Mutex::new(None)),
+            plugin_installs: Arc::new(Mutex::new(HashMap::new())),
+            max_message_size: Arc::new(AtomicUsize::new(DEFAULT_MAX_MESSAGE_SIZE)),
+            last_diff: Arc::new(Mutex::new(DiffInfo::default())),
+            out_of_sync: Arc::new(Mutex::new(HashSet::new())),
+            held_opens: Arc::new(Mutex::new(None)),
+            unattached: Arc::new(Mutex::new(HashMap::new())),
+            lsp_attach_delay: Arc::new(AtomicU32::new(0)),
+            workspace_fs_change_handler: Arc::new(Mutex::new(None)),
+            plugin_config_edits: Arc::new(AtomicUsize::new(0)),
+        };
+        *dispatcher.file_watcher.lock() = Some(FileWatcher::new(dispatcher.clone()));
+        dispatcher.lsp.lock().dispatcher = Some(dispatcher.clone());
         dispatcher
     }
 
     pub fn mainloop(&self, receiver: Receiver<Value>) -> Result<()> {
+        let mut introduced = false;
         for msg in receiver {
             let rpc: RpcObject = msg.into();
             if rpc.is_response() {
             } else {
                 match rpc.into_rpc::<ProxyNotification, ProxyRequest>() {
+                    Ok(Call::Notification(ProxyNotification::Handshake {
+                        handshake,
+                    })) => {
+                        let proxy = Handshake::new(VERSION);
+                        if let Err(err) = proxy.check(&handshake, "core") {
+                            return Err(self.incompatible(err));
+                        }
+                        introduced = true;
+                    }
+                    Ok(_) if !introduced => {
+                        let err = anyhow!(
+                            "core didn't introduce itself, so it's older than \
+                             the proxy v{VERSION}, update core"
+                        );
+                        return Err(self.incompatible(err));
+                    }
                     Ok(Call::Request(id, request)) => {
                         self.handle_request(id, request);
                     }
                     Ok(Call::Notification(notification)) => {
                         if let ProxyNotification::Shutdown {} = &notification {
-                            for (_, sender) in self.terminals.lock().iter() {
-                                #[allow(deprecated)]
-                                let _ = sender.send(Msg::Shutdown);
+                            if let Err(err) = self.persist_state() {
+                                log::warn!("can't persist the proxy state: {err}");
                             }
-                            self.open_files.lock().clear();
-                            self.buffers.lock().clear();
-                            self.plugins.lock().stop();
-                            self.lsp.lock().stop();
-                            self.file_watcher.lock().take();
+                            self.shutdown();
                             return Ok(());
                         }
                         self.handle_notification(notification);
                     }
-                    Err(e) => {
-                        eprintln!("{e:?}")
-                    }
+                    Err(e) => log::warn!("invalid message from core: {e:?}"),
                 }
             }
         }
+        // core is gone without telling, so nothing is left to run for
+        self.shutdown();
         Ok(())
     }
 
+    /// Tells core why the proxy can't work with it, and stops everything so
+    /// that the proxy exits.
+    fn incompatible(&self, err: anyhow::Error) -> anyhow::Error {
+        eprintln!("{err}");
+        self.send_rpc_notification(CoreNotification::ProxyIncompatible {
+            reason: err.to_string(),
+        });
+        self.shutdown();
+        err
+    }
+
+    /// Stops the terminals, plugins and language servers.
+    fn shutdown(&self) {
+        for (_, sender) in self.terminals.lock().iter() {
+            #[allow(deprecated)]
+            let _ = sender.send(Msg::Shutdown);
+        }
+        self.open_files.lock().clear();
+        self.buffers.lock().clear();
+        self.plugins.lock().stop();
+        self.lsp.lock().stop();
+        self.file_watcher.lock().take();
+    }
+
     pub fn next<R: BufRead>(
         &self,
         reader: &mut R,
@@ -181,6 +313,39 @@ impl Dispatcher {
         let _ = self.sender.send(resp);
     }
 
+    /// Fails a language request about `buffer` for there being no server
+    /// to ask, so that core can tell it apart from an empty answer.
+    fn respond_no_language_server(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        detached: bool,
+    ) {
+        let message = if detached {
+            format!("{} is kept from language servers", buffer.path.display())
+        } else if let Some(extension) = buffer.path.extension() {
+            format!(
+                "no language server for .{} files",
+                extension.to_string_lossy()
+            )
+        } else if !buffer.language_id.is_empty() {
+            format!("no language server for {} files", buffer.language_id)
+        } else {
+            format!("no language server for {}", buffer.path.display())
+        };
+        let _ = self.sender.send(json!({
+            "id": id,
+            "error": {
+                "code": NO_LANGUAGE_SERVER,
+                "message": message,
+                "data": {
+                    "language_id": buffer.language_id,
+                    "detached": detached,
+                },
+            },
+        }));
+    }
+
     pub fn respond_rpc<T: serde::Serialize>(
         &self,
         id: RequestId,
@@ -205,6 +370,30 @@ impl Dispatcher {
             .send(serde_json::to_value(notification).unwrap());
     }
 
+    /// Sends core the diagnostics of the document of `params`, with those
+    /// of `source` replaced by the ones in `params`.
+    pub fn publish_diagnostics(
+        &self,
+        source: DiagnosticSource,
+        params: PublishDiagnosticsParams,
+    ) {
+        let diagnostics = self.diagnostics.lock().publish(source, params);
+        self.send_rpc_notification(CoreNotification::PublishDiagnostics {
+            diagnostics,
+        });
+    }
+
+    /// Drops what `source` published, sending core what's left of the
+    /// diagnostics of each document it had some for.
+    pub fn clear_diagnostics(&self, source: DiagnosticSource) {
+        let cleared = self.diagnostics.lock().clear_source(&source);
+        for diagnostics in cleared {
+            self.send_rpc_notification(CoreNotification::PublishDiagnostics {
+                diagnostics,
+            });
+        }
+    }
+
     pub fn send_notification(&self, method: &str, params: Value) {
         let _ = self.sender.send(json!({
             "method": method,
@@ -212,6 +401,318 @@ impl Dispatcher {
         }));
     }
 
+    /// What tells the file at `path` apart from the other files, however
+    /// it's reached.
+    pub(crate) fn path_key(&self, path: &Path) -> String {
+        let path_keys = *self.path_keys.lock();
+        path_keys.key(path)
+    }
+
+    /// The buffer of the file at `path`, if it's open.
+    pub(crate) fn open_buffer_id(&self, path: &Path) -> Option<BufferId> {
+        let path_keys = *self.path_keys.lock();
+        let key = path_keys.key(path);
+        self.open_files.lock().get(&key).copied()
+    }
+
+    /// The versions of the proxy and of the servers running in it.
+    fn versions(&self) -> VersionsResponse {
+        VersionsResponse {
+            proxy: VERSION.to_string(),
+            wasmer: wasmer::VERSION.to_string(),
+            servers: self.lsp.lock().server_versions(),
+        }
+    }
+
+    fn insert_open_file(&self, path: &Path, buffer_id: BufferId) {
+        let path_keys = *self.path_keys.lock();
+        let key = path_keys.key(path);
+        self.open_files.lock().insert(key, buffer_id);
+    }
+
+    /// The version of the open document at `uri` as the language servers
+    /// know it, which is the revision of its buffer.
+    fn document_version(&self, uri: &Url) -> Option<i32> {
+        let path = uri.to_file_path().ok()?;
+        let buffer_id = self.open_buffer_id(&path)?;
+        let rev = self.buffers.lock().get(&buffer_id)?.rev;
+        Some(rev as i32)
+    }
+
+    /// The lines `edit` changes in each file, in the open documents as they
+    /// are in their buffer and in the others as they are on disk.
+    pub fn preview_workspace_edit(
+        &self,
+        edit: &WorkspaceEdit,
+    ) -> Result<Vec<FileEditPreview>> {
+        let operations =
+            normalize_workspace_edit(edit, |uri| self.document_version(uri))?;
+        let workspace = self.workspace.lock().clone();
+        let mut files = Vec::new();
+        for operation in operations {
+            let (uri, edits) = match operation {
+                WorkspaceEditOperation::Edit { uri, edits } => (uri, edits),
+                WorkspaceEditOperation::Resource(_) => continue,
+            };
+            let open = uri.to_file_path().ok().and_then(|path| {
+                let buffer_id = self.open_buffer_id(&path)?;
+                self.buffers.lock().get(&buffer_id).cloned()
+            });
+            let buffer = match open {
+                Some(buffer) => buffer,
+                None => {
+                    let path = workspace_file(workspace.as_deref(), &uri)?;
+                    Buffer::new(BufferId(0), path)
+                }
+            };
+            files.push(FileEditPreview {
+                path: buffer.path.clone(),
+                changes: preview_text_edits(&buffer, &edits)?,
+            });
+        }
+        Ok(files)
+    }
+
+    /// Writes the snapshot of the workspace for the next proxy of it to
+    /// start from.
+    pub fn persist_state(&self) -> Result<()> {
+        let workspace = self
+            .workspace
+            .lock()
+            .clone()
+            .ok_or_else(|| anyhow!("no workspace"))?;
+        let path =
+            snapshot_path(&workspace).ok_or_else(|| anyhow!("no data directory"))?;
+        let mut snapshot = ProxySnapshot::new(&workspace);
+        snapshot.auto_disabled = self.plugins.lock().auto_disabled();
+        {
+            let lsp = self.lsp.lock();
+            snapshot.workspace_symbols = lsp.workspace_symbols();
+            snapshot.document_versions = lsp.document_versions();
+            snapshot.idle_servers = lsp.idle_servers();
+        }
+        snapshot.write(&path)
+    }
+
+    /// Starts from the snapshot of the workspace where it's still true. A
+    /// snapshot that can't be read or is out of date is ignored, leaving
+    /// everything to be done from scratch.
+    pub fn restore_state(&self) {
+        let workspace = match self.workspace.lock().clone() {
+            Some(workspace) => workspace,
+            None => return,
+        };
+        let path = match snapshot_path(&workspace) {
+            Some(path) if path.exists() => path,
+            _ => return,
+        };
+        let snapshot = match ProxySnapshot::read(&path, &workspace) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::info!("ignoring the proxy state in {path:?}: {err}");
+                return;
+            }
+        };
+        for name in snapshot.auto_disabled.iter() {
+            self.auto_disable_plugin(
+                name,
+                "it kept crashing before the editor was restarted".to_string(),
+            );
+        }
+        let symbols = if snapshot.workspace_unchanged() {
+            snapshot
+                .workspace_symbols
+                .iter()
+                .filter(|(_, symbols)| snapshot.unchanged(symbols))
+                .map(|(id, symbols)| (id.clone(), symbols.clone()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let mut lsp = self.lsp.lock();
+        lsp.restore_workspace_symbols(symbols);
+        lsp.restore_servers(snapshot.document_versions, snapshot.idle_servers);
+    }
+
+    fn set_lsp_ignore(&self, patterns: &[String]) {
+        let buffers = self.buffers.lock();
+        self.lsp.lock().set_ignore(patterns, buffers.values());
+    }
+
+    /// Starts the servers waiting for a file matching their activation globs
+    /// if the workspace has one, looking for them in the background.
+    pub fn activate_servers_in_workspace(&self) {
+        let workspace = match self.workspace.lock().clone() {
+            Some(workspace) => workspace,
+            None => return,
+        };
+        let mut dormant = self.lsp.lock().dormant_servers();
+        if dormant.is_empty() {
+            return;
+        }
+        let dispatcher = self.clone();
+        thread::spawn(move || {
+            for entry in ignore::Walk::new(&workspace).flatten() {
+                if dormant.is_empty() {
+                    return;
+                }
+                if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                    continue;
+                }
+                let path = activation_path(Some(&workspace), entry.path());
+                dormant.retain(|(exec_path, globs)| {
+                    if !globs.is_match(path) {
+                        return true;
+                    }
+                    dispatcher.lsp.lock().activate(exec_path);
+                    false
+                });
+            }
+        });
+    }
+
+    /// Shuts language servers down once they've been idle for `timeout`,
+    /// until the proxy stops.
+    fn check_idle_servers(&self, timeout: Duration) {
+        let dispatcher = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(LSP_IDLE_CHECK_INTERVAL.min(timeout));
+            let mut lsp = dispatcher.lsp.lock();
+            if lsp.dispatcher.is_none() {
+                return;
+            }
+            lsp.shut_down_idle();
+            lsp.trim_idle();
+        });
+    }
+
+    /// Interrupts the plugins stuck handling a message, for as long as the
+    /// plugins are around.
+    fn watch_plugins(&self) {
+        let plugins = Arc::downgrade(&self.plugins);
+        thread::spawn(move || loop {
+            thread::sleep(PLUGIN_WATCHDOG_INTERVAL);
+            match plugins.upgrade() {
+                Some(plugins) => {
+                    plugins.lock().interrupt_stuck(PLUGIN_STUCK_TIMEOUT)
+                }
+                None => return,
+            }
+        });
+    }
+
+    /// Called once the `initialize` of the plugin `name` returned, which
+    /// hands the held back buffers to the servers if it was the last one.
+    pub(crate) fn plugin_started(&self, name: &str) {
+        self.plugins.lock().started(name);
+        self.plugins_started();
+    }
+
+    /// Hands the buffers opened while the plugins were starting to the
+    /// language servers, unless a plugin still is.
+    fn plugins_started(&self) {
+        if !self.plugins.lock().is_starting() {
+            self.open_held_buffers();
+        }
+    }
+
+    /// Gives `buffer_id` to the language servers once it's been open for
+    /// `lsp_attach_delay`, if it's still open then, and answers the requests
+    /// about it made in the meantime.
+    fn attach_after_delay(&self, buffer_id: BufferId) {
+        let delay = self.lsp_attach_delay.load(Ordering::Relaxed);
+        if delay == 0 {
+            if let Some(buffer) = self.buffers.lock().get(&buffer_id) {
+                self.attach_buffer(buffer);
+            }
+            return;
+        }
+        self.unattached.lock().insert(buffer_id, Vec::new());
+        let dispatcher = self.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(delay as u64));
+            let waiting = {
+                // held so that no edit goes between attaching and the check
+                // of whether the buffer is attached yet
+                let buffers = dispatcher.buffers.lock();
+                let waiting = match dispatcher.unattached.lock().remove(&buffer_id) {
+                    Some(waiting) => waiting,
+                    None => return,
+                };
+                if let Some(buffer) = buffers.get(&buffer_id) {
+                    dispatcher.attach_buffer(buffer);
+                }
+                waiting
+            };
+            for (id, rpc) in waiting {
+                dispatcher.handle_request(id, rpc);
+            }
+        });
+    }
+
+    /// Gives `buffer` to the language servers, or holds it back for them
+    /// while the plugins are starting theirs.
+    fn attach_buffer(&self, buffer: &Buffer) {
+        match self.held_opens.lock().as_mut() {
+            Some(held) => held.push(buffer.id),
+            None => self.lsp.lock().open_buffer(buffer),
+        }
+    }
+
+    fn close_buffer(&self, buffer_id: BufferId) {
+        let waiting = self.unattached.lock().remove(&buffer_id);
+        for (id, _) in waiting.unwrap_or_default() {
+            self.respond(id, Err(anyhow!("the buffer was closed")));
+        }
+        if let Some(held) = self.held_opens.lock().as_mut() {
+            held.retain(|held| *held != buffer_id);
+        }
+        self.out_of_sync.lock().remove(&buffer_id);
+        let buffer = match self.buffers.lock().remove(&buffer_id) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        // the file may be open in another buffer still
+        if self.open_buffer_id(&buffer.path) == Some(buffer_id) {
+            let path_keys = *self.path_keys.lock();
+            self.open_files.lock().remove(&path_keys.key(&buffer.path));
+            if let Some(watcher) = self.file_watcher.lock().as_mut() {
+                watcher.unwatch(&buffer.path, OPEN_FILE_EVENT_TOKEN);
+            }
+        }
+        self.lsp.lock().close_buffer(&buffer);
+    }
+
+    fn open_held_buffers(&self) {
+        let held = match self.held_opens.lock().take() {
+            Some(held) => held,
+            None => return,
+        };
+        let buffers = self.buffers.lock();
+        let mut lsp = self.lsp.lock();
+        for buffer_id in held {
+            if let Some(buffer) = buffers.get(&buffer_id) {
+                lsp.open_buffer(buffer);
+            }
+        }
+    }
+
+    /// Disables the plugin `name` for the `reason` it keeps crashing, and
+    /// stops its servers, until the user enables it again.
+    pub(crate) fn auto_disable_plugin(&self, name: &str, reason: String) {
+        self.plugins.lock().auto_disable(name);
+        self.lsp.lock().stop_plugin_servers(name);
+        self.send_rpc_notification(CoreNotification::PluginAutoDisabled {
+            plugin: name.to_string(),
+            reason,
+        });
+    }
+
+    fn send_plugin_commands(&self) {
+        let commands = { self.plugins.lock().commands() };
+        self.send_rpc_notification(CoreNotification::PluginCommands { commands });
+    }
+
     fn handle_fs_events(&self) {
         let mut events = {
             self.file_watcher
@@ -225,11 +726,84 @@ impl Dispatcher {
             match token {
                 OPEN_FILE_EVENT_TOKEN => self.handle_open_file_fs_event(event),
                 WORKSPACE_EVENT_TOKEN => self.handle_workspace_fs_event(event),
+                PLUGIN_CONFIG_EVENT_TOKEN => {
+                    self.handle_plugin_config_fs_event(event)
+                }
                 _ => {}
             }
         }
     }
 
+    /// Watches the directory of `plugins.toml` rather than the file, which
+    /// editors often replace when they save it.
+    fn watch_plugin_config(&self) {
+        let path = plugin_config_path();
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return,
+        };
+        if let Err(e) = fs::create_dir_all(dir) {
+            log::warn!("can't watch {path:?}: {e}");
+            return;
+        }
+        let name = path.file_name().map(|name| name.to_os_string());
+        if let Some(watcher) = self.file_watcher.lock().as_mut() {
+            watcher.watch_filtered(
+                dir,
+                false,
+                PLUGIN_CONFIG_EVENT_TOKEN,
+                move |changed| changed.file_name() == name.as_deref(),
+            );
+        }
+    }
+
+    /// Enables and disables the plugins as `plugins.toml` says once it's
+    /// been left alone for `PLUGIN_CONFIG_DEBOUNCE`, and tells core.
+    fn handle_plugin_config_fs_event(&self, event: notify::Event) {
+        // reading the file is an event too
+        if !matches!(
+            event.kind,
+            notify::EventKind::Create(_)
+                | notify::EventKind::Modify(_)
+                | notify::EventKind::Remove(_)
+        ) {
+            return;
+        }
+        let edit = self.plugin_config_edits.fetch_add(1, Ordering::AcqRel) + 1;
+        let dispatcher = self.clone();
+        thread::spawn(move || {
+            thread::sleep(PLUGIN_CONFIG_DEBOUNCE);
+            if dispatcher.plugin_config_edits.load(Ordering::Acquire) != edit {
+                return;
+            }
+            let changes =
+                match dispatcher.plugins.lock().reload_config(dispatcher.clone()) {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        log::warn!("ignoring plugins.toml until it's fixed: {e}");
+                        return;
+                    }
+                };
+            if changes.enabled.is_empty() && changes.disabled.is_empty() {
+                return;
+            }
+            dispatcher.send_rpc_notification(
+                CoreNotification::PluginConfigReloaded {
+                    enabled: changes.enabled,
+                    disabled: changes.disabled,
+                },
+            );
+            let plugins = { dispatcher.plugins.lock().disabled.clone() };
+            dispatcher.send_notification(
+                "disabled_plugins",
+                json!({
+                    "plugins": plugins,
+                }),
+            );
+            dispatcher.send_plugin_commands();
+        });
+    }
+
     fn handle_open_file_fs_event(&self, event: notify::Event) {
         use notify::event::*;
         let path = match event.kind {
@@ -239,20 +813,16 @@ impl Dispatcher {
             }
         };
 
-        if let Some(path) = path.to_str() {
-            if let Some(buffer_id) = self.open_files.lock().get(path) {
-                if let Some(buffer) = self.buffers.lock().get_mut(buffer_id) {
-                    if get_mod_time(&buffer.path) == buffer.mod_time {
-                        return;
-                    }
-                    if let Ok(content) = load_file(&buffer.path) {
-                        self.send_rpc_notification(
-                            CoreNotification::OpenFileChanged {
-                                path: buffer.path.clone(),
-                                content,
-                            },
-                        );
-                    }
+        if let Some(buffer_id) = self.open_buffer_id(path) {
+            if let Some(buffer) = self.buffers.lock().get_mut(&buffer_id) {
+                if get_mod_time(&buffer.path) == buffer.mod_time {
+                    return;
+                }
+                if let Ok(content) = load_file(&buffer.path) {
+                    self.send_rpc_notification(CoreNotification::OpenFileChanged {
+                        path: buffer.path.clone(),
+                        content,
+                    });
                 }
             }
         }
@@ -269,6 +839,7 @@ impl Dispatcher {
                 notify::EventKind::Modify(_) => false,
                 _ => return,
             };
+            self.lsp.lock().invalidate_workspace_symbols(&event.paths);
 
             let mut handler = self.workspace_fs_change_handler.lock();
             if let Some(sender) = handler.as_mut() {
@@ -292,6 +863,11 @@ impl Dispatcher {
                 {
                     local_handler.lock().take();
                 }
+                {
+                    let lsp = local_dispatcher.lsp.lock();
+                    lsp.refresh_workspace_symbols();
+                    lsp.refresh_workspace_diagnostics();
+                }
 
                 let mut explorer_change = false;
                 for e in receiver {
@@ -325,8 +901,98 @@ impl Dispatcher {
     fn handle_notification(&self, rpc: ProxyNotification) {
         use ProxyNotification::*;
         match rpc {
-            Initialize { workspace } => {
+            Initialize {
+                workspace,
+                file_watcher_poll_interval,
+                max_language_servers,
+                completion_trigger_characters,
+                lsp_settings,
+                max_lsp_document_size,
+                lsp_document_limits,
+                lsp_env,
+                lsp_launch,
+                lsp_max_in_flight_requests,
+                save_stages,
+                lsp_idle_timeout,
+                lsp_memory_trim,
+                lsp_retry_error_codes,
+                max_message_size,
+                filter_completion,
+                cache_workspace_symbols,
+                completion_documentation,
+                completion_suppression,
+                lsp_ignore,
+                workspace_roots,
+                per_root_language_servers,
+                lsp_startup_order,
+                lsp_attach_delay,
+            } => {
                 *self.workspace.lock() = Some(workspace.clone());
+                *self.path_keys.lock() = PathKeys::for_dir(&workspace);
+                if let Some(max) = max_message_size.filter(|max| *max > 0) {
+                    self.max_message_size.store(max, Ordering::Relaxed);
+                }
+                self.lsp_attach_delay
+                    .store(lsp_attach_delay, Ordering::Relaxed);
+                {
+                    let mut lsp = self.lsp.lock();
+                    lsp.max_servers = max_language_servers.filter(|max| *max > 0);
+                    lsp.trigger_character_override = completion_trigger_characters;
+                    lsp.user_settings = lsp_settings;
+                    lsp.max_document_size =
+                        max_lsp_document_size.filter(|max| *max > 0);
+                    lsp.document_limits = lsp_document_limits;
+                    lsp.server_env = lsp_env;
+                    lsp.server_launch = lsp_launch;
+                    lsp.max_in_flight_requests = lsp_max_in_flight_requests;
+                    lsp.save_stages = save_stages;
+                    lsp.filter_completion = filter_completion;
+                    lsp.cache_workspace_symbols = cache_workspace_symbols;
+                    lsp.completion_documentation = completion_documentation;
+                    lsp.completion_suppression =
+                        Duration::from_millis(completion_suppression);
+                    lsp.set_workspace_roots(&workspace, &workspace_roots);
+                    lsp.per_root_languages =
+                        per_root_language_servers.into_iter().collect();
+                    lsp.idle_timeout = lsp_idle_timeout
+                        .filter(|timeout| *timeout > 0)
+                        .map(Duration::from_secs);
+                    lsp.memory_trim = lsp_memory_trim;
+                    // checked often enough for the shortest of the timeouts
+                    let timeout = lsp
+                        .memory_trim
+                        .values()
+                        .map(|trim| Duration::from_secs(trim.idle_timeout))
+                        .chain(lsp.idle_timeout)
+                        .min();
+                    if let Some(timeout) = timeout {
+                        self.check_idle_servers(timeout.max(Duration::from_secs(1)));
+                    }
+                    if let Some(codes) = lsp_retry_error_codes {
+                        lsp.retry_error_codes = codes;
+                    }
+                    lsp.workspace_settings = load_lsp_settings(
+                        &workspace.join(".lapce").join("settings.toml"),
+                    );
+                }
+                self.set_lsp_ignore(&lsp_ignore);
+                if lsp_startup_order == LspStartupOrder::Concurrent {
+                    self.open_held_buffers();
+                }
+                self.restore_state();
+                let poll_interval = file_watcher_poll_interval
+                    .filter(|interval| *interval > 0)
+                    .map(Duration::from_millis)
+                    .or_else(|| {
+                        is_network_filesystem(&workspace)
+                            .then_some(NETWORK_FS_POLL_INTERVAL)
+                    });
+                if let Some(interval) = poll_interval {
+                    *self.file_watcher.lock() = Some(
+                        FileWatcher::with_poll_interval(self.clone(), interval),
+                    );
+                    self.watch_plugin_config();
+                }
                 self.file_watcher.lock().as_mut().unwrap().watch(
                     &workspace,
                     true,
@@ -341,27 +1007,139 @@ impl Dispatcher {
                     );
                     *self.last_diff.lock() = diff;
                 }
+                self.activate_servers_in_workspace();
             }
-            Shutdown {} => {}
+            // handled by the main loop
+            Handshake { .. } | Shutdown {} => {}
             Update {
                 buffer_id,
                 delta,
                 rev,
+                base_rev,
             } => {
                 let mut buffers = self.buffers.lock();
                 let buffer = buffers.get_mut(&buffer_id).unwrap();
+                let base_rev = base_rev.unwrap_or_else(|| rev.saturating_sub(1));
+                if buffer.rev != base_rev || rev != base_rev + 1 {
+                    // applying the delta would garble the text
+                    if self.out_of_sync.lock().insert(buffer_id) {
+                        self.send_rpc_notification(
+                            CoreNotification::BufferOutOfSync {
+                                path: buffer.path.clone(),
+                                rev: buffer.rev,
+                            },
+                        );
+                    }
+                    return;
+                }
                 if let Some(content_change) = buffer.update(&delta, rev) {
-                    self.lsp.lock().update(buffer, &content_change, buffer.rev);
+                    // the servers get the text as it is once it's attached
+                    if self.unattached.lock().contains_key(&buffer_id) {
+                        return;
+                    }
+                    let mut lsp = self.lsp.lock();
+                    if !lsp.is_detached(buffer) {
+                        lsp.restart_evicted(&buffer.language_id);
+                    }
+                    lsp.update(buffer, &content_change, buffer.rev);
+                }
+            }
+            SyncBuffer {
+                buffer_id,
+                content,
+                rev,
+            } => {
+                self.out_of_sync.lock().remove(&buffer_id);
+                let mut buffers = self.buffers.lock();
+                if let Some(buffer) = buffers.get_mut(&buffer_id) {
+                    let content_change = buffer.replace(content, rev);
+                    if !self.unattached.lock().contains_key(&buffer_id) {
+                        self.lsp.lock().update(buffer, &content_change, rev);
+                    }
+                }
+            }
+            CompletionAccepted { buffer_id } => {
+                self.lsp.lock().completion_accepted(buffer_id);
+            }
+            CloseBuffer { buffer_id } => {
+                self.close_buffer(buffer_id);
+            }
+            ChangeDocumentLanguage { path, language_id } => {
+                if let Some(buffer_id) = self.open_buffer_id(&path) {
+                    let mut buffers = self.buffers.lock();
+                    if let Some(buffer) = buffers.get_mut(&buffer_id) {
+                        self.lsp
+                            .lock()
+                            .change_document_language(buffer, language_id);
+                    }
+                }
+            }
+            RestartLanguageServerWithOptions {
+                language_id,
+                options,
+            } => {
+                let buffers = self.buffers.lock();
+                self.lsp.lock().restart_language_server_with_options(
+                    &language_id,
+                    options,
+                    buffers.values(),
+                );
+            }
+            SetLspTrace {
+                language_id,
+                level,
+                to_core,
+            } => {
+                self.lsp
+                    .lock()
+                    .set_trace(&language_id, TraceSetting { level, to_core });
+            }
+            SetServerTrace { language_id, value } => {
+                self.lsp.lock().set_server_trace(&language_id, value);
+            }
+            SetLspIgnore { patterns } => {
+                self.set_lsp_ignore(&patterns);
+            }
+            PersistState {} => {
+                if let Err(err) = self.persist_state() {
+                    log::warn!("can't persist the proxy state: {err}");
                 }
             }
             InstallPlugin { plugin } => {
                 let catalog = self.plugins.clone();
                 let dispatcher = self.clone();
+                let cancel = Arc::new(AtomicBool::new(false));
+                self.plugin_installs
+                    .lock()
+                    .insert(plugin.name.clone(), cancel.clone());
                 std::thread::spawn(move || {
-                    if let Err(e) =
-                        catalog.lock().install_plugin(dispatcher.clone(), plugin)
+                    let name = plugin.name.clone();
+                    let result = catalog.lock().install_plugin(
+                        dispatcher.clone(),
+                        plugin,
+                        &cancel,
+                    );
                     {
-                        eprintln!("install plugin error {e}");
+                        let mut installs = dispatcher.plugin_installs.lock();
+                        // a newer installation of it may have started
+                        if installs
+                            .get(&name)
+                            .map(|c| Arc::ptr_eq(c, &cancel))
+                            .unwrap_or(false)
+                        {
+                            installs.remove(&name);
+                        }
+                    }
+                    match result {
+                        Ok(()) => {}
+                        Err(e) if e.is::<InstallCancelled>() => {
+                            dispatcher.send_rpc_notification(
+                                CoreNotification::PluginInstallCancelled {
+                                    plugin: name,
+                                },
+                            );
+                        }
+                        Err(e) => eprintln!("install plugin error {e}"),
                     }
                     let plugins = { dispatcher.plugins.lock().items.clone() };
                     dispatcher.send_notification(
@@ -370,8 +1148,14 @@ impl Dispatcher {
                             "plugins": plugins,
                         }),
                     );
+                    dispatcher.send_plugin_commands();
                 });
             }
+            CancelPluginInstall { name } => {
+                if let Some(cancel) = self.plugin_installs.lock().get(&name) {
+                    cancel.store(true, Ordering::Release);
+                }
+            }
             DisablePlugin { plugin } => {
                 let catalog = self.plugins.clone();
                 let dispatcher = self.clone();
@@ -388,7 +1172,8 @@ impl Dispatcher {
                         json!({
                             "plugins": plugins,
                         }),
-                    )
+                    );
+                    dispatcher.send_plugin_commands();
                 });
             }
             EnablePlugin { plugin } => {
@@ -407,7 +1192,8 @@ impl Dispatcher {
                         json!({
                             "plugins": plugins,
                         }),
-                    )
+                    );
+                    dispatcher.send_plugin_commands();
                 });
             }
             RemovePlugin { plugin } => {
@@ -435,14 +1221,22 @@ impl Dispatcher {
                             "plugins": disabled_plugins,
                         }),
                     );
+                    dispatcher.send_plugin_commands();
                 });
             }
+            ExecutePluginCommand { command } => {
+                if let Err(e) = self.plugins.lock().execute_command(&command) {
+                    eprintln!("execute plugin command error {e}");
+                }
+            }
             NewTerminal {
                 term_id,
                 cwd,
                 shell,
+                output_limit,
             } => {
-                let mut terminal = Terminal::new(term_id, cwd, shell, 50, 10);
+                let mut terminal =
+                    Terminal::new(term_id, cwd, shell, output_limit, 50, 10);
                 let tx = terminal.tx.clone();
                 self.terminals.lock().insert(term_id, tx);
                 let dispatcher = self.clone();
@@ -544,6 +1338,38 @@ impl Dispatcher {
 
     fn handle_request(&self, id: RequestId, rpc: ProxyRequest) {
         use ProxyRequest::*;
+        let waits_for_attach = match &rpc {
+            PrepareSave { buffer_id, .. } => Some(*buffer_id),
+            rpc => language_request_buffer(rpc),
+        };
+        if let Some(buffer_id) = waits_for_attach {
+            if let Some(waiting) = self.unattached.lock().get_mut(&buffer_id) {
+                waiting.push((id, rpc));
+                return;
+            }
+        }
+        if let Some(buffer_id) = language_request_buffer(&rpc) {
+            // the servers of the buffer may have been shut down for being idle
+            let buffers = self.buffers.lock();
+            if let Some(buffer) = buffers.get(&buffer_id) {
+                let mut lsp = self.lsp.lock();
+                if !lsp.is_detached(buffer) {
+                    lsp.restart_evicted(&buffer.language_id);
+                }
+                let formatted = matches!(
+                    rpc,
+                    GetDocumentFormatting {
+                        server_id: None,
+                        ..
+                    }
+                ) && lsp.has_formatter(&buffer.language_id);
+                if lsp.buffer_clients(buffer).is_empty() && !formatted {
+                    let detached = lsp.is_detached(buffer);
+                    self.respond_no_language_server(id, buffer, detached);
+                    return;
+                }
+            }
+        }
         match rpc {
             NewBuffer { buffer_id, path } => {
                 self.file_watcher.lock().as_mut().unwrap().watch(
@@ -551,12 +1377,11 @@ impl Dispatcher {
                     false,
                     OPEN_FILE_EVENT_TOKEN,
                 );
-                self.open_files
-                    .lock()
-                    .insert(path.to_str().unwrap().to_string(), buffer_id);
+                self.insert_open_file(&path, buffer_id);
                 let buffer = Buffer::new(buffer_id, path);
                 let content = buffer.rope.to_string();
                 self.buffers.lock().insert(buffer_id, buffer);
+                self.attach_after_delay(buffer_id);
                 let resp = NewBufferResponse { content };
                 let _ = self.sender.send(json!({
                     "id": id,
@@ -582,12 +1407,26 @@ impl Dispatcher {
                 buffer_id,
                 position,
                 request_id,
+                trigger_kind,
+                trigger_character,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp
-                    .lock()
-                    .get_completion(id, request_id, buffer, position);
+                let lsp = self.lsp.lock();
+                if lsp.completion_suppressed(buffer_id, trigger_kind) {
+                    self.respond(id, Ok(json!([])));
+                    return;
+                }
+                lsp.get_completion(
+                    id,
+                    request_id,
+                    buffer,
+                    position,
+                    trigger_kind,
+                    trigger_character,
+                    server_id.as_deref(),
+                );
             }
             CompletionResolve {
                 buffer_id,
@@ -599,58 +1438,148 @@ impl Dispatcher {
                     .lock()
                     .completion_resolve(id, buffer, &completion_item);
             }
+            GetEffectiveServerConfig { language_id } => {
+                let config = self.lsp.lock().effective_server_config(&language_id);
+                self.respond(id, Ok(json!(config)));
+            }
             GetHover {
                 buffer_id,
                 position,
                 request_id,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_hover(id, request_id, buffer, position);
+                self.lsp.lock().get_hover(
+                    id,
+                    request_id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                );
             }
             GetSignature {
                 buffer_id,
                 position,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_signature(id, buffer, position);
+                self.lsp.lock().get_signature(
+                    id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                );
             }
             GetReferences {
                 buffer_id,
                 position,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_references(id, buffer, position);
+                self.lsp.lock().get_references(
+                    id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                );
             }
             GetDefinition {
                 buffer_id,
                 position,
                 request_id,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp
-                    .lock()
-                    .get_definition(id, request_id, buffer, position);
+                self.lsp.lock().get_definition(
+                    id,
+                    request_id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                );
             }
             GetTypeDefinition {
                 request_id,
                 buffer_id,
                 position,
+                server_id,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp
-                    .lock()
-                    .get_type_definition(id, request_id, buffer, position);
+                self.lsp.lock().get_type_definition(
+                    id,
+                    request_id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                );
             }
             GetInlayHints { buffer_id } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
                 self.lsp.lock().get_inlay_hints(id, buffer);
             }
+            InlayHintResolve {
+                buffer_id,
+                inlay_hint,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().inlay_hint_resolve(id, buffer, &inlay_hint);
+            }
+            GetCodeLens { buffer_id } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().get_code_lens(id, buffer);
+            }
+            CodeLensResolve {
+                buffer_id,
+                code_lens,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().code_lens_resolve(id, buffer, &code_lens);
+            }
+            GetLinkedEditingRange {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp
+                    .lock()
+                    .get_linked_editing_range(id, buffer, position);
+            }
+            GetMoniker {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().get_moniker(id, buffer, position);
+            }
+            PrepareCallHierarchy {
+                buffer_id,
+                position,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().prepare_call_hierarchy(id, buffer, position);
+            }
+            GetIncomingCalls { buffer_id, item } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().get_incoming_calls(id, buffer, item);
+            }
+            GetOutgoingCalls { buffer_id, item } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().get_outgoing_calls(id, buffer, item);
+            }
             GetSemanticTokens { buffer_id } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
@@ -659,10 +1588,18 @@ impl Dispatcher {
             GetCodeActions {
                 buffer_id,
                 position,
+                server_id,
+                only,
             } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_code_actions(id, buffer, position);
+                self.lsp.lock().get_code_actions(
+                    id,
+                    buffer,
+                    position,
+                    server_id.as_deref(),
+                    only,
+                );
             }
             GetDocumentSymbols { buffer_id } => {
                 let buffers = self.buffers.lock();
@@ -674,10 +1611,41 @@ impl Dispatcher {
                 let buffer = buffers.get(&buffer_id).unwrap();
                 self.lsp.lock().get_workspace_symbols(id, buffer, query);
             }
-            GetDocumentFormatting { buffer_id } => {
+            GetDocumentFormatting {
+                buffer_id,
+                server_id,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp.lock().get_document_formatting(
+                    id,
+                    buffer,
+                    server_id.as_deref(),
+                );
+            }
+            OnTypeFormatting {
+                buffer_id,
+                position,
+                ch,
+                options,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                self.lsp
+                    .lock()
+                    .on_type_formatting(id, buffer, position, ch, options);
+            }
+            GetDocumentDiagnostic {
+                buffer_id,
+                previous_result_id,
+            } => {
                 let buffers = self.buffers.lock();
                 let buffer = buffers.get(&buffer_id).unwrap();
-                self.lsp.lock().get_document_formatting(id, buffer);
+                self.lsp.lock().get_document_diagnostic(
+                    id,
+                    buffer,
+                    previous_result_id,
+                );
             }
             ReadDir { path } => {
                 let local_dispatcher = self.clone();
@@ -711,6 +1679,102 @@ impl Dispatcher {
                     local_dispatcher.respond_rpc(id, result);
                 });
             }
+            GetVersions {} => {
+                self.respond_rpc(id, Ok(self.versions()));
+            }
+            GetPluginIcon { name } => {
+                let icon = {
+                    let plugins = self.plugins.lock();
+                    plugins
+                        .items
+                        .get(&name)
+                        .or_else(|| plugins.disabled.get(&name))
+                        .and_then(|plugin| plugin.icon.clone())
+                };
+                let dispatcher = self.clone();
+                thread::spawn(move || {
+                    let result = icon
+                        .ok_or_else(|| anyhow!("{name} has no icon"))
+                        .and_then(|icon| read_plugin_icon(&icon))
+                        .map(|data| PluginIconResponse {
+                            data: base64::encode(data),
+                        });
+                    dispatcher.respond_rpc(id, result);
+                });
+            }
+            GetLanguageServers { language_id } => {
+                let servers = self.lsp.lock().language_servers(&language_id);
+                self.respond_rpc(id, Ok(LanguageServersResponse { servers }));
+            }
+            GetQueueStats {} => {
+                let servers = self.lsp.lock().queue_stats();
+                self.respond_rpc(id, Ok(QueueStatsResponse { servers }));
+            }
+            GetPluginStats {} => {
+                let plugins = self.plugins.lock().plugin_stats();
+                self.respond_rpc(id, Ok(PluginStatsResponse { plugins }));
+            }
+            CollectDiagnosticsBundle {} => {
+                let bundle = DiagnosticsBundleResponse {
+                    versions: self.versions(),
+                    platform: format!(
+                        "{} {}",
+                        std::env::consts::OS,
+                        std::env::consts::ARCH
+                    ),
+                    plugins: self.plugins.lock().plugin_reports(),
+                    servers: self.lsp.lock().server_reports(),
+                    log: log_tail::lines(),
+                };
+                self.respond_rpc(id, Ok(bundle));
+            }
+            GetServersForPath { path } => {
+                let buffer_id = self.open_buffer_id(&path);
+                let buffers = self.buffers.lock();
+                let servers = buffer_id
+                    .and_then(|buffer_id| buffers.get(&buffer_id))
+                    .map(|buffer| self.lsp.lock().buffer_servers(buffer))
+                    .unwrap_or_default();
+                drop(buffers);
+                self.respond_rpc(id, Ok(ServersForPathResponse { servers }));
+            }
+            RefreshDiagnostics { path } => {
+                let buffer_id = self.open_buffer_id(&path);
+                let buffers = self.buffers.lock();
+                let servers = buffer_id
+                    .and_then(|buffer_id| buffers.get(&buffer_id))
+                    .map(|buffer| self.lsp.lock().refresh_diagnostics(buffer))
+                    .unwrap_or_default();
+                drop(buffers);
+                self.respond_rpc(id, Ok(RefreshDiagnosticsResponse { servers }));
+            }
+            RenamePreview {
+                path,
+                position,
+                new_name,
+            } => {
+                let buffer_id = self.open_buffer_id(&path);
+                let buffers = self.buffers.lock();
+                match buffer_id.and_then(|buffer_id| buffers.get(&buffer_id)) {
+                    Some(buffer) => {
+                        self.lsp
+                            .lock()
+                            .get_rename_preview(id, buffer, position, new_name);
+                    }
+                    None => {
+                        self.respond(id, Err(anyhow!("{path:?} isn't open")));
+                    }
+                }
+            }
+            NormalizeWorkspaceEdit { edit } => {
+                let result = normalize_workspace_edit(&edit, |uri| {
+                    self.document_version(uri)
+                });
+                self.respond_rpc(
+                    id,
+                    result.map(|operations| WorkspaceEditResponse { operations }),
+                );
+            }
             GetFiles { .. } => {
                 if let Some(workspace) = self.workspace.lock().clone() {
                     let local_dispatcher = self.clone();
@@ -728,12 +1792,33 @@ impl Dispatcher {
                     });
                 }
             }
+            PrepareSave {
+                rev,
+                buffer_id,
+                format,
+            } => {
+                let buffers = self.buffers.lock();
+                let buffer = buffers.get(&buffer_id).unwrap();
+                if buffer.rev != rev {
+                    self.respond(id, Err(anyhow!("not the right rev")));
+                } else {
+                    let pipeline =
+                        SavePipeline::new(&self.lsp.lock(), buffer, format);
+                    let dispatcher = self.clone();
+                    thread::spawn(move || {
+                        let result = pipeline.run(&dispatcher);
+                        dispatcher.respond_rpc(id, result);
+                    });
+                }
+            }
             Save { rev, buffer_id } => {
                 if let Some(workspace) = self.workspace.lock().as_ref() {
                     let mut buffers = self.buffers.lock();
                     let buffer = buffers.get_mut(&buffer_id).unwrap();
                     let resp = buffer.save(rev).map(|_r| json!({}));
-                    self.lsp.lock().save_buffer(buffer, workspace);
+                    if !self.unattached.lock().contains_key(&buffer_id) {
+                        self.lsp.lock().save_buffer(buffer, workspace);
+                    }
                     self.respond(id, resp);
                 }
             }
@@ -749,9 +1834,7 @@ impl Dispatcher {
                 let resp = buffer.save(rev).map(|_r| json!({}));
                 if resp.is_ok() {
                     self.buffers.lock().insert(buffer_id, buffer);
-                    self.open_files
-                        .lock()
-                        .insert(path.to_str().unwrap().to_string(), buffer_id);
+                    self.insert_open_file(&path, buffer_id);
                 }
                 self.respond(id, resp);
             }
@@ -848,6 +1931,37 @@ pub struct DiffHunk {
     pub header: String,
 }
 
+/// The buffer whose language servers answer `rpc`, if they do.
+fn language_request_buffer(rpc: &ProxyRequest) -> Option<BufferId> {
+    use ProxyRequest::*;
+    match rpc {
+        GetCompletion { buffer_id, .. }
+        | CompletionResolve { buffer_id, .. }
+        | GetHover { buffer_id, .. }
+        | GetSignature { buffer_id, .. }
+        | GetReferences { buffer_id, .. }
+        | GetDefinition { buffer_id, .. }
+        | GetTypeDefinition { buffer_id, .. }
+        | GetInlayHints { buffer_id, .. }
+        | InlayHintResolve { buffer_id, .. }
+        | GetCodeLens { buffer_id, .. }
+        | CodeLensResolve { buffer_id, .. }
+        | GetLinkedEditingRange { buffer_id, .. }
+        | GetMoniker { buffer_id, .. }
+        | PrepareCallHierarchy { buffer_id, .. }
+        | GetIncomingCalls { buffer_id, .. }
+        | GetOutgoingCalls { buffer_id, .. }
+        | GetSemanticTokens { buffer_id, .. }
+        | GetCodeActions { buffer_id, .. }
+        | GetDocumentSymbols { buffer_id, .. }
+        | GetWorkspaceSymbols { buffer_id, .. }
+        | GetDocumentFormatting { buffer_id, .. }
+        | OnTypeFormatting { buffer_id, .. }
+        | GetDocumentDiagnostic { buffer_id, .. } => Some(*buffer_id),
+        _ => None,
+    }
+}
+
 fn git_init(workspace_path: &Path) -> Result<()> {
     Repository::init(workspace_path)?;
     Ok(())
@@ -1068,3 +2182,234 @@ fn file_get_head(workspace_path: &Path, path: &Path) -> Result<(String, String)>
         .to_string();
     Ok((id, content))
 }
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{CompletionOptions, ServerCapabilities};
+    use xi_rope::RopeDelta;
+
+    use super::*;
+    use crate::fake_lsp::FakeLspServer;
+
+    #[test]
+    fn test_no_language_server() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let buffer_id = BufferId(1);
+        let buffer = Buffer::new(buffer_id, PathBuf::from("/tmp/notes.xyz"));
+        dispatcher.buffers.lock().insert(buffer_id, buffer);
+
+        dispatcher.handle_request(
+            7,
+            ProxyRequest::GetCompletion {
+                request_id: 1,
+                buffer_id,
+                position: lsp_types::Position::new(0, 0),
+                trigger_kind: None,
+                trigger_character: None,
+                server_id: None,
+            },
+        );
+        let response = receiver.iter().find(|msg| msg["id"] == 7).unwrap();
+        assert_eq!(response["error"]["code"], NO_LANGUAGE_SERVER);
+        assert_eq!(
+            response["error"]["message"],
+            "no language server for .xyz files"
+        );
+    }
+
+    #[test]
+    fn test_buffer_out_of_sync() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let buffer_id = BufferId(1);
+        let path = PathBuf::from("/tmp/notes.xyz");
+        let mut buffer = Buffer::new(buffer_id, path.clone());
+        buffer.replace("ab".to_string(), 1);
+        dispatcher.buffers.lock().insert(buffer_id, buffer);
+        let update = |rev: u64, text: &str| ProxyNotification::Update {
+            buffer_id,
+            delta: RopeDelta::simple_edit(
+                xi_rope::Interval::new(0, 0),
+                Rope::from(text),
+                2,
+            ),
+            rev,
+            base_rev: Some(rev - 1),
+        };
+        let out_of_sync = || {
+            receiver
+                .try_iter()
+                .filter(|msg| msg["method"] == "buffer_out_of_sync")
+                .collect::<Vec<_>>()
+        };
+
+        // an edit of core was lost, so the next ones can't be applied
+        dispatcher.handle_notification(update(3, "x"));
+        dispatcher.handle_notification(update(4, "y"));
+        let asked = out_of_sync();
+        assert_eq!(asked.len(), 1);
+        assert_eq!(asked[0]["params"]["path"], json!(path));
+        assert_eq!(asked[0]["params"]["rev"], 1);
+        assert_eq!(dispatcher.buffers.lock()[&buffer_id].get_document(), "ab");
+
+        dispatcher.handle_notification(ProxyNotification::SyncBuffer {
+            buffer_id,
+            content: "yxzab".to_string(),
+            rev: 4,
+        });
+        dispatcher.handle_notification(ProxyNotification::Update {
+            buffer_id,
+            delta: RopeDelta::simple_edit(
+                xi_rope::Interval::new(0, 0),
+                Rope::from("w"),
+                5,
+            ),
+            rev: 5,
+            base_rev: Some(4),
+        });
+        assert!(out_of_sync().is_empty());
+        let buffers = dispatcher.buffers.lock();
+        assert_eq!(buffers[&buffer_id].get_document(), "wyxzab");
+        assert_eq!(buffers[&buffer_id].rev, 5);
+    }
+
+    #[test]
+    fn test_lsp_attach_delay() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        dispatcher.lsp_attach_delay.store(200, Ordering::Relaxed);
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-attach-delay-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.xyz");
+        fs::write(&path, "notes").unwrap();
+        let completion = |buffer_id| ProxyRequest::GetCompletion {
+            request_id: 1,
+            buffer_id,
+            position: lsp_types::Position::new(0, 0),
+            trigger_kind: None,
+            trigger_character: None,
+            server_id: None,
+        };
+        let response =
+            |id: u64| receiver.iter().find(|msg| msg["id"] == id).unwrap();
+
+        // closed right after being opened, the buffer is never attached
+        let closed = BufferId(1);
+        dispatcher.handle_request(
+            1,
+            ProxyRequest::NewBuffer {
+                buffer_id: closed,
+                path: path.clone(),
+            },
+        );
+        dispatcher.handle_request(2, completion(closed));
+        dispatcher.handle_notification(ProxyNotification::CloseBuffer {
+            buffer_id: closed,
+        });
+        assert_eq!(response(2)["error"]["message"], "the buffer was closed");
+        assert!(dispatcher.buffers.lock().is_empty());
+        assert_eq!(dispatcher.open_buffer_id(&path), None);
+        assert!(dispatcher.unattached.lock().is_empty());
+
+        // kept open, it's attached and the request waiting on it answered
+        let kept = BufferId(2);
+        dispatcher.handle_request(
+            3,
+            ProxyRequest::NewBuffer {
+                buffer_id: kept,
+                path: path.clone(),
+            },
+        );
+        dispatcher.handle_request(4, completion(kept));
+        assert!(dispatcher.unattached.lock().contains_key(&kept));
+        assert_eq!(response(4)["error"]["code"], NO_LANGUAGE_SERVER);
+        assert!(dispatcher.unattached.lock().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_edit_before_attach() {
+        let (client, _core, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                completion_provider: Some(CompletionOptions::default()),
+                ..Default::default()
+            })
+            .start_client();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        dispatcher.lsp_attach_delay.store(200, Ordering::Relaxed);
+        dispatcher.lsp.lock().insert_client(client.clone());
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-edit-before-attach-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let documents = || {
+            std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_millis(400)).ok()
+            })
+            .filter(|msg| {
+                msg["method"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .starts_with("textDocument/")
+            })
+            .collect::<Vec<_>>()
+        };
+        let open_edited = |buffer_id: BufferId, name: &str| {
+            let path = dir.join(name);
+            fs::write(&path, "fn main() {}").unwrap();
+            dispatcher.handle_request(
+                1,
+                ProxyRequest::NewBuffer {
+                    buffer_id,
+                    path: path.clone(),
+                },
+            );
+            dispatcher.handle_notification(ProxyNotification::Update {
+                buffer_id,
+                delta: RopeDelta::simple_edit(
+                    xi_rope::Interval::new(0, 0),
+                    Rope::from("pub "),
+                    12,
+                ),
+                rev: 2,
+                base_rev: Some(1),
+            });
+            dispatcher.handle_request(1, ProxyRequest::Save { rev: 2, buffer_id });
+        };
+
+        // the edits before the buffer is attached aren't sent, the document
+        // is opened with them once it is
+        open_edited(BufferId(1), "main.rs");
+        dispatcher.handle_request(
+            2,
+            ProxyRequest::GetCompletion {
+                request_id: 1,
+                buffer_id: BufferId(1),
+                position: lsp_types::Position::new(0, 0),
+                trigger_kind: None,
+                trigger_character: None,
+                server_id: None,
+            },
+        );
+        let sent = documents();
+        assert_eq!(sent[0]["method"], "textDocument/didOpen");
+        assert_eq!(
+            sent[0]["params"]["textDocument"]["text"],
+            "pub fn main() {}"
+        );
+        assert!(sent[1..]
+            .iter()
+            .all(|msg| msg["method"] == "textDocument/completion"));
+
+        // closed before then, the servers never hear of it
+        open_edited(BufferId(2), "lib.rs");
+        dispatcher.handle_notification(ProxyNotification::CloseBuffer {
+            buffer_id: BufferId(2),
+        });
+        assert!(documents().is_empty());
+        dispatcher.lsp.lock().stop();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
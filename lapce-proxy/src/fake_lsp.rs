@@ -0,0 +1,165 @@
+//! A language server answering with canned data, running in a thread of the
+//! tests, to drive clients end to end without a real server installed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufReader, Write},
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use lsp_types::ServerCapabilities;
+use serde_json::{json, Value};
+
+use crate::dispatch::Dispatcher;
+use crate::lsp::{
+    prepare_lsp_json, read_message, HeaderWatch, LspClient, LspServerSpec,
+    ServerConfig, ServerConnection,
+};
+use crate::pipe::{ChannelReader, ChannelWriter};
+
+/// The error a fake server answers the requests it has no data for with
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Default)]
+pub struct FakeLspServer {
+    capabilities: ServerCapabilities,
+    /// The result of the requests of each method
+    results: HashMap<String, Value>,
+    /// The methods whose requests are never answered
+    ignored: HashSet<String>,
+}
+
+impl FakeLspServer {
+    pub fn new() -> FakeLspServer {
+        FakeLspServer::default()
+    }
+
+    /// Sets the capabilities the server answers `initialize` with.
+    pub fn capabilities(mut self, capabilities: ServerCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Answers the requests of `method` with `result`.
+    pub fn respond(mut self, method: &str, result: Value) -> Self {
+        self.results.insert(method.to_string(), result);
+        self
+    }
+
+    /// Never answers the requests of `method`, like a server still busy
+    /// with them.
+    pub fn ignore(mut self, method: &str) -> Self {
+        self.ignored.insert(method.to_string());
+        self
+    }
+
+    /// Starts the server, giving the connection a client talks to it over
+    /// and the receiver of every message the server gets. The server stops
+    /// on `exit`, or once the client is gone.
+    pub fn start(self) -> (ServerConnection, Receiver<Value>) {
+        let (client_tx, server_rx) = crossbeam_channel::unbounded();
+        let (server_tx, client_rx) = crossbeam_channel::unbounded();
+        let (received_tx, received_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            self.serve(
+                BufReader::new(ChannelReader::new(server_rx)),
+                ChannelWriter(server_tx),
+                received_tx,
+            )
+        });
+
+        let connection = ServerConnection {
+            writer: Box::new(ChannelWriter(client_tx)),
+            reader: Box::new(ChannelReader::new(client_rx)),
+            stderr: None,
+            process: None,
+        };
+        (connection, received_rx)
+    }
+
+    /// Starts the server and a client of it for rust files, in a dispatcher
+    /// of its own that loads no plugin. Gives back the client, the receiver
+    /// of what the client sends core and that of what the server gets.
+    pub fn start_client(self) -> (Arc<LspClient>, Receiver<Value>, Receiver<Value>) {
+        let (sender, core) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let (connection, received) = self.start();
+        let client = LspClient::start(
+            fake_spec(),
+            ServerConfig::default(),
+            dispatcher,
+            connection,
+        );
+        (client, core, received)
+    }
+
+    fn serve(
+        self,
+        mut reader: BufReader<ChannelReader>,
+        mut writer: ChannelWriter,
+        received: Sender<Value>,
+    ) {
+        let watch = HeaderWatch::default();
+        while let Ok(message) = read_message(&mut reader, usize::MAX, &watch) {
+            let message: Value = match serde_json::from_str(&message) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let _ = received.send(message.clone());
+
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                return;
+            }
+            let id = match message.get("id") {
+                Some(id) if !self.ignored.contains(method) && !method.is_empty() => {
+                    id.clone()
+                }
+                // notifications and responses aren't answered
+                _ => continue,
+            };
+            let response = match method {
+                "initialize" => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": { "capabilities": self.capabilities },
+                }),
+                "shutdown" => json!({ "jsonrpc": "2.0", "id": id, "result": null }),
+                method => match self.results.get(method) {
+                    Some(result) => {
+                        json!({ "jsonrpc": "2.0", "id": id, "result": result })
+                    }
+                    None => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {
+                            "code": METHOD_NOT_FOUND,
+                            "message": format!("no data for {method}"),
+                        },
+                    }),
+                },
+            };
+            let response = prepare_lsp_json(&response).unwrap();
+            if writer.write_all(response.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The spec of a fake server for rust files.
+pub fn fake_spec() -> LspServerSpec {
+    serde_json::from_value::<LspServerSpec>(json!({
+        "exec_path": "fake",
+        "language_id": "rust",
+        "options": null,
+        "system_lsp": true,
+    }))
+    .unwrap()
+    .resolve(Path::new("/"))
+    .unwrap()
+}
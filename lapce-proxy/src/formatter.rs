@@ -0,0 +1,181 @@
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+use std::{
+    io::Write,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::lsp::one_or_many;
+
+/// What's replaced with the path of the document in the arguments of a
+/// formatter, for formatters that pick their settings by file name.
+const PATH_ARG: &str = "${path}";
+
+/// A command formatting the documents of some languages, as a plugin sends
+/// it in `register_formatter`. The command reads a document on stdin and
+/// writes it formatted to stdout. It's used for the languages whose servers
+/// can't format documents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormatterSpec {
+    pub command: String,
+    /// The languages the command formats. Plugins can also pass a single
+    /// `language_id`.
+    #[serde(alias = "language_id", deserialize_with = "one_or_many")]
+    pub language_ids: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Whether `command` names a program on the `PATH` rather than a path
+    /// inside the plugin's directory
+    #[serde(default)]
+    pub system: bool,
+    /// The plugin that registered the formatter
+    #[serde(skip)]
+    pub plugin: Option<String>,
+}
+
+impl FormatterSpec {
+    /// Checks the spec and resolves its command against the directory of
+    /// the plugin that sent it, which the command can't be outside of.
+    pub fn resolve(mut self, plugin_dir: &Path) -> Result<FormatterSpec> {
+        self.language_ids.retain(|id| !id.is_empty());
+        if self.language_ids.is_empty() {
+            return Err(anyhow!("no language id for {}", self.command));
+        }
+        if !self.system {
+            let inside = Path::new(&self.command)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !inside {
+                return Err(anyhow!(
+                    "{} isn't in the plugin's directory",
+                    self.command
+                ));
+            }
+            self.command = plugin_dir
+                .join(&self.command)
+                .to_str()
+                .ok_or_else(|| anyhow!("invalid command {}", self.command))?
+                .to_string();
+        }
+        Ok(self)
+    }
+
+    /// The file name of the command, to tell the user which formatter failed.
+    pub fn name(&self) -> String {
+        Path::new(&self.command)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.command.clone())
+    }
+
+    /// Runs the command on `text`, the content of the document at `path`,
+    /// and gives back the formatted text. When the command fails, what it
+    /// wrote to stderr is the error.
+    pub fn format(
+        &self,
+        workspace: Option<PathBuf>,
+        path: &Path,
+        text: String,
+    ) -> Result<String> {
+        let mut command = Command::new(&self.command);
+        if let Some(workspace) = workspace {
+            command.current_dir(workspace);
+        }
+        let path = path.to_string_lossy();
+        command.args(self.args.iter().map(|arg| arg.replace(PATH_ARG, &path)));
+        #[cfg(target_os = "windows")]
+        command.creation_flags(0x08000000);
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("can't run {}: {e}", self.command))?;
+
+        // written from another thread, the formatter may start writing
+        // before it read the whole document
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(text.as_bytes()));
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!(
+                "{} failed ({}): {}",
+                self.name(),
+                output.status,
+                stderr.trim()
+            ));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| anyhow!("{} wrote invalid utf8", self.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_format() {
+        let spec = FormatterSpec {
+            command: "tr".to_string(),
+            language_ids: vec!["plaintext".to_string()],
+            args: vec!["a-z".to_string(), "A-Z".to_string()],
+            system: true,
+            plugin: None,
+        };
+        let formatted = spec
+            .format(None, Path::new("/notes.txt"), "hello\n".to_string())
+            .unwrap();
+        assert_eq!(formatted, "HELLO\n");
+
+        let spec = FormatterSpec {
+            command: "sh".to_string(),
+            language_ids: vec!["plaintext".to_string()],
+            args: vec![
+                "-c".to_string(),
+                "echo \"can't format $0\" >&2; exit 2".to_string(),
+                PATH_ARG.to_string(),
+            ],
+            system: true,
+            plugin: None,
+        };
+        let err = spec
+            .format(None, Path::new("/notes.txt"), "hello\n".to_string())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "sh failed (exit status: 2): can't format /notes.txt"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let spec = |command: &str| FormatterSpec {
+            command: command.to_string(),
+            language_ids: vec!["plaintext".to_string()],
+            args: Vec::new(),
+            system: false,
+            plugin: None,
+        };
+        let plugin_dir = Path::new("/plugins/fmt");
+
+        let resolved = spec("bin/fmt").resolve(plugin_dir).unwrap();
+        assert_eq!(Path::new(&resolved.command), plugin_dir.join("bin/fmt"));
+        for outside in ["../other/fmt", "bin/../../fmt", "/usr/bin/fmt"] {
+            let err = spec(outside).resolve(plugin_dir).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{outside} isn't in the plugin's directory")
+            );
+        }
+    }
+}
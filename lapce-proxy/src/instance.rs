@@ -0,0 +1,219 @@
+//! The lock a proxy takes before changing what the proxies of every window
+//! share, `plugins.toml` and the directories of the installed plugins. Two
+//! windows changing them at once could leave either half written.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{plugin::plugin_config_path, snapshot::millis_since_epoch};
+
+/// How long a lock that can't be read is taken to be still being written
+/// by the proxy that created it
+const LOCK_WRITE_GRACE: Duration = Duration::from_secs(2);
+/// How many times taking the lock is tried, as other proxies may be
+/// breaking the same stale lock
+const LOCK_ATTEMPTS: usize = 3;
+
+/// Who holds the lock, as written in the lockfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    /// When it was taken, in milliseconds since the epoch
+    pub since: u64,
+}
+
+impl LockOwner {
+    fn read(path: &Path) -> Option<LockOwner> {
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+}
+
+/// The lock on the state the proxies share, released when dropped.
+#[derive(Debug)]
+pub struct InstanceLock {
+    path: PathBuf,
+    owner: LockOwner,
+    /// Kept open so that the lock can't be moved away on Windows while the
+    /// proxy runs, which is how it's told apart from a stale one there
+    file: Option<File>,
+}
+
+impl InstanceLock {
+    /// Takes the lock at `path`, breaking it if the proxy holding it is no
+    /// longer running. Fails if another proxy holds it.
+    pub fn acquire(path: &Path) -> Result<InstanceLock> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut holder = None;
+        for _ in 0..LOCK_ATTEMPTS {
+            match create(path) {
+                Ok(lock) => return Ok(lock),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+            match LockOwner::read(path) {
+                Some(owner) if is_running(owner.pid) => {
+                    holder = Some(owner);
+                    break;
+                }
+                Some(owner) => {
+                    if let Err(owner) = break_stale(path, &owner) {
+                        holder = owner;
+                        break;
+                    }
+                }
+                None if recently_modified(path) => break,
+                None => {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+        Err(match holder {
+            Some(owner) => anyhow!(
+                "another window (proxy {}) is changing the plugins",
+                owner.pid
+            ),
+            None => anyhow!("another window is changing the plugins"),
+        })
+    }
+
+    pub fn owner(&self) -> &LockOwner {
+        &self.owner
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // closed first, as Windows doesn't remove a file that's open
+        self.file.take();
+        // it's only removed if it's still ours, in case it was broken
+        if LockOwner::read(&self.path).as_ref() == Some(&self.owner) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where the lock of the proxies of all windows is.
+pub fn instance_lock_path() -> PathBuf {
+    plugin_config_path().with_file_name("proxy.lock")
+}
+
+fn create(path: &Path) -> io::Result<InstanceLock> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_SHARE_READ, others can read who holds the lock but can't
+        // move or remove it
+        options.share_mode(1);
+    }
+    let mut file = options.open(path)?;
+    let owner = LockOwner {
+        pid: process::id(),
+        since: millis_since_epoch(SystemTime::now()),
+    };
+    if let Err(e) = file.write_all(&serde_json::to_vec(&owner)?) {
+        drop(file);
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(InstanceLock {
+        path: path.to_path_buf(),
+        owner,
+        file: Some(file),
+    })
+}
+
+/// Moves the lock at `path`, which `owner` left behind, out of the way.
+/// Another proxy may have broken it already and taken the lock, which is
+/// then put back, failing with the new owner.
+fn break_stale(path: &Path, owner: &LockOwner) -> Result<(), Option<LockOwner>> {
+    let stale = path.with_extension(format!("stale-{}", process::id()));
+    if fs::rename(path, &stale).is_err() {
+        // it's gone already, or held open by its owner on Windows
+        return if path.exists() {
+            Err(LockOwner::read(path))
+        } else {
+            Ok(())
+        };
+    }
+    let moved = LockOwner::read(&stale);
+    if moved.as_ref() != Some(owner) {
+        // a link fails rather than replace a lock taken in the meantime
+        let _ = fs::hard_link(&stale, path);
+        let _ = fs::remove_file(&stale);
+        return Err(moved);
+    }
+    let _ = fs::remove_file(&stale);
+    Ok(())
+}
+
+fn recently_modified(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|elapsed| elapsed < LOCK_WRITE_GRACE)
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks that the process can be signalled
+    let running = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    running || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Every lock is taken as stale here, as the lock of a running proxy can't
+/// be moved away on Windows anyway.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_instance_lock() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-instance-lock-{}", process::id()));
+        let path = dir.join("proxy.lock");
+
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(LockOwner::read(&path).as_ref(), Some(lock.owner()));
+        assert!(InstanceLock::acquire(&path).is_err());
+        drop(lock);
+        assert!(!path.exists());
+
+        // the lock of a proxy that's gone is broken
+        let mut child = process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let stale = LockOwner {
+            pid: child.id(),
+            since: 0,
+        };
+        fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(lock.owner().pid, process::id());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        drop(lock);
+
+        // a lock just created may not be written yet
+        fs::write(&path, "{ \"pid\"").unwrap();
+        assert!(InstanceLock::acquire(&path).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
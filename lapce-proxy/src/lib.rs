@@ -1,14 +1,104 @@
 pub mod buffer;
+pub mod diagnostics;
 pub mod dispatch;
+#[cfg(test)]
+pub mod fake_lsp;
+pub mod formatter;
+pub mod instance;
+pub mod log_tail;
 pub mod lsp;
+pub mod markdown;
+pub mod patterns;
+pub mod pipe;
 pub mod plugin;
+pub mod queue;
+pub mod recording;
+pub mod registry;
+pub mod save;
+pub mod snapshot;
 pub mod terminal;
+pub mod trace;
+pub mod watchdog;
 pub mod watcher;
+pub mod workspace;
 
+use std::{
+    io::BufReader,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{Receiver, Sender};
 use dispatch::Dispatcher;
+use serde_json::Value;
 
 pub fn mainloop() {
     let (sender, receiver) = lapce_rpc::stdio();
+    run(sender, receiver);
+}
+
+/// Listens on `addr` and serves the first core that connects, for when the
+/// proxy runs on another host than core. Whoever connects can run commands,
+/// so only loopback addresses are listened on, which a core on another host
+/// reaches through a forwarded port, like one of ssh.
+pub fn mainloop_tcp(addr: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(&loopback_addrs(addr)?[..])?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let (sender, receiver) = lapce_rpc::transport(stream, reader);
+    run(sender, receiver);
+    Ok(())
+}
+
+fn loopback_addrs(addr: impl ToSocketAddrs) -> Result<Vec<SocketAddr>> {
+    let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+    if let Some(addr) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+        return Err(anyhow!("{addr} isn't a loopback address"));
+    }
+    Ok(addrs)
+}
+
+/// Listens on the Unix socket at `path` and serves the first core that
+/// connects. A socket left at `path` by a proxy that's gone is replaced, and
+/// as no other core is served, the socket is removed once one connects.
+#[cfg(unix)]
+pub fn mainloop_unix(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow::anyhow!("{path:?} exists and isn't a socket"));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let accepted = listener.accept();
+    let _ = std::fs::remove_file(path);
+    let (stream, _) = accepted?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let (sender, receiver) = lapce_rpc::transport(stream, reader);
+    run(sender, receiver);
+    Ok(())
+}
+
+fn run(sender: Sender<Value>, receiver: Receiver<Value>) {
     let dispatcher = Dispatcher::new(sender);
     let _ = dispatcher.mainloop(receiver);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback_addrs() {
+        assert!(loopback_addrs("127.0.0.1:9000").is_ok());
+        assert!(loopback_addrs("[::1]:9000").is_ok());
+        assert_eq!(
+            loopback_addrs("0.0.0.0:9000").unwrap_err().to_string(),
+            "0.0.0.0:9000 isn't a loopback address"
+        );
+        assert!(loopback_addrs("192.168.1.2:9000").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! The last lines the proxy logged, kept for the bug reports core asks for.
+
+use std::collections::VecDeque;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use parking_lot::{const_mutex, Mutex};
+
+/// How many of the lines the proxy logged are kept
+const LOG_TAIL_LINES: usize = 200;
+
+static TAIL: Mutex<Option<VecDeque<String>>> = const_mutex(None);
+
+/// A logger keeping the last lines the proxy logged at `Info` or above.
+/// The proxy logs with it alone when it runs on its own, and core chains
+/// it to its own logger when the proxy runs in it.
+pub struct TailLogger;
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("lapce_proxy")
+            && metadata.level() <= LevelFilter::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "[{}][{}] {}",
+            record.target(),
+            record.level(),
+            record.args()
+        );
+        let mut tail = TAIL.lock();
+        let tail = tail.get_or_insert_with(VecDeque::new);
+        if tail.len() == LOG_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs with the `TailLogger` alone, for the proxy running on its own.
+pub fn init() {
+    if log::set_logger(&TailLogger).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// The last lines the proxy logged, the oldest first.
+pub fn lines() -> Vec<String> {
+    TAIL.lock()
+        .as_ref()
+        .map(|tail| tail.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_tail_logger() {
+        let log = |target: &str, level: Level, message: &str| {
+            TailLogger.log(
+                &Record::builder()
+                    .target(target)
+                    .level(level)
+                    .args(format_args!("{message}"))
+                    .build(),
+            )
+        };
+        log("lapce_proxy::lsp", Level::Warn, "kept");
+        log("lapce_proxy::lsp", Level::Debug, "too verbose");
+        log("lapce_data::proxy", Level::Error, "core's own");
+        for _ in 0..LOG_TAIL_LINES {
+            log("lapce_proxy::plugin", Level::Info, "filler");
+        }
+        let lines = lines();
+        assert_eq!(lines.len(), LOG_TAIL_LINES);
+        assert!(lines
+            .iter()
+            .all(|line| line == "[lapce_proxy::plugin][INFO] filler"));
+    }
+}
@@ -1,37 +1,112 @@
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader, BufWriter, Write},
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    fs,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
-    process::{self, Child, ChildStderr, ChildStdout, Command, Stdio},
+    process::{self, Child, Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
         mpsc::channel,
         Arc,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use globset::{Glob, GlobSet};
 use jsonrpc_lite::{Id, JsonRpc, Params};
 use lapce_core::encoding::offset_utf16_to_utf8;
 use lapce_rpc::{
     buffer::BufferId,
+    core::{CoreNotification, TraceDirection},
+    proxy::{
+        CompletionDocumentation, CompletionItemData, CompletionMatch,
+        DocumentLimits, LanguageServerInfo, MemoryTrim, QueueStats,
+        RenamePreviewResponse, SaveStages, ServerDescriptor, ServerLaunch,
+        ServerReport, ServerState, ServerVersion, TriggerCharacterOverride,
+    },
     style::{LineStyle, SemanticStyles, Style},
     RequestId,
 };
 use log::error;
 use lsp_types::{request::GotoTypeDefinitionParams, *};
 use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{json, to_value, Value};
 
-use crate::{buffer::Buffer, dispatch::Dispatcher};
+use crate::{
+    buffer::Buffer,
+    diagnostics::DiagnosticSource,
+    dispatch::Dispatcher,
+    formatter::FormatterSpec,
+    markdown,
+    patterns::glob_set,
+    plugin::transform_completion,
+    queue::{BoundedQueue, Overflow},
+    recording::{LspRecorder, ReplayServer},
+    trace::{LspTracer, TraceSetting},
+};
 
 pub type Callback = Box<dyn Callable>;
+type PartialResultHandler = Arc<dyn Fn(&LspClient, Value) + Send + Sync>;
 const HEADER_CONTENT_LENGTH: &str = "content-length";
 const HEADER_CONTENT_TYPE: &str = "content-type";
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many requests can wait on a server before new ones get queued, for
+/// servers neither their plugin nor the user set a limit for
+const MAX_IN_FLIGHT_REQUESTS: usize = 4;
+/// How much of a message too large to read is looked at for its id
+const MESSAGE_ID_PREFIX_LEN: usize = 1024;
+/// How long a request that failed with one of the `retry_error_codes` waits
+/// before it's sent again, for the server to catch up with the changes
+const RETRY_DELAY: Duration = Duration::from_millis(150);
+/// How long completion waits on the other servers of a language once
+/// one of them has answered
+const COMPLETION_SERVER_TIMEOUT: Duration = Duration::from_millis(300);
+/// How many items of a completion list a server is asked to resolve at once
+/// when their documentation is resolved eagerly
+const COMPLETION_RESOLVE_CONCURRENCY: usize = 4;
+/// The most items of a completion list resolved eagerly, the first ones
+const MAX_EAGER_RESOLVES: usize = 50;
+/// How long resolving the items eagerly may hold back a completion list
+const COMPLETION_RESOLVE_TIMEOUT: Duration = Duration::from_millis(200);
+/// Servers giving more symbols than this for the whole workspace are always
+/// asked for the symbols matching a search
+const MAX_CACHED_SYMBOLS: usize = 100_000;
+/// How long a server may take to finish the header of a message it started
+/// writing. Servers that don't frame their messages with `Content-Length`
+/// never finish it.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Headers longer than this aren't from a language server
+const MAX_HEADER_SIZE: usize = 4096;
+/// How much of what a server speaking another protocol wrote is reported
+const UNEXPECTED_PROTOCOL_PREFIX_LEN: usize = 64;
+/// How many prepared call hierarchies a server's answers are kept for
+const CALL_HIERARCHY_CACHE_SIZE: usize = 32;
+/// How many messages can wait to be written to a server before the ones
+/// sending more wait for it to read them
+const OUTGOING_QUEUE_CAPACITY: usize = 256;
+/// How many of the last lines a server wrote to its stderr are kept for bug
+/// reports
+const STDERR_TAIL_LINES: usize = 50;
+/// What the names of environment variables whose values may be secrets
+/// contain, in upper case
+const SECRET_ENV_NAMES: &[&str] = &[
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "KEY",
+    "AUTH",
+    "CREDENTIAL",
+];
+/// What the values of secrets are replaced with in bug reports
+const REDACTED: &str = "<redacted>";
 
 pub trait Callable: Send {
     fn call(self: Box<Self>, client: &LspClient, result: Result<Value>);
@@ -45,112 +120,236 @@ impl<F: Send + FnOnce(&LspClient, Result<Value>)> Callable for F {
 
 pub struct LspCatalog {
     pub dispatcher: Option<Dispatcher>,
-    clients: HashMap<String, Arc<LspClient>>,
+    /// The servers of each language id, in the order they were started. A
+    /// server handling several languages is in here once for each of them.
+    clients: HashMap<String, Vec<Arc<LspClient>>>,
+    /// How many servers may run at once, if limited
+    pub max_servers: Option<usize>,
+    /// The user's changes to the completion trigger characters of servers
+    pub trigger_character_override: TriggerCharacterOverride,
+    /// Settings merged into the initialization options of servers, by
+    /// language id, from the user's settings and then the workspace's
+    pub user_settings: HashMap<String, Value>,
+    pub workspace_settings: HashMap<String, Value>,
+    /// Documents larger than this many bytes aren't opened in the servers
+    pub max_document_size: Option<usize>,
+    /// The user's limits on the documents given to servers, by server name
+    pub document_limits: HashMap<String, DocumentLimits>,
+    /// The user's limits on the requests servers answer at once, by server
+    /// name
+    pub max_in_flight_requests: HashMap<String, usize>,
+    /// What the documents of each language id go through before they're
+    /// saved
+    pub save_stages: HashMap<String, SaveStages>,
+    /// The user's environment variables for servers, by server name
+    pub server_env: HashMap<String, HashMap<String, String>>,
+    /// The user's commands to launch servers through, by server name
+    pub server_launch: HashMap<String, ServerLaunch>,
+    /// Servers nothing was sent to for this long are shut down
+    pub idle_timeout: Option<Duration>,
+    /// How the servers of each language id free memory when they're idle
+    pub memory_trim: HashMap<String, MemoryTrim>,
+    /// The error codes read requests are sent again once for
+    pub retry_error_codes: Vec<i64>,
+    /// Keep the symbols of the whole workspace from the servers to search
+    /// them locally
+    pub cache_workspace_symbols: bool,
+    /// Filter and rank complete lists of completion items before sending
+    /// them to core
+    pub filter_completion: bool,
+    /// When the documentation of completion items is resolved
+    pub completion_documentation: CompletionDocumentation,
+    /// How long after an item is accepted the completions triggered by a
+    /// character are ignored
+    pub completion_suppression: Duration,
+    /// The buffer a completion item was last accepted in, and when
+    completion_accepted: Option<(BufferId, Instant)>,
+    /// Options merged last into the initialization options of servers, by
+    /// their `exec_path`, when they're restarted with new options
+    option_overrides: HashMap<String, Value>,
+    /// How the traffic of the servers is traced, by language id, as set
+    /// from core
+    traces: HashMap<String, TraceSetting>,
+    /// How much the servers log about themselves with `$/logTrace`, by
+    /// language id
+    server_traces: HashMap<String, TraceValue>,
+    /// The commands formatting the documents of each language id whose
+    /// servers can't
+    formatters: HashMap<String, FormatterSpec>,
+    /// The documents kept from the servers for their size
+    detached: HashSet<BufferId>,
+    /// The user's globs of the files never given to the servers, relative
+    /// to the workspace
+    ignore: GlobSet,
+    /// The documents kept from the servers by `ignore`
+    ignored: HashSet<BufferId>,
+    /// Servers shut down to make room for others or for being idle, to start
+    /// them again when their language is used
+    evicted: HashMap<String, Vec<LspServerSpec>>,
+    /// Servers waiting for a file matching their activation globs to be
+    /// opened or found in the workspace to start
+    dormant: Vec<(GlobSet, LspServerSpec)>,
+    /// The roots of the projects in the workspace, the workspace itself
+    /// first. Files belong to the deepest root they're in.
+    workspace_roots: Vec<PathBuf>,
+    /// The language ids whose servers run once for each workspace root
+    /// rather than once for all of them
+    pub per_root_languages: HashSet<String>,
+    /// The servers started for a root once a file of their languages is
+    /// opened in it
+    per_root: Vec<LspServerSpec>,
+    /// The symbols of the whole workspace servers gave before the editor
+    /// was restarted, by server id, for the servers once they start
+    restored_symbols: HashMap<String, Vec<SymbolInformation>>,
+    /// The last versions servers were given of their documents before the
+    /// editor was restarted, by server id, for the servers once they start
+    restored_versions: HashMap<String, HashMap<Url, i32>>,
+    /// The servers that were shut down for being idle before the editor
+    /// was restarted, by id, which aren't started until they're needed
+    restored_idle: HashSet<String>,
 }
 
-pub struct LspState {
-    next_id: u64,
-    writer: Box<dyn Write + Send>,
-    process: Child,
-    pending: HashMap<u64, Callback>,
-    pub server_capabilities: Option<ServerCapabilities>,
-    pub opened_documents: HashMap<BufferId, Url>,
-    pub is_initialized: bool,
-    pub did_save_capabilities: Vec<DidSaveCapability>,
+/// How a language server talks to the proxy.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum LspTransport {
+    /// JSON-RPC over the server's stdin and stdout
+    #[default]
+    Stdio,
+    /// JSON-RPC over a TCP connection to the server on this port
+    Socket { port: u16 },
 }
 
-pub struct DocumentFilter {
-    /// The document must have this language id, if it exists
-    pub language_id: Option<String>,
-    /// The document's path must match this glob, if it exists
-    pub pattern: Option<globset::GlobMatcher>,
-    // TODO: URI Scheme from lsp-types document filter
+/// Everything needed to start a language server, as a plugin sends it in
+/// `start_lsp_server`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LspServerSpec {
+    pub exec_path: String,
+    /// The languages the server handles. Plugins can also pass a single
+    /// `language_id`.
+    #[serde(alias = "language_id", deserialize_with = "one_or_many")]
+    pub language_ids: Vec<String>,
+    #[serde(default)]
+    pub transport: LspTransport,
+    /// The arguments to start the server with. Older plugins pass them
+    /// in `options.binary.args` instead.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment variables set for the server process on top of the
+    /// proxy's, with the user's own for the server winning over them. An
+    /// empty value unsets the variable.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// A program the server's command is given to run it, with its
+    /// arguments, like `["nix-shell", "--run"]`, when the server can't be
+    /// run directly
+    #[serde(default)]
+    pub wrapper: Vec<String>,
+    /// Whether the server's command is given to the wrapper, or to the
+    /// system's shell without one, as one line for a shell
+    #[serde(default)]
+    pub shell: bool,
+    /// The largest documents the server is given
+    #[serde(default)]
+    pub document_limits: DocumentLimits,
+    /// How many requests the server is given to answer at once, for
+    /// servers that slow down when flooded
+    #[serde(default)]
+    pub max_in_flight_requests: Option<usize>,
+    /// Sent to the server as its `initializationOptions`
+    pub options: Option<Value>,
+    /// Whether `exec_path` names a program on the `PATH` rather than a
+    /// path inside the plugin's directory
+    pub system_lsp: Option<bool>,
+    /// The plugin that started the server
+    #[serde(skip)]
+    pub plugin: Option<String>,
+    /// The initialization options recommended in the plugin's manifest,
+    /// which `options` and the settings for the server are merged over
+    #[serde(skip)]
+    pub default_options: Option<Value>,
+    /// Globs of the files whose opening starts the server, from its plugin's
+    /// manifest. Without any, it starts right away.
+    #[serde(skip)]
+    pub activation: Vec<String>,
+    /// The workspace root the server runs for, if its languages get a
+    /// server for each root
+    #[serde(skip)]
+    pub root: Option<PathBuf>,
 }
-impl DocumentFilter {
-    /// Constructs a document filter from the LSP version
-    /// This ignores any fields that are badly constructed
-    fn from_lsp_filter_loose(filter: lsp_types::DocumentFilter) -> DocumentFilter {
-        DocumentFilter {
-            language_id: filter.language,
-            // TODO: clean this up
-            pattern: filter
-                .pattern
-                .as_deref()
-                .map(globset::Glob::new)
-                .and_then(Result::ok)
-                .map(|x| globset::Glob::compile_matcher(&x)),
+
+impl LspServerSpec {
+    /// Checks the spec and resolves it against the directory of the plugin
+    /// that sent it, so the catalog only ever deals with runnable specs.
+    pub fn resolve(mut self, plugin_dir: &Path) -> Result<LspServerSpec> {
+        if self.language_ids.iter().all(|id| id.is_empty()) {
+            return Err(anyhow!("no language id for {}", self.exec_path));
+        }
+        self.language_ids.retain(|id| !id.is_empty());
+        if let LspTransport::Socket { .. } = self.transport {
+            return Err(anyhow!("the socket transport isn't supported yet"));
         }
-    }
-}
-pub struct DidSaveCapability {
-    /// A filter on what documents this applies to
-    filter: DocumentFilter,
-    /// Whether we are supposed to include the text when sending a didSave event
-    include_text: bool,
-}
 
-#[derive(Clone)]
-pub struct LspClient {
-    exec_path: String,
-    args: Vec<String>,
-    options: Option<Value>,
-    state: Arc<Mutex<LspState>>,
-    dispatcher: Dispatcher,
-    active: Arc<AtomicBool>,
-}
+        self.exec_path = if self.system_lsp.unwrap_or(false) {
+            // System LSP should be handled by PATH during
+            // process creation, so we forbid anything that
+            // is not just an executable name
+            PathBuf::from(&self.exec_path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("invalid exec path {}", self.exec_path))?
+                .to_string()
+        } else {
+            plugin_dir
+                .join(&self.exec_path)
+                .to_str()
+                .ok_or_else(|| anyhow!("invalid exec path {}", self.exec_path))?
+                .to_string()
+        };
 
-impl LspCatalog {
-    pub fn new() -> LspCatalog {
-        LspCatalog {
-            dispatcher: None,
-            clients: HashMap::new(),
+        if self.args.is_empty() {
+            self.args = self.binary_args().unwrap_or_default();
         }
+
+        Ok(self)
     }
 
-    pub fn stop(&mut self) {
-        for (_, client) in self.clients.iter() {
-            client.stop();
+    /// Tells the server apart from the others running, the same every
+    /// time it's started.
+    fn id(&self) -> String {
+        let id = format!("{}:{}", self.language_ids.join(","), self.exec_path);
+        match self.root.as_ref() {
+            Some(root) => format!("{id}:{}", root.display()),
+            None => id,
         }
-        self.clients.clear();
-        self.dispatcher.take();
     }
 
-    pub fn stop_language_lsp(&mut self, lang: &String) {
-        if let Some(lsp) = self.clients.get(lang) {
-            lsp.stop();
-        }
+    /// Whether both specs are of the same instance of a server, which a
+    /// server running for each workspace root has one of per root.
+    fn same_server(&self, other: &LspServerSpec) -> bool {
+        self.exec_path == other.exec_path && self.root == other.root
     }
 
-    pub fn start_server(
-        &mut self,
-        exec_path: &str,
-        language_id: &str,
-        options: Option<Value>,
-    ) {
-        let args = self
-            .get_plugin_binary_args(options.clone())
-            .unwrap_or_default();
-        let client = LspClient::new(
-            language_id.to_string(),
-            exec_path,
-            options,
-            args,
-            self.dispatcher.clone().unwrap(),
-        );
-        self.clients.insert(language_id.to_string(), client);
+    /// The name the items of this server are tagged with, which is the
+    /// file name of its executable.
+    fn source_name(&self) -> String {
+        Path::new(&self.exec_path)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.exec_path.clone())
     }
 
-    fn get_plugin_binary_args(
-        &mut self,
-        option: Option<Value>,
-    ) -> Option<Vec<String>> {
-        let option = option?;
+    fn handles(&self, language_id: &str) -> bool {
+        self.language_ids.iter().any(|id| id == language_id)
+    }
+
+    fn binary_args(&self) -> Option<Vec<String>> {
+        let options = self.options.as_ref()?;
 
-        match option["binary"].as_object()?.get("args")?.as_array() {
-            Some(options) => {
+        match options["binary"].as_object()?.get("args")?.as_array() {
+            Some(args) => {
                 return Some(
-                    options
-                        .iter()
+                    args.iter()
                         .filter_map(Value::as_str)
                         .map(ToString::to_string)
                         .collect::<Vec<_>>(),
@@ -163,1608 +362,8764 @@ impl LspCatalog {
 
         None
     }
+}
 
-    pub fn new_buffer(
-        &self,
-        buffer_id: &BufferId,
-        path: &str,
-        language_id: &str,
-        text: String,
-    ) {
-        if let Some(client) = self.clients.get(language_id) {
-            {
-                let state = client.state.lock();
-                if !state.is_initialized {
-                    return;
-                }
-            }
+/// How urgently a request should reach the server. When the server already
+/// has as many requests to answer as it's allowed, new requests are queued and sent
+/// highest priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// Requests the editor makes on its own, like inlay hints
+    Background,
+    Normal,
+    /// Requests the user is waiting on, like a definition jump
+    Interactive,
+    /// Lifecycle requests, which are never queued
+    Immediate,
+}
 
-            let document_uri = Url::from_file_path(path).unwrap();
-            client.send_did_open(buffer_id, document_uri, language_id, text);
+impl RequestPriority {
+    fn from_method(method: &str) -> RequestPriority {
+        match method {
+            "initialize" | "shutdown" => RequestPriority::Immediate,
+            "textDocument/completion"
+            | "completionItem/resolve"
+            | "textDocument/definition"
+            | "textDocument/typeDefinition"
+            | "textDocument/references"
+            | "textDocument/rename"
+            | "textDocument/hover"
+            | "textDocument/signatureHelp"
+            | "textDocument/codeAction"
+            | "textDocument/willSaveWaitUntil"
+            | "textDocument/formatting" => RequestPriority::Interactive,
+            "textDocument/inlayHint"
+            | "inlayHint/resolve"
+            | "textDocument/documentHighlight"
+            | "textDocument/semanticTokens/full"
+            | "textDocument/diagnostic"
+            | "workspace/diagnostic"
+            | "textDocument/linkedEditingRange" => RequestPriority::Background,
+            _ => RequestPriority::Normal,
         }
     }
+}
 
-    pub fn save_buffer(&self, buffer: &Buffer, workspace_path: &Path) {
-        for (client_language_id, client) in self.clients.iter() {
-            {
-                let state = client.state.lock();
-                if !state.is_initialized {
-                    return;
-                }
-            }
+/// Whether the request only reads what the server knows, so that sending it
+/// again can't do anything twice.
+fn is_read_request(method: &str) -> bool {
+    matches!(
+        method,
+        "textDocument/completion"
+            | "completionItem/resolve"
+            | "textDocument/hover"
+            | "textDocument/signatureHelp"
+            | "textDocument/definition"
+            | "textDocument/typeDefinition"
+            | "textDocument/references"
+            | "textDocument/documentHighlight"
+            | "textDocument/documentSymbol"
+            | "textDocument/codeAction"
+            | "textDocument/codeLens"
+            | "codeLens/resolve"
+            | "textDocument/inlayHint"
+            | "inlayHint/resolve"
+            | "textDocument/semanticTokens/full"
+            | "textDocument/linkedEditingRange"
+            | "textDocument/moniker"
+            | "textDocument/prepareCallHierarchy"
+            | "callHierarchy/incomingCalls"
+            | "callHierarchy/outgoingCalls"
+            | "textDocument/diagnostic"
+            | "workspace/symbol"
+    )
+}
 
-            // Get rid of the workspace path prefix so that it can be used with the filters
-            let buffer_path = buffer
-                .path
-                .strip_prefix(workspace_path)
-                .unwrap_or(&buffer.path);
+/// Whether the value of the environment variable `name` may be a secret,
+/// judging by its name.
+fn is_secret_env(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRET_ENV_NAMES.iter().any(|secret| name.contains(secret))
+}
 
-            let mut passed_filter = client_language_id == &buffer.language_id;
-            let mut include_text = false;
-            if !passed_filter {
-                let lsp_state = client.state.lock();
+/// The fields of `capabilities` the server set, other than to `false`.
+fn capability_names(capabilities: &ServerCapabilities) -> Vec<String> {
+    let value = serde_json::to_value(capabilities).unwrap_or_default();
+    let mut names = value
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter(|(_, value)| !value.is_null() && **value != json!(false))
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
 
-                // TODO: Should we iterate in reverse order so that later capabilities
-                // can overwrite old ones?
-                // Find the first capability that wants this file, if any.
-                for cap in &lsp_state.did_save_capabilities {
-                    if let Some(language_id) = &cap.filter.language_id {
-                        if language_id != &buffer.language_id {
-                            continue;
-                        }
-                    }
+/// The actions of `response` of one of the kinds in `only`, or a subkind of
+/// one, like `source.organizeImports.rust` of `source`. Commands, which
+/// have no kind, are dropped.
+fn filter_code_actions(response: Value, only: &[CodeActionKind]) -> Value {
+    let actions =
+        match serde_json::from_value::<CodeActionResponse>(response.clone()) {
+            Ok(actions) => actions,
+            // left for core to fail to read
+            Err(_) => return response,
+        };
+    let is_wanted = |kind: &CodeActionKind| {
+        only.iter().any(|only| {
+            kind.as_str() == only.as_str()
+                || kind
+                    .as_str()
+                    .strip_prefix(only.as_str())
+                    .map(|rest| rest.starts_with('.'))
+                    .unwrap_or(false)
+        })
+    };
+    let actions = actions
+        .into_iter()
+        .filter(|action| match action {
+            CodeActionOrCommand::CodeAction(action) => {
+                action.kind.as_ref().map(is_wanted).unwrap_or(false)
+            }
+            CodeActionOrCommand::Command(_) => false,
+        })
+        .collect::<CodeActionResponse>();
+    json!(actions)
+}
 
-                    if let Some(pattern) = &cap.filter.pattern {
-                        if !pattern.is_match(buffer_path) {
-                            continue;
-                        }
-                    }
+/// Whether the request is one the user explicitly asked for, whose failure
+/// they should be told about. The errors of anything else the editor asks for
+/// on its own, or as the user types, only go to the log.
+fn is_user_request(method: &str) -> bool {
+    matches!(
+        method,
+        "textDocument/definition"
+            | "textDocument/typeDefinition"
+            | "textDocument/declaration"
+            | "textDocument/implementation"
+            | "textDocument/references"
+            | "textDocument/prepareRename"
+            | "textDocument/rename"
+            | "textDocument/formatting"
+            | "textDocument/rangeFormatting"
+            | "codeAction/resolve"
+            | "workspace/executeCommand"
+            | "textDocument/prepareCallHierarchy"
+            | "callHierarchy/incomingCalls"
+            | "callHierarchy/outgoingCalls"
+    )
+}
 
-                    passed_filter = true;
-                    include_text = cap.include_text;
-                    break;
-                }
+/// A read request in flight, kept to be sent again if it fails with one of
+/// the `retry_error_codes`.
+struct RetryableRequest {
+    method: String,
+    /// The params moved along with the changes made to the document since
+    /// the request was sent, `None` once a change couldn't be followed
+    params: Option<Params>,
+}
 
-                // Get rid of the mutex guard
-                drop(lsp_state);
-            }
+struct QueuedRequest {
+    priority: RequestPriority,
+    /// Keeps requests of the same priority in the order they were made
+    seq: Reverse<u64>,
+    method: String,
+    params: Params,
+    callback: Callback,
+}
 
-            if passed_filter {
-                let uri = client.get_uri(buffer);
-                let text = if include_text {
-                    Some(buffer.get_document())
-                } else {
-                    None
-                };
-                client.send_did_save(uri, text);
-            }
-        }
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &Self) -> bool {
+        (self.priority, self.seq) == (other.priority, other.seq)
     }
+}
 
-    pub fn get_semantic_tokens(&self, id: RequestId, buffer: &Buffer) {
-        let buffer = buffer.clone();
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+impl Eq for QueuedRequest {}
 
-                if !state.is_initialized {
-                    return;
-                }
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.semantic_tokens_provider.as_ref())
-                    .map(|prov| match prov {
-                        SemanticTokensServerCapabilities::SemanticTokensOptions(opts) => opts,
-                        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(reg) => &reg.semantic_tokens_options,
-                    }).map(|opts| {
-                        // TODO: handle opts.full delta
-                        opts.full.is_some()
-                    }).unwrap_or(false);
+impl Ord for QueuedRequest {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
+/// Merges the completion responses of every server of a language into
+/// the one response core gets.
+struct CompletionMerge {
+    id: RequestId,
+    /// The completion request of core, to tell it what partial results
+    /// belong to
+    request_id: usize,
+    position: Position,
+    dispatcher: Dispatcher,
+    /// How many servers haven't answered yet
+    waiting: usize,
+    /// Whether the servers still waiting have had their time
+    timed_out: bool,
+    sent: bool,
+    answered: bool,
+    is_incomplete: bool,
+    items: Vec<CompletionItem>,
+    /// Labels and details of the items so far, to skip duplicates
+    seen: HashSet<(String, Option<String>)>,
+    error: Option<anyhow::Error>,
+    /// The word before the cursor, to filter and rank complete lists of
+    /// items against when the proxy does it
+    filter: Option<String>,
+}
 
-            let uri = client.get_uri(&buffer);
-            let old_buffer = buffer;
+impl CompletionMerge {
+    fn new(
+        id: RequestId,
+        request_id: usize,
+        position: Position,
+        servers: usize,
+        filter: Option<String>,
+        dispatcher: Dispatcher,
+    ) -> Self {
+        Self {
+            id,
+            request_id,
+            position,
+            dispatcher,
+            waiting: servers,
+            timed_out: false,
+            sent: false,
+            answered: false,
+            is_incomplete: false,
+            items: Vec::new(),
+            seen: HashSet::new(),
+            error: None,
+            filter,
+        }
+    }
 
-            client.request_semantic_tokens(uri, move |lsp_client, result| {
-                let buffers = lsp_client.dispatcher.buffers.lock();
-                let buffer = buffers.get(&old_buffer.id).unwrap();
-                // If the revision changed while we were requesting, then we refuse the request as it will have made another one
-                if buffer.rev != old_buffer.rev {
-                    lsp_client
-                        .dispatcher
-                        .respond(id, Err(anyhow!("revision changed")));
-                    return;
+    fn add(&mut self, source: &str, result: Result<Value>) {
+        self.waiting = self.waiting.saturating_sub(1);
+        match result.and_then(|v| {
+            let mode = default_insert_text_mode(&v);
+            Ok((
+                serde_json::from_value::<Option<CompletionResponse>>(v)?,
+                mode,
+            ))
+        }) {
+            Ok((resp, mode)) => {
+                self.answered = true;
+                if let Some(resp) = resp {
+                    self.merge(source, resp, mode);
                 }
+            }
+            Err(e) => {
+                log::warn!("completion from {source} failed: {e}");
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+            }
+        }
+        if self.waiting == 0 || (self.timed_out && self.answered) {
+            self.send();
+        }
+    }
 
-                let lsp_state = lsp_client.state.lock();
-                let semantic_tokens_provider = &lsp_state
-                    .server_capabilities
-                    .as_ref()
-                    .unwrap()
-                    .semantic_tokens_provider;
-                let result = result.and_then(|value| {
-                    format_semantic_styles(buffer, semantic_tokens_provider, value)
-                        .map(|styles| {
-                            serde_json::to_value(SemanticStyles {
-                                rev: buffer.rev,
-                                buffer_id: buffer.id,
-                                path: buffer.path.clone(),
-                                styles,
-                                len: buffer.len(),
-                            })
-                            .unwrap()
-                        })
-                        .ok_or_else(|| anyhow!("can't format semantic styles"))
-                });
+    /// Forwards the items a server streamed ahead of its response to core
+    /// straight away. They are in the response core gets later too.
+    fn add_partial(&mut self, source: &str, value: Value) {
+        if self.sent {
+            return;
+        }
+        let mode = default_insert_text_mode(&value);
+        let resp = match serde_json::from_value::<CompletionResponse>(value) {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::warn!("invalid partial completion from {source}: {e}");
+                return;
+            }
+        };
+        let start = self.items.len();
+        self.merge(source, resp, mode);
+        if self.items.len() > start {
+            self.dispatcher.send_rpc_notification(
+                CoreNotification::CompletionPartialResult {
+                    request_id: self.request_id,
+                    position: self.position,
+                    items: self.items[start..].to_vec(),
+                },
+            );
+        }
+    }
 
-                lsp_client.dispatcher.respond(id, result);
-            });
+    /// Adds the items of `resp` not seen yet, tagged with their source.
+    /// Those without an `insertTextMode` get the list's default `mode`, as
+    /// core doesn't know about the list.
+    fn merge(
+        &mut self,
+        source: &str,
+        resp: CompletionResponse,
+        mode: Option<InsertTextMode>,
+    ) {
+        let items = match resp {
+            CompletionResponse::Array(items) => items,
+            CompletionResponse::List(list) => {
+                self.is_incomplete |= list.is_incomplete;
+                list.items
+            }
+        };
+        for mut item in items {
+            let key = (item.label.clone(), item.detail.clone());
+            if self.seen.insert(key) {
+                item.insert_text_mode = item.insert_text_mode.or(mode);
+                item.data = Some(
+                    serde_json::to_value(CompletionItemData {
+                        source: source.to_string(),
+                        data: item.data.take(),
+                        matched: None,
+                    })
+                    .unwrap(),
+                );
+                self.items.push(item);
+            }
         }
     }
 
-    pub fn get_document_symbols(&self, id: RequestId, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+    fn time_out(&mut self) {
+        self.timed_out = true;
+        if self.answered {
+            self.send();
+        }
+    }
 
-                if !state.is_initialized {
-                    return;
-                }
+    fn send(&mut self) {
+        if self.sent {
+            return;
+        }
+        self.sent = true;
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.document_symbol_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
+        let is_incomplete = self.is_incomplete || self.waiting > 0;
+        let mut items = std::mem::take(&mut self.items);
+        if let Some(filter) = self.filter.as_ref().filter(|_| !is_incomplete) {
+            items = filter_completion_items(items, filter);
+        }
+        let result = match self.error.take() {
+            Some(e) if !self.answered => Err(e),
+            _ => Ok(to_value(CompletionResponse::List(CompletionList {
+                is_incomplete,
+                items,
+            }))
+            .unwrap()),
+        };
+        self.dispatcher.respond(self.id, result);
+    }
+}
 
-                if !is_enabled {
-                    return;
-                }
+/// The `insertTextMode` of the items of a completion list that don't have
+/// one, from its `itemDefaults`, which lsp-types doesn't know about.
+fn default_insert_text_mode(list: &Value) -> Option<InsertTextMode> {
+    list.pointer("/itemDefaults/insertTextMode")
+        .and_then(|mode| serde_json::from_value(mode.clone()).ok())
+}
+
+/// The `items` matching `word`, the best matches first. Items matching as
+/// well are ordered by their `sortText`, and each is tagged with how it
+/// matched.
+fn filter_completion_items(
+    items: Vec<CompletionItem>,
+    word: &str,
+) -> Vec<CompletionItem> {
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let mut matched = items
+        .into_iter()
+        .filter_map(|mut item| {
+            let text = item.filter_text.as_deref().unwrap_or(&item.label);
+            let (score, indices) = matcher.fuzzy_indices(text, word)?;
+            if let Some(mut data) = CompletionItemData::of(&item) {
+                data.matched = Some(CompletionMatch { score, indices });
+                item.data = Some(to_value(data).unwrap());
             }
+            Some((score, item))
+        })
+        .collect::<Vec<_>>();
+    matched.sort_by(|(a_score, a), (b_score, b)| {
+        let sort_text = |item: &CompletionItem| {
+            item.sort_text.clone().unwrap_or_else(|| item.label.clone())
+        };
+        b_score
+            .cmp(a_score)
+            .then_with(|| sort_text(a).cmp(&sort_text(b)))
+    });
+    matched.into_iter().map(|(_, item)| item).collect()
+}
 
-            let uri = client.get_uri(buffer);
-            client.request_document_symbols(uri, move |lsp_client, result| {
-                lsp_client.dispatcher.respond(id, result);
-            });
+/// The word ending at `position`, which completion items are matched
+/// against.
+fn word_before(buffer: &Buffer, position: &Position) -> String {
+    let offset = match buffer.offset_of_position(position) {
+        Some(offset) => offset,
+        None => return String::new(),
+    };
+    let line_start = buffer.offset_of_line(position.line as usize);
+    let line = buffer.slice_to_cow(line_start..offset);
+    let word = line
+        .chars()
+        .rev()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect::<Vec<_>>();
+    word.into_iter().rev().collect()
+}
+
+/// The items of a completion response, a list or an array of them.
+fn completion_items_mut(response: &mut Value) -> Option<&mut Vec<Value>> {
+    if response.is_array() {
+        response.as_array_mut()
+    } else {
+        response.get_mut("items").and_then(Value::as_array_mut)
+    }
+}
+
+/// Gets a completion response of a server ready for core, without the
+/// documentation of its items when core goes without it.
+fn finish_completion(response: &mut Value, documentation: CompletionDocumentation) {
+    if documentation == CompletionDocumentation::Off {
+        for item in completion_items_mut(response).into_iter().flatten() {
+            if let Some(item) = item.as_object_mut() {
+                item.remove("documentation");
+            }
         }
     }
+    markdown::normalize_completion(response);
+}
 
-    pub fn get_workspace_symbols(
-        &self,
-        id: RequestId,
-        buffer: &Buffer,
-        query: String,
-    ) {
-        // TODO: We could collate workspace symbols from all the lsps?
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
-
-                if !state.is_initialized {
-                    return;
-                }
-
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.workspace_symbol_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
-
-                if !is_enabled {
-                    return;
+/// Resolves the documentation of the first `MAX_EAGER_RESOLVES` items of a
+/// completion response that don't have any, with at most
+/// `COMPLETION_RESOLVE_CONCURRENCY` requests to `client` at once. Items not
+/// resolved within `COMPLETION_RESOLVE_TIMEOUT` are left as they are.
+fn resolve_completion_documentation(client: &LspClient, response: &mut Value) {
+    let items = match completion_items_mut(response) {
+        Some(items) => items,
+        None => return,
+    };
+    let mut missing = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| {
+            item.get("documentation")
+                .map(Value::is_null)
+                .unwrap_or(true)
+        })
+        .filter_map(|(i, item)| {
+            let item =
+                serde_json::from_value::<CompletionItem>(item.clone()).ok()?;
+            Some((i, item))
+        })
+        .take(MAX_EAGER_RESOLVES)
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let deadline = Instant::now() + COMPLETION_RESOLVE_TIMEOUT;
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mut in_flight = 0;
+    loop {
+        while in_flight < COMPLETION_RESOLVE_CONCURRENCY {
+            let (i, item) = match missing.next() {
+                Some(missing) => missing,
+                None => break,
+            };
+            let tx = tx.clone();
+            client.completion_resolve(&item, move |_, result| {
+                let _ = tx.send((i, result));
+            });
+            in_flight += 1;
+        }
+        if in_flight == 0 {
+            break;
+        }
+        let (i, result) = match rx.recv_deadline(deadline) {
+            Ok(resolved) => resolved,
+            Err(_) => break,
+        };
+        in_flight -= 1;
+        // the rest of the item stays as the server listed it, core resolves
+        // it again for its edits once it's picked
+        if let Ok(resolved) = result {
+            for field in ["documentation", "detail"] {
+                if let Some(value) = resolved.get(field).filter(|v| !v.is_null()) {
+                    items[i][field] = value.clone();
                 }
             }
-
-            client.request_workspace_symbols(query, move |lsp_client, result| {
-                lsp_client.dispatcher.respond(id, result);
-            });
         }
     }
+}
 
-    pub fn get_document_formatting(&self, id: RequestId, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+/// Tags a resolved completion item with the server it came from again.
+fn tag_completion_item(item: &mut Value, source: &str) {
+    if let Some(item) = item.as_object_mut() {
+        let data = CompletionItemData {
+            source: source.to_string(),
+            data: item.remove("data"),
+            matched: None,
+        };
+        item.insert("data".to_string(), to_value(data).unwrap());
+    }
+}
 
-                if !state.is_initialized {
-                    return;
-                }
+/// Whether the server advertised `inlayHint/resolve`, statically or in the
+/// options it registers inlay hints with.
+fn can_resolve_inlay_hints(capabilities: &ServerCapabilities) -> bool {
+    let options = match capabilities.inlay_hint_provider.as_ref() {
+        Some(OneOf::Right(InlayHintServerCapabilities::Options(options))) => options,
+        Some(OneOf::Right(InlayHintServerCapabilities::RegistrationOptions(
+            options,
+        ))) => &options.inlay_hint_options,
+        _ => return false,
+    };
+    options.resolve_provider == Some(true)
+}
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.document_formatting_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
+/// Whether the server advertised `completionItem/resolve`.
+fn can_resolve_completion(capabilities: &ServerCapabilities) -> bool {
+    capabilities
+        .completion_provider
+        .as_ref()
+        .and_then(|options| options.resolve_provider)
+        .unwrap_or(false)
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
+/// Whether the server advertised `codeLens/resolve`.
+fn can_resolve_code_lens(capabilities: &ServerCapabilities) -> bool {
+    capabilities
+        .code_lens_provider
+        .as_ref()
+        .and_then(|options| options.resolve_provider)
+        .unwrap_or(false)
+}
 
-            let uri = client.get_uri(buffer);
-            client.request_document_formatting(uri, move |lsp_client, result| {
-                lsp_client.dispatcher.respond(id, result);
-            });
-        } else {
-            self.dispatcher
-                .as_ref()
-                .unwrap()
-                .respond(id, Err(anyhow!("no document formatting")));
-        }
-    }
+pub struct LspState {
+    next_id: u64,
+    /// The messages to write to the server, in the order they were sent
+    outgoing: Arc<BoundedQueue<String>>,
+    /// The server's process, unless the server runs in the proxy
+    process: Option<Child>,
+    pending: HashMap<u64, Callback>,
+    /// The requests in flight that are sent again if they fail with one of
+    /// the `retry_error_codes`
+    retryable: HashMap<u64, RetryableRequest>,
+    /// The ids of the requests in flight about each document, cancelled
+    /// when the document is closed
+    document_requests: HashMap<Url, HashSet<u64>>,
+    /// Requests not sent yet because the server is busy
+    queued: BinaryHeap<QueuedRequest>,
+    next_queued_seq: u64,
+    pub server_capabilities: Option<ServerCapabilities>,
+    pub opened_documents: HashMap<BufferId, Url>,
+    /// The version of each open document the server was last given. It's
+    /// the revision of the buffer, unless the server was given a version
+    /// of the document while it was prepared for saving.
+    document_versions: HashMap<BufferId, i32>,
+    /// The buffers open on each document, as a file can be opened more than
+    /// once. The server has the text of the first one, the others' changes
+    /// aren't sent.
+    document_buffers: HashMap<Url, Vec<BufferId>>,
+    /// The uri each open document was opened with, by the `PathKeys` key
+    /// of its path, so that a file reached by another path isn't opened
+    /// again
+    document_keys: HashMap<String, Url>,
+    /// The last version the server was given of each document before the
+    /// editor was restarted, which the versions of the document go on from
+    /// once it's opened again
+    restored_versions: HashMap<Url, i32>,
+    /// The buffers that took over a document from a buffer that was closed,
+    /// whose full text the server is given with their next change, with
+    /// the last version it was given of the document
+    handed_over: HashMap<BufferId, i32>,
+    /// The buffers the server was given a draft of to save, whose changes
+    /// are sent as their full text until the draft is over, as they're made
+    /// against a text the server doesn't have
+    drafts: HashSet<BufferId>,
+    /// The documents opened before the server was initialized, with the
+    /// text they have now, which it's given once it is
+    unsent_opens: HashMap<Url, TextDocumentItem>,
+    /// Where the server is in the lifecycle of the protocol
+    lifecycle: Lifecycle,
+    /// The trace value last given to the server, kept across restarts
+    server_trace: TraceValue,
+    pub did_save_capabilities: Vec<DidSaveCapability>,
+    /// The `resultId` of the last pulled diagnostic report per document
+    pub diagnostic_result_ids: HashMap<Url, String>,
+    pub server_info: Option<ServerInfo>,
+    /// When a message was last sent to the server
+    pub last_used: Instant,
+    /// The `last_used` of when the server was last told to free memory,
+    /// which it isn't told again until it's used
+    trimmed: Option<Instant>,
+    /// What to do with the partial results of the requests in flight, by
+    /// their partial result token
+    partial_results: HashMap<String, PartialResultHandler>,
+    /// The requests of core the progress the server reports with each work
+    /// done token is of
+    work_done: HashMap<String, RequestId>,
+    /// The characters that trigger completion from the server, with the
+    /// user's override applied
+    pub completion_trigger_characters: Vec<String>,
+    /// The characters after typing which the server formats what was
+    /// typed, its `firstTriggerCharacter` and `moreTriggerCharacter`
+    pub on_type_formatting_trigger_characters: Vec<String>,
+    workspace_symbols: WorkspaceSymbolCache,
+    /// Bumped when files change, to drop the symbols loaded before
+    workspace_symbols_generation: u64,
+    workspace_diagnostics: WorkspaceDiagnosticsPull,
+    call_hierarchy: CallHierarchyCache,
+}
 
-    pub fn get_completion(
-        &self,
-        id: RequestId,
-        _request_id: usize,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+impl LspState {
+    /// Whether the server answered `initialize` and was sent `initialized`,
+    /// so that it can be sent anything
+    pub fn is_initialized(&self) -> bool {
+        self.lifecycle == Lifecycle::Initialized
+    }
 
-                if !state.is_initialized {
-                    return;
-                }
+    /// Takes the callback of the request `id` out of the requests in flight.
+    fn take_pending(&mut self, id: u64) -> Option<Callback> {
+        self.retryable.remove(&id);
+        let callback = self.pending.remove(&id)?;
+        self.document_requests.retain(|_, ids| {
+            ids.remove(&id);
+            !ids.is_empty()
+        });
+        Some(callback)
+    }
+}
 
-                // TODO: pay attention to trigger characters
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .map(|cap| cap.completion_provider.is_some())
-                    .unwrap_or(false);
+/// The document a request is about, from the `textDocument` of its params.
+fn request_document(params: &Params) -> Option<Url> {
+    match params {
+        Params::Map(map) => {
+            let uri = map.get("textDocument")?.get("uri")?.as_str()?;
+            Url::parse(uri).ok()
+        }
+        _ => None,
+    }
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
+/// Moves `position` along with the text replaced by `change`, a position in
+/// the replaced text ending up after the text replacing it. `None` if the
+/// change replaced the whole document.
+fn transform_position(
+    position: Position,
+    change: &TextDocumentContentChangeEvent,
+) -> Option<Position> {
+    let range = change.range?;
+    if position <= range.start {
+        return Some(position);
+    }
+    let lines = change.text.matches('\n').count() as u32;
+    let last_line = change.text.rsplit('\n').next().unwrap_or("");
+    let last_line_len = last_line.encode_utf16().count() as u32;
+    let end = if lines == 0 {
+        Position::new(range.start.line, range.start.character + last_line_len)
+    } else {
+        Position::new(range.start.line + lines, last_line_len)
+    };
+    Some(if position < range.end {
+        end
+    } else if position.line == range.end.line {
+        Position::new(
+            end.line,
+            end.character + position.character - range.end.character,
+        )
+    } else {
+        Position::new(
+            position.line + end.line - range.end.line,
+            position.character,
+        )
+    })
+}
 
-            let uri = client.get_uri(buffer);
-            client.request_completion(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+/// Moves the `position` and `range` of the params of a request along with
+/// the `changes` made to its document, `None` if they can't be followed.
+fn transform_params(
+    params: Params,
+    changes: &[TextDocumentContentChangeEvent],
+) -> Option<Params> {
+    let mut map = match params {
+        Params::Map(map) => map,
+        params => return Some(params),
+    };
+    if let Some(value) = map.get_mut("position") {
+        let mut position: Position = serde_json::from_value(value.clone()).ok()?;
+        for change in changes {
+            position = transform_position(position, change)?;
         }
+        *value = serde_json::to_value(position).ok()?;
     }
-
-    pub fn completion_resolve(
-        &self,
-        id: RequestId,
-        buffer: &Buffer,
-        completion_item: &CompletionItem,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            client.completion_resolve(completion_item, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+    if let Some(value) = map.get_mut("range") {
+        let mut range: Range = serde_json::from_value(value.clone()).ok()?;
+        for change in changes {
+            range.start = transform_position(range.start, change)?;
+            range.end = transform_position(range.end, change)?;
         }
+        *value = serde_json::to_value(range).ok()?;
     }
+    Some(Params::Map(map))
+}
 
-    pub fn get_hover(
-        &self,
-        id: RequestId,
-        _request_id: usize,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+/// Where the pull of the diagnostics of the whole workspace from a server
+/// is at. Only one pull is in flight at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WorkspaceDiagnosticsPull {
+    Idle,
+    Pulling,
+    /// Things changed since the pull in flight was sent, so it's sent
+    /// again once it's answered
+    PullingStale,
+}
 
-                if !state.is_initialized {
-                    return;
-                }
+/// The symbols of the whole workspace a server gives for an empty query,
+/// searched without asking it again.
+enum WorkspaceSymbolCache {
+    NotLoaded,
+    Loading,
+    Ready(Vec<SymbolInformation>),
+    /// Files changed, and the symbols left are to be loaded again
+    Stale(Vec<SymbolInformation>),
+    /// The server can't give all the symbols, so it's asked every time
+    Unavailable,
+}
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.hover_provider.as_ref())
-                    .map(|prov| prov != &HoverProviderCapability::Simple(false))
-                    .unwrap_or(false);
+/// The answers to `textDocument/prepareCallHierarchy`, by the document, the
+/// position and the version of the document they were given for, so that
+/// exploring a call tree doesn't prepare the same items over and over. The
+/// least recently used are dropped past `CALL_HIERARCHY_CACHE_SIZE`.
+#[derive(Default)]
+struct CallHierarchyCache {
+    /// The most recently used last
+    entries: VecDeque<((Url, Position, i32), Value)>,
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
+impl CallHierarchyCache {
+    fn get(&mut self, uri: &Url, position: Position, version: i32) -> Option<Value> {
+        let index = self.entries.iter().position(|((u, p, v), _)| {
+            u == uri && *p == position && *v == version
+        })?;
+        let entry = self.entries.remove(index)?;
+        let items = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(items)
+    }
 
-            let uri = client.get_uri(buffer);
-            client.request_hover(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}", e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+    fn insert(&mut self, uri: Url, position: Position, version: i32, items: Value) {
+        // the items prepared for another version are no use anymore
+        self.entries
+            .retain(|((u, _, v), _)| u != &uri || *v == version);
+        self.entries.push_back(((uri, position, version), items));
+        while self.entries.len() > CALL_HIERARCHY_CACHE_SIZE {
+            self.entries.pop_front();
         }
     }
 
-    pub fn get_signature(&self, id: RequestId, buffer: &Buffer, position: Position) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
-
-                if !state.is_initialized {
-                    return;
-                }
+    fn invalidate(&mut self, uri: &Url) {
+        self.entries.retain(|((u, ..), _)| u != uri);
+    }
+}
 
-                // TODO: use the trigger characters fields
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .map(|cap| cap.signature_help_provider.is_some())
-                    .unwrap_or(false);
+/// The stages of the connection to a server. `initialize` is the first
+/// request, `initialized` is sent once after it's answered, and nothing
+/// but `exit` follows `shutdown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    NotStarted,
+    /// `initialize` was sent and isn't answered yet
+    Initializing,
+    Initialized,
+    /// `shutdown` was sent
+    ShuttingDown,
+    Exited,
+}
 
-                if !is_enabled {
-                    return;
-                }
+impl Lifecycle {
+    /// The stage after sending `method`, or a response when it's `None`,
+    /// or an error if it can't be sent at this stage.
+    fn send(self, method: Option<&str>) -> Result<Lifecycle> {
+        use Lifecycle::*;
+        let method = match method {
+            Some(method) => method,
+            // the server may ask things while it's initializing
+            None if self != ShuttingDown && self != Exited => return Ok(self),
+            None => "response",
+        };
+        match (self, method) {
+            (NotStarted, "initialize") => Ok(Initializing),
+            (Initializing, "initialized") => Ok(Initialized),
+            (Initialized, "shutdown") => Ok(ShuttingDown),
+            (Exited, _) => Err(anyhow!("can't send {method}, the server exited")),
+            (_, "exit") => Ok(Exited),
+            (Initializing | Initialized, "initialize" | "initialized") => {
+                Err(anyhow!("can't send {method} again"))
             }
+            (Initialized, _) => Ok(Initialized),
+            (NotStarted | Initializing, _) => Err(anyhow!(
+                "can't send {method} before the server is initialized"
+            )),
+            (ShuttingDown, _) => Err(anyhow!("can't send {method} after shutdown")),
+        }
+    }
+}
 
-            let uri = client.get_uri(buffer);
-            client.request_signature(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+pub struct DocumentFilter {
+    /// The document must have this language id, if it exists
+    pub language_id: Option<String>,
+    /// The document's path must match this glob, if it exists
+    pub pattern: Option<GlobSet>,
+    // TODO: URI Scheme from lsp-types document filter
+}
+impl DocumentFilter {
+    /// Constructs a document filter from the LSP version
+    /// This ignores any fields that are badly constructed
+    fn from_lsp_filter_loose(filter: lsp_types::DocumentFilter) -> DocumentFilter {
+        DocumentFilter {
+            language_id: filter.language,
+            // TODO: clean this up
+            pattern: filter.pattern.and_then(|pattern| glob_set(&[pattern]).ok()),
         }
     }
+}
+pub struct DidSaveCapability {
+    /// A filter on what documents this applies to
+    filter: DocumentFilter,
+    /// Whether we are supposed to include the text when sending a didSave event
+    include_text: bool,
+}
 
-    pub fn get_references(
-        &self,
-        id: RequestId,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+/// The streams the proxy talks to a server over.
+pub struct ServerConnection {
+    pub writer: Box<dyn Write + Send>,
+    pub reader: Box<dyn Read + Send>,
+    pub stderr: Option<Box<dyn Read + Send>>,
+    /// The server's process, killed when the server is stopped, unless the
+    /// server runs in the proxy
+    pub process: Option<Child>,
+}
 
-                if !state.is_initialized {
-                    return;
-                }
+/// What a server is started with besides its spec.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub initialization_options: Option<Value>,
+    pub trigger_character_override: TriggerCharacterOverride,
+    pub document_limits: DocumentLimits,
+    /// How many requests the server answers at once before new ones are
+    /// queued, `MAX_IN_FLIGHT_REQUESTS` if unset
+    pub max_in_flight_requests: Option<usize>,
+    pub trace: Option<TraceSetting>,
+    /// The trace value the server is initialized with
+    pub server_trace: Option<TraceValue>,
+    /// The environment variables of the server process, the user's over
+    /// the plugin's
+    pub env: HashMap<String, String>,
+    /// How the server's command is run, the user's or else the plugin's
+    pub launch: ServerLaunch,
+    /// The error codes read requests are sent again once for before they
+    /// fail
+    pub retry_error_codes: Vec<i64>,
+    /// The workspace folders the server is given, the one it's rooted in
+    /// first
+    pub workspace_roots: Vec<PathBuf>,
+}
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.references_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
+#[derive(Clone)]
+pub struct LspClient {
+    spec: LspServerSpec,
+    config: Arc<ServerConfig>,
+    state: Arc<Mutex<LspState>>,
+    dispatcher: Dispatcher,
+    active: Arc<AtomicBool>,
+    tracer: Arc<Mutex<Option<LspTracer>>>,
+    /// The recording of the traffic of the server, if `RECORD_ENV` asks for one
+    recorder: Arc<Mutex<Option<LspRecorder>>>,
+    /// The last `STDERR_TAIL_LINES` lines the server wrote to its stderr
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
-
-            let uri = client.get_uri(buffer);
-            client.request_references(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
-        }
+pub(crate) fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
     }
 
-    pub fn get_inlay_hints(&self, id: RequestId, buffer: &Buffer) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(id) => vec![id],
+        OneOrMany::Many(ids) => ids,
+    })
+}
 
-                if !state.is_initialized {
-                    return;
-                }
+/// The program a server is run with and its arguments: the server's
+/// `exec_path` and `args`, or the wrapper of `launch` with them after its own
+/// arguments. The server talks over the stdio of the program either way, so
+/// the wrapper has to hand its stdio to the server.
+fn launch_command(
+    exec_path: &str,
+    args: &[String],
+    launch: &ServerLaunch,
+) -> (String, Vec<String>) {
+    let command = std::iter::once(exec_path.to_string())
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>();
+    let (wrapper, command) = if launch.shell {
+        let line = command
+            .iter()
+            .map(|word| shell_quote(word))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !launch.wrapper.is_empty() {
+            (launch.wrapper.clone(), vec![line])
+        } else if cfg!(target_os = "windows") {
+            (vec!["cmd".to_string(), "/C".to_string()], vec![line])
+        } else {
+            // the shell gives way to the server, which keeps its stdio
+            (
+                vec!["sh".to_string(), "-c".to_string()],
+                vec![format!("exec {line}")],
+            )
+        }
+    } else {
+        (launch.wrapper.clone(), command)
+    };
+    let mut words = wrapper.into_iter().chain(command);
+    let program = words.next().unwrap_or_default();
+    (program, words.collect())
+}
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.inlay_hint_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
+/// `word` quoted to be one word of a shell's command line.
+fn shell_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        word.to_string()
+    } else if cfg!(target_os = "windows") {
+        format!("\"{}\"", word.replace('"', "\"\""))
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
 
-                if !is_enabled {
-                    return;
-                }
-            }
+/// The globs a plugin's servers start on the files of.
+pub fn activation_globs(patterns: &[String]) -> Result<GlobSet> {
+    Ok(glob_set(patterns)?)
+}
 
-            let uri = client.get_uri(buffer);
-            // Range over the entire buffer
-            let range = Range {
-                start: Position::new(0, 0),
-                end: buffer.offset_to_position(buffer.len()).unwrap(),
-            };
-            client.request_inlay_hints(uri, range, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}", e),
-                        })
-                    }
-                }
+/// The path activation globs are matched against, relative to the
+/// workspace for the files in it.
+pub fn activation_path<'a>(workspace: Option<&Path>, path: &'a Path) -> &'a Path {
+    workspace
+        .and_then(|workspace| path.strip_prefix(workspace).ok())
+        .unwrap_or(path)
+}
 
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+impl LspCatalog {
+    pub fn new() -> LspCatalog {
+        LspCatalog {
+            dispatcher: None,
+            clients: HashMap::new(),
+            max_servers: None,
+            trigger_character_override: TriggerCharacterOverride::default(),
+            user_settings: HashMap::new(),
+            workspace_settings: HashMap::new(),
+            max_document_size: None,
+            document_limits: HashMap::new(),
+            max_in_flight_requests: HashMap::new(),
+            save_stages: HashMap::new(),
+            server_env: HashMap::new(),
+            server_launch: HashMap::new(),
+            idle_timeout: None,
+            memory_trim: HashMap::new(),
+            retry_error_codes: vec![error_codes::CONTENT_MODIFIED],
+            cache_workspace_symbols: false,
+            filter_completion: false,
+            completion_documentation: CompletionDocumentation::default(),
+            completion_suppression: Duration::ZERO,
+            completion_accepted: None,
+            option_overrides: HashMap::new(),
+            traces: HashMap::new(),
+            server_traces: HashMap::new(),
+            formatters: HashMap::new(),
+            detached: HashSet::new(),
+            ignore: GlobSet::empty(),
+            ignored: HashSet::new(),
+            evicted: HashMap::new(),
+            dormant: Vec::new(),
+            workspace_roots: Vec::new(),
+            per_root_languages: HashSet::new(),
+            per_root: Vec::new(),
+            restored_symbols: HashMap::new(),
+            restored_versions: HashMap::new(),
+            restored_idle: HashSet::new(),
         }
     }
 
-    pub fn get_code_actions(
-        &self,
-        id: RequestId,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+    /// The first server started for `language_id`, which answers the
+    /// requests only one server can.
+    fn client(&self, language_id: &str) -> Option<&Arc<LspClient>> {
+        self.clients
+            .get(language_id)
+            .and_then(|clients| clients.first())
+    }
 
-                if !state.is_initialized {
-                    return;
-                }
+    /// Every server started for `language_id`.
+    fn clients_for(&self, language_id: &str) -> &[Arc<LspClient>] {
+        self.clients
+            .get(language_id)
+            .map(|clients| clients.as_slice())
+            .unwrap_or(&[])
+    }
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.code_action_provider.as_ref())
-                    .map(|prov| prov != &CodeActionProviderCapability::Simple(false))
-                    .unwrap_or(false);
+    /// The first server of `buffer`'s language that takes the buffer.
+    fn buffer_client(&self, buffer: &Buffer) -> Option<&Arc<LspClient>> {
+        self.buffer_clients(buffer).into_iter().next()
+    }
 
-                if !is_enabled {
-                    return;
-                }
-            }
+    /// The servers of `buffer`'s language that take the buffer, which they
+    /// don't when it's over their limits or kept from every server.
+    pub(crate) fn buffer_clients(&self, buffer: &Buffer) -> Vec<&Arc<LspClient>> {
+        if self.is_detached(buffer) {
+            return Vec::new();
+        }
+        self.clients_for(&buffer.language_id)
+            .iter()
+            .filter(|client| self.serves_root(client, &buffer.path))
+            .filter(|client| client.accepts(buffer))
+            .collect()
+    }
 
-            let uri = client.get_uri(buffer);
-            let range = Range {
-                start: position,
-                end: position,
-            };
-            client.request_code_actions(uri, range, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+    /// Sets the roots of the projects in the workspace, from the other
+    /// `roots` besides the `workspace`, relative to it.
+    pub fn set_workspace_roots(&mut self, workspace: &Path, roots: &[PathBuf]) {
+        self.workspace_roots = vec![workspace.to_path_buf()];
+        for root in roots {
+            let root = workspace.join(root);
+            if !self.workspace_roots.contains(&root) {
+                self.workspace_roots.push(root);
+            }
         }
     }
 
-    pub fn get_definition(
-        &self,
-        id: RequestId,
-        _request_id: usize,
-        buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
+    /// The workspace and the roots in it, empty if no folder is open.
+    pub fn workspace_roots(&self) -> &[PathBuf] {
+        &self.workspace_roots
+    }
 
-                if !state.is_initialized {
-                    return;
-                }
+    /// The workspace root `path` belongs to, the workspace for the files
+    /// outside of every root.
+    fn root_of(&self, path: &Path) -> Option<&PathBuf> {
+        self.workspace_roots
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .or_else(|| self.workspace_roots.first())
+    }
 
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.definition_provider.as_ref())
-                    .map(|prov| prov != &OneOf::Left(false))
-                    .unwrap_or(false);
+    /// Whether `client` takes the files at `path`, which a server running
+    /// for one workspace root only does for the files of its root.
+    fn serves_root(&self, client: &LspClient, path: &Path) -> bool {
+        match client.spec.root.as_ref() {
+            Some(root) => self.root_of(path) == Some(root),
+            None => true,
+        }
+    }
 
-                if !is_enabled {
-                    return;
-                }
-            }
+    /// Whether the languages of `spec` get a server for each workspace root.
+    fn is_per_root(&self, spec: &LspServerSpec) -> bool {
+        !self.workspace_roots.is_empty()
+            && spec
+                .language_ids
+                .iter()
+                .any(|id| self.per_root_languages.contains(id))
+    }
 
-            let uri = client.get_uri(buffer);
-            client.request_definition(uri, position, move |lsp_client, result| {
-                let mut resp = json!({ "id": id });
-                match result {
-                    Ok(v) => resp["result"] = v,
-                    Err(e) => {
-                        resp["error"] = json!({
-                            "code": 0,
-                            "message": format!("{}",e),
-                        })
-                    }
-                }
-                let _ = lsp_client.dispatcher.sender.send(resp);
-            });
+    /// Starts the servers of `buffer`'s language that run for each root
+    /// for the root of `buffer`, unless they're running already.
+    fn start_root_servers(&mut self, buffer: &Buffer) {
+        let root = match self.root_of(&buffer.path) {
+            Some(root) => root.clone(),
+            None => return,
+        };
+        let specs = self
+            .per_root
+            .iter()
+            .filter(|spec| spec.handles(&buffer.language_id))
+            .map(|spec| LspServerSpec {
+                root: Some(root.clone()),
+                ..spec.clone()
+            })
+            .filter(|spec| {
+                !self
+                    .clients_for(&buffer.language_id)
+                    .iter()
+                    .any(|client| client.spec.same_server(spec))
+            })
+            .collect::<Vec<_>>();
+        for spec in specs {
+            self.start_server(spec);
         }
     }
 
-    pub fn get_type_definition(
+    /// The server answering a request about `buffer`: the one `server_id`
+    /// names if it takes the buffer, or else the first one.
+    fn buffer_server(
         &self,
-        id: RequestId,
-        _request_id: usize,
         buffer: &Buffer,
-        position: Position,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            {
-                let state = client.state.lock();
-
-                if !state.is_initialized {
-                    return;
-                }
-
-                let is_enabled = state
-                    .server_capabilities
-                    .as_ref()
-                    .and_then(|cap| cap.type_definition_provider.as_ref())
-                    .map(|prov| {
-                        prov != &TypeDefinitionProviderCapability::Simple(false)
-                    })
-                    .unwrap_or(false);
+        server_id: Option<&str>,
+    ) -> Option<&Arc<LspClient>> {
+        match server_id {
+            Some(server_id) => self
+                .buffer_clients(buffer)
+                .into_iter()
+                .find(|client| client.spec.id() == server_id),
+            None => self.buffer_client(buffer),
+        }
+    }
 
-                if !is_enabled {
-                    return;
-                }
+    /// Every running server, once, however many languages it handles.
+    fn servers(&self) -> Vec<&Arc<LspClient>> {
+        let mut servers: Vec<&Arc<LspClient>> = Vec::new();
+        for client in self.clients.values().flatten() {
+            if !servers.iter().any(|server| Arc::ptr_eq(server, client)) {
+                servers.push(client);
             }
-
-            let uri = client.get_uri(buffer);
-            client.request_type_definition(
-                uri,
-                position,
-                move |lsp_client, result| {
-                    let mut resp = json!({ "id": id });
-                    match result {
-                        Ok(v) => resp["result"] = v,
-                        Err(e) => {
-                            resp["error"] = json!({
-                                "code": 0,
-                                "message": format!("{}", e),
-                            })
-                        }
-                    }
-
-                    let _ = lsp_client.dispatcher.sender.send(resp);
-                },
-            );
         }
+        servers
     }
 
-    pub fn update(
-        &self,
-        buffer: &Buffer,
-        content_change: &TextDocumentContentChangeEvent,
-        rev: u64,
-    ) {
-        if let Some(client) = self.clients.get(&buffer.language_id) {
-            client.update(buffer, content_change, rev);
+    pub fn stop(&mut self) {
+        for client in self.servers() {
+            client.stop();
         }
+        self.clients.clear();
+        self.evicted.clear();
+        self.dormant.clear();
+        self.per_root.clear();
+        self.dispatcher.take();
     }
-}
 
-impl Default for LspCatalog {
-    fn default() -> Self {
-        Self::new()
+    /// How full the queue of each running server is, by the server's id.
+    pub fn queue_stats(&self) -> HashMap<String, QueueStats> {
+        self.servers()
+            .into_iter()
+            .map(|client| (client.spec.id(), client.queue_stats()))
+            .collect()
     }
-}
 
-impl LspClient {
-    pub fn new(
-        language_id: String,
-        exec_path: &str,
-        options: Option<Value>,
-        args: Vec<String>,
-        dispatcher: Dispatcher,
-    ) -> Arc<LspClient> {
-        //TODO: better handling of binary args in plugin
-        let workspace = dispatcher.workspace.lock().clone();
-        let mut process = Self::process(workspace, exec_path, args.clone());
-        let writer = Box::new(BufWriter::new(process.stdin.take().unwrap()));
-        let stdout = process.stdout.take().unwrap();
-        let stderr = process.stderr.take().unwrap();
+    /// The name and version each running server reported when it was
+    /// initialized, by language id.
+    pub fn server_versions(&self) -> HashMap<String, ServerVersion> {
+        self.clients
+            .keys()
+            .filter_map(|language_id| {
+                let client = self.client(language_id)?;
+                let info = client.state.lock().server_info.clone()?;
+                Some((
+                    language_id.clone(),
+                    ServerVersion {
+                        name: info.name,
+                        version: info.version,
+                    },
+                ))
+            })
+            .collect()
+    }
 
-        let lsp_client = Arc::new(LspClient {
-            dispatcher,
-            exec_path: exec_path.to_string(),
-            args,
-            options,
-            state: Arc::new(Mutex::new(LspState {
-                next_id: 0,
-                writer,
-                process,
-                pending: HashMap::new(),
-                server_capabilities: None,
-                opened_documents: HashMap::new(),
-                is_initialized: false,
-                did_save_capabilities: Vec::new(),
-            })),
-            active: Arc::new(AtomicBool::new(true)),
-        });
+    /// The servers running for `language_id`, in the order they were
+    /// started.
+    pub fn language_servers(&self, language_id: &str) -> Vec<LanguageServerInfo> {
+        self.clients_for(language_id)
+            .iter()
+            .map(|client| LanguageServerInfo {
+                id: client.spec.id(),
+                name: client.spec.source_name(),
+                language_ids: client.spec.language_ids.clone(),
+            })
+            .collect()
+    }
 
-        lsp_client.handle_stdout(stdout);
-        lsp_client.handle_stderr(stderr, language_id);
-        lsp_client.initialize();
+    /// The servers `buffer` is routed to, with how far along each one is.
+    pub fn buffer_servers(&self, buffer: &Buffer) -> Vec<ServerDescriptor> {
+        self.buffer_clients(buffer)
+            .into_iter()
+            .map(|client| client.descriptor())
+            .collect()
+    }
 
-        lsp_client
+    /// The reports of all the running servers, for a bug report.
+    pub fn server_reports(&self) -> Vec<ServerReport> {
+        self.servers()
+            .into_iter()
+            .map(|client| client.report())
+            .collect()
     }
 
-    fn handle_stdout(&self, stdout: ChildStdout) {
-        let local_lsp_client = self.clone();
-        thread::spawn(move || {
-            let mut reader = Box::new(BufReader::new(stdout));
-            loop {
-                match read_message(&mut reader) {
-                    Ok(message_str) => {
-                        local_lsp_client.handle_message(message_str.as_ref());
-                    }
-                    Err(_err) => {
-                        if !local_lsp_client.active.load(Ordering::Acquire) {
-                            return;
-                        }
-                        local_lsp_client.stop();
-                        local_lsp_client.reload();
-                        return;
-                    }
-                };
-            }
-        });
+    pub fn stop_language_lsp(&mut self, lang: &String) {
+        self.evicted.remove(lang);
+        self.dormant
+            .retain(|(_, spec)| !spec.language_ids.contains(lang));
+        self.per_root
+            .retain(|spec| !spec.language_ids.contains(lang));
+        for lsp in self.clients_for(lang) {
+            lsp.stop();
+        }
     }
 
-    fn handle_stderr(&self, stderr: ChildStderr, language_id: String) {
-        thread::spawn(move || {
-            let mut reader = Box::new(BufReader::new(stderr));
-            let mut buffer = String::new();
+    /// Stops the servers `plugin` started, for good.
+    pub fn stop_plugin_servers(&mut self, plugin: &str) {
+        let of_plugin =
+            |spec: &LspServerSpec| spec.plugin.as_deref() == Some(plugin);
+        for client in self.servers() {
+            if of_plugin(&client.spec) {
+                client.shutdown();
+            }
+        }
+        for clients in self.clients.values_mut() {
+            clients.retain(|client| !of_plugin(&client.spec));
+        }
+        self.clients.retain(|_, clients| !clients.is_empty());
+        for specs in self.evicted.values_mut() {
+            specs.retain(|spec| !of_plugin(spec));
+        }
+        self.evicted.retain(|_, specs| !specs.is_empty());
+        self.dormant.retain(|(_, spec)| !of_plugin(spec));
+        self.per_root.retain(|spec| !of_plugin(spec));
+    }
 
-            loop {
-                buffer.clear();
-                match reader.read_line(&mut buffer) {
-                    Ok(bytes) => {
-                        if bytes == 0 {
-                            return;
-                        }
-                    }
-                    Err(_) => return,
-                }
-                if buffer.trim().is_empty() {
-                    continue;
-                }
-                error!("[LSP::{}] {}", language_id, buffer.trim())
+    /// Starts the server of `spec`, or once a file matching its activation
+    /// globs is opened if none of the `open` buffers does. Whether the
+    /// workspace has such a file is left to
+    /// `Dispatcher::activate_servers_in_workspace`.
+    pub fn add_server<'a>(
+        &mut self,
+        spec: LspServerSpec,
+        open: impl Iterator<Item = &'a Buffer>,
+    ) {
+        let open = open.collect::<Vec<_>>();
+        if self.restored_idle.remove(&spec.id())
+            && !open
+                .iter()
+                .any(|buffer| spec.language_ids.contains(&buffer.language_id))
+        {
+            // it was idle when the editor was closed, so it waits to be used
+            for language_id in spec.language_ids.iter() {
+                self.evicted
+                    .entry(language_id.clone())
+                    .or_default()
+                    .push(spec.clone());
             }
-        });
+            return;
+        }
+        if spec.activation.is_empty() {
+            self.start_server(spec);
+            self.start_open_root_servers(&open);
+            self.open_documents(&open);
+            return;
+        }
+        let globs = match activation_globs(&spec.activation) {
+            Ok(globs) => globs,
+            Err(err) => {
+                log::warn!("starting {} right away: {err}", spec.source_name());
+                self.start_server(spec);
+                self.start_open_root_servers(&open);
+                self.open_documents(&open);
+                return;
+            }
+        };
+        let workspace = self.workspace();
+        if open.iter().any(|buffer| {
+            globs.is_match(activation_path(workspace.as_deref(), &buffer.path))
+        }) {
+            self.start_server(spec);
+            self.start_open_root_servers(&open);
+            self.open_documents(&open);
+            return;
+        }
+        self.dormant
+            .retain(|(_, dormant)| dormant.exec_path != spec.exec_path);
+        self.dormant.push((globs, spec));
     }
 
-    fn process(
-        workspace: Option<PathBuf>,
-        exec_path: &str,
-        args: Vec<String>,
-    ) -> Child {
-        let mut process = Command::new(exec_path);
-        if let Some(workspace) = workspace {
-            process.current_dir(&workspace);
+    /// Starts the servers running for each root for the roots of the `open`
+    /// buffers that aren't kept from the servers.
+    fn start_open_root_servers(&mut self, open: &[&Buffer]) {
+        for buffer in open {
+            if !self.is_detached(buffer) {
+                self.start_root_servers(buffer);
+            }
         }
+    }
 
-        process.args(args);
+    /// Opens the `open` buffers in the servers taking them that don't have
+    /// them yet, like the servers just started.
+    fn open_documents(&self, open: &[&Buffer]) {
+        for buffer in open {
+            for client in self.buffer_clients(buffer) {
+                client.open_document(buffer);
+            }
+        }
+    }
 
-        #[cfg(target_os = "windows")]
-        let process = process.creation_flags(0x08000000);
-        process
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("Error Occurred")
+    /// The activation globs of the servers that haven't started yet, by
+    /// their `exec_path`.
+    pub fn dormant_servers(&self) -> Vec<(String, GlobSet)> {
+        self.dormant
+            .iter()
+            .map(|(globs, spec)| (spec.exec_path.clone(), globs.clone()))
+            .collect()
     }
 
-    fn reload(&self) {
-        //TODO: avoid clone using a &[String] ?
-        let mut process = Self::process(
-            self.dispatcher.workspace.lock().clone(),
-            &self.exec_path,
-            self.args.clone(),
-        );
-        let writer = Box::new(BufWriter::new(process.stdin.take().unwrap()));
-        let stdout = process.stdout.take().unwrap();
+    /// Starts the server of `exec_path` if it hasn't started yet.
+    pub fn activate(&mut self, exec_path: &str) {
+        if let Some(i) = self
+            .dormant
+            .iter()
+            .position(|(_, spec)| spec.exec_path == exec_path)
+        {
+            let (_, spec) = self.dormant.remove(i);
+            self.start_server(spec);
+        }
+    }
 
-        let mut state = self.state.lock();
-        state.next_id = 0;
-        state.pending.clear();
-        state.opened_documents.clear();
-        state.server_capabilities = None;
-        state.is_initialized = false;
-        state.writer = writer;
-        state.process = process;
+    /// Starts the servers waiting for a file like the one at `path`.
+    fn activate_for(&mut self, path: &Path) {
+        let workspace = self.workspace();
+        let path = activation_path(workspace.as_deref(), path);
+        let activated = self
+            .dormant
+            .iter()
+            .filter(|(globs, _)| globs.is_match(path))
+            .map(|(_, spec)| spec.exec_path.clone())
+            .collect::<Vec<_>>();
+        for exec_path in activated {
+            self.activate(&exec_path);
+        }
+    }
 
-        self.handle_stdout(stdout);
-        self.initialize();
+    fn workspace(&self) -> Option<PathBuf> {
+        self.dispatcher.as_ref()?.workspace.lock().clone()
     }
 
-    fn stop(&self) {
-        self.active.store(false, Ordering::Release);
-        let _ = self.state.lock().process.kill();
-        let _ = self.state.lock().process.wait();
+    pub fn start_server(&mut self, spec: LspServerSpec) {
+        if spec.root.is_none() && self.is_per_root(&spec) {
+            // started for a root once a file of its languages is opened there
+            self.per_root
+                .retain(|per_root| per_root.exec_path != spec.exec_path);
+            self.per_root.push(spec);
+            return;
+        }
+        for language_id in spec.language_ids.iter() {
+            if let Some(specs) = self.evicted.get_mut(language_id) {
+                specs.retain(|evicted| !evicted.same_server(&spec));
+                if specs.is_empty() {
+                    self.evicted.remove(language_id);
+                }
+            }
+        }
+        self.make_room(&spec);
+
+        let language_ids = spec.language_ids.clone();
+        let config = self.server_config(&spec);
+        let client = LspClient::new(spec, config, self.dispatcher.clone().unwrap());
+        if let Some(symbols) = self.restored_symbols.remove(&client.spec.id()) {
+            client.state.lock().workspace_symbols =
+                WorkspaceSymbolCache::Ready(symbols);
+        }
+        if let Some(versions) = self.restored_versions.remove(&client.spec.id()) {
+            client.state.lock().restored_versions = versions;
+        }
+        for language_id in language_ids {
+            let clients = self.clients.entry(language_id).or_default();
+            // a server started again replaces its old instance
+            clients.retain(|c| !c.spec.same_server(&client.spec));
+            clients.push(client.clone());
+        }
     }
 
-    pub fn get_uri(&self, buffer: &Buffer) -> Url {
-        let exists = {
-            let state = self.state.lock();
-            state.opened_documents.contains_key(&buffer.id)
-        };
-        if !exists {
-            let document_uri =
-                Url::from_file_path(&buffer.path).unwrap_or_else(|_| {
-                    panic!("Failed to create URL from path {:?}", buffer.path)
-                });
-            self.send_did_open(
-                &buffer.id,
-                document_uri,
-                &buffer.language_id,
-                buffer.get_document(),
-            );
+    /// Puts in `client`, started already, as if `start_server` had started
+    /// it.
+    #[cfg(test)]
+    pub(crate) fn insert_client(&mut self, client: Arc<LspClient>) {
+        for language_id in client.spec.language_ids.iter() {
+            self.clients
+                .entry(language_id.clone())
+                .or_default()
+                .push(client.clone());
         }
-        self.state
-            .lock()
-            .opened_documents
-            .get(&buffer.id)
-            .unwrap()
-            .clone()
     }
 
-    pub fn handle_message(&self, message: &str) {
-        match JsonRpc::parse(message) {
-            Ok(value @ JsonRpc::Request(_)) => {
-                let id = value.get_id().unwrap();
-                self.handle_request(
-                    value.get_method().unwrap(),
-                    id,
-                    value.get_params().unwrap(),
-                )
-            }
-            Ok(value @ JsonRpc::Notification(_)) => {
-                self.handle_notification(
-                    value.get_method().unwrap(),
-                    value.get_params().unwrap(),
+    /// Shuts down the servers of `language_id` and starts them again with
+    /// `options` merged into their initialization options, opening the
+    /// `buffers` they had open in them again. The options are kept for when
+    /// the servers are next started.
+    pub fn restart_language_server_with_options<'a>(
+        &mut self,
+        language_id: &str,
+        options: Value,
+        buffers: impl Iterator<Item = &'a Buffer>,
+    ) {
+        let running = self.clients_for(language_id).to_vec();
+        let evicted = self.evicted.get(language_id).cloned().unwrap_or_default();
+        for spec in running.iter().map(|client| &client.spec).chain(&evicted) {
+            merge_json(
+                self.option_overrides
+                    .entry(spec.exec_path.clone())
+                    .or_insert_with(|| json!({})),
+                options.clone(),
+            );
+        }
+
+        let dispatcher = match self.dispatcher.clone() {
+            Some(dispatcher) => dispatcher,
+            None => return,
+        };
+        let buffers = buffers.collect::<Vec<_>>();
+        for old in running {
+            let token = NumberOrString::String(format!(
+                "lapce/restart/{}",
+                old.spec.source_name()
+            ));
+            let progress = |value| {
+                dispatcher.send_rpc_notification(
+                    CoreNotification::WorkDoneProgress {
+                        progress: ProgressParams {
+                            token: token.clone(),
+                            value: ProgressParamsValue::WorkDone(value),
+                        },
+                    },
                 );
+            };
+            progress(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: format!("Restarting {}", old.spec.source_name()),
+                ..Default::default()
+            }));
+
+            // the old instance won't answer what it was asked anymore
+            old.fail_requests("the server restarted");
+            old.shutdown();
+            self.start_server(old.spec.clone());
+            for buffer in buffers.iter() {
+                // opens the buffer in the new instance, if it takes it
+                if let Some(client) = self
+                    .buffer_clients(buffer)
+                    .into_iter()
+                    .find(|client| client.spec.same_server(&old.spec))
+                {
+                    // sent once the new instance is initialized
+                    client.get_uri(buffer);
+                }
             }
-            Ok(value @ JsonRpc::Success(_)) => {
-                let id = number_from_id(&value.get_id().unwrap());
-                let result = value.get_result().unwrap();
-                self.handle_response(id, Ok(result.clone()));
-            }
-            Ok(value @ JsonRpc::Error(_)) => {
-                let id = number_from_id(&value.get_id().unwrap());
-                let error = value.get_error().unwrap();
-                self.handle_response(id, Err(anyhow!("{}", error)));
-            }
-            Err(_err) => {}
+
+            progress(WorkDoneProgress::End(WorkDoneProgressEnd { message: None }));
         }
     }
 
-    pub fn handle_request(&self, method: &str, id: Id, params: Params) {
-        match method {
-            "window/workDoneProgress/create" => {
-                // Token is ignored as the workProgress Widget is always working
-                // In the future, for multiple workProgress Handling we should
-                // probably store the token
-                self.send_success_response(id, &Value::Null);
+    /// What the server of `spec` is started with on top of its spec. This is
+    /// where its initialization options are merged, each of these winning
+    /// over the ones before: the defaults of the plugin's manifest, the
+    /// options the plugin gives, the user's and then the workspace's
+    /// settings for the server's languages, and the options it was last
+    /// restarted with.
+    fn server_config(&self, spec: &LspServerSpec) -> ServerConfig {
+        let mut options = spec.default_options.clone();
+        if let Some(overlay) = spec.options.clone() {
+            merge_json(options.get_or_insert_with(|| json!({})), overlay);
+        }
+        for settings in [&self.user_settings, &self.workspace_settings] {
+            for language_id in spec.language_ids.iter() {
+                if let Some(overlay) = settings.get(language_id) {
+                    merge_json(
+                        options.get_or_insert_with(|| json!({})),
+                        overlay.clone(),
+                    );
+                }
             }
-            "client/registerCapability" => {
-                if let Ok(registrations) =
-                    serde_json::from_value::<RegistrationParams>(json!(params))
-                {
-                    for registration in registrations.registrations {
-                        match registration.method.as_str() {
-                            "textDocument/didSave" => {
-                                if let Some(options) = registration.register_options {
-                                    if let Ok(options) = serde_json::from_value::<TextDocumentSaveRegistrationOptions>(options) {
-                                        if let Some(selectors) = options.text_document_registration_options.document_selector {
-                                            // TODO: is false a reasonable default?
-                                            let include_text = options.include_text.unwrap_or(false);
+        }
+        if let Some(overlay) = self.option_overrides.get(&spec.exec_path) {
+            merge_json(options.get_or_insert_with(|| json!({})), overlay.clone());
+        }
+        let document_limits = match self.document_limits.get(&spec.source_name()) {
+            Some(limits) => spec.document_limits.overlay(*limits),
+            None => spec.document_limits,
+        };
+        let max_in_flight_requests = self
+            .max_in_flight_requests
+            .get(&spec.source_name())
+            .copied()
+            .or(spec.max_in_flight_requests)
+            .filter(|max| *max > 0);
+        let trace = spec
+            .language_ids
+            .iter()
+            .find_map(|language_id| self.traces.get(language_id).copied())
+            .or_else(|| {
+                let mut names = spec.language_ids.clone();
+                names.push(spec.source_name());
+                TraceSetting::from_env(&names)
+            });
+        let server_trace = spec
+            .language_ids
+            .iter()
+            .find_map(|language_id| self.server_traces.get(language_id).copied());
+        let mut env = spec.env.clone();
+        if let Some(overrides) = self.server_env.get(&spec.source_name()) {
+            env.extend(overrides.clone());
+        }
+        let launch = self
+            .server_launch
+            .get(&spec.source_name())
+            .cloned()
+            .unwrap_or_else(|| ServerLaunch {
+                wrapper: spec.wrapper.clone(),
+                shell: spec.shell,
+            });
+        let workspace_roots = match spec.root.as_ref() {
+            Some(root) => vec![root.clone()],
+            None => self.workspace_roots.clone(),
+        };
+        ServerConfig {
+            initialization_options: options,
+            document_limits,
+            max_in_flight_requests,
+            trigger_character_override: self.trigger_character_override.clone(),
+            trace,
+            server_trace,
+            env,
+            launch,
+            retry_error_codes: self.retry_error_codes.clone(),
+            workspace_roots,
+        }
+    }
 
-                                            let mut lsp_state = self.state.lock();
+    /// Sets how much the servers of `language_id` log with `$/logTrace`,
+    /// including the ones started later.
+    pub fn set_server_trace(&mut self, language_id: &str, value: TraceValue) {
+        self.server_traces.insert(language_id.to_string(), value);
+        for client in self.clients_for(language_id) {
+            client.set_server_trace(value);
+        }
+    }
 
-                                            // Add each selector our did save filtering
-                                            for selector in selectors {
-                                                let filter = DocumentFilter::from_lsp_filter_loose(selector);
-                                                let cap = DidSaveCapability {
-                                                    filter,
-                                                    include_text,
-                                                };
+    /// Traces the traffic of the servers of `language_id` as `setting`
+    /// says, including the ones started later.
+    pub fn set_trace(&mut self, language_id: &str, setting: TraceSetting) {
+        self.traces.insert(language_id.to_string(), setting);
+        for client in self.clients_for(language_id) {
+            client.set_trace(setting);
+        }
+    }
 
-                                                lsp_state.did_save_capabilities.push(cap);
-                                            }
-                                        }
-                                    }
-                                }
-                                // TODO: report error?
-                            }
-                            _ => println!("Received unhandled client/registerCapability request {}", registration.method),
-                        }
-                    }
-                }
-            }
-            "workspace/configuration" => {
-                if let Ok(config) =
-                    serde_json::from_value::<ConfigurationParams>(json!(params))
-                {
-                    let items = config
-                        .items
-                        .into_iter()
-                        .map(|_item| Value::Null)
-                        .collect::<Vec<Value>>();
+    /// The initialization options the server of `language_id` is given,
+    /// exactly as they are sent to it.
+    pub fn effective_server_config(&self, language_id: &str) -> Option<Value> {
+        let client = self.client(language_id)?;
+        self.server_config(&client.spec).initialization_options
+    }
 
-                    self.send_success_response(id, &Value::Array(items));
-                }
-            }
-            method => {
-                println!("Received unhandled request {method}");
+    /// Called when `buffer` is opened, keeping it from the servers of its
+    /// language if it's larger than `max_document_size`. The buffer can
+    /// still be edited, only without language features.
+    pub fn open_buffer(&mut self, buffer: &Buffer) {
+        if self.is_ignored(&buffer.path) {
+            self.ignore_buffer(buffer);
+            return;
+        }
+        let size = buffer.rope.len();
+        if let Some(limit) = self.max_document_size.filter(|limit| size > *limit) {
+            self.detached.insert(buffer.id);
+            if let Some(dispatcher) = self.dispatcher.as_ref() {
+                dispatcher.send_rpc_notification(
+                    CoreNotification::DocumentTooLargeForLsp {
+                        path: buffer.path.clone(),
+                        size,
+                        limit,
+                    },
+                );
             }
+            return;
         }
+        self.activate_for(&buffer.path);
+        self.restart_evicted(&buffer.language_id);
+        self.start_root_servers(buffer);
+        self.report_refusals(buffer);
     }
 
-    pub fn handle_notification(&self, method: &str, params: Params) {
-        match method {
-            "textDocument/publishDiagnostics" => {
-                self.dispatcher.send_notification(
-                    "publish_diagnostics",
-                    json!({
-                        "diagnostics": params,
-                    }),
-                );
+    /// Tells core about the servers of `buffer`'s language it's too large
+    /// for, as it's opened.
+    fn report_refusals(&self, buffer: &Buffer) {
+        let dispatcher = match self.dispatcher.as_ref() {
+            Some(dispatcher) => dispatcher,
+            None => return,
+        };
+        for client in self.clients_for(&buffer.language_id) {
+            if !self.serves_root(client, &buffer.path) {
+                continue;
             }
-            "$/progress" => {
-                self.dispatcher.send_notification(
-                    "work_done_progress",
-                    json!({
-                        "progress": params,
-                    }),
+            if let Some(reason) = client.refusal(buffer) {
+                dispatcher.send_rpc_notification(
+                    CoreNotification::DocumentTooLargeForServer {
+                        path: buffer.path.clone(),
+                        server: client.spec.source_name(),
+                        reason,
+                    },
                 );
             }
-            "window/showMessage" => {
-                // TODO: send message to display
-            }
-            "window/logMessage" => {
-                // TODO: We should log the message here. Waiting for
-                // the discussion about handling plugins logs before doing anything
-            }
-            "experimental/serverStatus" => {
-                //TODO: Logging of server status
-            }
-            method => {
-                println!("Received unhandled notification {}", method);
-            }
         }
     }
 
-    pub fn handle_response(&self, id: u64, result: Result<Value>) {
-        let callback =
-            {
-                self.state.lock().pending.remove(&id).unwrap_or_else(|| {
-                    panic!("id {} missing from request table", id)
-                })
-            };
-        callback.call(self, result);
+    /// Whether `buffer` is kept from the servers, for its size or by the
+    /// user's ignore globs.
+    pub fn is_detached(&self, buffer: &Buffer) -> bool {
+        self.detached.contains(&buffer.id) || self.ignored.contains(&buffer.id)
     }
 
-    pub fn write(&self, msg: &str) -> Result<()> {
-        let mut state = self.state.lock();
-        state.writer.write_all(msg.as_bytes())?;
-        state.writer.flush()?;
-        Ok(())
+    /// Whether the commands of `language_id` format its documents.
+    pub fn has_formatter(&self, language_id: &str) -> bool {
+        self.formatters.contains_key(language_id)
     }
 
-    fn send_rpc(&self, value: &Value) {
-        let rpc = match prepare_lsp_json(value) {
-            Ok(r) => r,
-            Err(err) => panic!("Encoding Error {:?}", err),
-        };
-
-        let _ = self.write(rpc.as_ref());
+    fn is_ignored(&self, path: &Path) -> bool {
+        let workspace = self.workspace();
+        self.ignore
+            .is_match(activation_path(workspace.as_deref(), path))
     }
 
-    pub fn send_notification(&self, method: &str, params: Params) {
-        let notification = JsonRpc::notification_with_params(method, params);
-        let res = to_value(&notification).unwrap();
-        self.send_rpc(&res);
+    /// Called when `buffer` is closed, closing it in the servers it's open
+    /// in. Core drops the trigger characters of the buffer itself.
+    pub fn close_buffer(&mut self, buffer: &Buffer) {
+        for client in self.servers() {
+            client.close_document(buffer.id);
+        }
+        self.detached.remove(&buffer.id);
+        self.ignored.remove(&buffer.id);
     }
 
-    pub fn send_request(&self, method: &str, params: Params, completion: Callback) {
-        let request = {
-            let mut state = self.state.lock();
-            let next_id = state.next_id;
-            state.pending.insert(next_id, completion);
-            state.next_id += 1;
-
-            JsonRpc::request_with_params(Id::Num(next_id as i64), method, params)
-        };
-
-        self.send_rpc(&to_value(&request).unwrap());
+    fn ignore_buffer(&mut self, buffer: &Buffer) {
+        for client in self.clients_for(&buffer.language_id) {
+            if client.close_document(buffer.id) {
+                client.clear_trigger_characters(buffer.id);
+            }
+        }
+        self.ignored.insert(buffer.id);
+        if let Some(dispatcher) = self.dispatcher.as_ref() {
+            dispatcher.send_rpc_notification(
+                CoreNotification::DocumentIgnoredForLsp {
+                    path: buffer.path.clone(),
+                },
+            );
+        }
+    }
+
+    /// Keeps the files matching `patterns` from the servers from now on,
+    /// closing the open `buffers` that match them in the servers and
+    /// opening the ones that no longer do. Invalid patterns are left out.
+    pub fn set_ignore<'a>(
+        &mut self,
+        patterns: &[String],
+        buffers: impl Iterator<Item = &'a Buffer>,
+    ) {
+        // the invalid patterns are only looked for once the set fails
+        let ignore = glob_set(patterns).or_else(|_| {
+            let valid = patterns
+                .iter()
+                .filter(|pattern| match Glob::new(pattern) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        log::error!(
+                            "can't keep {pattern} from language servers: {err}"
+                        );
+                        false
+                    }
+                })
+                .collect::<Vec<_>>();
+            glob_set(&valid)
+        });
+        self.ignore = ignore.unwrap_or_else(|err| {
+            log::error!("can't keep files from language servers: {err}");
+            GlobSet::empty()
+        });
+
+        for buffer in buffers {
+            let ignored = self.is_ignored(&buffer.path);
+            if ignored == self.ignored.contains(&buffer.id) {
+                continue;
+            }
+            if ignored {
+                self.ignore_buffer(buffer);
+                continue;
+            }
+            self.ignored.remove(&buffer.id);
+            if self.is_detached(buffer) {
+                continue;
+            }
+            self.activate_for(&buffer.path);
+            self.restart_evicted(&buffer.language_id);
+            self.start_root_servers(buffer);
+            for client in self.buffer_clients(buffer) {
+                // opens the document in the server
+                client.get_uri(buffer);
+            }
+        }
+    }
+
+    /// Starts the servers of `language_id` again if they were shut down to
+    /// make room for other servers or for being idle.
+    pub fn restart_evicted(&mut self, language_id: &str) {
+        if let Some(specs) = self.evicted.remove(language_id) {
+            for spec in specs {
+                self.start_server(spec);
+            }
+        }
+    }
+
+    /// Shuts down the least recently used idle servers until starting
+    /// the server of `spec` stays within `max_servers`.
+    fn make_room(&mut self, spec: &LspServerSpec) {
+        let max_servers = match self.max_servers {
+            Some(max_servers) => max_servers,
+            None => return,
+        };
+
+        loop {
+            // the servers it replaces don't count
+            let others = self
+                .servers()
+                .into_iter()
+                .filter(|client| !client.spec.same_server(spec))
+                .collect::<Vec<_>>();
+            if others.len() < max_servers {
+                return;
+            }
+
+            let lru = others
+                .into_iter()
+                .filter(|client| {
+                    // a server still answering requests isn't idle
+                    client.state.lock().pending.is_empty()
+                })
+                .min_by_key(|client| client.state.lock().last_used)
+                .cloned();
+            let lru = match lru {
+                Some(lru) => lru,
+                None => {
+                    log::warn!(
+                        "can't shut down a busy language server to start {}",
+                        spec.exec_path
+                    );
+                    return;
+                }
+            };
+
+            self.evict(&lru);
+        }
+    }
+
+    /// Shuts down the servers nothing was sent to for `idle_timeout` and
+    /// that have no documents open, as those would lose their diagnostics.
+    /// They're started again when their languages are used.
+    pub fn shut_down_idle(&mut self) {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        let idle = self
+            .servers()
+            .into_iter()
+            .filter(|client| {
+                let state = client.state.lock();
+                state.pending.is_empty()
+                    && state.queued.is_empty()
+                    && state.opened_documents.is_empty()
+                    && state.last_used.elapsed() >= idle_timeout
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for client in idle {
+            log::info!(
+                "shutting down {}, idle for {idle_timeout:?}",
+                client.spec.exec_path
+            );
+            self.evict(&client);
+        }
+    }
+
+    /// Sends the servers idle for long enough the `memory_trim` command of
+    /// their languages, once for each time they're idle. The servers idle
+    /// for `idle_timeout` are shut down instead.
+    pub fn trim_idle(&self) {
+        for client in self.servers() {
+            let trim = client
+                .spec
+                .language_ids
+                .iter()
+                .find_map(|language_id| self.memory_trim.get(language_id));
+            if let Some(trim) = trim {
+                client.trim_memory(trim);
+            }
+        }
+    }
+
+    /// Shuts `client` down, keeping its spec to start it again when its
+    /// languages are next used.
+    fn evict(&mut self, client: &Arc<LspClient>) {
+        client.shutdown();
+        for clients in self.clients.values_mut() {
+            clients.retain(|c| !Arc::ptr_eq(c, client));
+        }
+        self.clients.retain(|_, clients| !clients.is_empty());
+        for language_id in client.spec.language_ids.iter() {
+            self.evicted
+                .entry(language_id.clone())
+                .or_default()
+                .push(client.spec.clone());
+        }
+    }
+
+    pub fn new_buffer(
+        &self,
+        buffer_id: &BufferId,
+        path: &str,
+        language_id: &str,
+        text: String,
+        rev: u64,
+    ) {
+        for client in self.clients_for(language_id) {
+            if !self.serves_root(client, Path::new(path)) {
+                continue;
+            }
+            {
+                let state = client.state.lock();
+                if !state.is_initialized() {
+                    continue;
+                }
+            }
+
+            let document_uri = Url::from_file_path(path).unwrap();
+            client.send_did_open(
+                buffer_id,
+                document_uri,
+                language_id,
+                text.clone(),
+                rev as i32,
+            );
+        }
+    }
+
+    /// Closes `buffer` in the servers of its language and opens it in the
+    /// servers of `language_id` instead, with the text it has now.
+    pub fn change_document_language(
+        &mut self,
+        buffer: &mut Buffer,
+        language_id: String,
+    ) {
+        if buffer.language_id == language_id {
+            return;
+        }
+
+        for client in self.clients_for(&buffer.language_id) {
+            if client.close_document(buffer.id) {
+                client.clear_trigger_characters(buffer.id);
+            }
+        }
+        buffer.language_id = language_id;
+        if !self.is_detached(buffer) {
+            self.restart_evicted(&buffer.language_id);
+            self.start_root_servers(buffer);
+        }
+        for client in self.buffer_clients(buffer) {
+            // opens the document in the server
+            client.get_uri(buffer);
+        }
+    }
+
+    pub fn save_buffer(&self, buffer: &Buffer, workspace_path: &Path) {
+        if self.is_detached(buffer) {
+            return;
+        }
+        for client in self.servers() {
+            {
+                let state = client.state.lock();
+                if !state.is_initialized() {
+                    return;
+                }
+            }
+
+            // Get rid of the workspace path prefix so that it can be used with the filters
+            let buffer_path = buffer
+                .path
+                .strip_prefix(workspace_path)
+                .unwrap_or(&buffer.path);
+
+            let mut passed_filter = client.spec.handles(&buffer.language_id);
+            let mut include_text = false;
+            if !passed_filter {
+                let lsp_state = client.state.lock();
+
+                // TODO: Should we iterate in reverse order so that later capabilities
+                // can overwrite old ones?
+                // Find the first capability that wants this file, if any.
+                for cap in &lsp_state.did_save_capabilities {
+                    if let Some(language_id) = &cap.filter.language_id {
+                        if language_id != &buffer.language_id {
+                            continue;
+                        }
+                    }
+
+                    if let Some(pattern) = &cap.filter.pattern {
+                        if !pattern.is_match(buffer_path) {
+                            continue;
+                        }
+                    }
+
+                    passed_filter = true;
+                    include_text = cap.include_text;
+                    break;
+                }
+
+                // Get rid of the mutex guard
+                drop(lsp_state);
+            }
+
+            if passed_filter && client.accepts(buffer) {
+                let uri = client.get_uri(buffer);
+                let text = if include_text {
+                    Some(buffer.get_document())
+                } else {
+                    None
+                };
+                client.send_did_save(uri, text);
+            }
+        }
+    }
+
+    pub fn get_semantic_tokens(&self, id: RequestId, buffer: &Buffer) {
+        let buffer = buffer.clone();
+        if let Some(client) = self.buffer_client(&buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.semantic_tokens_provider.as_ref())
+                    .map(|prov| match prov {
+                        SemanticTokensServerCapabilities::SemanticTokensOptions(opts) => opts,
+                        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(reg) => &reg.semantic_tokens_options,
+                    }).map(|opts| {
+                        // TODO: handle opts.full delta
+                        opts.full.is_some()
+                    }).unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(&buffer);
+            let old_buffer = buffer;
+
+            client.request_semantic_tokens(uri, move |lsp_client, result| {
+                let buffers = lsp_client.dispatcher.buffers.lock();
+                let buffer = buffers.get(&old_buffer.id).unwrap();
+                // If the revision changed while we were requesting, then we refuse the request as it will have made another one
+                if buffer.rev != old_buffer.rev {
+                    lsp_client
+                        .dispatcher
+                        .respond(id, Err(anyhow!("revision changed")));
+                    return;
+                }
+
+                let lsp_state = lsp_client.state.lock();
+                let semantic_tokens_provider = &lsp_state
+                    .server_capabilities
+                    .as_ref()
+                    .unwrap()
+                    .semantic_tokens_provider;
+                let result = result.and_then(|value| {
+                    format_semantic_styles(buffer, semantic_tokens_provider, value)
+                        .map(|styles| {
+                            serde_json::to_value(SemanticStyles {
+                                rev: buffer.rev,
+                                buffer_id: buffer.id,
+                                path: buffer.path.clone(),
+                                styles,
+                                len: buffer.len(),
+                            })
+                            .unwrap()
+                        })
+                        .ok_or_else(|| anyhow!("can't format semantic styles"))
+                });
+
+                lsp_client.dispatcher.respond(id, result);
+            });
+        }
+    }
+
+    pub fn get_document_symbols(&self, id: RequestId, buffer: &Buffer) {
+        if let Some(client) = self.buffer_client(buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.document_symbol_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_document_symbols(
+                uri,
+                Some(id),
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        }
+    }
+
+    pub fn get_workspace_symbols(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        query: String,
+    ) {
+        // TODO: We could collate workspace symbols from all the lsps?
+        if let Some(client) = self.buffer_client(buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.workspace_symbol_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            if self.cache_workspace_symbols {
+                if let Some(symbols) = client.cached_workspace_symbols(&query) {
+                    client.dispatcher.respond(id, Ok(json!(symbols)));
+                    return;
+                }
+            }
+            client.request_workspace_symbols(
+                query,
+                Some(id),
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        }
+    }
+
+    /// Drops the cached symbols of the files at `paths`, which changed on
+    /// disk, until the symbols are loaded again.
+    pub fn invalidate_workspace_symbols(&self, paths: &[PathBuf]) {
+        if !self.cache_workspace_symbols {
+            return;
+        }
+        let uris = paths
+            .iter()
+            .filter_map(|path| Url::from_file_path(path).ok())
+            .collect::<HashSet<_>>();
+        for client in self.servers() {
+            let mut state = client.state.lock();
+            let cache = std::mem::replace(
+                &mut state.workspace_symbols,
+                WorkspaceSymbolCache::NotLoaded,
+            );
+            state.workspace_symbols = match cache {
+                WorkspaceSymbolCache::Ready(mut symbols)
+                | WorkspaceSymbolCache::Stale(mut symbols) => {
+                    symbols.retain(|symbol| !uris.contains(&symbol.location.uri));
+                    WorkspaceSymbolCache::Stale(symbols)
+                }
+                // what's loading may be from before the change
+                WorkspaceSymbolCache::Loading => {
+                    WorkspaceSymbolCache::Stale(Vec::new())
+                }
+                cache => cache,
+            };
+            state.workspace_symbols_generation += 1;
+        }
+    }
+
+    /// The symbols of the whole workspace the servers gave, by server id.
+    pub fn workspace_symbols(&self) -> HashMap<String, Vec<SymbolInformation>> {
+        self.servers()
+            .into_iter()
+            .filter_map(|client| match &client.state.lock().workspace_symbols {
+                WorkspaceSymbolCache::Ready(symbols) => {
+                    Some((client.spec.id(), symbols.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Gives the servers started from now on the symbols of the whole
+    /// workspace they gave before, by server id, instead of asking them.
+    pub fn restore_workspace_symbols(
+        &mut self,
+        symbols: HashMap<String, Vec<SymbolInformation>>,
+    ) {
+        if self.cache_workspace_symbols {
+            self.restored_symbols = symbols;
+        }
+    }
+
+    /// The last version each server was given of the documents open in it,
+    /// by server id.
+    pub fn document_versions(&self) -> HashMap<String, HashMap<Url, i32>> {
+        self.servers()
+            .into_iter()
+            .map(|client| {
+                let state = client.state.lock();
+                let versions = state
+                    .document_buffers
+                    .iter()
+                    .filter_map(|(uri, buffers)| {
+                        let version =
+                            state.document_versions.get(buffers.first()?)?;
+                        Some((uri.clone(), *version))
+                    })
+                    .collect();
+                (client.spec.id(), versions)
+            })
+            .collect()
+    }
+
+    /// The ids of the servers shut down for being idle.
+    pub fn idle_servers(&self) -> Vec<String> {
+        let mut ids = self
+            .evicted
+            .values()
+            .flatten()
+            .map(|spec| spec.id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Has the servers started from now on go on from the versions of their
+    /// documents they were given before, by server id, and keeps the servers
+    /// that were idle from starting until they're needed.
+    pub fn restore_servers(
+        &mut self,
+        versions: HashMap<String, HashMap<Url, i32>>,
+        idle: Vec<String>,
+    ) {
+        self.restored_versions = versions;
+        self.restored_idle = idle.into_iter().collect();
+    }
+
+    /// Pulls the diagnostics of the whole workspace again from the servers
+    /// that give them, for files changed outside the editor.
+    pub fn refresh_workspace_diagnostics(&self) {
+        for client in self.servers() {
+            client.pull_workspace_diagnostics();
+        }
+    }
+
+    /// Has the servers of `buffer` compute its diagnostics again, for when
+    /// they got stuck, giving the ids of the servers that were asked.
+    pub fn refresh_diagnostics(&self, buffer: &Buffer) -> Vec<String> {
+        self.buffer_clients(buffer)
+            .into_iter()
+            .filter(|client| client.refresh_diagnostics(buffer))
+            .map(|client| client.spec.id())
+            .collect()
+    }
+
+    /// Loads again the symbols dropped for files changing.
+    pub fn refresh_workspace_symbols(&self) {
+        for client in self.servers() {
+            let stale = matches!(
+                client.state.lock().workspace_symbols,
+                WorkspaceSymbolCache::Stale(_)
+            );
+            if stale {
+                client.load_workspace_symbols();
+            }
+        }
+    }
+
+    /// Formats `buffer` with its server if the server can, or with the
+    /// formatter a plugin registered for its language if there's one.
+    pub fn get_document_formatting(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.formatting_server(buffer, server_id) {
+            let uri = client.get_uri(buffer);
+            client.request_document_formatting(uri, move |lsp_client, result| {
+                lsp_client.dispatcher.respond(id, result);
+            });
+        } else if server_id.is_none()
+            && self.formatters.contains_key(&buffer.language_id)
+        {
+            self.format_document_external(id, buffer);
+        } else {
+            self.dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(anyhow!("no document formatting")));
+        }
+    }
+
+    /// Asks the server that formats after `ch` is typed to format what was
+    /// typed before `position`.
+    pub fn on_type_formatting(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        ch: String,
+        options: FormattingOptions,
+    ) {
+        let client = self.buffer_clients(buffer).into_iter().find(|client| {
+            let state = client.state.lock();
+            state.is_initialized()
+                && state.on_type_formatting_trigger_characters.contains(&ch)
+        });
+        let client = match client {
+            Some(client) => client,
+            None => {
+                self.dispatcher
+                    .as_ref()
+                    .unwrap()
+                    .respond(id, Err(anyhow!("no on type formatting for {ch}")));
+                return;
+            }
+        };
+
+        let uri = client.get_uri(buffer);
+        client.request_on_type_formatting(
+            uri,
+            position,
+            ch,
+            options,
+            move |lsp_client, result| {
+                // a null result is no edits
+                let result = result.map(|v| if v.is_null() { json!([]) } else { v });
+                lsp_client.dispatcher.respond(id, result);
+            },
+        );
+    }
+
+    /// The server formatting `buffer`, the one of `server_id` if given, if
+    /// it can.
+    pub fn formatting_server(
+        &self,
+        buffer: &Buffer,
+        server_id: Option<&str>,
+    ) -> Option<&Arc<LspClient>> {
+        self.buffer_server(buffer, server_id).filter(|client| {
+            let state = client.state.lock();
+            state.is_initialized()
+                && state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.document_formatting_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false)
+        })
+    }
+
+    /// The formatter registered for `language_id`, if any.
+    pub fn formatter(&self, language_id: &str) -> Option<FormatterSpec> {
+        self.formatters.get(language_id).cloned()
+    }
+
+    /// Lets the formatter a plugin registered for `spec.language_ids` format
+    /// their documents when their servers can't.
+    pub fn register_formatter(&mut self, spec: FormatterSpec) {
+        for language_id in spec.language_ids.iter() {
+            if let Some(old) = self.formatters.get(language_id) {
+                log::warn!(
+                    "{} replaces {} as the formatter of {language_id}",
+                    spec.command,
+                    old.command
+                );
+            }
+            self.formatters.insert(language_id.clone(), spec.clone());
+        }
+    }
+
+    /// Drops the formatters `plugin` registered, as it stopped.
+    pub fn unregister_formatters(&mut self, plugin: &str) {
+        self.formatters
+            .retain(|_, spec| spec.plugin.as_deref() != Some(plugin));
+    }
+
+    /// Formats `buffer` with the formatter registered for its language,
+    /// answering with an edit replacing the whole document. The formatter
+    /// gets the text of the buffer rather than the file, as the buffer is
+    /// formatted before it's saved.
+    pub fn format_document_external(&self, id: RequestId, buffer: &Buffer) {
+        let dispatcher = self.dispatcher.clone().unwrap();
+        let formatter = match self.formatters.get(&buffer.language_id) {
+            Some(formatter) => formatter.clone(),
+            None => {
+                dispatcher.respond(
+                    id,
+                    Err(anyhow!("no formatter for {}", buffer.language_id)),
+                );
+                return;
+            }
+        };
+
+        let path = buffer.path.clone();
+        let text = buffer.get_document();
+        let end = buffer.offset_to_position(buffer.len());
+        thread::spawn(move || {
+            let workspace = dispatcher.workspace.lock().clone();
+            let result =
+                formatter
+                    .format(workspace, &path, text.clone())
+                    .map(|formatted| {
+                        if formatted == text {
+                            return Vec::new();
+                        }
+                        vec![TextEdit {
+                            range: Range {
+                                start: Position::new(0, 0),
+                                end: end.unwrap_or_default(),
+                            },
+                            new_text: formatted,
+                        }]
+                    });
+            if let Err(e) = result.as_ref() {
+                dispatcher.send_rpc_notification(
+                    CoreNotification::FormatterFailed {
+                        path,
+                        formatter: formatter.name(),
+                        error: e.to_string(),
+                    },
+                );
+            }
+            dispatcher.respond_rpc(id, result);
+        });
+    }
+
+    /// Remembers that a completion item was just accepted in the buffer.
+    pub fn completion_accepted(&mut self, buffer_id: BufferId) {
+        self.completion_accepted = Some((buffer_id, Instant::now()));
+    }
+
+    /// Whether a completion of the kind is to be ignored for coming too
+    /// soon after an item was accepted in the buffer, whose text likely
+    /// ended with the trigger character. Invoked ones are always answered.
+    pub fn completion_suppressed(
+        &self,
+        buffer_id: BufferId,
+        trigger_kind: Option<CompletionTriggerKind>,
+    ) -> bool {
+        let triggered = trigger_kind
+            .map(|kind| kind != CompletionTriggerKind::INVOKED)
+            .unwrap_or(false);
+        triggered
+            && self
+                .completion_accepted
+                .map(|(accepted_in, at)| {
+                    accepted_in == buffer_id
+                        && at.elapsed() < self.completion_suppression
+                })
+                .unwrap_or(false)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_completion(
+        &self,
+        id: RequestId,
+        request_id: usize,
+        buffer: &Buffer,
+        position: Position,
+        trigger_kind: Option<CompletionTriggerKind>,
+        trigger_character: Option<String>,
+        server_id: Option<&str>,
+    ) {
+        let clients = self
+            .buffer_clients(buffer)
+            .into_iter()
+            .filter(|client| match server_id {
+                Some(server_id) => client.spec.id() == server_id,
+                None => true,
+            })
+            .filter_map(|client| {
+                let state = client.state.lock();
+                if !state.is_initialized() {
+                    return None;
+                }
+                let options = state
+                    .server_capabilities
+                    .as_ref()?
+                    .completion_provider
+                    .as_ref()?;
+
+                let trigger_kind = match trigger_kind {
+                    Some(trigger_kind) => trigger_kind,
+                    None => return Some((client.clone(), None)),
+                };
+                let context = match trigger_character.as_ref() {
+                    Some(c)
+                        if trigger_kind
+                            == CompletionTriggerKind::TRIGGER_CHARACTER =>
+                    {
+                        if !state.completion_trigger_characters.contains(c) {
+                            return None;
+                        }
+                        let own = options
+                            .trigger_characters
+                            .as_ref()
+                            .map(|own| own.contains(c))
+                            .unwrap_or(false);
+                        if own {
+                            CompletionContext {
+                                trigger_kind,
+                                trigger_character: Some(c.clone()),
+                            }
+                        } else {
+                            // a server is only told about the trigger
+                            // characters it advertised itself
+                            CompletionContext {
+                                trigger_kind: CompletionTriggerKind::INVOKED,
+                                trigger_character: None,
+                            }
+                        }
+                    }
+                    _ => CompletionContext {
+                        trigger_kind,
+                        trigger_character: None,
+                    },
+                };
+                Some((client.clone(), Some(context)))
+            })
+            .collect::<Vec<_>>();
+        if clients.is_empty() {
+            return;
+        }
+
+        let filter = self
+            .filter_completion
+            .then(|| word_before(buffer, &position))
+            .filter(|word| !word.is_empty());
+        let merge = Arc::new(Mutex::new(CompletionMerge::new(
+            id,
+            request_id,
+            position,
+            clients.len(),
+            filter,
+            clients[0].0.dispatcher.clone(),
+        )));
+        if clients.len() > 1 {
+            // don't let a slow server hold back the others' items
+            let merge = merge.clone();
+            thread::spawn(move || {
+                thread::sleep(COMPLETION_SERVER_TIMEOUT);
+                merge.lock().time_out();
+            });
+        }
+
+        // servers that can stream their items send them with this token,
+        // others just answer as usual
+        let token = format!("lapce/completion/{id}");
+        let documentation = self.completion_documentation;
+        for (client, context) in clients {
+            let uri = client.get_uri(buffer);
+            let source = client.spec.source_name();
+            {
+                let language_id = buffer.language_id.clone();
+                let source = source.clone();
+                let merge = merge.clone();
+                client.state.lock().partial_results.insert(
+                    token.clone(),
+                    Arc::new(move |lsp_client, value| {
+                        let mut value = transform_completion(
+                            &lsp_client.dispatcher,
+                            &language_id,
+                            value,
+                        );
+                        finish_completion(&mut value, documentation);
+                        merge.lock().add_partial(&source, value);
+                    }),
+                );
+            }
+
+            let language_id = buffer.language_id.clone();
+            let merge = merge.clone();
+            let token = token.clone();
+            client.request_completion(
+                uri,
+                position,
+                context,
+                Some(NumberOrString::String(token.clone())),
+                move |lsp_client, result| {
+                    lsp_client.state.lock().partial_results.remove(&token);
+                    let mut result = result.map(|v| {
+                        transform_completion(&lsp_client.dispatcher, &language_id, v)
+                    });
+                    let finish = move |mut result: Result<Value>| {
+                        if let Ok(v) = result.as_mut() {
+                            finish_completion(v, documentation);
+                        }
+                        merge.lock().add(&source, result);
+                    };
+                    let eager = documentation == CompletionDocumentation::Eager
+                        && result.is_ok()
+                        && lsp_client
+                            .state
+                            .lock()
+                            .server_capabilities
+                            .as_ref()
+                            .map(can_resolve_completion)
+                            .unwrap_or(false);
+                    if eager {
+                        // the resolves are answered on the thread this runs on
+                        let lsp_client = lsp_client.clone();
+                        thread::spawn(move || {
+                            if let Ok(v) = result.as_mut() {
+                                resolve_completion_documentation(&lsp_client, v);
+                            }
+                            finish(result);
+                        });
+                    } else {
+                        finish(result);
+                    }
+                },
+            );
+        }
+    }
+
+    pub fn completion_resolve(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        completion_item: &CompletionItem,
+    ) {
+        // send the item back to the server it came from, as it was given
+        let mut completion_item = completion_item.clone();
+        let source = CompletionItemData::of(&completion_item).map(|tag| {
+            completion_item.data = tag.data;
+            tag.source
+        });
+        let client = match source.as_ref() {
+            Some(source) => self
+                .buffer_clients(buffer)
+                .into_iter()
+                .find(|client| &client.spec.source_name() == source),
+            None => self.buffer_client(buffer),
+        };
+
+        let documentation = self.completion_documentation;
+        if let Some(client) = client {
+            client.completion_resolve(
+                &completion_item,
+                move |lsp_client, result| {
+                    let mut resp = json!({ "id": id });
+                    match result {
+                        Ok(mut v) => {
+                            if documentation == CompletionDocumentation::Off {
+                                if let Some(item) = v.as_object_mut() {
+                                    item.remove("documentation");
+                                }
+                            }
+                            markdown::normalize_completion_item(&mut v);
+                            if let Some(source) = source {
+                                tag_completion_item(&mut v, &source);
+                            }
+                            resp["result"] = v;
+                        }
+                        Err(e) => {
+                            resp["error"] = json!({
+                                "code": 0,
+                                "message": format!("{}",e),
+                            })
+                        }
+                    }
+                    let _ = lsp_client.dispatcher.sender.send(resp);
+                },
+            );
+        }
+    }
+
+    pub fn get_hover(
+        &self,
+        id: RequestId,
+        _request_id: usize,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.hover_provider.as_ref())
+                    .map(|prov| prov != &HoverProviderCapability::Simple(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_hover(uri, position, move |lsp_client, result| {
+                let mut resp = json!({ "id": id });
+                match result {
+                    Ok(mut v) => {
+                        markdown::normalize_hover(&mut v);
+                        resp["result"] = v;
+                    }
+                    Err(e) => {
+                        resp["error"] = json!({
+                            "code": 0,
+                            "message": format!("{}", e),
+                        })
+                    }
+                }
+                let _ = lsp_client.dispatcher.sender.send(resp);
+            });
+        }
+    }
+
+    pub fn get_signature(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                // TODO: use the trigger characters fields
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .map(|cap| cap.signature_help_provider.is_some())
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_signature(uri, position, move |lsp_client, result| {
+                let mut resp = json!({ "id": id });
+                match result {
+                    Ok(v) => resp["result"] = v,
+                    Err(e) => {
+                        resp["error"] = json!({
+                            "code": 0,
+                            "message": format!("{}",e),
+                        })
+                    }
+                }
+                let _ = lsp_client.dispatcher.sender.send(resp);
+            });
+        }
+    }
+
+    pub fn get_references(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.references_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_references(
+                uri,
+                position,
+                Some(id),
+                move |lsp_client, result| {
+                    let mut resp = json!({ "id": id });
+                    match result {
+                        Ok(v) => resp["result"] = v,
+                        Err(e) => {
+                            resp["error"] = json!({
+                                "code": 0,
+                                "message": format!("{}",e),
+                            })
+                        }
+                    }
+                    let _ = lsp_client.dispatcher.sender.send(resp);
+                },
+            );
+        }
+    }
+
+    pub fn get_inlay_hints(&self, id: RequestId, buffer: &Buffer) {
+        if let Some(client) = self.buffer_client(buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.inlay_hint_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            // Range over the entire buffer
+            let range = Range {
+                start: Position::new(0, 0),
+                end: buffer.offset_to_position(buffer.len()).unwrap(),
+            };
+            client.request_inlay_hints(uri, range, move |lsp_client, result| {
+                let mut resp = json!({ "id": id });
+                match result {
+                    Ok(v) => resp["result"] = v,
+                    Err(e) => {
+                        resp["error"] = json!({
+                            "code": 0,
+                            "message": format!("{}", e),
+                        })
+                    }
+                }
+
+                let _ = lsp_client.dispatcher.sender.send(resp);
+            });
+        }
+    }
+
+    pub fn inlay_hint_resolve(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        inlay_hint: &InlayHint,
+    ) {
+        let client = match self.buffer_client(buffer) {
+            Some(client) => client,
+            None => return,
+        };
+        let can_resolve = {
+            let state = client.state.lock();
+            state.is_initialized()
+                && state
+                    .server_capabilities
+                    .as_ref()
+                    .map(can_resolve_inlay_hints)
+                    .unwrap_or(false)
+        };
+        if !can_resolve {
+            client.dispatcher.respond(id, Ok(json!(inlay_hint)));
+            return;
+        }
+
+        client.inlay_hint_resolve(inlay_hint, move |lsp_client, result| {
+            lsp_client.dispatcher.respond(id, result);
+        });
+    }
+
+    pub fn get_code_lens(&self, id: RequestId, buffer: &Buffer) {
+        let client = match self.buffer_client(buffer) {
+            Some(client) => client,
+            None => return,
+        };
+        {
+            let state = client.state.lock();
+            if !state.is_initialized()
+                || state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.code_lens_provider.as_ref())
+                    .is_none()
+            {
+                return;
+            }
+        }
+
+        let uri = client.get_uri(buffer);
+        client.request_code_lens(uri, move |lsp_client, result| {
+            // a null result is no lenses
+            let result = result.map(|v| if v.is_null() { json!([]) } else { v });
+            lsp_client.dispatcher.respond(id, result);
+        });
+    }
+
+    /// Resolves `code_lens` if it has no command yet, which servers leave
+    /// out when it's costly to work out, like the references of a function.
+    pub fn code_lens_resolve(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        code_lens: &CodeLens,
+    ) {
+        let client = match self.buffer_client(buffer) {
+            Some(client) => client,
+            None => return,
+        };
+        let can_resolve = code_lens.command.is_none() && {
+            let state = client.state.lock();
+            state.is_initialized()
+                && state
+                    .server_capabilities
+                    .as_ref()
+                    .map(can_resolve_code_lens)
+                    .unwrap_or(false)
+        };
+        if !can_resolve {
+            client.dispatcher.respond(id, Ok(json!(code_lens)));
+            return;
+        }
+
+        client.code_lens_resolve(code_lens, move |lsp_client, result| {
+            lsp_client.dispatcher.respond(id, result);
+        });
+    }
+
+    pub fn get_code_actions(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+        only: Vec<CodeActionKind>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.code_action_provider.as_ref())
+                    .map(|prov| prov != &CodeActionProviderCapability::Simple(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            let range = Range {
+                start: position,
+                end: position,
+            };
+            client.request_code_actions(
+                uri,
+                range,
+                only,
+                move |lsp_client, result| {
+                    let mut resp = json!({ "id": id });
+                    match result {
+                        Ok(v) => resp["result"] = v,
+                        Err(e) => {
+                            resp["error"] = json!({
+                                "code": 0,
+                                "message": format!("{}",e),
+                            })
+                        }
+                    }
+                    let _ = lsp_client.dispatcher.sender.send(resp);
+                },
+            );
+        }
+    }
+
+    pub fn get_definition(
+        &self,
+        id: RequestId,
+        _request_id: usize,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.definition_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_definition(uri, position, move |lsp_client, result| {
+                let mut resp = json!({ "id": id });
+                match result {
+                    Ok(v) => resp["result"] = v,
+                    Err(e) => {
+                        resp["error"] = json!({
+                            "code": 0,
+                            "message": format!("{}",e),
+                        })
+                    }
+                }
+                let _ = lsp_client.dispatcher.sender.send(resp);
+            });
+        }
+    }
+
+    pub fn get_type_definition(
+        &self,
+        id: RequestId,
+        _request_id: usize,
+        buffer: &Buffer,
+        position: Position,
+        server_id: Option<&str>,
+    ) {
+        if let Some(client) = self.buffer_server(buffer, server_id) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.type_definition_provider.as_ref())
+                    .map(|prov| {
+                        prov != &TypeDefinitionProviderCapability::Simple(false)
+                    })
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_type_definition(
+                uri,
+                position,
+                move |lsp_client, result| {
+                    let mut resp = json!({ "id": id });
+                    match result {
+                        Ok(v) => resp["result"] = v,
+                        Err(e) => {
+                            resp["error"] = json!({
+                                "code": 0,
+                                "message": format!("{}", e),
+                            })
+                        }
+                    }
+
+                    let _ = lsp_client.dispatcher.sender.send(resp);
+                },
+            );
+        }
+    }
+
+    pub fn get_linked_editing_range(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        if let Some(client) = self.buffer_client(buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.linked_editing_range_provider.as_ref())
+                    .map(|prov| {
+                        prov != &LinkedEditingRangeServerCapabilities::Simple(false)
+                    })
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_linked_editing_range(
+                uri,
+                position,
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        }
+    }
+
+    pub fn get_moniker(&self, id: RequestId, buffer: &Buffer, position: Position) {
+        if let Some(client) = self.buffer_client(buffer) {
+            {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.moniker_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    return;
+                }
+            }
+
+            let uri = client.get_uri(buffer);
+            client.request_moniker(uri, position, move |lsp_client, result| {
+                lsp_client.dispatcher.respond(id, result);
+            });
+        }
+    }
+
+    /// The call hierarchy items at `position`, to ask the incoming and
+    /// outgoing calls of.
+    pub fn prepare_call_hierarchy(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+    ) {
+        match self.call_hierarchy_client(buffer) {
+            Some(client) => {
+                client.prepare_call_hierarchy(
+                    buffer,
+                    position,
+                    move |lsp_client, result| {
+                        lsp_client.dispatcher.respond(id, result);
+                    },
+                );
+            }
+            None => self.respond_no_call_hierarchy(id),
+        }
+    }
+
+    pub fn get_incoming_calls(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        item: CallHierarchyItem,
+    ) {
+        match self.call_hierarchy_client(buffer) {
+            Some(client) => {
+                client.request_incoming_calls(item, move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                });
+            }
+            None => self.respond_no_call_hierarchy(id),
+        }
+    }
+
+    pub fn get_outgoing_calls(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        item: CallHierarchyItem,
+    ) {
+        match self.call_hierarchy_client(buffer) {
+            Some(client) => {
+                client.request_outgoing_calls(item, move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                });
+            }
+            None => self.respond_no_call_hierarchy(id),
+        }
+    }
+
+    /// The server of `buffer`, if it's ready to give call hierarchies.
+    fn call_hierarchy_client(&self, buffer: &Buffer) -> Option<&Arc<LspClient>> {
+        let client = self.buffer_client(buffer)?;
+        let state = client.state.lock();
+        let is_enabled = state.is_initialized()
+            && state
+                .server_capabilities
+                .as_ref()
+                .and_then(|cap| cap.call_hierarchy_provider.as_ref())
+                .map(|prov| prov != &CallHierarchyServerCapability::Simple(false))
+                .unwrap_or(false);
+        if is_enabled {
+            Some(client)
+        } else {
+            None
+        }
+    }
+
+    fn respond_no_call_hierarchy(&self, id: RequestId) {
+        self.dispatcher
+            .as_ref()
+            .unwrap()
+            .respond(id, Err(anyhow!("no call hierarchy")));
+    }
+
+    /// Asks the server of `buffer` for the edit renaming the symbol at
+    /// `position` to `new_name`, and answers with it and the lines it would
+    /// change, without applying it.
+    pub fn get_rename_preview(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        position: Position,
+        new_name: String,
+    ) {
+        let client = match self.buffer_client(buffer) {
+            Some(client) => client,
+            None => {
+                let err = anyhow!("no language server for {:?}", buffer.path);
+                self.dispatcher.as_ref().unwrap().respond(id, Err(err));
+                return;
+            }
+        };
+        let is_enabled = {
+            let state = client.state.lock();
+            state.is_initialized()
+                && state
+                    .server_capabilities
+                    .as_ref()
+                    .and_then(|cap| cap.rename_provider.as_ref())
+                    .map(|prov| prov != &OneOf::Left(false))
+                    .unwrap_or(false)
+        };
+        if !is_enabled {
+            let err = anyhow!("{} can't rename", client.name());
+            client.dispatcher.respond(id, Err(err));
+            return;
+        }
+
+        let uri = client.get_uri(buffer);
+        client.request_rename(uri, position, new_name, move |lsp_client, result| {
+            let dispatcher = &lsp_client.dispatcher;
+            let result = result
+                .and_then(|value| {
+                    serde_json::from_value::<Option<WorkspaceEdit>>(value)?
+                        .ok_or_else(|| anyhow!("there's nothing to rename there"))
+                })
+                .and_then(|edit| {
+                    let files = dispatcher.preview_workspace_edit(&edit)?;
+                    Ok(RenamePreviewResponse { edit, files })
+                });
+            dispatcher.respond_rpc(id, result);
+        });
+    }
+
+    /// Pull the diagnostics of a document from servers using the pull model.
+    /// Without an explicit `previous_result_id`, the id of the last report
+    /// for the document is sent so the server can answer with `unchanged`,
+    /// which keeps the diagnostics core already has of it.
+    pub fn get_document_diagnostic(
+        &self,
+        id: RequestId,
+        buffer: &Buffer,
+        previous_result_id: Option<String>,
+    ) {
+        if let Some(client) = self.buffer_client(buffer) {
+            let uri = client.get_uri(buffer);
+            let previous_result_id = {
+                let state = client.state.lock();
+
+                if !state.is_initialized() {
+                    return;
+                }
+
+                let is_enabled = state
+                    .server_capabilities
+                    .as_ref()
+                    .map(|cap| cap.diagnostic_provider.is_some())
+                    .unwrap_or(false);
+
+                if !is_enabled {
+                    drop(state);
+                    client
+                        .dispatcher
+                        .respond(id, Err(anyhow!("no document diagnostic")));
+                    return;
+                }
+
+                previous_result_id
+                    .or_else(|| state.diagnostic_result_ids.get(&uri).cloned())
+            };
+
+            client.pull_document_diagnostics(
+                uri,
+                previous_result_id,
+                move |lsp_client, result| {
+                    lsp_client.dispatcher.respond(id, result);
+                },
+            );
+        } else {
+            self.dispatcher
+                .as_ref()
+                .unwrap()
+                .respond(id, Err(anyhow!("no document diagnostic")));
+        }
+    }
+
+    pub fn update(
+        &self,
+        buffer: &Buffer,
+        content_change: &TextDocumentContentChangeEvent,
+        rev: u64,
+    ) {
+        for client in self.buffer_clients(buffer) {
+            client.update(buffer, content_change, rev);
+        }
+    }
+}
+
+impl Default for LspCatalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LspClient {
+    pub fn new(
+        spec: LspServerSpec,
+        config: ServerConfig,
+        dispatcher: Dispatcher,
+    ) -> Arc<LspClient> {
+        let workspace = dispatcher.workspace.lock().clone();
+        let connection = Self::connect(workspace, &spec, &config);
+        Self::start(spec, config, dispatcher, connection)
+    }
+
+    /// Starts the server, or its replay if there's a recording of it to
+    /// replay.
+    fn connect(
+        workspace: Option<PathBuf>,
+        spec: &LspServerSpec,
+        config: &ServerConfig,
+    ) -> ServerConnection {
+        match ReplayServer::from_env(&spec.id()) {
+            Some(replay) => replay.start(),
+            None => Self::process(workspace, spec, config),
+        }
+    }
+
+    /// Starts talking to the server of `spec` over `connection`.
+    pub fn start(
+        spec: LspServerSpec,
+        config: ServerConfig,
+        dispatcher: Dispatcher,
+        connection: ServerConnection,
+    ) -> Arc<LspClient> {
+        let ServerConnection {
+            writer,
+            reader,
+            stderr,
+            process,
+        } = connection;
+
+        let language_id = spec.language_ids.join(",");
+        let recorder = LspRecorder::from_env(&spec.id());
+        let server_trace = config.server_trace.unwrap_or(TraceValue::Off);
+        let lsp_client = Arc::new(LspClient {
+            dispatcher,
+            spec,
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(LspState {
+                next_id: 0,
+                outgoing: Self::write_outgoing(writer),
+                process,
+                pending: HashMap::new(),
+                retryable: HashMap::new(),
+                document_requests: HashMap::new(),
+                queued: BinaryHeap::new(),
+                next_queued_seq: 0,
+                server_capabilities: None,
+                opened_documents: HashMap::new(),
+                document_versions: HashMap::new(),
+                document_buffers: HashMap::new(),
+                document_keys: HashMap::new(),
+                restored_versions: HashMap::new(),
+                handed_over: HashMap::new(),
+                drafts: HashSet::new(),
+                unsent_opens: HashMap::new(),
+                lifecycle: Lifecycle::NotStarted,
+                server_trace,
+                did_save_capabilities: Vec::new(),
+                diagnostic_result_ids: HashMap::new(),
+                partial_results: HashMap::new(),
+                work_done: HashMap::new(),
+                workspace_symbols: WorkspaceSymbolCache::NotLoaded,
+                workspace_symbols_generation: 0,
+                workspace_diagnostics: WorkspaceDiagnosticsPull::Idle,
+                call_hierarchy: CallHierarchyCache::default(),
+                completion_trigger_characters: Vec::new(),
+                on_type_formatting_trigger_characters: Vec::new(),
+                server_info: None,
+                last_used: Instant::now(),
+                trimmed: None,
+            })),
+            active: Arc::new(AtomicBool::new(true)),
+            tracer: Arc::new(Mutex::new(None)),
+            recorder: Arc::new(Mutex::new(recorder)),
+            stderr_tail: Arc::new(Mutex::new(VecDeque::new())),
+        });
+
+        if let Some(setting) = lsp_client.config.trace {
+            lsp_client.set_trace(setting);
+        }
+        lsp_client.handle_stdout(reader);
+        if let Some(stderr) = stderr {
+            lsp_client.handle_stderr(stderr, language_id);
+        }
+        lsp_client.initialize();
+
+        lsp_client
+    }
+
+    fn handle_stdout(&self, stdout: Box<dyn Read + Send>) {
+        let watch = Arc::new(HeaderWatch::default());
+        self.watch_header(watch.clone());
+
+        let local_lsp_client = self.clone();
+        thread::spawn(move || {
+            let mut reader = Box::new(BufReader::new(stdout));
+            loop {
+                let max_size = local_lsp_client
+                    .dispatcher
+                    .max_message_size
+                    .load(Ordering::Relaxed);
+                match read_message(&mut reader, max_size, &watch) {
+                    Ok(message_str) => {
+                        local_lsp_client.trace_received(&message_str);
+                        local_lsp_client.handle_message(message_str.as_ref());
+                    }
+                    Err(err) if err.is::<MessageTooLarge>() => {
+                        local_lsp_client.drop_message(
+                            err.downcast_ref::<MessageTooLarge>().unwrap(),
+                        );
+                    }
+                    Err(err) if err.is::<UnexpectedProtocol>() => {
+                        if local_lsp_client.active.load(Ordering::Acquire) {
+                            local_lsp_client.unexpected_protocol(
+                                err.downcast_ref::<UnexpectedProtocol>().unwrap(),
+                            );
+                        }
+                        return;
+                    }
+                    Err(_err) => {
+                        if !local_lsp_client.active.load(Ordering::Acquire) {
+                            return;
+                        }
+                        local_lsp_client.handle_crash();
+                        return;
+                    }
+                };
+            }
+        });
+    }
+
+    /// Watches the headers `watch` sees being read, for as long as they're
+    /// read, and gives up on the server when one takes too long.
+    fn watch_header(&self, watch: Arc<HeaderWatch>) {
+        let local_lsp_client = self.clone();
+        thread::spawn(move || {
+            // the reader holds the other reference until the server is gone
+            while Arc::strong_count(&watch) > 1 {
+                thread::sleep(HEADER_TIMEOUT / 10);
+                if !local_lsp_client.active.load(Ordering::Acquire) {
+                    return;
+                }
+                if let Some(received) = watch.stalled(HEADER_TIMEOUT) {
+                    local_lsp_client
+                        .unexpected_protocol(&UnexpectedProtocol { received });
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Shuts down the server, which speaks something else than the language
+    /// server protocol. It's left stopped, as it would only do it again.
+    fn unexpected_protocol(&self, err: &UnexpectedProtocol) {
+        error!("[LSP::{}] {err}", self.spec.exec_path);
+        self.stop();
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::UnexpectedServerProtocol {
+                server: self.spec.source_name(),
+                error: err.to_string(),
+            },
+        );
+    }
+
+    fn handle_stderr(&self, stderr: Box<dyn Read + Send>, language_id: String) {
+        let tail = self.stderr_tail.clone();
+        thread::spawn(move || {
+            let mut reader = Box::new(BufReader::new(stderr));
+            let mut buffer = String::new();
+
+            loop {
+                buffer.clear();
+                match reader.read_line(&mut buffer) {
+                    Ok(bytes) => {
+                        if bytes == 0 {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                }
+                if buffer.trim().is_empty() {
+                    continue;
+                }
+                error!("[LSP::{}] {}", language_id, buffer.trim());
+                let mut tail = tail.lock();
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(buffer.trim().to_string());
+            }
+        });
+    }
+
+    fn process(
+        workspace: Option<PathBuf>,
+        spec: &LspServerSpec,
+        config: &ServerConfig,
+    ) -> ServerConnection {
+        let (program, args) =
+            launch_command(&spec.exec_path, &spec.args, &config.launch);
+        let mut process = Command::new(program);
+        if let Some(dir) = spec.root.clone().or(workspace) {
+            process.current_dir(&dir);
+        }
+
+        process.args(args);
+        for (key, value) in config.env.iter() {
+            if value.is_empty() {
+                process.env_remove(key);
+            } else {
+                process.env(key, value);
+            }
+        }
+
+        #[cfg(target_os = "windows")]
+        let process = process.creation_flags(0x08000000);
+        let mut process = process
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Error Occurred");
+        ServerConnection {
+            writer: Box::new(BufWriter::new(process.stdin.take().unwrap())),
+            reader: Box::new(process.stdout.take().unwrap()),
+            stderr: Some(Box::new(process.stderr.take().unwrap())),
+            process: Some(process),
+        }
+    }
+
+    /// Starts the server again after it crashed, unless it crashed too
+    /// often lately, in which case its plugin is disabled. It's done from a
+    /// thread of its own rather than the reader of the server that exited.
+    fn handle_crash(&self) {
+        let lsp_client = self.clone();
+        thread::spawn(move || {
+            lsp_client.stop();
+            if let Some(plugin) = lsp_client.spec.plugin.as_ref() {
+                if lsp_client.dispatcher.plugins.lock().record_crash(plugin) {
+                    lsp_client.dispatcher.auto_disable_plugin(
+                        plugin,
+                        format!(
+                            "its language server {} keeps crashing",
+                            lsp_client.spec.source_name()
+                        ),
+                    );
+                    return;
+                }
+            }
+            lsp_client.reload();
+        });
+    }
+
+    fn reload(&self) {
+        let connection = Self::connect(
+            self.dispatcher.workspace.lock().clone(),
+            &self.spec,
+            &self.config,
+        );
+        self.fail_requests("the server restarted");
+
+        let mut state = self.state.lock();
+        state.next_id = 0;
+        state.opened_documents.clear();
+        state.document_versions.clear();
+        state.document_buffers.clear();
+        state.document_keys.clear();
+        state.handed_over.clear();
+        state.drafts.clear();
+        state.unsent_opens.clear();
+        state.server_capabilities = None;
+        state.lifecycle = Lifecycle::NotStarted;
+        state.workspace_symbols = WorkspaceSymbolCache::NotLoaded;
+        state.workspace_symbols_generation += 1;
+        state.workspace_diagnostics = WorkspaceDiagnosticsPull::Idle;
+        state.call_hierarchy = CallHierarchyCache::default();
+        state.outgoing.close();
+        state.outgoing = Self::write_outgoing(connection.writer);
+        state.process = connection.process;
+        drop(state);
+        self.active.store(true, Ordering::Release);
+
+        self.handle_stdout(connection.reader);
+        if let Some(stderr) = connection.stderr {
+            self.handle_stderr(stderr, self.spec.language_ids.join(","));
+        }
+        self.initialize();
+    }
+
+    /// Sends the server the command of `trim` if it's been idle for long
+    /// enough since it was last sent it, and it advertises the command.
+    fn trim_memory(&self, trim: &MemoryTrim) {
+        let last_used = {
+            let state = self.state.lock();
+            let advertised = state
+                .server_capabilities
+                .as_ref()
+                .and_then(|cap| cap.execute_command_provider.as_ref())
+                .map(|provider| provider.commands.contains(&trim.command))
+                .unwrap_or(false);
+            if !advertised
+                || !state.is_initialized()
+                || !state.pending.is_empty()
+                || state.trimmed == Some(state.last_used)
+                || state.last_used.elapsed() < Duration::from_secs(trim.idle_timeout)
+            {
+                return;
+            }
+            state.last_used
+        };
+        log::info!("telling {} to free memory", self.spec.exec_path);
+        let params = ExecuteCommandParams {
+            command: trim.command.clone(),
+            arguments: Vec::new(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        self.send_request_with_priority(
+            "workspace/executeCommand",
+            Params::from(serde_json::to_value(params).unwrap()),
+            RequestPriority::Background,
+            Box::new(|_: &LspClient, _| {}),
+        );
+        // the server is as idle as it was, for it to be shut down in time
+        let mut state = self.state.lock();
+        state.last_used = last_used;
+        state.trimmed = Some(last_used);
+    }
+
+    /// Sends core the server's diagnostics of a document, unless it's
+    /// being stopped and had its diagnostics dropped already.
+    fn publish_diagnostics(&self, params: PublishDiagnosticsParams) {
+        if self.active.load(Ordering::Acquire) {
+            self.dispatcher.publish_diagnostics(
+                DiagnosticSource::Server(self.spec.id()),
+                params,
+            );
+        }
+    }
+
+    fn clear_diagnostics(&self) {
+        self.dispatcher
+            .clear_diagnostics(DiagnosticSource::Server(self.spec.id()));
+    }
+
+    /// Fails the requests waiting on the server's answer and the ones queued
+    /// for it with `reason`, as they won't be answered.
+    fn fail_requests(&self, reason: &str) {
+        let failed = {
+            let mut state = self.state.lock();
+            state.document_requests.clear();
+            state.retryable.clear();
+            let mut failed = state
+                .pending
+                .drain()
+                .map(|(_, callback)| callback)
+                .collect::<Vec<_>>();
+            failed.extend(
+                std::mem::take(&mut state.queued)
+                    .into_vec()
+                    .into_iter()
+                    .map(|request| request.callback),
+            );
+            failed
+        };
+        for callback in failed {
+            callback.call(self, Err(anyhow!("{reason}")));
+        }
+    }
+
+    /// Asks the server to shut down and exit, killing it if it hasn't
+    /// answered the `shutdown` request within `SHUTDOWN_TIMEOUT`.
+    fn shutdown(&self) {
+        self.active.store(false, Ordering::Release);
+        // dropped now rather than once it exited, as an instance started
+        // in its place may publish its own by then
+        self.clear_diagnostics();
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.send_request(
+            "shutdown",
+            Params::None(()),
+            Box::new(move |lsp_client: &LspClient, _result| {
+                lsp_client.send_notification("exit", Params::None(()));
+                let _ = tx.send(());
+            }),
+        );
+        let lsp_client = self.clone();
+        thread::spawn(move || {
+            let _ = rx.recv_timeout(SHUTDOWN_TIMEOUT);
+            lsp_client.kill();
+        });
+    }
+
+    fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+        self.clear_diagnostics();
+        self.kill();
+    }
+
+    fn kill(&self) {
+        let mut state = self.state.lock();
+        state.outgoing.close();
+        if let Some(process) = state.process.as_mut() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+
+    /// Writes the messages sent to the server to `writer` from a thread of
+    /// its own, until the queue is closed or the server stops reading.
+    fn write_outgoing(
+        mut writer: Box<dyn Write + Send>,
+    ) -> Arc<BoundedQueue<String>> {
+        let queue = Arc::new(BoundedQueue::<String>::new(OUTGOING_QUEUE_CAPACITY));
+        let outgoing = queue.clone();
+        thread::spawn(move || {
+            while let Some(rpc) = outgoing.pop() {
+                if writer
+                    .write_all(rpc.as_bytes())
+                    .and_then(|_| writer.flush())
+                    .is_err()
+                {
+                    outgoing.close();
+                    return;
+                }
+            }
+        });
+        queue
+    }
+
+    fn descriptor(&self) -> ServerDescriptor {
+        ServerDescriptor {
+            id: self.spec.id(),
+            name: self.spec.source_name(),
+            language_ids: self.spec.language_ids.clone(),
+            state: self.server_state(),
+        }
+    }
+
+    /// What's put in a bug report about the server, with the values of the
+    /// environment variables that may be secrets replaced, there and in
+    /// what it wrote to its stderr.
+    fn report(&self) -> ServerReport {
+        let (server_info, capabilities) = {
+            let state = self.state.lock();
+            let server_info = state.server_info.clone().map(|info| ServerVersion {
+                name: info.name,
+                version: info.version,
+            });
+            (server_info, state.server_capabilities.clone())
+        };
+        let secrets = self
+            .config
+            .env
+            .iter()
+            .filter(|(name, value)| is_secret_env(name) && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>();
+        ServerReport {
+            server: self.descriptor(),
+            server_info,
+            queue: self.queue_stats(),
+            capabilities: capabilities
+                .map(|capabilities| capability_names(&capabilities))
+                .unwrap_or_default(),
+            env: self
+                .config
+                .env
+                .iter()
+                .map(|(name, value)| {
+                    let value = if is_secret_env(name) {
+                        REDACTED.to_string()
+                    } else {
+                        value.clone()
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+            stderr: self
+                .stderr_tail
+                .lock()
+                .iter()
+                .map(|line| {
+                    secrets.iter().fold(line.clone(), |line, secret| {
+                        line.replace(secret, REDACTED)
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn server_state(&self) -> ServerState {
+        let lifecycle = self.state.lock().lifecycle;
+        match lifecycle {
+            Lifecycle::ShuttingDown => ServerState::ShuttingDown,
+            _ if !self.active.load(Ordering::Acquire) => ServerState::Stopped,
+            Lifecycle::NotStarted | Lifecycle::Initializing => ServerState::Starting,
+            Lifecycle::Initialized => ServerState::Ready,
+            Lifecycle::Exited => ServerState::Stopped,
+        }
+    }
+
+    /// How full the queue of the messages to the server is.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.state.lock().outgoing.stats()
+    }
+
+    /// Whether `buffer` is within the limits of the documents the server is
+    /// given, which it is once it's opened in the server.
+    fn accepts(&self, buffer: &Buffer) -> bool {
+        self.refusal(buffer).is_none()
+    }
+
+    /// Why `buffer` is over the limits of the documents the server is
+    /// given, if it is and it isn't opened in the server already.
+    fn refusal(&self, buffer: &Buffer) -> Option<String> {
+        if self.state.lock().opened_documents.contains_key(&buffer.id) {
+            return None;
+        }
+        self.config
+            .document_limits
+            .exceeded_by(buffer.len(), buffer.num_lines())
+    }
+
+    /// The name of the server's executable, which the user knows it by
+    pub fn name(&self) -> String {
+        self.spec.source_name()
+    }
+
+    pub fn get_uri(&self, buffer: &Buffer) -> Url {
+        let exists = {
+            let state = self.state.lock();
+            state.opened_documents.contains_key(&buffer.id)
+        };
+        if !exists {
+            let document_uri =
+                Url::from_file_path(&buffer.path).unwrap_or_else(|_| {
+                    panic!("Failed to create URL from path {:?}", buffer.path)
+                });
+            self.send_did_open(
+                &buffer.id,
+                document_uri,
+                &buffer.language_id,
+                buffer.get_document(),
+                buffer.rev as i32,
+            );
+        }
+        self.state
+            .lock()
+            .opened_documents
+            .get(&buffer.id)
+            .unwrap()
+            .clone()
+    }
+
+    pub fn handle_message(&self, message: &str) {
+        match JsonRpc::parse(message) {
+            Ok(value @ JsonRpc::Request(_)) => {
+                let id = value.get_id().unwrap();
+                self.handle_request(
+                    value.get_method().unwrap(),
+                    id,
+                    value.get_params().unwrap(),
+                )
+            }
+            Ok(value @ JsonRpc::Notification(_)) => {
+                self.handle_notification(
+                    value.get_method().unwrap(),
+                    value.get_params().unwrap(),
+                );
+            }
+            Ok(value @ JsonRpc::Success(_)) => {
+                let id = number_from_id(&value.get_id().unwrap());
+                let result = value.get_result().unwrap();
+                self.handle_response(id, Ok(result.clone()));
+            }
+            Ok(value @ JsonRpc::Error(_)) => {
+                let id = number_from_id(&value.get_id().unwrap());
+                let error = value.get_error().unwrap();
+                let error = ServerError {
+                    code: error.code,
+                    message: error.message.clone(),
+                };
+                if let Err(error) = self.retry_request(id, error) {
+                    self.handle_response(id, Err(error.into()));
+                }
+            }
+            Err(_err) => {}
+        }
+    }
+
+    pub fn handle_request(&self, method: &str, id: Id, params: Params) {
+        match method {
+            "window/workDoneProgress/create" => {
+                // Token is ignored as the workProgress Widget is always working
+                // In the future, for multiple workProgress Handling we should
+                // probably store the token
+                self.send_success_response(id, &Value::Null);
+            }
+            "client/registerCapability" => {
+                if let Ok(registrations) =
+                    serde_json::from_value::<RegistrationParams>(json!(params))
+                {
+                    for registration in registrations.registrations {
+                        match registration.method.as_str() {
+                            "textDocument/didSave" => {
+                                if let Some(options) = registration.register_options {
+                                    if let Ok(options) = serde_json::from_value::<TextDocumentSaveRegistrationOptions>(options) {
+                                        if let Some(selectors) = options.text_document_registration_options.document_selector {
+                                            // TODO: is false a reasonable default?
+                                            let include_text = options.include_text.unwrap_or(false);
+
+                                            let mut lsp_state = self.state.lock();
+
+                                            // Add each selector our did save filtering
+                                            for selector in selectors {
+                                                let filter = DocumentFilter::from_lsp_filter_loose(selector);
+                                                let cap = DidSaveCapability {
+                                                    filter,
+                                                    include_text,
+                                                };
+
+                                                lsp_state.did_save_capabilities.push(cap);
+                                            }
+                                        }
+                                    }
+                                }
+                                // TODO: report error?
+                            }
+                            _ => println!("Received unhandled client/registerCapability request {}", registration.method),
+                        }
+                    }
+                }
+            }
+            "workspace/diagnostic/refresh" => {
+                self.send_success_response(id, &Value::Null);
+                self.pull_workspace_diagnostics();
+            }
+            // none of these are kept in the proxy, core asks for them again
+            // for the documents it shows
+            "workspace/semanticTokens/refresh" => {
+                self.send_success_response(id, &Value::Null);
+                self.dispatcher.send_rpc_notification(
+                    CoreNotification::SemanticTokensRefresh {
+                        paths: self.opened_paths(),
+                    },
+                );
+            }
+            "workspace/inlayHint/refresh" => {
+                self.send_success_response(id, &Value::Null);
+                self.dispatcher.send_rpc_notification(
+                    CoreNotification::InlayHintsRefresh {
+                        paths: self.opened_paths(),
+                    },
+                );
+            }
+            "workspace/codeLens/refresh" => {
+                self.send_success_response(id, &Value::Null);
+                self.dispatcher.send_rpc_notification(
+                    CoreNotification::CodeLensRefresh {
+                        paths: self.opened_paths(),
+                    },
+                );
+            }
+            "workspace/workspaceFolders" => {
+                let folders = self.workspace_folders();
+                self.send_success_response(id, &json!(folders));
+            }
+            "workspace/configuration" => {
+                if let Ok(config) =
+                    serde_json::from_value::<ConfigurationParams>(json!(params))
+                {
+                    let items = config
+                        .items
+                        .into_iter()
+                        .map(|_item| Value::Null)
+                        .collect::<Vec<Value>>();
+
+                    self.send_success_response(id, &Value::Array(items));
+                }
+            }
+            method => {
+                println!("Received unhandled request {method}");
+            }
+        }
+    }
+
+    /// The paths of the documents open in the server.
+    fn opened_paths(&self) -> Vec<PathBuf> {
+        self.state
+            .lock()
+            .opened_documents
+            .values()
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect()
+    }
+
+    pub fn handle_notification(&self, method: &str, params: Params) {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                match serde_json::from_value::<PublishDiagnosticsParams>(json!(
+                    params
+                )) {
+                    Ok(params) => self.publish_diagnostics(params),
+                    Err(err) => {
+                        error!(
+                            "[LSP::{}] invalid diagnostics: {err}",
+                            self.spec.exec_path
+                        );
+                    }
+                }
+            }
+            "$/progress" => {
+                let params = json!(params);
+                let handler = params["token"].as_str().and_then(|token| {
+                    self.state.lock().partial_results.get(token).cloned()
+                });
+                if let Some(handler) = handler {
+                    handler(self, params["value"].clone());
+                    return;
+                }
+                let request_id = params["token"].as_str().and_then(|token| {
+                    self.state.lock().work_done.get(token).copied()
+                });
+                if let Some(request_id) = request_id {
+                    match serde_json::from_value(params["value"].clone()) {
+                        Ok(progress) => self.dispatcher.send_rpc_notification(
+                            CoreNotification::RequestProgress {
+                                request_id,
+                                progress,
+                            },
+                        ),
+                        Err(err) => error!(
+                            "[LSP::{}] invalid progress: {err}",
+                            self.spec.exec_path
+                        ),
+                    }
+                    return;
+                }
+                self.dispatcher.send_notification(
+                    "work_done_progress",
+                    json!({
+                        "progress": params,
+                    }),
+                );
+            }
+            "window/showMessage" => {
+                // TODO: send message to display
+            }
+            "window/logMessage" => {
+                // TODO: We should log the message here. Waiting for
+                // the discussion about handling plugins logs before doing anything
+            }
+            "$/logTrace" => {
+                if let Ok(params) =
+                    serde_json::from_value::<LogTraceParams>(json!(params))
+                {
+                    log::info!(
+                        "[LSP::{}] {} {}",
+                        self.spec.source_name(),
+                        params.message,
+                        params.verbose.unwrap_or_default()
+                    );
+                }
+            }
+            "experimental/serverStatus" => {
+                //TODO: Logging of server status
+            }
+            method => {
+                println!("Received unhandled notification {}", method);
+            }
+        }
+    }
+
+    /// The message was skipped without reading it. If it answered one of
+    /// our requests, the request fails rather than waiting forever.
+    fn drop_message(&self, too_large: &MessageTooLarge) {
+        error!("[LSP::{}] {too_large}", self.spec.exec_path);
+        if let Some(id) = too_large.id {
+            if self.state.lock().pending.contains_key(&id) {
+                self.handle_response(id, Err(anyhow!("{too_large}")));
+            }
+        }
+    }
+
+    /// Sends the request `id` again after `RETRY_DELAY` if it failed with
+    /// one of the `retry_error_codes`, like `ContentModified` for a document
+    /// edited while the server worked on it. The request stays in flight
+    /// meanwhile, and is sent with its params moved along with the changes
+    /// made to its document since. A request is sent again only once, the
+    /// error is given back otherwise.
+    fn retry_request(&self, id: u64, error: ServerError) -> Result<(), ServerError> {
+        if !self.config.retry_error_codes.contains(&error.code)
+            || !self.state.lock().retryable.contains_key(&id)
+        {
+            return Err(error);
+        }
+        let client = self.clone();
+        thread::spawn(move || {
+            thread::sleep(RETRY_DELAY);
+            client.resend_request(id, error);
+        });
+        Ok(())
+    }
+
+    /// Sends the request `id` again as a new request, unless it was
+    /// cancelled in the meantime. It fails with `error` if its params
+    /// couldn't be moved along with the changes to its document.
+    fn resend_request(&self, id: u64, error: ServerError) {
+        let (request, callback) = {
+            let mut state = self.state.lock();
+            let request = match state.retryable.remove(&id) {
+                Some(request) => request,
+                None => return,
+            };
+            match state.take_pending(id) {
+                Some(callback) => (request, callback),
+                None => return,
+            }
+        };
+        match request.params {
+            Some(params) => {
+                log::debug!(
+                    "[LSP::{}] sending {} again after error {}",
+                    self.spec.exec_path,
+                    request.method,
+                    error.code
+                );
+                self.write_request(&request.method, params, callback, false);
+            }
+            None => {
+                self.send_queued_request();
+                callback.call(self, Err(error.into()));
+            }
+        }
+    }
+
+    pub fn handle_response(&self, id: u64, result: Result<Value>) {
+        // the request may have been cancelled, with its callback called
+        let callback = self.state.lock().take_pending(id);
+        self.send_queued_request();
+        if let Some(callback) = callback {
+            callback.call(self, result);
+        }
+    }
+
+    /// Starts or stops tracing the traffic of the server.
+    fn set_trace(&self, setting: TraceSetting) {
+        let tracer =
+            LspTracer::new(&self.spec.source_name(), setting, &self.dispatcher)
+                .unwrap_or_else(|err| {
+                    error!("can't trace {}: {err}", self.spec.source_name());
+                    None
+                });
+        *self.tracer.lock() = tracer;
+    }
+
+    /// Changes the trace value of the server. One that isn't initialized
+    /// yet gets it in `initialize`.
+    fn set_server_trace(&self, value: TraceValue) {
+        let initialized = {
+            let mut state = self.state.lock();
+            state.server_trace = value;
+            state.is_initialized()
+        };
+        if initialized {
+            self.send_notification(
+                "$/setTrace",
+                Params::from(json!(SetTraceParams { value })),
+            );
+        }
+    }
+
+    fn trace_received(&self, message: &str) {
+        let recording = self.recorder.lock().is_some();
+        let mut tracer = self.tracer.lock();
+        if tracer.is_none() && !recording {
+            return;
+        }
+        let message = serde_json::from_str(message)
+            .unwrap_or_else(|_| Value::String(message.to_string()));
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.record(TraceDirection::Received, &message);
+        }
+        drop(tracer);
+        self.record(TraceDirection::Received, &message);
+    }
+
+    /// Adds `message` to the recording of the server, if it's recorded. The
+    /// recording stops at the first message that can't be written.
+    fn record(&self, direction: TraceDirection, message: &Value) {
+        let mut recorder = self.recorder.lock();
+        if let Some(r) = recorder.as_mut() {
+            if let Err(err) = r.record(direction, message) {
+                error!("stopped recording {}: {err}", self.spec.source_name());
+                *recorder = None;
+            }
+        }
+    }
+
+    /// Queues `value` to be written to the server if the lifecycle allows
+    /// sending it, moving the lifecycle along. Messages that can't be sent
+    /// are dropped with an error. A full queue is dealt with as `overflow`
+    /// says.
+    fn send_rpc(&self, value: &Value, overflow: Overflow) -> Result<()> {
+        let rpc = match prepare_lsp_json(value) {
+            Ok(r) => r,
+            Err(err) => panic!("Encoding Error {:?}", err),
+        };
+
+        let method = value.get("method").and_then(|method| method.as_str());
+        let outgoing = {
+            let mut state = self.state.lock();
+            state.lifecycle = state.lifecycle.send(method).map_err(|err| {
+                error!("[LSP::{}] {err}", self.spec.exec_path);
+                err
+            })?;
+            if let Some(tracer) = self.tracer.lock().as_mut() {
+                tracer.record(TraceDirection::Sent, value);
+            }
+            // recorded in the order the server gets them
+            self.record(TraceDirection::Sent, value);
+            state.last_used = Instant::now();
+            state.outgoing.clone()
+        };
+        // waiting for room mustn't keep the server's messages from being
+        // handled, or a server waiting to write them would never read ours
+        outgoing.push(rpc, overflow)
+    }
+
+    pub fn send_notification(&self, method: &str, params: Params) {
+        self.send_notification_with_overflow(method, params, Overflow::Block);
+    }
+
+    pub fn send_notification_with_overflow(
+        &self,
+        method: &str,
+        params: Params,
+        overflow: Overflow,
+    ) {
+        let notification = JsonRpc::notification_with_params(method, params);
+        let res = to_value(&notification).unwrap();
+        let _ = self.send_rpc(&res, overflow);
+    }
+
+    pub fn send_request(&self, method: &str, params: Params, completion: Callback) {
+        self.send_request_with_priority(
+            method,
+            params,
+            RequestPriority::from_method(method),
+            completion,
+        );
+    }
+
+    /// Sends the request right away unless the server is busy, in which
+    /// case it waits in the queue behind requests of a higher `priority`.
+    pub fn send_request_with_priority(
+        &self,
+        method: &str,
+        params: Params,
+        priority: RequestPriority,
+        completion: Callback,
+    ) {
+        let completion = self.report_errors(method, priority, completion);
+        {
+            let mut state = self.state.lock();
+            if priority != RequestPriority::Immediate
+                && (state.pending.len() >= self.max_in_flight_requests()
+                    || !state.queued.is_empty())
+            {
+                let seq = state.next_queued_seq;
+                state.next_queued_seq += 1;
+                state.queued.push(QueuedRequest {
+                    priority,
+                    seq: Reverse(seq),
+                    method: method.to_string(),
+                    params,
+                    callback: completion,
+                });
+                return;
+            }
+        }
+
+        self.write_request(method, params, completion, is_read_request(method));
+    }
+
+    fn max_in_flight_requests(&self) -> usize {
+        self.config
+            .max_in_flight_requests
+            .unwrap_or(MAX_IN_FLIGHT_REQUESTS)
+    }
+
+    /// Wraps `completion` so that the error the server may answer with says
+    /// which request failed, and is told to core when `is_user_request` has
+    /// it that the user is waiting on the request. Background requests are
+    /// never reported, whatever their method.
+    fn report_errors(
+        &self,
+        method: &str,
+        priority: RequestPriority,
+        completion: Callback,
+    ) -> Callback {
+        let method = method.to_string();
+        let report =
+            priority != RequestPriority::Background && is_user_request(&method);
+        Box::new(move |client: &LspClient, result: Result<Value>| {
+            let result = result.map_err(|err| {
+                let error = match err.downcast::<ServerError>() {
+                    Ok(error) => error,
+                    // cancelled or failed without the server answering
+                    Err(err) => return err,
+                };
+                let server = client.spec.source_name();
+                if report {
+                    client.dispatcher.send_rpc_notification(
+                        CoreNotification::LanguageServerRequestFailed {
+                            server,
+                            method: method.clone(),
+                            code: error.code,
+                            message: error.message.clone(),
+                        },
+                    );
+                } else {
+                    log::warn!("{server} failed {method}: {error}");
+                }
+                anyhow!("{method} failed: {error}")
+            });
+            completion.call(client, result)
+        })
+    }
+
+    /// Sends the most urgent queued request, if the server has room for it.
+    fn send_queued_request(&self) {
+        let request = {
+            let mut state = self.state.lock();
+            if state.pending.len() >= self.max_in_flight_requests() {
+                return;
+            }
+            state.queued.pop()
+        };
+        if let Some(request) = request {
+            let retry = is_read_request(&request.method);
+            self.write_request(
+                &request.method,
+                request.params,
+                request.callback,
+                retry,
+            );
+        }
+    }
+
+    /// Writes the request to the server. With `retry`, it's sent again once
+    /// if it fails with one of the `retry_error_codes`.
+    fn write_request(
+        &self,
+        method: &str,
+        params: Params,
+        completion: Callback,
+        retry: bool,
+    ) {
+        let (id, request) = {
+            let mut state = self.state.lock();
+            let next_id = state.next_id;
+            state.pending.insert(next_id, completion);
+            state.next_id += 1;
+            if retry && !self.config.retry_error_codes.is_empty() {
+                state.retryable.insert(
+                    next_id,
+                    RetryableRequest {
+                        method: method.to_string(),
+                        params: Some(params.clone()),
+                    },
+                );
+            }
+            if let Some(uri) = request_document(&params) {
+                state
+                    .document_requests
+                    .entry(uri)
+                    .or_default()
+                    .insert(next_id);
+            }
+
+            let request = JsonRpc::request_with_params(
+                Id::Num(next_id as i64),
+                method,
+                params,
+            );
+            (next_id, request)
+        };
+
+        if let Err(err) =
+            self.send_rpc(&to_value(&request).unwrap(), Overflow::Block)
+        {
+            // a request the server won't answer fails right away
+            let callback = self.state.lock().take_pending(id);
+            if let Some(callback) = callback {
+                callback.call(self, Err(err));
+            }
+        }
     }
 
     pub fn send_success_response(&self, id: Id, result: &Value) {
         let response = JsonRpc::success(id, result);
 
-        self.send_rpc(&to_value(&response).unwrap());
+        let _ = self.send_rpc(&to_value(&response).unwrap(), Overflow::Block);
+    }
+
+    pub fn send_error_response(
+        &self,
+        id: jsonrpc_lite::Id,
+        error: jsonrpc_lite::Error,
+    ) {
+        let response = JsonRpc::error(id, error);
+
+        let _ = self.send_rpc(&to_value(&response).unwrap(), Overflow::Block);
+    }
+
+    fn initialize(&self) {
+        let root = self
+            .spec
+            .root
+            .clone()
+            .or_else(|| self.dispatcher.workspace.lock().clone());
+        if let Some(root) = root {
+            let root_url = Url::from_directory_path(root).unwrap();
+            let (sender, receiver) = channel();
+            self.send_initialize(Some(root_url), move |lsp_client, result| {
+                if let Ok(result) = result {
+                    let buffer_ids = {
+                        let init_result: InitializeResult =
+                            serde_json::from_value(result).unwrap();
+                        let mut state = lsp_client.state.lock();
+                        if let Some(options) =
+                            init_result.capabilities.completion_provider.as_ref()
+                        {
+                            state.completion_trigger_characters =
+                                lsp_client.config.trigger_character_override.apply(
+                                    options
+                                        .trigger_characters
+                                        .as_deref()
+                                        .unwrap_or_default(),
+                                );
+                        }
+                        if let Some(options) = init_result
+                            .capabilities
+                            .document_on_type_formatting_provider
+                            .as_ref()
+                        {
+                            state.on_type_formatting_trigger_characters =
+                                std::iter::once(&options.first_trigger_character)
+                                    .chain(
+                                        options
+                                            .more_trigger_character
+                                            .iter()
+                                            .flatten(),
+                                    )
+                                    .cloned()
+                                    .collect();
+                        }
+                        state.server_capabilities = Some(init_result.capabilities);
+                        state.server_info = init_result.server_info;
+                        state.opened_documents.keys().copied().collect::<Vec<_>>()
+                    };
+                    lsp_client.send_initialized();
+                    for buffer_id in buffer_ids {
+                        lsp_client.send_trigger_characters(buffer_id);
+                    }
+                    lsp_client.send_unsent_opens();
+                }
+                let _ = sender.send(true);
+            });
+            let _ = receiver.recv_timeout(Duration::from_millis(1000));
+        }
+    }
+
+    /// Opens the document in the server at `version`, the revision of its
+    /// buffer, which the versions of the changes sent after it follow.
+    pub fn send_did_open(
+        &self,
+        buffer_id: &BufferId,
+        document_uri: Url,
+        language_id: &str,
+        document_text: String,
+        version: i32,
+    ) {
+        let key = document_uri
+            .to_file_path()
+            .ok()
+            .map(|path| self.dispatcher.path_key(&path));
+        let (document_uri, version, is_initialized, first) = {
+            let mut state = self.state.lock();
+            let document_uri = match key {
+                Some(key) => state
+                    .document_keys
+                    .entry(key)
+                    .or_insert(document_uri)
+                    .clone(),
+                None => document_uri,
+            };
+            state
+                .opened_documents
+                .insert(*buffer_id, document_uri.clone());
+            let buffers = state
+                .document_buffers
+                .entry(document_uri.clone())
+                .or_default();
+            let first = buffers.is_empty();
+            if !buffers.contains(buffer_id) {
+                buffers.push(*buffer_id);
+            }
+            let owner = buffers[0];
+            // the other buffers of the document share the version of the
+            // one whose text the server has
+            let version = if first {
+                match state.restored_versions.remove(&document_uri) {
+                    Some(restored) => version.max(restored + 1),
+                    None => version,
+                }
+            } else {
+                state
+                    .document_versions
+                    .get(&owner)
+                    .copied()
+                    .unwrap_or(version)
+            };
+            state.document_versions.insert(*buffer_id, version);
+            let is_initialized = state.is_initialized();
+            if !is_initialized && first {
+                state.unsent_opens.insert(
+                    document_uri.clone(),
+                    TextDocumentItem {
+                        language_id: language_id.to_string(),
+                        uri: document_uri.clone(),
+                        version,
+                        text: document_text.clone(),
+                    },
+                );
+            }
+            (document_uri, version, is_initialized, first)
+        };
+
+        if !is_initialized {
+            return;
+        }
+        self.send_trigger_characters(*buffer_id);
+        if !first {
+            return;
+        }
+
+        self.send_text_document_did_open(TextDocumentItem {
+            language_id: language_id.to_string(),
+            uri: document_uri,
+            version,
+            text: document_text,
+        });
+    }
+
+    fn send_text_document_did_open(&self, text_document: TextDocumentItem) {
+        let params = DidOpenTextDocumentParams { text_document };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("textDocument/didOpen", params);
+    }
+
+    /// Opens the documents opened before the server was initialized, now
+    /// that it is. Core is told to ask for what it shows of them again, as
+    /// what it asked before went unanswered.
+    fn send_unsent_opens(&self) {
+        let opens = std::mem::take(&mut self.state.lock().unsent_opens);
+        if opens.is_empty() {
+            return;
+        }
+        let paths = opens
+            .keys()
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect::<Vec<_>>();
+        for (_, text_document) in opens {
+            self.send_text_document_did_open(text_document);
+        }
+        self.send_refresh(paths);
+    }
+
+    /// Tells core to ask for the semantic tokens, inlay hints and code
+    /// lenses of the documents at `paths` again.
+    fn send_refresh(&self, paths: Vec<PathBuf>) {
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::SemanticTokensRefresh {
+                paths: paths.clone(),
+            },
+        );
+        self.dispatcher
+            .send_rpc_notification(CoreNotification::InlayHintsRefresh {
+                paths: paths.clone(),
+            });
+        self.dispatcher
+            .send_rpc_notification(CoreNotification::CodeLensRefresh { paths });
+    }
+
+    /// Opens `buffer` in the server if it doesn't have it yet, for a server
+    /// started after the buffer was opened.
+    pub fn open_document(&self, buffer: &Buffer) {
+        if self.state.lock().opened_documents.contains_key(&buffer.id) {
+            return;
+        }
+        self.get_uri(buffer);
+        // otherwise core is told once the server is
+        if self.state.lock().is_initialized() {
+            self.send_refresh(vec![buffer.path.clone()]);
+        }
+    }
+
+    /// Tells core which characters typed in `buffer_id` should request
+    /// completion from this server, and which formatting.
+    fn send_trigger_characters(&self, buffer_id: BufferId) {
+        let (completion, on_type_formatting) = {
+            let state = self.state.lock();
+            (
+                state.completion_trigger_characters.clone(),
+                state.on_type_formatting_trigger_characters.clone(),
+            )
+        };
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::CompletionTriggerCharacters {
+                buffer_id,
+                server: self.spec.source_name(),
+                trigger_characters: completion,
+            },
+        );
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::OnTypeFormattingTriggerCharacters {
+                buffer_id,
+                server: self.spec.source_name(),
+                trigger_characters: on_type_formatting,
+            },
+        );
+    }
+
+    /// Sends `didClose` for `buffer_id` if the server has it open, returning
+    /// whether it had. The requests about the document still in flight are
+    /// cancelled, so that their answers aren't applied to whatever the
+    /// buffer is used for next.
+    fn close_document(&self, buffer_id: BufferId) -> bool {
+        let (uri, is_initialized, cancelled) = {
+            let mut state = self.state.lock();
+            state.drafts.remove(&buffer_id);
+            let uri = match state.opened_documents.remove(&buffer_id) {
+                Some(uri) => uri,
+                None => return false,
+            };
+            let version = state
+                .document_versions
+                .remove(&buffer_id)
+                .max(state.handed_over.remove(&buffer_id))
+                .unwrap_or(0);
+            if let Some(buffers) = state.document_buffers.get_mut(&uri) {
+                let owned = buffers.first() == Some(&buffer_id);
+                buffers.retain(|id| *id != buffer_id);
+                if let Some(owner) = buffers.first().copied() {
+                    // the document stays open for the other buffers
+                    if owned {
+                        state.handed_over.insert(owner, version);
+                    }
+                    return true;
+                }
+                state.document_buffers.remove(&uri);
+            }
+            state.document_keys.retain(|_, opened| *opened != uri);
+            state.unsent_opens.remove(&uri);
+            state.diagnostic_result_ids.remove(&uri);
+            state.call_hierarchy.invalidate(&uri);
+
+            let mut cancelled = Vec::new();
+            for id in state.document_requests.remove(&uri).unwrap_or_default() {
+                if let Some(callback) = state.pending.remove(&id) {
+                    cancelled.push((Some(id), callback));
+                }
+            }
+            let (dropped, queued): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut state.queued)
+                    .into_vec()
+                    .into_iter()
+                    .partition(|request| {
+                        request_document(&request.params).as_ref() == Some(&uri)
+                    });
+            state.queued = queued.into();
+            cancelled.extend(dropped.into_iter().map(|r| (None, r.callback)));
+            (uri, state.is_initialized(), cancelled)
+        };
+
+        for (id, callback) in cancelled {
+            if let Some(id) = id {
+                let params = CancelParams {
+                    id: NumberOrString::Number(id as i32),
+                };
+                let params = Params::from(serde_json::to_value(params).unwrap());
+                self.send_notification("$/cancelRequest", params);
+            }
+            callback.call(self, Err(anyhow!("{uri} was closed")));
+        }
+        self.send_queued_request();
+
+        if is_initialized {
+            let params = DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri },
+            };
+            let params = Params::from(serde_json::to_value(params).unwrap());
+            self.send_notification("textDocument/didClose", params);
+        }
+        true
+    }
+
+    /// Tells core that typing in `buffer_id` doesn't trigger anything of
+    /// this server anymore.
+    fn clear_trigger_characters(&self, buffer_id: BufferId) {
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::CompletionTriggerCharacters {
+                buffer_id,
+                server: self.spec.source_name(),
+                trigger_characters: Vec::new(),
+            },
+        );
+        self.dispatcher.send_rpc_notification(
+            CoreNotification::OnTypeFormattingTriggerCharacters {
+                buffer_id,
+                server: self.spec.source_name(),
+                trigger_characters: Vec::new(),
+            },
+        );
+    }
+
+    pub fn send_did_save(&self, uri: Url, text: Option<String>) {
+        let params = DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri },
+            text,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_notification("textDocument/didSave", params);
+    }
+
+    pub fn send_initialized(&self) {
+        self.send_notification("initialized", Params::from(json!({})));
+    }
+
+    pub fn send_initialize<CB>(&self, root_uri: Option<Url>, on_init: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let client_capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                synchronization: Some(TextDocumentSyncClientCapabilities {
+                    did_save: Some(true),
+                    will_save_wait_until: Some(true),
+                    dynamic_registration: Some(true),
+                    ..Default::default()
+                }),
+                completion: Some(CompletionClientCapabilities {
+                    completion_item: Some(CompletionItemCapability {
+                        snippet_support: Some(true),
+                        resolve_support: Some(
+                            CompletionItemCapabilityResolveSupport {
+                                properties: vec!["additionalTextEdits".to_string()],
+                            },
+                        ),
+                        insert_text_mode_support: Some(InsertTextModeSupport {
+                            value_set: vec![
+                                InsertTextMode::AS_IS,
+                                InsertTextMode::ADJUST_INDENTATION,
+                            ],
+                        }),
+                        ..Default::default()
+                    }),
+                    completion_list: Some(CompletionListCapability {
+                        item_defaults: Some(vec!["insertTextMode".to_string()]),
+                    }),
+                    ..Default::default()
+                }),
+                // signature_help: Some(SignatureHelpCapability {
+                //     signature_information: Some(SignatureInformationSettings {
+                //         parameter_information: Some(ParameterInformationSettings {
+                //             label_offset_support: Some(true),
+                //         }),
+                //         active_parameter_support: Some(true),
+                //         documentation_format: Some(vec![
+                //             MarkupKind::Markdown,
+                //             MarkupKind::PlainText,
+                //         ]),
+                //     }),
+                //     ..Default::default()
+                // }),
+                hover: Some(HoverClientCapabilities {
+                    content_format: Some(vec![
+                        MarkupKind::Markdown,
+                        MarkupKind::PlainText,
+                    ]),
+                    ..Default::default()
+                }),
+                inlay_hint: Some(InlayHintClientCapabilities {
+                    resolve_support: Some(InlayHintResolveClientCapabilities {
+                        properties: vec![
+                            "tooltip".to_string(),
+                            "textEdits".to_string(),
+                            "label.tooltip".to_string(),
+                            "label.location".to_string(),
+                            "label.command".to_string(),
+                        ],
+                    }),
+                    ..Default::default()
+                }),
+                code_lens: Some(CodeLensClientCapabilities::default()),
+                on_type_formatting: Some(
+                    DocumentOnTypeFormattingClientCapabilities::default(),
+                ),
+                code_action: Some(CodeActionClientCapabilities {
+                    code_action_literal_support: Some(CodeActionLiteralSupport {
+                        code_action_kind: CodeActionKindLiteralSupport {
+                            value_set: vec![
+                                CodeActionKind::EMPTY.as_str().to_string(),
+                                CodeActionKind::QUICKFIX.as_str().to_string(),
+                                CodeActionKind::REFACTOR.as_str().to_string(),
+                                CodeActionKind::REFACTOR_EXTRACT
+                                    .as_str()
+                                    .to_string(),
+                                CodeActionKind::REFACTOR_INLINE.as_str().to_string(),
+                                CodeActionKind::REFACTOR_REWRITE
+                                    .as_str()
+                                    .to_string(),
+                                CodeActionKind::SOURCE.as_str().to_string(),
+                                CodeActionKind::SOURCE_ORGANIZE_IMPORTS
+                                    .as_str()
+                                    .to_string(),
+                            ],
+                        },
+                    }),
+                    ..Default::default()
+                }),
+                semantic_tokens: Some(SemanticTokensClientCapabilities {
+                    ..Default::default()
+                }),
+                type_definition: Some(GotoCapability {
+                    // Note: This is explicitly specified rather than left to the Default because
+                    // of a bug in lsp-types https://github.com/gluon-lang/lsp-types/pull/244
+                    link_support: Some(false),
+                    ..Default::default()
+                }),
+                diagnostic: Some(DiagnosticClientCapabilities {
+                    ..Default::default()
+                }),
+                linked_editing_range: Some(LinkedEditingRangeClientCapabilities {
+                    ..Default::default()
+                }),
+                moniker: Some(MonikerClientCapabilities {
+                    ..Default::default()
+                }),
+                call_hierarchy: Some(CallHierarchyClientCapabilities {
+                    ..Default::default()
+                }),
+                rename: Some(RenameClientCapabilities {
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            window: Some(WindowClientCapabilities {
+                work_done_progress: Some(true),
+                show_message: Some(ShowMessageRequestClientCapabilities {
+                    message_action_item: Some(MessageActionItemCapabilities {
+                        additional_properties_support: Some(true),
+                    }),
+                }),
+                ..Default::default()
+            }),
+            workspace: Some(WorkspaceClientCapabilities {
+                symbol: Some(WorkspaceSymbolClientCapabilities {
+                    ..Default::default()
+                }),
+                configuration: Some(true),
+                diagnostic: Some(DiagnosticWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                semantic_tokens: Some(SemanticTokensWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                inlay_hint: Some(InlayHintWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                code_lens: Some(CodeLensWorkspaceClientCapabilities {
+                    refresh_support: Some(true),
+                }),
+                workspace_folders: Some(true),
+                ..Default::default()
+            }),
+
+            // TODO: We could support the extension for utf8 and the upcoming stable support for requesting utf8
+            // so then we can just directly deal with (without translation) utf8 positions from the lsp.
+            // However implementing that has complexities in terms of knowing what lsp is getting our data
+            // before we send it, since we typically let the proxy decide.
+            // So, currently, we only send/receive UTF16 positions/offsets from the LSP clients
+            experimental: Some(json!({
+                "serverStatusNotification": true,
+            })),
+            ..Default::default()
+        };
+
+        #[allow(deprecated)]
+        let init_params = InitializeParams {
+            process_id: Some(process::id()),
+            root_uri: root_uri.clone(),
+            initialization_options: self.config.initialization_options.clone(),
+            capabilities: client_capabilities,
+            trace: Some(self.state.lock().server_trace),
+            workspace_folders: self.workspace_folders().or_else(|| {
+                root_uri.map(|uri| {
+                    vec![WorkspaceFolder {
+                        name: uri.as_str().to_string(),
+                        uri,
+                    }]
+                })
+            }),
+            client_info: None,
+            root_path: None,
+            locale: None,
+        };
+
+        let params = Params::from(serde_json::to_value(init_params).unwrap());
+        self.send_request("initialize", params, Box::new(on_init));
+    }
+
+    /// The workspace roots the server is given, if it's given any.
+    fn workspace_folders(&self) -> Option<Vec<WorkspaceFolder>> {
+        let folders = self
+            .config
+            .workspace_roots
+            .iter()
+            .filter_map(|root| Url::from_directory_path(root).ok())
+            .map(|uri| WorkspaceFolder {
+                name: uri.as_str().to_string(),
+                uri,
+            })
+            .collect::<Vec<_>>();
+        if folders.is_empty() {
+            None
+        } else {
+            Some(folders)
+        }
+    }
+
+    /// The work done params of a request made for core's request
+    /// `request_id`, if it's for one, with `cb` wrapped to forget the token
+    /// once the server answers. The server may then report its progress on
+    /// the request, which goes to core as that of `request_id` rather than
+    /// as the server's own work.
+    fn work_done_progress<CB>(
+        &self,
+        request_id: Option<RequestId>,
+        cb: CB,
+    ) -> (WorkDoneProgressParams, Callback)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let request_id = match request_id {
+            Some(request_id) => request_id,
+            None => return (WorkDoneProgressParams::default(), Box::new(cb)),
+        };
+        let token = format!("lapce/request/{request_id}");
+        self.state
+            .lock()
+            .work_done
+            .insert(token.clone(), request_id);
+        let params = WorkDoneProgressParams {
+            work_done_token: Some(NumberOrString::String(token.clone())),
+        };
+        let cb = move |lsp_client: &LspClient, result: Result<Value>| {
+            lsp_client.state.lock().work_done.remove(&token);
+            cb(lsp_client, result)
+        };
+        (params, Box::new(cb))
+    }
+
+    pub fn request_document_symbols<CB>(
+        &self,
+        document_uri: Url,
+        request_id: Option<RequestId>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let (work_done_progress_params, cb) =
+            self.work_done_progress(request_id, cb);
+        let params = DocumentSymbolParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params,
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/documentSymbol", params, cb);
+    }
+
+    /// The cached symbols matching `query`, or `None` if the server has to
+    /// be asked, in which case the symbols start loading if they aren't.
+    fn cached_workspace_symbols(
+        &self,
+        query: &str,
+    ) -> Option<Vec<SymbolInformation>> {
+        let not_loaded = {
+            let state = self.state.lock();
+            match &state.workspace_symbols {
+                WorkspaceSymbolCache::Ready(symbols) => {
+                    return Some(
+                        symbols
+                            .iter()
+                            .filter(|symbol| symbol_matches(&symbol.name, query))
+                            .cloned()
+                            .collect(),
+                    );
+                }
+                WorkspaceSymbolCache::NotLoaded => true,
+                _ => false,
+            }
+        };
+        if not_loaded {
+            self.load_workspace_symbols();
+        }
+        None
+    }
+
+    /// Asks the server for all the symbols of the workspace. Servers that
+    /// fail to give them, give none, or give too many are always asked.
+    fn load_workspace_symbols(&self) {
+        let generation = {
+            let mut state = self.state.lock();
+            state.workspace_symbols = WorkspaceSymbolCache::Loading;
+            state.workspace_symbols_generation
+        };
+        self.request_workspace_symbols(
+            String::new(),
+            None,
+            move |lsp_client, result| {
+                let symbols = result.ok().and_then(|value| {
+                    serde_json::from_value::<Vec<SymbolInformation>>(value).ok()
+                });
+                let mut state = lsp_client.state.lock();
+                if state.workspace_symbols_generation != generation {
+                    return;
+                }
+                state.workspace_symbols = match symbols {
+                    Some(symbols)
+                        if !symbols.is_empty()
+                            && symbols.len() < MAX_CACHED_SYMBOLS =>
+                    {
+                        WorkspaceSymbolCache::Ready(symbols)
+                    }
+                    _ => WorkspaceSymbolCache::Unavailable,
+                };
+            },
+        );
+    }
+
+    pub fn request_workspace_symbols<CB>(
+        &self,
+        query: String,
+        request_id: Option<RequestId>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let (work_done_progress_params, cb) =
+            self.work_done_progress(request_id, cb);
+        let params = WorkspaceSymbolParams {
+            query,
+            work_done_progress_params,
+            ..Default::default()
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("workspace/symbol", params, cb);
+    }
+
+    pub fn request_document_formatting<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentFormattingParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            options: FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                ..Default::default()
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/formatting", params, Box::new(cb));
+    }
+
+    pub fn request_on_type_formatting<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        ch: String,
+        options: FormattingOptions,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentOnTypeFormattingParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            ch,
+            options,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/onTypeFormatting", params, Box::new(cb));
+    }
+
+    pub fn request_will_save_wait_until<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = WillSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            reason: TextDocumentSaveReason::MANUAL,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/willSaveWaitUntil", params, Box::new(cb));
+    }
+
+    /// Whether the server wants to edit documents before they're saved.
+    pub fn edits_before_save(&self) -> bool {
+        let state = self.state.lock();
+        let sync = state
+            .server_capabilities
+            .as_ref()
+            .and_then(|cap| cap.text_document_sync.as_ref());
+        state.is_initialized()
+            && matches!(
+                sync,
+                Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        will_save_wait_until: Some(true),
+                        ..
+                    }
+                ))
+            )
+    }
+
+    pub fn request_semantic_tokens<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SemanticTokensParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/semanticTokens/full", params, Box::new(cb));
+    }
+
+    pub fn request_inlay_hints<CB>(&self, document_uri: Url, range: Range, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = InlayHintParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            range,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/inlayHint", params, Box::new(cb));
+    }
+
+    /// Sends `inlay_hint` as the server gave it, its `data` included, for
+    /// the server to fill in the rest.
+    pub fn inlay_hint_resolve<CB>(&self, inlay_hint: &InlayHint, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(inlay_hint).unwrap());
+        self.send_request("inlayHint/resolve", params, Box::new(cb));
+    }
+
+    pub fn request_code_lens<CB>(&self, document_uri: Url, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CodeLensParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/codeLens", params, Box::new(cb));
+    }
+
+    /// Sends `code_lens` with its `data`, for the server to fill in its
+    /// command.
+    pub fn code_lens_resolve<CB>(&self, code_lens: &CodeLens, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(code_lens).unwrap());
+        self.send_request("codeLens/resolve", params, Box::new(cb));
+    }
+
+    /// The code actions for `range`, only those of the kinds in `only` if
+    /// it isn't empty. The server is asked for just those, and the actions
+    /// of other kinds it may give anyway are dropped.
+    pub fn request_code_actions<CB>(
+        &self,
+        document_uri: Url,
+        range: Range,
+        only: Vec<CodeActionKind>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CodeActionParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            range,
+            context: CodeActionContext {
+                only: if only.is_empty() {
+                    None
+                } else {
+                    Some(only.clone())
+                },
+                ..Default::default()
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request(
+            "textDocument/codeAction",
+            params,
+            Box::new(move |lsp_client: &LspClient, result: Result<Value>| {
+                let result = if only.is_empty() {
+                    result
+                } else {
+                    result.map(|value| filter_code_actions(value, &only))
+                };
+                cb(lsp_client, result)
+            }),
+        );
+    }
+
+    pub fn request_references<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        request_id: Option<RequestId>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let (work_done_progress_params, cb) =
+            self.work_done_progress(request_id, cb);
+        let params = ReferenceParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params,
+            partial_result_params: PartialResultParams::default(),
+            context: ReferenceContext {
+                include_declaration: false,
+            },
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/references", params, cb);
+    }
+
+    pub fn request_definition<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/definition", params, Box::new(cb));
+    }
+
+    pub fn request_type_definition<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = GotoTypeDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/typeDefinition", params, Box::new(cb));
+    }
+
+    pub fn request_linked_editing_range<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = LinkedEditingRangeParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/linkedEditingRange", params, Box::new(cb));
+    }
+
+    pub fn request_moniker<CB>(&self, document_uri: Url, position: Position, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = MonikerParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/moniker", params, Box::new(cb));
+    }
+
+    /// The call hierarchy items at `position` in `buffer`, which are
+    /// prepared again only once the document changes.
+    pub fn prepare_call_hierarchy<CB>(
+        &self,
+        buffer: &Buffer,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let uri = self.get_uri(buffer);
+        let (version, cached) = {
+            let mut state = self.state.lock();
+            let version = state.document_versions.get(&buffer.id).copied();
+            let cached = version.and_then(|version| {
+                state.call_hierarchy.get(&uri, position, version)
+            });
+            (version, cached)
+        };
+        if let Some(items) = cached {
+            cb(self, Ok(items));
+            return;
+        }
+
+        let params = CallHierarchyPrepareParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        let buffer_id = buffer.id;
+        self.send_request(
+            "textDocument/prepareCallHierarchy",
+            params,
+            Box::new(move |lsp_client: &LspClient, result: Result<Value>| {
+                if let (Ok(items), Some(version)) = (result.as_ref(), version) {
+                    let mut state = lsp_client.state.lock();
+                    // the document may have changed while it was prepared
+                    if state.document_versions.get(&buffer_id) == Some(&version) {
+                        state.call_hierarchy.insert(
+                            uri,
+                            position,
+                            version,
+                            items.clone(),
+                        );
+                    }
+                }
+                cb(lsp_client, result);
+            }),
+        );
+    }
+
+    pub fn request_incoming_calls<CB>(&self, item: CallHierarchyItem, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CallHierarchyIncomingCallsParams {
+            item,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("callHierarchy/incomingCalls", params, Box::new(cb));
+    }
+
+    pub fn request_outgoing_calls<CB>(&self, item: CallHierarchyItem, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = CallHierarchyOutgoingCallsParams {
+            item,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("callHierarchy/outgoingCalls", params, Box::new(cb));
+    }
+
+    pub fn request_rename<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        new_name: String,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = RenameParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            new_name,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/rename", params, Box::new(cb));
+    }
+
+    /// The diagnostic options of the server if it gives the diagnostics
+    /// of the whole workspace.
+    fn workspace_diagnostic_options(&self) -> Option<DiagnosticOptions> {
+        let state = self.state.lock();
+        if !state.is_initialized() {
+            return None;
+        }
+        let options = match state
+            .server_capabilities
+            .as_ref()?
+            .diagnostic_provider
+            .as_ref()?
+        {
+            DiagnosticServerCapabilities::Options(options) => options,
+            DiagnosticServerCapabilities::RegistrationOptions(options) => {
+                &options.diagnostic_options
+            }
+        };
+        Some(options.clone()).filter(|options| options.workspace_diagnostics)
+    }
+
+    /// Pulls the diagnostics of the whole workspace, sending core the
+    /// reports that changed. A pull asked for while one is in flight is
+    /// sent after it.
+    fn pull_workspace_diagnostics(&self) {
+        let options = match self.workspace_diagnostic_options() {
+            Some(options) => options,
+            None => return,
+        };
+        let previous_result_ids = {
+            let mut state = self.state.lock();
+            match state.workspace_diagnostics {
+                WorkspaceDiagnosticsPull::Idle => {
+                    state.workspace_diagnostics = WorkspaceDiagnosticsPull::Pulling;
+                }
+                WorkspaceDiagnosticsPull::Pulling
+                | WorkspaceDiagnosticsPull::PullingStale => {
+                    state.workspace_diagnostics =
+                        WorkspaceDiagnosticsPull::PullingStale;
+                    return;
+                }
+            }
+            state
+                .diagnostic_result_ids
+                .iter()
+                .map(|(uri, value)| PreviousResultId {
+                    uri: uri.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        };
+
+        let params = WorkspaceDiagnosticParams {
+            identifier: options.identifier,
+            previous_result_ids,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request(
+            "workspace/diagnostic",
+            params,
+            Box::new(|lsp_client: &LspClient, result: Result<Value>| {
+                if let Ok(WorkspaceDiagnosticReportResult::Report(report)) =
+                    result.and_then(|value| Ok(serde_json::from_value(value)?))
+                {
+                    lsp_client.publish_workspace_diagnostics(report.items);
+                }
+                let stale = {
+                    let mut state = lsp_client.state.lock();
+                    let stale = state.workspace_diagnostics
+                        == WorkspaceDiagnosticsPull::PullingStale;
+                    state.workspace_diagnostics = WorkspaceDiagnosticsPull::Idle;
+                    stale
+                };
+                if stale {
+                    lsp_client.pull_workspace_diagnostics();
+                }
+            }),
+        );
+    }
+
+    /// Sends core the diagnostics of the `reports` that changed, the way
+    /// the server would publish them.
+    fn publish_workspace_diagnostics(
+        &self,
+        reports: Vec<WorkspaceDocumentDiagnosticReport>,
+    ) {
+        for report in reports {
+            match report {
+                WorkspaceDocumentDiagnosticReport::Full(report) => self
+                    .record_diagnostic_report(
+                        report.uri,
+                        DocumentDiagnosticReportKind::Full(
+                            report.full_document_diagnostic_report,
+                        ),
+                        report.version.map(|v| v as i32),
+                    ),
+                WorkspaceDocumentDiagnosticReport::Unchanged(report) => self
+                    .record_diagnostic_report(
+                        report.uri,
+                        DocumentDiagnosticReportKind::Unchanged(
+                            report.unchanged_document_diagnostic_report,
+                        ),
+                        None,
+                    ),
+            }
+        }
+    }
+
+    /// Keeps the result id of the report of the document at `uri` for the
+    /// next pull, sending core its diagnostics only if they're given in
+    /// full. An `unchanged` report means the ones core has are still right,
+    /// so they're left as they are.
+    fn record_diagnostic_report(
+        &self,
+        uri: Url,
+        report: DocumentDiagnosticReportKind,
+        version: Option<i32>,
+    ) {
+        let (result_id, diagnostics) = match report {
+            DocumentDiagnosticReportKind::Full(full) => {
+                (full.result_id, Some(full.items))
+            }
+            DocumentDiagnosticReportKind::Unchanged(unchanged) => {
+                (Some(unchanged.result_id), None)
+            }
+        };
+        {
+            let mut state = self.state.lock();
+            match result_id {
+                Some(result_id) => {
+                    state.diagnostic_result_ids.insert(uri.clone(), result_id);
+                }
+                None => {
+                    state.diagnostic_result_ids.remove(&uri);
+                }
+            }
+        }
+        if let Some(diagnostics) = diagnostics {
+            self.publish_diagnostics(PublishDiagnosticsParams::new(
+                uri,
+                diagnostics,
+                version,
+            ));
+        }
+    }
+
+    /// Pulls the diagnostics of the document at `uri`, recording the
+    /// report of it and of the documents related to it before `cb` is
+    /// given the server's answer.
+    fn pull_document_diagnostics<CB>(
+        &self,
+        uri: Url,
+        previous_result_id: Option<String>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        self.request_document_diagnostic(
+            uri.clone(),
+            previous_result_id,
+            move |lsp_client, result| {
+                let report = result.as_ref().ok().and_then(|value| {
+                    serde_json::from_value::<DocumentDiagnosticReport>(value.clone())
+                        .ok()
+                });
+                if let Some(report) = report {
+                    let (kind, related) = match report {
+                        DocumentDiagnosticReport::Full(report) => (
+                            DocumentDiagnosticReportKind::Full(
+                                report.full_document_diagnostic_report,
+                            ),
+                            report.related_documents,
+                        ),
+                        DocumentDiagnosticReport::Unchanged(report) => (
+                            DocumentDiagnosticReportKind::Unchanged(
+                                report.unchanged_document_diagnostic_report,
+                            ),
+                            report.related_documents,
+                        ),
+                    };
+                    lsp_client.record_diagnostic_report(uri, kind, None);
+                    for (uri, kind) in related.unwrap_or_default() {
+                        lsp_client.record_diagnostic_report(uri, kind, None);
+                    }
+                }
+                cb(lsp_client, result);
+            },
+        );
+    }
+
+    pub fn request_document_diagnostic<CB>(
+        &self,
+        document_uri: Url,
+        previous_result_id: Option<String>,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = DocumentDiagnosticParams {
+            text_document: TextDocumentIdentifier { uri: document_uri },
+            identifier: None,
+            previous_result_id,
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/diagnostic", params, Box::new(cb));
+    }
+
+    pub fn request_completion<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        context: Option<CompletionContext>,
+        partial_result_token: Option<ProgressToken>,
+        on_completion: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let completion_params = CompletionParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams {
+                partial_result_token,
+            },
+            context,
+        };
+        let params = Params::from(serde_json::to_value(completion_params).unwrap());
+        self.send_request(
+            "textDocument/completion",
+            params,
+            Box::new(on_completion),
+        );
+    }
+
+    pub fn completion_resolve<CB>(
+        &self,
+        completion_item: &CompletionItem,
+        on_result: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = Params::from(serde_json::to_value(completion_item).unwrap());
+        self.send_request("completionItem/resolve", params, Box::new(on_result));
+    }
+
+    pub fn request_hover<CB>(&self, document_uri: Url, position: Position, cb: CB)
+    where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let hover_params = HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let params = Params::from(serde_json::to_value(hover_params).unwrap());
+        self.send_request("textDocument/hover", params, Box::new(cb));
+    }
+
+    pub fn request_signature<CB>(
+        &self,
+        document_uri: Url,
+        position: Position,
+        cb: CB,
+    ) where
+        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
+    {
+        let params = SignatureHelpParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: document_uri },
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            context: None,
+        };
+        let params = Params::from(serde_json::to_value(params).unwrap());
+        self.send_request("textDocument/signatureHelp", params, Box::new(cb));
+    }
+
+    /// Sends `changes` to the document of `buffer`, at `version` or, if the
+    /// server was given that one already, the one after the last it got.
+    pub fn send_did_change(
+        &self,
+        buffer: &Buffer,
+        changes: Vec<TextDocumentContentChangeEvent>,
+        version: u64,
+    ) {
+        let uri = self.get_uri(buffer);
+        let mut changes = changes;
+        let version = {
+            let mut state = self.state.lock();
+            let owner = state
+                .document_buffers
+                .get(&uri)
+                .and_then(|buffers| buffers.first().copied());
+            if owner.map(|owner| owner != buffer.id).unwrap_or(false) {
+                return;
+            }
+            let handed_over = state.handed_over.remove(&buffer.id);
+            if handed_over.is_some() || state.drafts.contains(&buffer.id) {
+                changes = vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: buffer.get_document(),
+                }];
+            }
+            if let Some(handed_over) = handed_over {
+                // the versions go on from the ones of the previous owner,
+                // which the server has seen
+                let last = state.document_versions.entry(buffer.id).or_insert(0);
+                *last = (*last).max(handed_over);
+            }
+            state.call_hierarchy.invalidate(&uri);
+            let last = state.document_versions.entry(buffer.id).or_insert(0);
+            *last = (*last + 1).max(version as i32);
+            let version = *last;
+            if let Some(unsent) = state.unsent_opens.get_mut(&uri) {
+                // the server gets the document as it is once it's initialized
+                unsent.text = buffer.get_document();
+                unsent.version = version;
+                return;
+            }
+            version
+        };
+        self.send_versioned_change(uri, version, changes);
+    }
+
+    /// Gives the server `draft`, a text of its document being prepared for
+    /// saving other than the one of its buffer, until `end_draft`.
+    pub fn send_draft(&self, draft: &Buffer) {
+        self.state.lock().drafts.insert(draft.id);
+        // sent as its full text, being a draft
+        self.send_did_change(draft, Vec::new(), draft.rev);
+    }
+
+    /// Gives the server the text of `buffer` again, if it was given a draft
+    /// of it.
+    pub fn end_draft(&self, buffer: &Buffer) {
+        if self.state.lock().drafts.remove(&buffer.id) {
+            let text = buffer.get_document();
+            let changes = vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text,
+            }];
+            self.send_did_change(buffer, changes, buffer.rev);
+        }
+    }
+
+    fn send_versioned_change(
+        &self,
+        uri: Url,
+        version: i32,
+        changes: Vec<TextDocumentContentChangeEvent>,
+    ) {
+        {
+            // the requests that may be sent again are sent about the
+            // document as it is then
+            let mut state = self.state.lock();
+            for request in state.retryable.values_mut() {
+                let params = match request.params.take() {
+                    Some(params) => params,
+                    None => continue,
+                };
+                request.params = if request_document(&params).as_ref() == Some(&uri)
+                {
+                    transform_params(params, &changes)
+                } else {
+                    Some(params)
+                };
+            }
+        }
+        let text_document_did_change_params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier { uri, version },
+            content_changes: changes,
+        };
+
+        let params = Params::from(
+            serde_json::to_value(text_document_did_change_params).unwrap(),
+        );
+        // never dropped, as the changes after it are made against the text
+        // it leaves the server with
+        self.send_notification("textDocument/didChange", params);
+    }
+
+    pub fn get_sync_kind(&self) -> Option<TextDocumentSyncKind> {
+        let state = self.state.lock();
+        let text_document_sync = state
+            .server_capabilities
+            .as_ref()
+            .and_then(|c| c.text_document_sync.as_ref())?;
+        match text_document_sync {
+            TextDocumentSyncCapability::Kind(kind) => Some(*kind),
+            TextDocumentSyncCapability::Options(options) => options.clone().change,
+        }
+    }
+
+    /// Clears the diagnostics of `buffer` the server gave, then asks for
+    /// them again if it gives them when asked, or sends it the document's
+    /// text as a change for it to publish them anew. Returns whether the
+    /// server is initialized to be asked.
+    fn refresh_diagnostics(&self, buffer: &Buffer) -> bool {
+        if !self.state.lock().is_initialized() {
+            return false;
+        }
+        let uri = self.get_uri(buffer);
+        let (owner, pull) = {
+            let state = self.state.lock();
+            let owner = state
+                .document_buffers
+                .get(&uri)
+                .and_then(|buffers| buffers.first().copied())
+                .unwrap_or(buffer.id);
+            let pull = state
+                .server_capabilities
+                .as_ref()
+                .map(|cap| cap.diagnostic_provider.is_some())
+                .unwrap_or(false);
+            (owner, pull)
+        };
+        self.publish_diagnostics(PublishDiagnosticsParams::new(
+            uri.clone(),
+            Vec::new(),
+            None,
+        ));
+
+        if pull {
+            // without a previous result id, the report is always full
+            self.state.lock().diagnostic_result_ids.remove(&uri);
+            self.pull_document_diagnostics(uri, None, |_, _| {});
+        } else {
+            // servers publish the diagnostics of a document as it changes
+            let version = {
+                let mut state = self.state.lock();
+                let last = state.document_versions.entry(owner).or_insert(0);
+                *last += 1;
+                *last
+            };
+            let change = TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: buffer.get_document(),
+            };
+            self.send_versioned_change(uri, version, vec![change]);
+        }
+        true
+    }
+
+    pub fn update(
+        &self,
+        buffer: &Buffer,
+        content_change: &TextDocumentContentChangeEvent,
+        rev: u64,
+    ) {
+        let sync_kind = self.get_sync_kind().unwrap_or(TextDocumentSyncKind::FULL);
+        let changes = get_change_for_sync_kind(sync_kind, buffer, content_change);
+        if let Some(changes) = changes {
+            self.send_did_change(buffer, changes, rev);
+        }
+    }
+}
+
+/// Merges `overlay` into `base`: objects key by key, anything else
+/// replacing what's in `base`.
+fn merge_json(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_json(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// The `[lsp.<language id>]` tables of the settings file at `path`.
+pub fn load_lsp_settings(path: &Path) -> HashMap<String, Value> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| toml::from_str::<toml::Value>(&content).ok())
+        .and_then(|settings| settings.get("lsp")?.clone().try_into().ok())
+        .unwrap_or_default()
+}
+
+pub enum LspHeader {
+    ContentType,
+    ContentLength(usize),
+}
+
+fn number_from_id(id: &Id) -> u64 {
+    match *id {
+        Id::Num(n) => n as u64,
+        Id::Str(ref s) => s
+            .parse::<u64>()
+            .expect("failed to convert string id to u64"),
+        _ => panic!("unexpected value for id: None"),
+    }
+}
+
+/// Whether the symbol `name` matches the search `query`, which it does when
+/// it has all the characters of the query in order, ignoring case.
+fn symbol_matches(name: &str, query: &str) -> bool {
+    let mut name = name.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|c| name.any(|n| n == c))
+}
+
+pub(crate) fn prepare_lsp_json(msg: &Value) -> Result<String> {
+    let request = serde_json::to_string(&msg)?;
+    Ok(format!(
+        "Content-Length: {}\r\n\r\n{}",
+        request.len(),
+        request
+    ))
+}
+
+fn parse_header(s: &str) -> Result<LspHeader> {
+    let split: Vec<String> =
+        s.splitn(2, ": ").map(|s| s.trim().to_lowercase()).collect();
+    if split.len() != 2 {
+        return Err(anyhow!("Malformed"));
+    };
+    match split[0].as_ref() {
+        HEADER_CONTENT_TYPE => Ok(LspHeader::ContentType),
+        HEADER_CONTENT_LENGTH => {
+            Ok(LspHeader::ContentLength(split[1].parse::<usize>()?))
+        }
+        _ => Err(anyhow!("Unknown parse error occurred")),
+    }
+}
+
+/// The error of a message over the size limit, which is skipped so the
+/// messages after it can still be read.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub limit: usize,
+    /// The id of the request the message answers, if it's a response
+    pub id: Option<u64>,
+}
+
+impl std::fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "message of {} bytes is over the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// What a server wrote instead of the header of a message, which tells it
+/// speaks another protocol or was started with the wrong command.
+#[derive(Debug)]
+pub struct UnexpectedProtocol {
+    /// The first bytes of it
+    pub received: Vec<u8>,
+}
+
+impl UnexpectedProtocol {
+    fn new(received: &[u8]) -> UnexpectedProtocol {
+        UnexpectedProtocol {
+            received: received[..received.len().min(UNEXPECTED_PROTOCOL_PREFIX_LEN)]
+                .to_vec(),
+        }
+    }
+}
+
+impl std::fmt::Display for UnexpectedProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server speaks unexpected protocol, it wrote {:?}",
+            String::from_utf8_lossy(&self.received)
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedProtocol {}
+
+/// The error object a server answered a request with.
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (error {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// The header a server is in the middle of writing, if it is, with when it
+/// started it and its first bytes.
+#[derive(Default)]
+pub struct HeaderWatch(Mutex<Option<(Instant, Vec<u8>)>>);
+
+impl HeaderWatch {
+    fn start(&self, received: &[u8]) {
+        let mut header = self.0.lock();
+        if header.is_none() {
+            *header =
+                Some((Instant::now(), UnexpectedProtocol::new(received).received));
+        }
+    }
+
+    fn finish(&self) {
+        *self.0.lock() = None;
+    }
+
+    /// The first bytes of the header the server started over `timeout` ago.
+    fn stalled(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .as_ref()
+            .filter(|(started, _)| started.elapsed() > timeout)
+            .map(|(_, received)| received.clone())
+    }
+}
+
+/// Reads a message no larger than `max_size` bytes. Larger ones fail with
+/// [`MessageTooLarge`] without being held in memory, and what isn't a message
+/// fails with [`UnexpectedProtocol`]. `watch` sees the header being read.
+pub fn read_message<T: BufRead>(
+    reader: &mut T,
+    max_size: usize,
+    watch: &HeaderWatch,
+) -> Result<String> {
+    let mut header = Vec::new();
+    let mut content_length: Option<usize> = None;
+
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() && header.is_empty() {
+            return Err(anyhow!("server closed its output"));
+        } else if available.is_empty() {
+            return Err(UnexpectedProtocol::new(&header).into());
+        }
+        watch.start(available);
+
+        let line_start = header.len();
+        let limit = (MAX_HEADER_SIZE - line_start) as u64;
+        Read::take(&mut *reader, limit).read_until(b'\n', &mut header)?;
+        if !header.ends_with(b"\n") {
+            if header.len() >= MAX_HEADER_SIZE {
+                return Err(UnexpectedProtocol::new(&header).into());
+            }
+            // the output ended in the middle of the line
+            continue;
+        }
+
+        let line = std::str::from_utf8(&header[line_start..])
+            .map_err(|_| UnexpectedProtocol::new(&header))?;
+        if line.trim().is_empty() {
+            break;
+        }
+        match parse_header(line).map_err(|_| UnexpectedProtocol::new(&header))? {
+            LspHeader::ContentLength(len) => content_length = Some(len),
+            LspHeader::ContentType => (),
+        };
+    }
+    watch.finish();
+
+    let content_length =
+        content_length.ok_or_else(|| UnexpectedProtocol::new(&header))?;
+    if content_length > max_size {
+        let id = skip_message(reader, content_length)?;
+        return Err(MessageTooLarge {
+            size: content_length,
+            limit: max_size,
+            id,
+        }
+        .into());
+    }
+
+    let mut body_buffer = vec![0; content_length];
+    reader.read_exact(&mut body_buffer)?;
+
+    let body = String::from_utf8(body_buffer)?;
+    Ok(body)
+}
+
+/// Reads past a message of `len` bytes, giving the id of the response it is,
+/// if it can be told from the start of the message.
+fn skip_message<T: BufRead>(reader: &mut T, len: usize) -> Result<Option<u64>> {
+    let mut prefix = vec![0; len.min(MESSAGE_ID_PREFIX_LEN)];
+    reader.read_exact(&mut prefix)?;
+    std::io::copy(
+        &mut Read::take(reader, (len - prefix.len()) as u64),
+        &mut std::io::sink(),
+    )?;
+
+    let prefix = String::from_utf8_lossy(&prefix);
+    // requests from the server have ids of their own
+    if prefix.contains("\"method\"") {
+        return Ok(None);
+    }
+    let id = prefix
+        .find("\"id\"")
+        .map(|start| prefix[start + 4..].trim_start())
+        .and_then(|rest| rest.strip_prefix(':'))
+        .map(|rest| rest.trim_start())
+        .and_then(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..end].parse().ok()
+        });
+    Ok(id)
+}
+
+pub fn get_change_for_sync_kind(
+    sync_kind: TextDocumentSyncKind,
+    buffer: &Buffer,
+    content_change: &TextDocumentContentChangeEvent,
+) -> Option<Vec<TextDocumentContentChangeEvent>> {
+    match sync_kind {
+        TextDocumentSyncKind::NONE => None,
+        TextDocumentSyncKind::FULL => {
+            let text_document_content_change_event =
+                TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: buffer.get_document(),
+                };
+            Some(vec![text_document_content_change_event])
+        }
+        TextDocumentSyncKind::INCREMENTAL => Some(vec![content_change.clone()]),
+        _ => None,
+    }
+}
+
+fn format_semantic_styles(
+    buffer: &Buffer,
+    semantic_tokens_provider: &Option<SemanticTokensServerCapabilities>,
+    value: Value,
+) -> Option<Vec<LineStyle>> {
+    let semantic_tokens: SemanticTokens = serde_json::from_value(value).ok()?;
+    let semantic_tokens_provider = semantic_tokens_provider.as_ref()?;
+    let semantic_legends = semantic_tokens_legend(semantic_tokens_provider);
+
+    let mut highlights = Vec::new();
+    let mut line = 0;
+    let mut start = 0;
+    let mut last_start = 0;
+    for semantic_token in &semantic_tokens.data {
+        if semantic_token.delta_line > 0 {
+            line += semantic_token.delta_line as usize;
+            start = buffer.offset_of_line(line);
+        }
+
+        let sub_text = buffer.char_indices_iter(start..);
+        if let Some(utf8_delta_start) =
+            offset_utf16_to_utf8(sub_text, semantic_token.delta_start as usize)
+        {
+            start += utf8_delta_start;
+        } else {
+            // Bad semantic token offsets
+            log::error!("Bad semantic token starty {semantic_token:?}");
+            break;
+        };
+
+        let sub_text = buffer.char_indices_iter(start..);
+        let end = if let Some(utf8_length) =
+            offset_utf16_to_utf8(sub_text, semantic_token.length as usize)
+        {
+            start + utf8_length
+        } else {
+            log::error!("Bad semantic token end {semantic_token:?}");
+            break;
+        };
+
+        let kind = semantic_legends.token_types[semantic_token.token_type as usize]
+            .as_str()
+            .to_string();
+        if start < last_start {
+            continue;
+        }
+        last_start = start;
+        highlights.push(LineStyle {
+            start,
+            end,
+            style: Style {
+                fg_color: Some(kind),
+            },
+        });
+    }
+
+    Some(highlights)
+}
+
+fn semantic_tokens_legend(
+    semantic_tokens_provider: &SemanticTokensServerCapabilities,
+) -> SemanticTokensLegend {
+    match semantic_tokens_provider {
+        SemanticTokensServerCapabilities::SemanticTokensOptions(options) => {
+            options.legend.clone()
+        }
+        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(
+            options,
+        ) => options.semantic_tokens_options.legend.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_lsp::{fake_spec, FakeLspServer};
+    use xi_rope::Rope;
+
+    #[test]
+    fn test_spec_language_ids() {
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "rust-analyzer",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        assert_eq!(spec.language_ids, vec!["rust".to_string()]);
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "clangd",
+            "language_ids": ["c", "cpp", "objective-c"],
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        assert_eq!(spec.language_ids, vec!["c", "cpp", "objective-c"]);
+    }
+
+    #[test]
+    fn test_completion_merge() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut merge =
+            CompletionMerge::new(1, 1, Position::default(), 2, None, dispatcher);
+
+        merge.add(
+            "rust-analyzer",
+            Ok(json!([
+                { "label": "foo", "detail": "fn()", "data": 1 },
+                { "label": "bar" },
+            ])),
+        );
+        // the dispatcher sends notifications of its own, skip those
+        let responses = || {
+            receiver
+                .try_iter()
+                .filter(|msg| msg.get("id").is_some())
+                .collect::<Vec<_>>()
+        };
+        assert!(responses().is_empty());
+        merge.add(
+            "other",
+            Ok(json!({
+                "isIncomplete": true,
+                "itemDefaults": { "insertTextMode": 2 },
+                "items": [
+                    { "label": "foo", "detail": "fn()" },
+                    { "label": "foo", "detail": "usize", "insertTextMode": 1 },
+                    { "label": "baz" },
+                ],
+            })),
+        );
+
+        let resp = responses().pop().unwrap();
+        let list: CompletionList =
+            serde_json::from_value(resp["result"].clone()).unwrap();
+        assert!(list.is_incomplete);
+        let items = list
+            .items
+            .iter()
+            .map(|item| {
+                let tag = CompletionItemData::of(item).unwrap();
+                (
+                    item.label.as_str(),
+                    tag.source,
+                    tag.data,
+                    item.insert_text_mode,
+                )
+            })
+            .collect::<Vec<_>>();
+        let other = |label, mode| (label, "other".to_string(), None, Some(mode));
+        assert_eq!(
+            items,
+            vec![
+                ("foo", "rust-analyzer".to_string(), Some(json!(1)), None),
+                ("bar", "rust-analyzer".to_string(), None, None),
+                other("foo", InsertTextMode::AS_IS),
+                other("baz", InsertTextMode::ADJUST_INDENTATION),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_completion_filter() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let items = json!([
+            { "label": "foo", "detail": "fn()", "sortText": "b" },
+            { "label": "bar" },
+            { "label": "foo", "detail": "usize", "sortText": "a" },
+            { "label": "format", "filterText": "xformat" },
+        ]);
+        let response = |filter: Option<&str>, items: Value| {
+            let mut merge = CompletionMerge::new(
+                1,
+                1,
+                Position::default(),
+                1,
+                filter.map(String::from),
+                dispatcher.clone(),
+            );
+            merge.add("server", Ok(items));
+            let resp = receiver
+                .try_iter()
+                .filter(|msg| msg.get("id").is_some())
+                .last()
+                .unwrap();
+            serde_json::from_value::<CompletionList>(resp["result"].clone()).unwrap()
+        };
+
+        let list = response(Some("fo"), items.clone());
+        let matched = list
+            .items
+            .iter()
+            .map(|item| {
+                let tag = CompletionItemData::of(item).unwrap();
+                (item.detail.as_deref(), tag.matched.unwrap().indices)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(matched.len(), 3);
+        // equal matches keep the server's order of `sortText`
+        assert_eq!(matched[0], (Some("usize"), vec![0, 1]));
+        assert_eq!(matched[1], (Some("fn()"), vec![0, 1]));
+        assert_eq!(matched[2], (None, vec![1, 2]));
+
+        // lists that aren't complete are left for core to filter
+        let list =
+            response(Some("fo"), json!({ "isIncomplete": true, "items": items }));
+        assert_eq!(list.items.len(), 4);
     }
 
-    pub fn send_error_response(
-        &self,
-        id: jsonrpc_lite::Id,
-        error: jsonrpc_lite::Error,
-    ) {
-        let response = JsonRpc::error(id, error);
+    #[test]
+    fn test_completion_partial_result() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let position = Position::new(3, 7);
+        let mut merge = CompletionMerge::new(1, 5, position, 1, None, dispatcher);
+        let messages = || {
+            receiver
+                .try_iter()
+                .filter(|msg| {
+                    msg.get("id").is_some()
+                        || msg["method"] == "completion_partial_result"
+                })
+                .collect::<Vec<_>>()
+        };
 
-        self.send_rpc(&to_value(&response).unwrap());
+        merge.add_partial("server", json!([{ "label": "foo" }]));
+        merge.add_partial("server", json!([{ "label": "foo" }, { "label": "bar" }]));
+        let partials = messages();
+        assert_eq!(partials.len(), 2);
+        assert_eq!(partials[0]["params"]["request_id"], 5);
+        assert_eq!(partials[0]["params"]["position"], json!(position));
+        assert_eq!(partials[1]["params"]["items"].as_array().unwrap().len(), 1);
+        assert_eq!(partials[1]["params"]["items"][0]["label"], "bar");
+
+        // the response itself has every item
+        merge.add("server", Ok(json!([])));
+        let resp = messages().pop().unwrap();
+        let list: CompletionList =
+            serde_json::from_value(resp["result"].clone()).unwrap();
+        let labels = list
+            .items
+            .iter()
+            .map(|item| item.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["foo", "bar"]);
+
+        merge.add_partial("server", json!([{ "label": "baz" }]));
+        assert!(messages().is_empty());
     }
 
-    fn initialize(&self) {
-        if let Some(workspace) = self.dispatcher.workspace.lock().clone() {
-            let root_url = Url::from_directory_path(workspace).unwrap();
-            let (sender, receiver) = channel();
-            self.send_initialize(Some(root_url), move |lsp_client, result| {
-                if let Ok(result) = result {
-                    {
-                        let init_result: InitializeResult =
-                            serde_json::from_value(result).unwrap();
-                        let mut state = lsp_client.state.lock();
-                        state.server_capabilities = Some(init_result.capabilities);
-                        state.is_initialized = true;
-                    }
-                    lsp_client.send_initialized();
-                }
-                let _ = sender.send(true);
-            });
-            let _ = receiver.recv_timeout(Duration::from_millis(1000));
-        }
+    #[test]
+    fn test_completion_suppression() {
+        let mut lsp = LspCatalog::new();
+        let triggered = Some(CompletionTriggerKind::TRIGGER_CHARACTER);
+        lsp.completion_accepted(BufferId(1));
+        // off unless a window is set
+        assert!(!lsp.completion_suppressed(BufferId(1), triggered));
+
+        lsp.completion_suppression = Duration::from_secs(60);
+        lsp.completion_accepted(BufferId(1));
+        assert!(lsp.completion_suppressed(BufferId(1), triggered));
+        assert!(!lsp.completion_suppressed(BufferId(2), triggered));
+        assert!(!lsp.completion_suppressed(
+            BufferId(1),
+            Some(CompletionTriggerKind::INVOKED)
+        ));
+        assert!(!lsp.completion_suppressed(BufferId(1), None));
+
+        lsp.completion_suppression = Duration::from_millis(10);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!lsp.completion_suppressed(BufferId(1), triggered));
     }
 
-    pub fn send_did_open(
-        &self,
-        buffer_id: &BufferId,
-        document_uri: Url,
-        language_id: &str,
-        document_text: String,
-    ) {
-        let is_initialized = {
-            let mut state = self.state.lock();
-            state
-                .opened_documents
-                .insert(*buffer_id, document_uri.clone());
-            state.is_initialized
-        };
+    #[cfg(unix)]
+    #[test]
+    fn test_multi_language_server_routing() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        // `cat` stands in for the server, it's never initialized
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_ids": ["javascript", "typescript"],
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+
+        let javascript = catalog.client("javascript").unwrap();
+        let typescript = catalog.client("typescript").unwrap();
+        assert!(Arc::ptr_eq(javascript, typescript));
+        assert!(catalog.client("rust").is_none());
+        assert_eq!(catalog.servers().len(), 1);
+
+        catalog.stop();
+    }
 
-        if !is_initialized {
-            self.initialize();
-            return;
+    #[cfg(unix)]
+    #[test]
+    fn test_route_to_server_by_id() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+        for exec_path in ["cat", "tee"] {
+            let spec: LspServerSpec = serde_json::from_value(json!({
+                "exec_path": exec_path,
+                "language_id": "rust",
+                "options": null,
+                "system_lsp": true,
+            }))
+            .unwrap();
+            catalog.start_server(spec.resolve(Path::new("/")).unwrap());
         }
 
-        let text_document_did_open_params = DidOpenTextDocumentParams {
-            text_document: TextDocumentItem {
-                language_id: language_id.to_string(),
-                uri: document_uri,
-                version: 0,
-                text: document_text,
+        let servers = catalog.language_servers("rust");
+        assert_eq!(
+            servers.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(),
+            vec!["rust:cat", "rust:tee"]
+        );
+        assert!(catalog.language_servers("python").is_empty());
+
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {}\n"),
+            path: PathBuf::from("/main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+        let server = |id| {
+            catalog
+                .buffer_server(&buffer, id)
+                .map(|client| client.spec.source_name())
+        };
+        assert_eq!(server(None), Some("cat".to_string()));
+        assert_eq!(server(Some("rust:tee")), Some("tee".to_string()));
+        assert_eq!(server(Some("rust:gopls")), None);
+        assert_eq!(
+            catalog
+                .buffer_servers(&buffer)
+                .into_iter()
+                .map(|server| (server.id, server.state))
+                .collect::<Vec<_>>(),
+            vec![
+                ("rust:cat".to_string(), ServerState::Starting),
+                ("rust:tee".to_string(), ServerState::Starting),
+            ]
+        );
+
+        catalog.stop();
+    }
+
+    #[test]
+    fn test_server_config_merges_settings() {
+        let mut catalog = LspCatalog::new();
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "clangd",
+            "language_ids": ["c", "cpp"],
+            "options": {
+                "fallbackFlags": ["-std=c11"],
+                "clangdFileStatus": true,
+                "index": { "background": "build", "threads": 4 },
             },
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.user_settings.insert(
+            "cpp".to_string(),
+            json!({ "index": { "threads": 8 }, "fallbackFlags": ["-std=c++17"] }),
+        );
+        catalog.workspace_settings.insert(
+            "c".to_string(),
+            json!({ "index": { "background": "skip" } }),
+        );
+
+        let config = catalog.server_config(&spec);
+        assert_eq!(
+            config.initialization_options,
+            Some(json!({
+                "fallbackFlags": ["-std=c++17"],
+                "clangdFileStatus": true,
+                "index": { "background": "skip", "threads": 8 },
+            }))
+        );
+
+        let spec = LspServerSpec {
+            options: None,
+            ..spec
         };
-        let params = Params::from(
-            serde_json::to_value(text_document_did_open_params).unwrap(),
+        assert_eq!(
+            catalog.server_config(&spec).initialization_options,
+            Some(json!({
+                "fallbackFlags": ["-std=c++17"],
+                "index": { "background": "skip", "threads": 8 },
+            }))
+        );
+
+        // the defaults of the plugin's manifest are under everything
+        let spec = LspServerSpec {
+            options: Some(json!({ "clangdFileStatus": false })),
+            default_options: Some(json!({
+                "clangdFileStatus": true,
+                "compilationDatabasePath": "build",
+                "index": { "threads": 2, "standardLibrary": true },
+            })),
+            ..spec
+        };
+        assert_eq!(
+            catalog.server_config(&spec).initialization_options,
+            Some(json!({
+                "fallbackFlags": ["-std=c++17"],
+                "clangdFileStatus": false,
+                "compilationDatabasePath": "build",
+                "index": {
+                    "background": "skip",
+                    "threads": 8,
+                    "standardLibrary": true,
+                },
+            }))
         );
-        self.send_notification("textDocument/didOpen", params);
     }
 
-    pub fn send_did_save(&self, uri: Url, text: Option<String>) {
-        let params = DidSaveTextDocumentParams {
-            text_document: TextDocumentIdentifier { uri },
-            text,
+    #[cfg(unix)]
+    #[test]
+    fn test_crashing_server_disables_plugin() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "false",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        spec.plugin = Some("crashy".to_string());
+        dispatcher
+            .lsp
+            .lock()
+            .start_server(spec.resolve(Path::new("/")).unwrap());
+
+        // `false` exits right away, every time it's started again
+        let disabled = std::iter::from_fn(|| {
+            receiver.recv_timeout(Duration::from_secs(10)).ok()
+        })
+        .find(|msg| msg["method"] == "plugin_auto_disabled")
+        .unwrap();
+        assert_eq!(disabled["params"]["plugin"], "crashy");
+        assert!(dispatcher.lsp.lock().client("rust").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_env() {
+        let mut catalog = LspCatalog::new();
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "sh",
+            "language_id": "shell",
+            "args": ["-c", "printf '%s:%s:%s' \"$LAPCE_A\" \"$LAPCE_B\" \"${HOME-unset}\""],
+            "env": { "LAPCE_A": "plugin", "LAPCE_B": "plugin" },
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.server_env.insert(
+            "sh".to_string(),
+            [("LAPCE_B", "user"), ("HOME", "")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+
+        let config = catalog.server_config(&spec);
+        let mut output = String::new();
+        LspClient::process(None, &spec, &config)
+            .reader
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "plugin:user:unset");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_launch() {
+        let mut catalog = LspCatalog::new();
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "printf",
+            "language_id": "shell",
+            "args": ["%s|%s|%s", "a b", "it's", "$HOME"],
+            "wrapper": ["env", "LAPCE_WRAPPED=1"],
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let output = |catalog: &LspCatalog| {
+            let config = catalog.server_config(&spec);
+            let mut output = String::new();
+            LspClient::process(None, &spec, &config)
+                .reader
+                .read_to_string(&mut output)
+                .unwrap();
+            output
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_notification("textDocument/didSave", params);
+        assert_eq!(output(&catalog), "a b|it's|$HOME");
+
+        // the user's launch wins, and the command survives the shell as is
+        catalog.server_launch.insert(
+            "printf".to_string(),
+            ServerLaunch {
+                wrapper: vec!["sh".to_string(), "-c".to_string()],
+                shell: true,
+            },
+        );
+        assert_eq!(output(&catalog), "a b|it's|$HOME");
+        catalog.server_launch.insert(
+            "printf".to_string(),
+            ServerLaunch {
+                wrapper: Vec::new(),
+                shell: true,
+            },
+        );
+        assert_eq!(output(&catalog), "a b|it's|$HOME");
+        assert_eq!(
+            launch_command("server", &[], &ServerLaunch::default()),
+            ("server".to_string(), Vec::new())
+        );
     }
 
-    pub fn send_initialized(&self) {
-        self.send_notification("initialized", Params::from(json!({})));
+    #[cfg(unix)]
+    #[test]
+    fn test_document_limits() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+        catalog.document_limits.insert(
+            "cat".to_string(),
+            DocumentLimits {
+                max_size: None,
+                max_lines: Some(2),
+            },
+        );
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "document_limits": { "max_size": 1024, "max_lines": 100 },
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+
+        let mut buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {\n}\n"),
+            path: PathBuf::from("/main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+        assert_eq!(catalog.buffer_clients(&buffer).len(), 1);
+
+        buffer.id = BufferId(2);
+        buffer.rope = Rope::from("fn main() {\n    loop {}\n}\n");
+        assert!(catalog.buffer_client(&buffer).is_none());
+        // core is only told as it's opened
+        assert!(receiver
+            .try_iter()
+            .all(|msg| msg["method"] != "document_too_large_for_server"));
+        catalog.open_buffer(&buffer);
+        assert!(catalog.buffer_client(&buffer).is_none());
+        let refusals = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "document_too_large_for_server")
+            .collect::<Vec<_>>();
+        assert_eq!(refusals.len(), 1);
+        assert_eq!(
+            refusals[0]["params"]["reason"],
+            "3 lines, over the limit of 2"
+        );
+
+        catalog.stop();
     }
 
-    pub fn send_initialize<CB>(&self, root_uri: Option<Url>, on_init: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let client_capabilities = ClientCapabilities {
-            text_document: Some(TextDocumentClientCapabilities {
-                synchronization: Some(TextDocumentSyncClientCapabilities {
-                    did_save: Some(true),
-                    dynamic_registration: Some(true),
-                    ..Default::default()
-                }),
-                completion: Some(CompletionClientCapabilities {
-                    completion_item: Some(CompletionItemCapability {
-                        snippet_support: Some(true),
-                        resolve_support: Some(
-                            CompletionItemCapabilityResolveSupport {
-                                properties: vec!["additionalTextEdits".to_string()],
-                            },
-                        ),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }),
-                // signature_help: Some(SignatureHelpCapability {
-                //     signature_information: Some(SignatureInformationSettings {
-                //         parameter_information: Some(ParameterInformationSettings {
-                //             label_offset_support: Some(true),
-                //         }),
-                //         active_parameter_support: Some(true),
-                //         documentation_format: Some(vec![
-                //             MarkupKind::Markdown,
-                //             MarkupKind::PlainText,
-                //         ]),
-                //     }),
-                //     ..Default::default()
-                // }),
-                hover: Some(HoverClientCapabilities {
-                    content_format: Some(vec![
-                        MarkupKind::Markdown,
-                        MarkupKind::PlainText,
-                    ]),
-                    ..Default::default()
-                }),
-                inlay_hint: Some(InlayHintClientCapabilities {
-                    ..Default::default()
-                }),
-                code_action: Some(CodeActionClientCapabilities {
-                    code_action_literal_support: Some(CodeActionLiteralSupport {
-                        code_action_kind: CodeActionKindLiteralSupport {
-                            value_set: vec![
-                                CodeActionKind::EMPTY.as_str().to_string(),
-                                CodeActionKind::QUICKFIX.as_str().to_string(),
-                                CodeActionKind::REFACTOR.as_str().to_string(),
-                                CodeActionKind::REFACTOR_EXTRACT
-                                    .as_str()
-                                    .to_string(),
-                                CodeActionKind::REFACTOR_INLINE.as_str().to_string(),
-                                CodeActionKind::REFACTOR_REWRITE
-                                    .as_str()
-                                    .to_string(),
-                                CodeActionKind::SOURCE.as_str().to_string(),
-                                CodeActionKind::SOURCE_ORGANIZE_IMPORTS
-                                    .as_str()
-                                    .to_string(),
-                            ],
-                        },
-                    }),
-                    ..Default::default()
-                }),
-                semantic_tokens: Some(SemanticTokensClientCapabilities {
-                    ..Default::default()
-                }),
-                type_definition: Some(GotoCapability {
-                    // Note: This is explicitly specified rather than left to the Default because
-                    // of a bug in lsp-types https://github.com/gluon-lang/lsp-types/pull/244
-                    link_support: Some(false),
+    #[cfg(unix)]
+    #[test]
+    fn test_shut_down_idle() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+
+        // never idle without a timeout
+        catalog.shut_down_idle();
+        assert!(catalog.client("rust").is_some());
+
+        catalog.idle_timeout = Some(Duration::from_secs(60));
+        catalog.shut_down_idle();
+        assert!(catalog.client("rust").is_some());
+
+        // nor while it has documents open
+        catalog.idle_timeout = Some(Duration::ZERO);
+        let client = catalog.client("rust").unwrap().clone();
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        client
+            .state
+            .lock()
+            .opened_documents
+            .insert(BufferId(1), uri);
+        catalog.shut_down_idle();
+        assert!(catalog.client("rust").is_some());
+
+        client.state.lock().opened_documents.clear();
+        catalog.shut_down_idle();
+        assert!(catalog.client("rust").is_none());
+
+        catalog.restart_evicted("rust");
+        assert!(catalog.client("rust").is_some());
+
+        catalog.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_servers() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let spec = spec.resolve(Path::new("/")).unwrap();
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        catalog.restore_servers(
+            HashMap::from([(spec.id(), HashMap::from([(uri.clone(), 7)]))]),
+            vec![spec.id()],
+        );
+
+        // a server that was idle waits to be used
+        catalog.add_server(spec.clone(), std::iter::empty());
+        assert!(catalog.client("rust").is_none());
+        assert_eq!(catalog.idle_servers(), vec![spec.id()]);
+
+        // and its documents go on from the versions it was given
+        catalog.restart_evicted("rust");
+        let client = catalog.client("rust").unwrap().clone();
+        client.send_did_open(&BufferId(1), uri.clone(), "rust", "".into(), 0);
+        assert_eq!(
+            catalog.document_versions()[&spec.id()],
+            HashMap::from([(uri, 8)])
+        );
+
+        catalog.stop();
+    }
+
+    #[test]
+    fn test_unregister_formatters() {
+        let formatter = |plugin: &str, language_id: &str| FormatterSpec {
+            command: format!("{plugin}-fmt"),
+            language_ids: vec![language_id.to_string()],
+            args: Vec::new(),
+            system: true,
+            plugin: Some(plugin.to_string()),
+        };
+        let mut catalog = LspCatalog::new();
+        catalog.register_formatter(formatter("black", "python"));
+        catalog.register_formatter(formatter("taplo", "toml"));
+
+        catalog.unregister_formatters("black");
+        assert!(catalog.formatter("python").is_none());
+        assert_eq!(catalog.formatter("toml").unwrap().command, "taplo-fmt");
+    }
+
+    #[test]
+    fn test_trim_memory() {
+        let (client, _receiver, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec!["fake.trim".to_string()],
                     ..Default::default()
                 }),
                 ..Default::default()
-            }),
-            window: Some(WindowClientCapabilities {
-                work_done_progress: Some(true),
-                show_message: Some(ShowMessageRequestClientCapabilities {
-                    message_action_item: Some(MessageActionItemCapabilities {
-                        additional_properties_support: Some(true),
-                    }),
-                }),
-                ..Default::default()
-            }),
-            workspace: Some(WorkspaceClientCapabilities {
-                symbol: Some(WorkspaceSymbolClientCapabilities {
-                    ..Default::default()
+            })
+            .respond("workspace/executeCommand", Value::Null)
+            .start_client();
+        let commands = || {
+            std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_millis(200)).ok()
+            })
+            .filter(|msg| msg["method"] == "workspace/executeCommand")
+            .map(|msg| msg["params"]["command"].clone())
+            .collect::<Vec<_>>()
+        };
+        commands();
+
+        let trim = |command: &str, idle_timeout: u64| MemoryTrim {
+            command: command.to_string(),
+            idle_timeout,
+        };
+        client.trim_memory(&trim("fake.trim", 60));
+        client.trim_memory(&trim("fake.other", 0));
+        assert!(commands().is_empty());
+
+        let last_used = client.state.lock().last_used;
+        client.trim_memory(&trim("fake.trim", 0));
+        assert_eq!(commands(), vec![json!("fake.trim")]);
+        // it stays as idle, and isn't told again until it's used
+        assert_eq!(client.state.lock().last_used, last_used);
+        client.trim_memory(&trim("fake.trim", 0));
+        assert!(commands().is_empty());
+        client.send_notification("$/ping", Params::None(()));
+        client.trim_memory(&trim("fake.trim", 0));
+        assert_eq!(commands(), vec![json!("fake.trim")]);
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_activation() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let workspace = std::env::temp_dir();
+        *dispatcher.workspace.lock() = Some(workspace.clone());
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        let mut spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        spec.activation = vec!["Cargo.toml".to_string(), "**/*.rs".to_string()];
+        let spec = spec.resolve(Path::new("/")).unwrap();
+        let buffer = |path: &str| Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from(""),
+            path: workspace.join(path),
+            rev: 0,
+            mod_time: None,
+        };
+
+        catalog.add_server(spec.clone(), [buffer("README.md")].iter());
+        assert!(catalog.client("rust").is_none());
+        assert_eq!(catalog.dormant_servers().len(), 1);
+        // only the workspace's own manifest counts
+        catalog.open_buffer(&buffer("vendor/Cargo.toml"));
+        assert!(catalog.client("rust").is_none());
+        catalog.open_buffer(&buffer("src/main.rs"));
+        assert!(catalog.client("rust").is_some());
+        assert!(catalog.dormant_servers().is_empty());
+        catalog.stop();
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(workspace.clone());
+        catalog.dispatcher = Some(dispatcher);
+        catalog.add_server(spec, [buffer("Cargo.toml")].iter());
+        assert!(catalog.client("rust").is_some());
+        catalog.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_per_root_servers() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let workspace =
+            std::env::temp_dir().join(format!("lapce-roots-{}", process::id()));
+        let server_root = workspace.join("crates/server");
+        fs::create_dir_all(&server_root).unwrap();
+        *dispatcher.workspace.lock() = Some(workspace.clone());
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+        catalog.set_workspace_roots(&workspace, &[PathBuf::from("crates/server")]);
+        catalog.per_root_languages.insert("rust".to_string());
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let spec = spec.resolve(Path::new("/")).unwrap();
+        // a server shared by the roots is told about all of them
+        assert_eq!(
+            catalog.server_config(&spec).workspace_roots,
+            vec![workspace.clone(), server_root.clone()]
+        );
+        let buffer = |id: u64, path: &str| Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(id),
+            rope: Rope::from(""),
+            path: workspace.join(path),
+            rev: 0,
+            mod_time: None,
+        };
+        let root = |catalog: &LspCatalog, buffer: &Buffer| {
+            let clients = catalog.buffer_clients(buffer);
+            assert_eq!(clients.len(), 1);
+            clients[0].spec.root.clone().unwrap()
+        };
+
+        let main = buffer(1, "src/main.rs");
+        catalog.add_server(spec, [&main].into_iter());
+        assert_eq!(catalog.servers().len(), 1);
+        assert_eq!(root(&catalog, &main), workspace);
+
+        let lib = buffer(2, "crates/server/src/lib.rs");
+        catalog.open_buffer(&lib);
+        assert_eq!(catalog.servers().len(), 2);
+        assert_eq!(root(&catalog, &lib), server_root);
+        assert_eq!(root(&catalog, &main), workspace);
+        let ids = catalog.language_servers("rust");
+        assert_ne!(ids[0].id, ids[1].id);
+        let client = catalog.buffer_client(&lib).unwrap();
+        assert_eq!(client.config.workspace_roots, vec![server_root.clone()]);
+
+        // the other files of a root go to its server
+        catalog.open_buffer(&buffer(3, "crates/server/src/main.rs"));
+        assert_eq!(catalog.servers().len(), 2);
+
+        catalog.stop();
+        let _ = fs::remove_dir_all(workspace);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ignore() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let workspace = std::env::temp_dir();
+        *dispatcher.workspace.lock() = Some(workspace.clone());
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+        let buffer = |id: u64, path: &str| Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(id),
+            rope: Rope::from(""),
+            path: workspace.join(path),
+            rev: 0,
+            mod_time: None,
+        };
+        let generated = buffer(1, "src/schema.generated.rs");
+        let vendored = buffer(2, "vendor/dep/lib.rs");
+        let main = buffer(3, "src/main.rs");
+
+        catalog.set_ignore(
+            &["*.generated.rs".to_string(), "vendor/**".to_string()],
+            [].iter(),
+        );
+        for buffer in [&generated, &vendored, &main] {
+            catalog.open_buffer(buffer);
+        }
+        assert!(catalog.buffer_client(&generated).is_none());
+        assert!(catalog.buffer_client(&vendored).is_none());
+        assert!(catalog.buffer_client(&main).is_some());
+        let ignored = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "document_ignored_for_lsp")
+            .count();
+        assert_eq!(ignored, 2);
+
+        // the open documents follow the new patterns
+        catalog.set_ignore(
+            &["src/main.rs".to_string(), "[".to_string()],
+            [&generated, &vendored, &main].into_iter(),
+        );
+        assert!(catalog.buffer_client(&generated).is_some());
+        assert!(catalog.buffer_client(&vendored).is_some());
+        assert!(catalog.buffer_client(&main).is_none());
+
+        catalog.stop();
+    }
+
+    #[allow(deprecated)]
+    fn symbol(name: &str, path: &str) -> SymbolInformation {
+        SymbolInformation {
+            name: name.to_string(),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: Url::from_file_path(path).unwrap(),
+                range: Range::default(),
+            },
+            container_name: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_workspace_symbol_cache() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+        catalog.cache_workspace_symbols = true;
+
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+        let client = catalog.client("rust").unwrap().clone();
+        client.state.lock().workspace_symbols = WorkspaceSymbolCache::Ready(vec![
+            symbol("parse_config", "/src/config.rs"),
+            symbol("ParseError", "/src/error.rs"),
+            symbol("render", "/src/ui.rs"),
+        ]);
+
+        let names = |symbols: Option<Vec<SymbolInformation>>| {
+            symbols.map(|symbols| {
+                symbols.into_iter().map(|s| s.name).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            names(client.cached_workspace_symbols("parse")),
+            Some(vec!["parse_config".to_string(), "ParseError".to_string()])
+        );
+        assert_eq!(
+            names(client.cached_workspace_symbols("pcfg")),
+            Some(vec!["parse_config".to_string()])
+        );
+
+        // the symbols of the changed file are dropped, and the server is
+        // asked until they're loaded again
+        catalog.invalidate_workspace_symbols(&[PathBuf::from("/src/config.rs")]);
+        assert_eq!(client.cached_workspace_symbols("parse"), None);
+        match &client.state.lock().workspace_symbols {
+            WorkspaceSymbolCache::Stale(symbols) => assert_eq!(symbols.len(), 2),
+            _ => panic!("the cache should be stale"),
+        }
+
+        catalog.stop();
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        use Lifecycle::*;
+        let send = |lifecycle: Lifecycle, method| lifecycle.send(method).ok();
+
+        assert_eq!(send(NotStarted, Some("initialize")), Some(Initializing));
+        assert_eq!(send(NotStarted, Some("textDocument/didOpen")), None);
+        assert_eq!(send(Initializing, Some("initialize")), None);
+        assert_eq!(send(Initializing, Some("textDocument/hover")), None);
+        // the server can be answered while it's initializing
+        assert_eq!(send(Initializing, None), Some(Initializing));
+        assert_eq!(send(Initializing, Some("initialized")), Some(Initialized));
+
+        assert_eq!(send(Initialized, Some("initialized")), None);
+        assert_eq!(
+            send(Initialized, Some("textDocument/hover")),
+            Some(Initialized)
+        );
+        assert_eq!(send(Initialized, Some("shutdown")), Some(ShuttingDown));
+
+        assert_eq!(send(ShuttingDown, Some("textDocument/hover")), None);
+        assert_eq!(send(ShuttingDown, Some("shutdown")), None);
+        assert_eq!(send(ShuttingDown, None), None);
+        assert_eq!(send(ShuttingDown, Some("exit")), Some(Exited));
+        assert_eq!(send(Initializing, Some("exit")), Some(Exited));
+        assert_eq!(send(Exited, Some("exit")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_requests_outside_lifecycle_fail() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
+        // `cat` never answers `initialize`
+        client.send_initialize(None, |_, _| {});
+        assert_eq!(client.state.lock().lifecycle, Lifecycle::Initializing);
+
+        let request_fails = |client: &LspClient| {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.send_request(
+                "textDocument/hover",
+                Params::None(()),
+                Box::new(move |_: &LspClient, result: Result<Value>| {
+                    let _ = tx.send(result.is_err());
                 }),
-                configuration: Some(true),
+            );
+            rx.try_recv() == Ok(true)
+        };
+        assert!(request_fails(&client));
+
+        client.send_initialized();
+        client.send_initialized();
+        assert_eq!(client.state.lock().lifecycle, Lifecycle::Initialized);
+        assert!(!request_fails(&client));
+
+        client.shutdown();
+        assert_eq!(client.state.lock().lifecycle, Lifecycle::ShuttingDown);
+        assert!(request_fails(&client));
+        client.stop();
+    }
+
+    #[test]
+    fn test_fake_server() {
+        let (client, _receiver, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
                 ..Default::default()
-            }),
+            })
+            .respond("textDocument/hover", json!({ "contents": "fn main()" }))
+            .start_client();
 
-            // TODO: We could support the extension for utf8 and the upcoming stable support for requesting utf8
-            // so then we can just directly deal with (without translation) utf8 positions from the lsp.
-            // However implementing that has complexities in terms of knowing what lsp is getting our data
-            // before we send it, since we typically let the proxy decide.
-            // So, currently, we only send/receive UTF16 positions/offsets from the LSP clients
-            experimental: Some(json!({
-                "serverStatusNotification": true,
-            })),
-            ..Default::default()
-        };
+        // `start` waits for the server to be initialized
+        {
+            let state = client.state.lock();
+            assert!(state.is_initialized());
+            assert!(state
+                .server_capabilities
+                .as_ref()
+                .and_then(|cap| cap.hover_provider.as_ref())
+                .is_some());
+        }
 
-        #[allow(deprecated)]
-        let init_params = InitializeParams {
-            process_id: Some(process::id()),
-            root_uri: root_uri.clone(),
-            initialization_options: self.options.clone(),
-            capabilities: client_capabilities,
-            trace: Some(TraceValue::Verbose),
-            workspace_folders: root_uri.map(|uri| {
-                vec![WorkspaceFolder {
-                    name: uri.as_str().to_string(),
-                    uri,
-                }]
-            }),
-            client_info: None,
-            root_path: None,
-            locale: None,
+        let request = |method: &str| {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.send_request(
+                method,
+                Params::None(()),
+                Box::new(move |_: &LspClient, result: Result<Value>| {
+                    let _ = tx.send(result);
+                }),
+            );
+            rx.recv_timeout(Duration::from_secs(1)).unwrap()
         };
+        assert_eq!(
+            request("textDocument/hover").unwrap(),
+            json!({ "contents": "fn main()" })
+        );
+        assert!(request("textDocument/definition").is_err());
+
+        client.shutdown();
+        let methods = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .map(|msg| msg["method"].as_str().unwrap_or_default().to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            vec![
+                "initialize",
+                "initialized",
+                "textDocument/hover",
+                "textDocument/definition",
+                "shutdown",
+                "exit",
+            ]
+        );
+    }
 
-        let params = Params::from(serde_json::to_value(init_params).unwrap());
-        self.send_request("initialize", params, Box::new(on_init));
+    #[test]
+    fn test_close_document_cancels_requests() {
+        let (client, core, received) = FakeLspServer::new()
+            .ignore("textDocument/completion")
+            .start_client();
+
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        client.send_did_open(&BufferId(1), uri.clone(), "rust", String::new(), 0);
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        client.request_completion(
+            uri,
+            Position::new(0, 0),
+            None,
+            None,
+            move |_, result| {
+                let _ = tx.send(result);
+            },
+        );
+        let completion = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/completion")
+        .unwrap();
+        core.try_iter().for_each(drop);
+
+        assert!(client.close_document(BufferId(1)));
+        assert!(!client.close_document(BufferId(1)));
+        assert!(rx.recv_timeout(Duration::from_secs(1)).unwrap().is_err());
+        // core drops the trigger characters of a closed buffer itself, they
+        // aren't sent again for it
+        assert_eq!(core.try_iter().count(), 0);
+        assert!(client.state.lock().pending.is_empty());
+        assert!(client.state.lock().document_requests.is_empty());
+        let cancel = received.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(cancel["method"], "$/cancelRequest");
+        assert_eq!(cancel["params"]["id"], completion["id"]);
+        let close = received.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(close["method"], "textDocument/didClose");
+
+        // the server answering anyway is ignored
+        client.handle_message(
+            &json!({ "jsonrpc": "2.0", "id": completion["id"], "result": null })
+                .to_string(),
+        );
+        client.shutdown();
     }
 
-    pub fn request_document_symbols<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = DocumentSymbolParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
+    #[test]
+    fn test_completion_documentation() {
+        let (client, _receiver, received) = FakeLspServer::new()
+            .respond(
+                "completionItem/resolve",
+                json!({ "label": "item", "documentation": "resolved" }),
+            )
+            .start_client();
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+
+        let mut response = json!({
+            "isIncomplete": false,
+            "items": [
+                { "label": "a" },
+                { "label": "b", "documentation": "own" },
+                { "label": "c" },
+            ],
+        });
+        resolve_completion_documentation(&client, &mut response);
+        let documentation = |response: &Value| {
+            response["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["documentation"].clone())
+                .collect::<Vec<_>>()
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/documentSymbol", params, Box::new(cb));
+        assert_eq!(
+            documentation(&response),
+            vec![json!("resolved"), json!("own"), json!("resolved")]
+        );
+        client.shutdown();
+        let resolves = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_millis(200)).ok()
+        })
+        .filter(|msg| msg["method"] == "completionItem/resolve")
+        .map(|msg| msg["params"]["label"].clone())
+        .collect::<Vec<_>>();
+        assert_eq!(resolves, vec![json!("a"), json!("c")]);
+
+        finish_completion(&mut response, CompletionDocumentation::Off);
+        assert_eq!(documentation(&response), vec![Value::Null; 3]);
     }
 
-    pub fn request_workspace_symbols<CB>(&self, query: String, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = WorkspaceSymbolParams {
-            query,
-            ..Default::default()
+    #[test]
+    fn test_refresh_diagnostics() {
+        let (sender, core) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let path = std::env::temp_dir().join("refresh.rs");
+        let uri = Url::from_file_path(&path).unwrap();
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {}"),
+            path: path.clone(),
+            rev: 2,
+            mod_time: None,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("workspace/symbol", params, Box::new(cb));
+        let start = |server: FakeLspServer| {
+            let (connection, received) = server.start();
+            let client = LspClient::start(
+                fake_spec(),
+                ServerConfig::default(),
+                dispatcher.clone(),
+                connection,
+            );
+            std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_secs(1)).ok()
+            })
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+            (client, received)
+        };
+        let published = || {
+            std::iter::from_fn(|| core.recv_timeout(Duration::from_millis(200)).ok())
+                .filter(|msg| msg["method"] == "publish_diagnostics")
+                .map(|msg| msg["params"]["diagnostics"]["diagnostics"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // a server publishing diagnostics is sent the document again
+        let (client, received) = start(FakeLspServer::new());
+        client.send_did_open(
+            &buffer.id,
+            uri.clone(),
+            "rust",
+            "fn main() {}".into(),
+            2,
+        );
+        assert!(client.refresh_diagnostics(&buffer));
+        let change = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/didChange")
+        .unwrap();
+        assert_eq!(change["params"]["textDocument"]["version"], 3);
+        assert_eq!(
+            change["params"]["contentChanges"][0]["text"],
+            "fn main() {}"
+        );
+        assert_eq!(published(), vec![json!([])]);
+        client.shutdown();
+
+        // a server giving them when asked is asked again
+        let diagnostic = json!({
+            "range": Range::default(),
+            "message": "unused",
+        });
+        let (client, received) = start(
+            FakeLspServer::new()
+                .capabilities(ServerCapabilities {
+                    diagnostic_provider: Some(
+                        DiagnosticServerCapabilities::Options(
+                            DiagnosticOptions::default(),
+                        ),
+                    ),
+                    ..Default::default()
+                })
+                .respond(
+                    "textDocument/diagnostic",
+                    json!({
+                        "kind": "full",
+                        "resultId": "2",
+                        "items": [diagnostic],
+                    }),
+                ),
+        );
+        client.send_did_open(
+            &buffer.id,
+            uri.clone(),
+            "rust",
+            "fn main() {}".into(),
+            2,
+        );
+        client
+            .state
+            .lock()
+            .diagnostic_result_ids
+            .insert(uri.clone(), "1".to_string());
+        assert!(client.refresh_diagnostics(&buffer));
+        let pull = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/diagnostic")
+        .unwrap();
+        assert_eq!(pull["params"]["previousResultId"], Value::Null);
+        let published = published();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0], json!([]));
+        assert_eq!(published[1][0]["message"], "unused");
+        assert_eq!(client.state.lock().diagnostic_result_ids[&uri], "2");
+        client.shutdown();
     }
 
-    pub fn request_document_formatting<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = DocumentFormattingParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            options: FormattingOptions {
-                tab_size: 4,
-                insert_spaces: true,
+    #[test]
+    fn test_unchanged_diagnostic_report() {
+        let uri = Url::from_file_path(std::env::temp_dir().join("main.rs")).unwrap();
+        let header =
+            Url::from_file_path(std::env::temp_dir().join("header.rs")).unwrap();
+        let (client, core, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                    DiagnosticOptions::default(),
+                )),
                 ..Default::default()
+            })
+            .respond(
+                "textDocument/diagnostic",
+                json!({
+                    "kind": "unchanged",
+                    "resultId": "2",
+                    "relatedDocuments": {
+                        header.as_str(): {
+                            "kind": "full",
+                            "resultId": "7",
+                            "items": [{
+                                "range": Range::default(),
+                                "message": "unused",
+                            }],
+                        },
+                    },
+                }),
+            )
+            .start_client();
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        client.pull_document_diagnostics(
+            uri.clone(),
+            Some("1".to_string()),
+            move |_, result| {
+                let _ = tx.send(result);
             },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/formatting", params, Box::new(cb));
+        );
+        let report = rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(report["kind"], "unchanged");
+
+        // only the related document given in full is published, the
+        // diagnostics core has of the other are kept
+        let published = std::iter::from_fn(|| {
+            core.recv_timeout(Duration::from_millis(200)).ok()
+        })
+        .filter(|msg| msg["method"] == "publish_diagnostics")
+        .map(|msg| msg["params"]["diagnostics"].clone())
+        .collect::<Vec<_>>();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0]["uri"], header.as_str());
+        assert_eq!(published[0]["diagnostics"][0]["message"], "unused");
+        let state = client.state.lock();
+        assert_eq!(state.diagnostic_result_ids[&uri], "2");
+        assert_eq!(state.diagnostic_result_ids[&header], "7");
+        drop(state);
+        client.shutdown();
     }
 
-    pub fn request_semantic_tokens<CB>(&self, document_uri: Url, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = SemanticTokensParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
+    #[test]
+    fn test_duplicate_did_open() {
+        let (client, _receiver, received) = FakeLspServer::new().start_client();
+        let documents = || {
+            std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_millis(200)).ok()
+            })
+            .filter(|msg| {
+                msg["method"]
+                    .as_str()
+                    .map(|method| method.starts_with("textDocument/did"))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>()
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/semanticTokens/full", params, Box::new(cb));
-    }
-
-    pub fn request_inlay_hints<CB>(&self, document_uri: Url, range: Range, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = InlayHintParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            range,
-            work_done_progress_params: WorkDoneProgressParams::default(),
+        documents();
+
+        let path = std::env::temp_dir().join("duplicate.rs");
+        let uri = Url::from_file_path(&path).unwrap();
+        let buffer = |id: u64, text: &str, rev: u64| Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(id),
+            rope: Rope::from(text),
+            path: path.clone(),
+            rev,
+            mod_time: None,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/inlayHint", params, Box::new(cb));
+        client.send_did_open(&BufferId(1), uri.clone(), "rust", "a".into(), 3);
+        client.send_did_open(&BufferId(2), uri.clone(), "rust", "a".into(), 0);
+        let opened = documents();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0]["method"], "textDocument/didOpen");
+        assert_eq!(opened[0]["params"]["textDocument"]["version"], 3);
+        assert_eq!(client.state.lock().document_versions[&BufferId(2)], 3);
+
+        // only the changes of the buffer the server has the text of are sent
+        client.send_did_change(&buffer(2, "b", 4), Vec::new(), 4);
+        assert!(documents().is_empty());
+        client.send_did_change(&buffer(1, "a", 8), Vec::new(), 8);
+        let changed = documents();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["params"]["textDocument"]["version"], 8);
+
+        client.close_document(BufferId(1));
+        assert!(documents().is_empty());
+        // the other buffer takes over with its whole text, at a version
+        // past the ones the server was given
+        client.send_did_change(&buffer(2, "ab", 5), Vec::new(), 5);
+        let changed = documents();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["method"], "textDocument/didChange");
+        assert_eq!(changed[0]["params"]["textDocument"]["version"], 9);
+        assert_eq!(changed[0]["params"]["contentChanges"][0]["text"], "ab");
+
+        client.close_document(BufferId(2));
+        let closed = documents();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0]["method"], "textDocument/didClose");
+        assert!(client.state.lock().document_buffers.is_empty());
+        client.shutdown();
     }
 
-    pub fn request_code_actions<CB>(&self, document_uri: Url, range: Range, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = CodeActionParams {
-            text_document: TextDocumentIdentifier { uri: document_uri },
-            range,
-            context: CodeActionContext::default(),
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
+    #[test]
+    #[cfg(unix)]
+    fn test_same_file_by_another_path() {
+        let (client, _receiver, received) = FakeLspServer::new().start_client();
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-same-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("real.rs");
+        let link = dir.join("link.rs");
+        fs::write(&real, "fn main() {}").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let buffer = |id: u64, path: &Path| Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(id),
+            rope: Rope::from("fn main() {}"),
+            path: path.to_path_buf(),
+            rev: 1,
+            mod_time: None,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/codeAction", params, Box::new(cb));
+
+        let uri = client.get_uri(&buffer(1, &real));
+        assert_eq!(client.get_uri(&buffer(2, &link)), uri);
+        let opened = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_millis(200)).ok()
+        })
+        .filter(|msg| msg["method"] == "textDocument/didOpen")
+        .collect::<Vec<_>>();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0]["params"]["textDocument"]["uri"], uri.as_str());
+
+        // once both are closed, it's opened by the path it's reached by
+        client.close_document(BufferId(1));
+        client.close_document(BufferId(2));
+        let uri = client.get_uri(&buffer(3, &link));
+        assert_eq!(uri, Url::from_file_path(&link).unwrap());
+        client.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub fn request_references<CB>(
-        &self,
-        document_uri: Url,
-        position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = ReferenceParams {
-            text_document_position: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-            context: ReferenceContext {
-                include_declaration: false,
-            },
+    #[test]
+    fn test_opens_before_initialized() {
+        // the server is still initializing as long as it isn't answered
+        let (client, core, received) =
+            FakeLspServer::new().ignore("initialize").start_client();
+        let path = std::env::temp_dir().join("early.rs");
+        let uri = Url::from_file_path(&path).unwrap();
+        client.send_did_open(&BufferId(1), uri.clone(), "rust", "a".into(), 1);
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("ab"),
+            path: path.clone(),
+            rev: 2,
+            mod_time: None,
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/references", params, Box::new(cb));
+        client.send_did_change(&buffer, Vec::new(), 2);
+        assert!(!client.state.lock().is_initialized());
+
+        client.handle_message(
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "result": { "capabilities": {} },
+            })
+            .to_string(),
+        );
+        let opened = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/didOpen")
+        .unwrap();
+        assert_eq!(opened["params"]["textDocument"]["text"], "ab");
+        assert_eq!(opened["params"]["textDocument"]["version"], 2);
+        assert!(client.state.lock().unsent_opens.is_empty());
+        // core asks again for what went unanswered
+        let refresh = std::iter::from_fn(|| {
+            core.recv_timeout(Duration::from_millis(200)).ok()
+        })
+        .find(|msg| msg["method"] == "semantic_tokens_refresh")
+        .unwrap();
+        assert_eq!(refresh["params"]["paths"], json!([path]));
+        client.shutdown();
     }
 
-    pub fn request_definition<CB>(
-        &self,
-        document_uri: Url,
-        position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = GotoDefinitionParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-        };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/definition", params, Box::new(cb));
+    #[test]
+    fn test_restart_fails_requests() {
+        let (client, _receiver, received) = FakeLspServer::new()
+            .ignore("workspace/symbol")
+            .start_client();
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for _ in 0..2 {
+            let tx = tx.clone();
+            client.send_request(
+                "workspace/symbol",
+                Params::from(json!({ "query": "" })),
+                Box::new(move |_: &LspClient, result: Result<Value>| {
+                    let _ = tx.send(result.map_err(|e| e.to_string()));
+                }),
+            );
+        }
+        assert_eq!(client.state.lock().pending.len(), 2);
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        client.fail_requests("the server restarted");
+        for _ in 0..2 {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                Err("the server restarted".to_string())
+            );
+        }
+        assert!(client.state.lock().pending.is_empty());
+        client.shutdown();
     }
 
-    pub fn request_type_definition<CB>(
-        &self,
-        document_uri: Url,
-        position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = GotoTypeDefinitionParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
+    #[test]
+    fn test_server_errors() {
+        let (client, core, received) = FakeLspServer::new().start_client();
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+
+        let request = |method: &str, priority: RequestPriority| {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.send_request_with_priority(
+                method,
+                Params::from(json!({})),
+                priority,
+                Box::new(move |_: &LspClient, result: Result<Value>| {
+                    let _ = tx.send(result.map_err(|e| e.to_string()));
+                }),
+            );
+            let result = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            let failed = std::iter::from_fn(|| {
+                core.recv_timeout(Duration::from_millis(200)).ok()
+            })
+            .filter(|msg| msg["method"] == "language_server_request_failed")
+            .map(|msg| msg["params"].clone())
+            .collect::<Vec<_>>();
+            (result, failed)
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/typeDefinition", params, Box::new(cb));
+
+        // the user is told of what they asked for failing
+        let (result, failed) = request(
+            "textDocument/definition",
+            RequestPriority::from_method("textDocument/definition"),
+        );
+        assert_eq!(
+            result,
+            Err("textDocument/definition failed: no data for \
+                 textDocument/definition (error -32601)"
+                .to_string())
+        );
+        assert_eq!(
+            failed,
+            vec![json!({
+                "server": "fake",
+                "method": "textDocument/definition",
+                "code": -32601,
+                "message": "no data for textDocument/definition",
+            })]
+        );
+
+        // but not of what the editor asked for on its own
+        let (result, failed) = request(
+            "textDocument/inlayHint",
+            RequestPriority::from_method("textDocument/inlayHint"),
+        );
+        assert!(result.is_err());
+        assert!(failed.is_empty());
+        let (result, failed) =
+            request("workspace/executeCommand", RequestPriority::Background);
+        assert!(result.is_err());
+        assert!(failed.is_empty());
+        client.shutdown();
     }
 
-    pub fn request_completion<CB>(
-        &self,
-        document_uri: Url,
-        position: Position,
-        on_completion: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let completion_params = CompletionParams {
-            text_document_position: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            partial_result_params: PartialResultParams::default(),
-            context: None,
+    #[test]
+    fn test_shutdown_clears_diagnostics() {
+        let (client, core, _received) = FakeLspServer::new().start_client();
+        let published = || {
+            std::iter::from_fn(|| core.recv_timeout(Duration::from_millis(200)).ok())
+                .filter(|msg| msg["method"] == "publish_diagnostics")
+                .map(|msg| msg["params"]["diagnostics"].clone())
+                .collect::<Vec<_>>()
         };
-        let params = Params::from(serde_json::to_value(completion_params).unwrap());
-        self.send_request(
-            "textDocument/completion",
-            params,
-            Box::new(on_completion),
+        let uri = Url::parse("file:///src/main.rs").unwrap();
+        let diagnostic = Diagnostic {
+            message: "unused".to_string(),
+            ..Default::default()
+        };
+        client.handle_notification(
+            "textDocument/publishDiagnostics",
+            Params::from(json!(PublishDiagnosticsParams::new(
+                uri.clone(),
+                vec![diagnostic],
+                None,
+            ))),
+        );
+        assert_eq!(published().len(), 1);
+
+        client.shutdown();
+        let cleared = published();
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0]["uri"], json!(uri));
+        assert_eq!(cleared[0]["diagnostics"], json!([]));
+
+        // what it sends while it exits is dropped
+        client.handle_notification(
+            "textDocument/publishDiagnostics",
+            Params::from(json!(PublishDiagnosticsParams::new(
+                uri,
+                Vec::new(),
+                None
+            ))),
         );
+        assert!(published().is_empty());
     }
 
-    pub fn completion_resolve<CB>(
-        &self,
-        completion_item: &CompletionItem,
-        on_result: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = Params::from(serde_json::to_value(completion_item).unwrap());
-        self.send_request("completionItem/resolve", params, Box::new(on_result));
+    #[test]
+    fn test_request_progress() {
+        let (client, core, received) = FakeLspServer::new()
+            .ignore("textDocument/references")
+            .start_client();
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+
+        let uri = Url::from_file_path(std::env::temp_dir().join("main.rs")).unwrap();
+        client.request_references(uri, Position::new(0, 0), Some(7), |_, _| {});
+        let request = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/references")
+        .unwrap();
+        assert_eq!(request["params"]["workDoneToken"], "lapce/request/7");
+
+        let progress = |token: &str, value: Value| {
+            client.handle_message(
+                &json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/progress",
+                    "params": { "token": token, "value": value },
+                })
+                .to_string(),
+            );
+            std::iter::from_fn(|| core.recv_timeout(Duration::from_millis(200)).ok())
+                .filter(|msg| {
+                    msg["method"] == "request_progress"
+                        || msg["method"] == "work_done_progress"
+                })
+                .collect::<Vec<_>>()
+        };
+        let report = json!({ "kind": "report", "percentage": 60 });
+        let sent = progress("lapce/request/7", report.clone());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["method"], "request_progress");
+        assert_eq!(sent[0]["params"]["request_id"], 7);
+        assert_eq!(sent[0]["params"]["progress"]["percentage"], 60);
+        // the server's own work is reported as it was
+        let sent = progress("indexing", report.clone());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["method"], "work_done_progress");
+
+        // the token is forgotten once the request is answered
+        client.handle_response(request["id"].as_u64().unwrap(), Ok(Value::Null));
+        assert!(client.state.lock().work_done.is_empty());
+        let sent = progress("lapce/request/7", report);
+        assert_eq!(sent[0]["method"], "work_done_progress");
+        client.shutdown();
     }
 
-    pub fn request_hover<CB>(&self, document_uri: Url, position: Position, cb: CB)
-    where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let hover_params = HoverParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
+    #[test]
+    fn test_server_report() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let (connection, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                references_provider: Some(OneOf::Left(false)),
+                ..Default::default()
+            })
+            .start();
+        let config = ServerConfig {
+            env: HashMap::from([
+                ("RUST_LOG".to_string(), "info".to_string()),
+                ("GITHUB_TOKEN".to_string(), "ghp_1234".to_string()),
+            ]),
+            ..Default::default()
         };
-        let params = Params::from(serde_json::to_value(hover_params).unwrap());
-        self.send_request("textDocument/hover", params, Box::new(cb));
+        let client = LspClient::start(fake_spec(), config, dispatcher, connection);
+        std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+            .find(|msg| msg["method"] == "initialized")
+            .unwrap();
+        client
+            .stderr_tail
+            .lock()
+            .push_back("authenticating with ghp_1234".to_string());
+
+        let report = client.report();
+        assert_eq!(report.server.state, ServerState::Ready);
+        assert_eq!(report.capabilities, vec!["hoverProvider".to_string()]);
+        assert_eq!(report.env["RUST_LOG"], "info");
+        assert_eq!(report.env["GITHUB_TOKEN"], REDACTED);
+        assert_eq!(report.stderr, vec!["authenticating with <redacted>"]);
+        client.shutdown();
     }
 
-    pub fn request_signature<CB>(
-        &self,
-        document_uri: Url,
-        position: Position,
-        cb: CB,
-    ) where
-        CB: 'static + Send + FnOnce(&LspClient, Result<Value>),
-    {
-        let params = SignatureHelpParams {
-            text_document_position_params: TextDocumentPositionParams {
-                text_document: TextDocumentIdentifier { uri: document_uri },
-                position,
-            },
-            work_done_progress_params: WorkDoneProgressParams::default(),
-            context: None,
+    #[test]
+    fn test_code_action_kinds() {
+        let actions = json!([
+            { "title": "Organize imports", "kind": "source.organizeImports" },
+            { "title": "Extract function", "kind": "refactor.extract" },
+            { "title": "Sources", "kind": "sourcemap" },
+            { "title": "Run", "command": "run" },
+        ]);
+        let (client, _receiver, received) = FakeLspServer::new()
+            .respond("textDocument/codeAction", actions.clone())
+            .start_client();
+        let uri = Url::from_file_path(std::env::temp_dir().join("main.rs")).unwrap();
+        let code_actions = |only: Vec<CodeActionKind>| {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.request_code_actions(
+                uri.clone(),
+                Range::default(),
+                only,
+                move |_, result| {
+                    tx.send(result.unwrap()).unwrap();
+                },
+            );
+            let request = std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_secs(1)).ok()
+            })
+            .find(|msg| msg["method"] == "textDocument/codeAction")
+            .unwrap();
+            let actions = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            (request["params"]["context"]["only"].clone(), actions)
         };
-        let params = Params::from(serde_json::to_value(params).unwrap());
-        self.send_request("textDocument/signatureHelp", params, Box::new(cb));
-    }
 
-    pub fn send_did_change(
-        &self,
-        buffer: &Buffer,
-        changes: Vec<TextDocumentContentChangeEvent>,
-        version: u64,
-    ) {
-        let uri = self.get_uri(buffer);
-        let text_document_did_change_params = DidChangeTextDocumentParams {
-            text_document: VersionedTextDocumentIdentifier {
-                uri,
-                version: version as i32,
-            },
-            content_changes: changes,
+        let (only, all) = code_actions(Vec::new());
+        assert_eq!(only, Value::Null);
+        assert_eq!(all, actions);
+
+        // a server giving more than it's asked for is filtered
+        let (only, filtered) = code_actions(vec![CodeActionKind::SOURCE]);
+        assert_eq!(only, json!(["source"]));
+        assert_eq!(filtered, json!([actions[0]]));
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_call_hierarchy_cache() {
+        let item = json!([{
+            "name": "main",
+            "kind": 12,
+            "uri": "file:///src/main.rs",
+            "range": { "start": { "line": 0, "character": 0 },
+                       "end": { "line": 0, "character": 12 } },
+            "selectionRange": { "start": { "line": 0, "character": 3 },
+                                "end": { "line": 0, "character": 7 } },
+        }]);
+        let (client, _receiver, received) = FakeLspServer::new()
+            .respond("textDocument/prepareCallHierarchy", item.clone())
+            .start_client();
+
+        let mut buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {}"),
+            path: std::env::temp_dir().join("main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+        let prepare = |buffer: &Buffer, position: Position| {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.prepare_call_hierarchy(buffer, position, move |_, result| {
+                let _ = tx.send(result);
+            });
+            rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap()
         };
+        assert_eq!(prepare(&buffer, Position::new(0, 4)), item);
+        // prepared once for a position until the document changes
+        assert_eq!(prepare(&buffer, Position::new(0, 4)), item);
+        prepare(&buffer, Position::new(0, 5));
+        buffer.rev = 1;
+        client.send_did_change(&buffer, Vec::new(), buffer.rev);
+        prepare(&buffer, Position::new(0, 4));
+
+        client.shutdown();
+        let prepared = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .filter(|msg| msg["method"] == "textDocument/prepareCallHierarchy")
+        .map(|msg| msg["params"]["position"]["character"].clone())
+        .collect::<Vec<_>>();
+        assert_eq!(prepared, vec![json!(4), json!(5), json!(4)]);
+    }
 
-        let params = Params::from(
-            serde_json::to_value(text_document_did_change_params).unwrap(),
+    #[cfg(unix)]
+    #[test]
+    fn test_max_in_flight_requests() {
+        let mut catalog = LspCatalog::new();
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "max_in_flight_requests": 8,
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let spec = spec.resolve(Path::new("/")).unwrap();
+        assert_eq!(catalog.server_config(&spec).max_in_flight_requests, Some(8));
+        // the user's limit wins over the plugin's
+        catalog.max_in_flight_requests.insert("cat".to_string(), 2);
+        let config = catalog.server_config(&spec);
+        assert_eq!(config.max_in_flight_requests, Some(2));
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let client =
+            LspClient::new(spec, config, Dispatcher::without_plugins(sender));
+        // `cat` never answers `initialize`, which takes one of the two slots
+        client.send_initialize(None, |_, _| {});
+        client.send_initialized();
+        for _ in 0..3 {
+            client.send_request(
+                "textDocument/hover",
+                Params::None(()),
+                Box::new(|_: &LspClient, _: Result<Value>| {}),
+            );
+        }
+        let (in_flight, queued) = {
+            let state = client.state.lock();
+            (state.pending.keys().max().copied(), state.queued.len())
+        };
+        assert_eq!(queued, 2);
+
+        // a response frees a slot for the next request
+        client.handle_message(
+            &json!({ "jsonrpc": "2.0", "id": in_flight, "result": null })
+                .to_string(),
         );
-        self.send_notification("textDocument/didChange", params);
+        let state = client.state.lock();
+        assert_eq!(state.pending.len(), 2);
+        assert_eq!(state.queued.len(), 1);
+        drop(state);
+        client.stop();
     }
 
-    pub fn get_sync_kind(&self) -> Option<TextDocumentSyncKind> {
-        let state = self.state.lock();
-        let text_document_sync = state
-            .server_capabilities
-            .as_ref()
-            .and_then(|c| c.text_document_sync.as_ref())?;
-        match text_document_sync {
-            TextDocumentSyncCapability::Kind(kind) => Some(*kind),
-            TextDocumentSyncCapability::Options(options) => options.clone().change,
-        }
+    #[test]
+    fn test_retry_request() {
+        let (connection, received) = FakeLspServer::new()
+            .ignore("textDocument/hover")
+            .ignore("textDocument/rename")
+            .start();
+        let spec = fake_spec();
+        let config = LspCatalog::new().server_config(&spec);
+        let (sender, _core) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let client = LspClient::start(spec, config, dispatcher, connection);
+        let path = std::env::temp_dir().join("retry.rs");
+        let uri = Url::from_file_path(&path).unwrap();
+        client.send_did_open(
+            &BufferId(1),
+            uri.clone(),
+            "rust",
+            "fn main() {}".into(),
+            0,
+        );
+        let sent = |method: &str| {
+            std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(1)).ok())
+                .find(|msg| msg["method"] == method)
+                .unwrap()
+        };
+        let content_modified = |request: &Value| {
+            client.handle_message(
+                &json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": {
+                        "code": error_codes::CONTENT_MODIFIED,
+                        "message": "content modified",
+                    },
+                })
+                .to_string(),
+            );
+        };
+
+        let (results, result) = crossbeam_channel::unbounded();
+        let hover_results = results.clone();
+        client.request_hover(uri.clone(), Position::new(0, 3), move |_, r| {
+            hover_results.send(r.is_ok()).unwrap();
+        });
+        let hover = sent("textDocument/hover");
+        content_modified(&hover);
+        // a line added above before it's sent again moves it down
+        client.send_did_change(
+            &Buffer {
+                language_id: "rust".to_string(),
+                id: BufferId(1),
+                rope: Rope::from("// main\nfn main() {}"),
+                path,
+                rev: 1,
+                mod_time: None,
+            },
+            vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                range_length: None,
+                text: "// main\n".to_string(),
+            }],
+            1,
+        );
+        assert!(result.try_recv().is_err());
+        let retry = sent("textDocument/hover");
+        assert_ne!(retry["id"], hover["id"]);
+        assert_eq!(
+            retry["params"]["position"],
+            json!({ "line": 1, "character": 3 })
+        );
+        // it's only sent again once
+        content_modified(&retry);
+        assert_eq!(result.recv_timeout(Duration::from_secs(1)), Ok(false));
+
+        client.send_request(
+            "textDocument/rename",
+            Params::from(json!({})),
+            Box::new(move |_: &LspClient, r: Result<Value>| {
+                results.send(r.is_ok()).unwrap();
+            }),
+        );
+        content_modified(&sent("textDocument/rename"));
+        assert_eq!(result.try_recv(), Ok(false));
+        assert!(client.state.lock().retryable.is_empty());
+        client.shutdown();
     }
 
-    pub fn update(
-        &self,
-        buffer: &Buffer,
-        content_change: &TextDocumentContentChangeEvent,
-        rev: u64,
-    ) {
-        let sync_kind = self.get_sync_kind().unwrap_or(TextDocumentSyncKind::FULL);
-        let changes = get_change_for_sync_kind(sync_kind, buffer, content_change);
-        if let Some(changes) = changes {
-            self.send_did_change(buffer, changes, rev);
-        }
+    #[test]
+    fn test_transform_position() {
+        let change = |start: (u32, u32), end: (u32, u32), text: &str| {
+            TextDocumentContentChangeEvent {
+                range: Some(Range::new(
+                    Position::new(start.0, start.1),
+                    Position::new(end.0, end.1),
+                )),
+                range_length: None,
+                text: text.to_string(),
+            }
+        };
+        let position = Position::new(2, 4);
+        // before the change
+        assert_eq!(
+            transform_position(position, &change((2, 6), (3, 0), "x")),
+            Some(position)
+        );
+        // on the line the change ends on
+        assert_eq!(
+            transform_position(position, &change((1, 2), (2, 1), "ab\ncd")),
+            Some(Position::new(2, 5))
+        );
+        assert_eq!(
+            transform_position(position, &change((2, 0), (2, 2), "é")),
+            Some(Position::new(2, 3))
+        );
+        // on a line after it
+        assert_eq!(
+            transform_position(position, &change((0, 0), (1, 0), "")),
+            Some(Position::new(1, 4))
+        );
+        // in the replaced text
+        assert_eq!(
+            transform_position(position, &change((2, 2), (2, 8), "xy")),
+            Some(Position::new(2, 4))
+        );
+        assert_eq!(
+            transform_position(
+                position,
+                &TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: String::new(),
+                },
+            ),
+            None
+        );
     }
-}
 
-pub enum LspHeader {
-    ContentType,
-    ContentLength(usize),
-}
+    #[cfg(unix)]
+    #[test]
+    fn test_server_trace() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig {
+                server_trace: Some(TraceValue::Messages),
+                ..Default::default()
+            },
+            dispatcher,
+        );
+        client.set_trace(TraceSetting {
+            level: TraceValue::Verbose,
+            to_core: true,
+        });
+        let sent = move || {
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .filter(|msg| {
+                    msg["method"] == "lsp_trace"
+                        && msg["params"]["direction"] == "sent"
+                })
+                .map(|msg| {
+                    serde_json::from_str::<Value>(
+                        msg["params"]["message"].as_str().unwrap(),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
 
-fn number_from_id(id: &Id) -> u64 {
-    match *id {
-        Id::Num(n) => n as u64,
-        Id::Str(ref s) => s
-            .parse::<u64>()
-            .expect("failed to convert string id to u64"),
-        _ => panic!("unexpected value for id: None"),
+        client.send_initialize(None, |_, _| {});
+        let messages = sent();
+        assert_eq!(messages[0]["method"], "initialize");
+        assert_eq!(messages[0]["params"]["trace"], "messages");
+
+        // a server that isn't initialized gets the value in `initialize`
+        client.set_server_trace(TraceValue::Verbose);
+        assert!(sent().is_empty());
+
+        client.send_initialized();
+        client.set_server_trace(TraceValue::Off);
+        let messages = sent();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["method"], "$/setTrace");
+        assert_eq!(messages[1]["params"], json!({ "value": "off" }));
+        client.stop();
     }
-}
 
-fn prepare_lsp_json(msg: &Value) -> Result<String> {
-    let request = serde_json::to_string(&msg)?;
-    Ok(format!(
-        "Content-Length: {}\r\n\r\n{}",
-        request.len(),
-        request
-    ))
-}
+    #[cfg(unix)]
+    #[test]
+    fn test_workspace_diagnostics_refresh() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
+        client.send_initialize(None, |_, _| {});
+        client.send_initialized();
+        client.state.lock().server_capabilities = Some(ServerCapabilities {
+            diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                DiagnosticOptions {
+                    workspace_diagnostics: true,
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        });
+        let pull = || client.state.lock().workspace_diagnostics;
+
+        client.pull_workspace_diagnostics();
+        assert_eq!(pull(), WorkspaceDiagnosticsPull::Pulling);
+        // the refresh waits for the pull in flight
+        client.pull_workspace_diagnostics();
+        assert_eq!(pull(), WorkspaceDiagnosticsPull::PullingStale);
+
+        client.handle_message(
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": { "items": [
+                    {
+                        "kind": "full",
+                        "uri": "file:///src/lib.rs",
+                        "version": null,
+                        "resultId": "1",
+                        "items": [{
+                            "range": {
+                                "start": { "line": 0, "character": 0 },
+                                "end": { "line": 0, "character": 1 },
+                            },
+                            "message": "unused",
+                        }],
+                    },
+                    {
+                        "kind": "unchanged",
+                        "uri": "file:///src/main.rs",
+                        "version": null,
+                        "resultId": "2",
+                    },
+                ]},
+            })
+            .to_string(),
+        );
+        let published = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "publish_diagnostics")
+            .collect::<Vec<_>>();
+        assert_eq!(published.len(), 1);
+        let diagnostics = &published[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics["uri"], "file:///src/lib.rs");
+        assert_eq!(diagnostics["diagnostics"][0]["message"], "unused");
+        assert_eq!(client.state.lock().diagnostic_result_ids.len(), 2);
+        // and it's sent again
+        assert_eq!(pull(), WorkspaceDiagnosticsPull::Pulling);
+        client.stop();
+    }
 
-fn parse_header(s: &str) -> Result<LspHeader> {
-    let split: Vec<String> =
-        s.splitn(2, ": ").map(|s| s.trim().to_lowercase()).collect();
-    if split.len() != 2 {
-        return Err(anyhow!("Malformed"));
-    };
-    match split[0].as_ref() {
-        HEADER_CONTENT_TYPE => Ok(LspHeader::ContentType),
-        HEADER_CONTENT_LENGTH => {
-            Ok(LspHeader::ContentLength(split[1].parse::<usize>()?))
+    #[cfg(unix)]
+    #[test]
+    fn test_refresh_requests() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
+        client.set_trace(TraceSetting {
+            level: TraceValue::Verbose,
+            to_core: true,
+        });
+        client.send_initialize(None, |_, _| {});
+        client.send_initialized();
+        client
+            .state
+            .lock()
+            .opened_documents
+            .insert(BufferId(1), Url::from_file_path("/src/main.rs").unwrap());
+
+        let refreshes = [
+            (
+                "workspace/semanticTokens/refresh",
+                "semantic_tokens_refresh",
+            ),
+            ("workspace/inlayHint/refresh", "inlay_hints_refresh"),
+            ("workspace/codeLens/refresh", "code_lens_refresh"),
+        ];
+        for (id, (method, notification)) in refreshes.into_iter().enumerate() {
+            client.handle_request(method, Id::Num(id as i64), Params::None(()));
+            let messages = receiver.try_iter().collect::<Vec<_>>();
+            let response = messages
+                .iter()
+                .filter(|msg| {
+                    msg["method"] == "lsp_trace"
+                        && msg["params"]["direction"] == "sent"
+                })
+                .map(|msg| {
+                    serde_json::from_str::<Value>(
+                        msg["params"]["message"].as_str().unwrap(),
+                    )
+                    .unwrap()
+                })
+                .next_back()
+                .unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], Value::Null);
+            let refresh = messages
+                .iter()
+                .find(|msg| msg["method"] == notification)
+                .unwrap();
+            assert_eq!(refresh["params"]["paths"], json!(["/src/main.rs"]));
         }
-        _ => Err(anyhow!("Unknown parse error occurred")),
+        client.stop();
     }
-}
 
-pub fn read_message<T: BufRead>(reader: &mut T) -> Result<String> {
-    let mut buffer = String::new();
-    let mut content_length: Option<usize> = None;
+    #[cfg(unix)]
+    #[test]
+    fn test_inlay_hint_resolve() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
+        client.set_trace(TraceSetting {
+            level: TraceValue::Verbose,
+            to_core: true,
+        });
+        client.send_initialize(None, |_, _| {});
+        client.send_initialized();
 
-    loop {
-        buffer.clear();
-        let _result = reader.read_line(&mut buffer);
-        // eprin
-        match &buffer {
-            s if s.trim().is_empty() => break,
-            s => {
-                match parse_header(s)? {
-                    LspHeader::ContentLength(len) => content_length = Some(len),
-                    LspHeader::ContentType => (),
-                };
-            }
+        let mut capabilities = ServerCapabilities {
+            inlay_hint_provider: Some(OneOf::Left(true)),
+            ..Default::default()
         };
+        assert!(!can_resolve_inlay_hints(&capabilities));
+        capabilities.inlay_hint_provider = Some(OneOf::Right(
+            InlayHintServerCapabilities::Options(InlayHintOptions {
+                resolve_provider: Some(true),
+                ..Default::default()
+            }),
+        ));
+        assert!(can_resolve_inlay_hints(&capabilities));
+
+        let data = json!({ "id": 7, "file": ["src", "lib.rs"] });
+        let hint: InlayHint = serde_json::from_value(json!({
+            "position": { "line": 1, "character": 4 },
+            "label": ": usize",
+            "data": data,
+        }))
+        .unwrap();
+        client.inlay_hint_resolve(&hint, |_, _| {});
+        let sent = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "lsp_trace")
+            .map(|msg| {
+                serde_json::from_str::<Value>(
+                    msg["params"]["message"].as_str().unwrap(),
+                )
+                .unwrap()
+            })
+            .find(|msg| msg["method"] == "inlayHint/resolve")
+            .unwrap();
+        assert_eq!(sent["params"]["data"], data);
+        client.stop();
     }
 
-    let content_length = content_length
-        .ok_or_else(|| anyhow!("missing content-length header: {}", buffer))?;
+    #[cfg(unix)]
+    #[test]
+    fn test_on_type_formatting() {
+        let (client, receiver, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                document_on_type_formatting_provider: Some(
+                    DocumentOnTypeFormattingOptions {
+                        first_trigger_character: "}".to_string(),
+                        more_trigger_character: Some(vec![";".to_string()]),
+                    },
+                ),
+                ..Default::default()
+            })
+            .respond("textDocument/onTypeFormatting", json!(null))
+            .start_client();
+        assert_eq!(
+            client.state.lock().on_type_formatting_trigger_characters,
+            vec!["}", ";"]
+        );
 
-    let mut body_buffer = vec![0; content_length];
-    reader.read_exact(&mut body_buffer)?;
+        // core learns the triggers of the documents the server has open
+        let uri = Url::from_file_path(std::env::temp_dir().join("main.rs")).unwrap();
+        client.send_did_open(&BufferId(1), uri.clone(), "rust", String::new(), 0);
+        let triggers = receiver
+            .try_iter()
+            .find(|msg| msg["method"] == "on_type_formatting_trigger_characters")
+            .unwrap();
+        assert_eq!(triggers["params"]["trigger_characters"], json!(["}", ";"]));
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        client.request_on_type_formatting(
+            uri,
+            Position::new(2, 1),
+            "}".to_string(),
+            FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                ..Default::default()
+            },
+            move |_, result| {
+                let _ = tx.send(result);
+            },
+        );
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap(),
+            Value::Null
+        );
+        let sent = std::iter::from_fn(|| {
+            received.recv_timeout(Duration::from_secs(1)).ok()
+        })
+        .find(|msg| msg["method"] == "textDocument/onTypeFormatting")
+        .unwrap();
+        assert_eq!(sent["params"]["ch"], "}");
+        assert_eq!(
+            sent["params"]["position"],
+            json!({ "line": 2, "character": 1 })
+        );
+        client.shutdown();
+    }
 
-    let body = String::from_utf8(body_buffer)?;
-    Ok(body)
-}
+    #[test]
+    fn test_code_lens_resolve() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "cat",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
+        client.set_trace(TraceSetting {
+            level: TraceValue::Verbose,
+            to_core: true,
+        });
+        client.send_initialize(None, |_, _| {});
+        client.send_initialized();
 
-pub fn get_change_for_sync_kind(
-    sync_kind: TextDocumentSyncKind,
-    buffer: &Buffer,
-    content_change: &TextDocumentContentChangeEvent,
-) -> Option<Vec<TextDocumentContentChangeEvent>> {
-    match sync_kind {
-        TextDocumentSyncKind::NONE => None,
-        TextDocumentSyncKind::FULL => {
-            let text_document_content_change_event =
-                TextDocumentContentChangeEvent {
-                    range: None,
-                    range_length: None,
-                    text: buffer.get_document(),
-                };
-            Some(vec![text_document_content_change_event])
-        }
-        TextDocumentSyncKind::INCREMENTAL => Some(vec![content_change.clone()]),
-        _ => None,
+        let mut capabilities = ServerCapabilities {
+            code_lens_provider: Some(CodeLensOptions {
+                resolve_provider: None,
+            }),
+            ..Default::default()
+        };
+        assert!(!can_resolve_code_lens(&capabilities));
+        capabilities.code_lens_provider = Some(CodeLensOptions {
+            resolve_provider: Some(true),
+        });
+        assert!(can_resolve_code_lens(&capabilities));
+
+        let data = json!({ "kind": "references", "offset": 42 });
+        let lens: CodeLens = serde_json::from_value(json!({
+            "range": {
+                "start": { "line": 3, "character": 0 },
+                "end": { "line": 3, "character": 10 },
+            },
+            "data": data,
+        }))
+        .unwrap();
+        client.code_lens_resolve(&lens, |_, _| {});
+        let sent = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "lsp_trace")
+            .map(|msg| {
+                serde_json::from_str::<Value>(
+                    msg["params"]["message"].as_str().unwrap(),
+                )
+                .unwrap()
+            })
+            .find(|msg| msg["method"] == "codeLens/resolve")
+            .unwrap();
+        assert_eq!(sent["params"]["data"], data);
+        client.stop();
     }
-}
 
-fn format_semantic_styles(
-    buffer: &Buffer,
-    semantic_tokens_provider: &Option<SemanticTokensServerCapabilities>,
-    value: Value,
-) -> Option<Vec<LineStyle>> {
-    let semantic_tokens: SemanticTokens = serde_json::from_value(value).ok()?;
-    let semantic_tokens_provider = semantic_tokens_provider.as_ref()?;
-    let semantic_legends = semantic_tokens_legend(semantic_tokens_provider);
+    #[test]
+    fn test_read_message_too_large() {
+        let large = r#"{"jsonrpc":"2.0","id":7,"result":"0123456789abcdef"}"#;
+        let small = r#"{"jsonrpc":"2.0","id":8,"result":null}"#;
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{large}Content-Length: {}\r\n\r\n{small}",
+            large.len(),
+            small.len()
+        );
+        let mut reader = BufReader::new(input.as_bytes());
+
+        let watch = HeaderWatch::default();
+
+        let err = read_message(&mut reader, small.len(), &watch).unwrap_err();
+        let err = err.downcast::<MessageTooLarge>().unwrap();
+        assert_eq!(err.id, Some(7));
+        assert_eq!((err.size, err.limit), (large.len(), small.len()));
+        // the messages after it are still read
+        assert_eq!(
+            read_message(&mut reader, small.len(), &watch).unwrap(),
+            small
+        );
+    }
 
-    let mut highlights = Vec::new();
-    let mut line = 0;
-    let mut start = 0;
-    let mut last_start = 0;
-    for semantic_token in &semantic_tokens.data {
-        if semantic_token.delta_line > 0 {
-            line += semantic_token.delta_line as usize;
-            start = buffer.offset_of_line(line);
-        }
+    #[test]
+    fn test_read_unexpected_protocol() {
+        let watch = HeaderWatch::default();
+        let mut reader = BufReader::new("Usage: server [OPTIONS]\n".as_bytes());
+        let err = read_message(&mut reader, 1024, &watch).unwrap_err();
+        assert_eq!(
+            err.downcast::<UnexpectedProtocol>().unwrap().received,
+            b"Usage: server [OPTIONS]\n"
+        );
 
-        let sub_text = buffer.char_indices_iter(start..);
-        if let Some(utf8_delta_start) =
-            offset_utf16_to_utf8(sub_text, semantic_token.delta_start as usize)
-        {
-            start += utf8_delta_start;
-        } else {
-            // Bad semantic token offsets
-            log::error!("Bad semantic token starty {semantic_token:?}");
-            break;
-        };
+        // a header that never ends is seen stalling
+        let watch = HeaderWatch::default();
+        let mut reader = BufReader::new("{\"jsonrpc\":\"2.0\"".as_bytes());
+        assert!(read_message(&mut reader, 1024, &watch).is_err());
+        assert_eq!(
+            watch.stalled(Duration::ZERO).unwrap(),
+            b"{\"jsonrpc\":\"2.0\""
+        );
+        assert!(watch.stalled(HEADER_TIMEOUT).is_none());
+    }
 
-        let sub_text = buffer.char_indices_iter(start..);
-        let end = if let Some(utf8_length) =
-            offset_utf16_to_utf8(sub_text, semantic_token.length as usize)
-        {
-            start + utf8_length
-        } else {
-            log::error!("Bad semantic token end {semantic_token:?}");
-            break;
-        };
+    #[cfg(unix)]
+    #[test]
+    fn test_server_speaking_unexpected_protocol() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "sh",
+            "language_id": "rust",
+            "args": ["-c", "echo 'Welcome!'; sleep 10"],
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::new(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+        );
 
-        let kind = semantic_legends.token_types[semantic_token.token_type as usize]
+        let error = std::iter::from_fn(|| {
+            receiver.recv_timeout(Duration::from_secs(10)).ok()
+        })
+        .find(|msg| msg["method"] == "unexpected_server_protocol")
+        .unwrap();
+        assert_eq!(error["params"]["server"], "sh");
+        assert!(error["params"]["error"]
             .as_str()
-            .to_string();
-        if start < last_start {
-            continue;
-        }
-        last_start = start;
-        highlights.push(LineStyle {
-            start,
-            end,
-            style: Style {
-                fg_color: Some(kind),
-            },
-        });
+            .unwrap()
+            .contains("Welcome!"));
+        assert!(!client.active.load(Ordering::Acquire));
     }
 
-    Some(highlights)
-}
+    #[cfg(unix)]
+    #[test]
+    fn test_restart_with_options() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let mut catalog = LspCatalog::new();
+        catalog.dispatcher = Some(dispatcher);
+
+        // answers `initialize` once it's sent and then keeps what it's sent
+        // in a file, with its stdout open so that it isn't taken for a crash
+        let log = std::env::temp_dir()
+            .join(format!("lapce-restart-test-{}", std::process::id()));
+        let script = r#"r='{"jsonrpc":"2.0","id":0,"result":{"capabilities":{}}}'
+            read -r header
+            printf 'Content-Length: %d\r\n\r\n%s' ${#r} "$r"
+            cat >> "$0""#;
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "sh",
+            "args": ["-c", script, log],
+            "language_id": "rust",
+            "options": { "cargo": { "features": [], "allTargets": true } },
+            "system_lsp": true,
+        }))
+        .unwrap();
+        catalog.start_server(spec.resolve(Path::new("/")).unwrap());
+        let old = catalog.client("rust").unwrap().clone();
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main() {}"),
+            path: std::env::temp_dir().join("main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
 
-fn semantic_tokens_legend(
-    semantic_tokens_provider: &SemanticTokensServerCapabilities,
-) -> SemanticTokensLegend {
-    match semantic_tokens_provider {
-        SemanticTokensServerCapabilities::SemanticTokensOptions(options) => {
-            options.legend.clone()
+        catalog.restart_language_server_with_options(
+            "rust",
+            json!({ "cargo": { "features": ["full"] } }),
+            std::iter::once(&buffer),
+        );
+        let new = catalog.client("rust").unwrap();
+        assert!(!Arc::ptr_eq(&old, new));
+        assert_eq!(
+            new.config.initialization_options,
+            Some(json!({ "cargo": { "features": ["full"], "allTargets": true } }))
+        );
+
+        let progress = receiver
+            .try_iter()
+            .filter(|msg| msg["method"] == "work_done_progress")
+            .map(|msg| msg["params"]["progress"]["value"]["kind"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(progress, vec![json!("begin"), json!("end")]);
+
+        // the buffers are opened in the new instance once it's initialized
+        let start = Instant::now();
+        while !fs::read_to_string(&log)
+            .unwrap_or_default()
+            .contains("textDocument/didOpen")
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
         }
-        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(
-            options,
-        ) => options.semantic_tokens_options.legend.clone(),
+        catalog.stop();
+        let _ = fs::remove_file(&log);
     }
 }
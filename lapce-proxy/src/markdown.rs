@@ -0,0 +1,414 @@
+//! The documentation servers give with hovers and completion items, made
+//! into the one dialect of markdown core renders, whatever the server and
+//! whether it gave markdown or plain text.
+
+use lsp_types::{
+    Documentation, Hover, HoverContents, MarkedString, MarkupContent, MarkupKind,
+};
+use serde_json::{json, Value};
+
+/// The attributes rustdoc takes on the info string of a code block, which
+/// mean the block is Rust when they're all there is
+const RUSTDOC_ATTRIBUTES: &[&str] = &[
+    "ignore",
+    "no_run",
+    "should_panic",
+    "compile_fail",
+    "allow_fail",
+    "test_harness",
+    "standalone_crate",
+];
+/// What servers escaping already escaped text put before punctuation
+const DOUBLE_ESCAPABLE: &str = "`*_{}[]()#+-.!<>|~";
+
+/// Makes `content` markdown without the constructs of the servers that
+/// core doesn't render.
+pub fn normalize_markup(content: MarkupContent) -> MarkupContent {
+    let value = match content.kind {
+        MarkupKind::Markdown => normalize_markdown(&content.value),
+        MarkupKind::PlainText => plaintext_to_markdown(&content.value),
+    };
+    MarkupContent {
+        kind: MarkupKind::Markdown,
+        value,
+    }
+}
+
+/// Normalizes markdown:
+///
+/// - line endings are `\n`
+/// - the info strings of code blocks are only their language, the hidden
+///   lines of Rust examples being left out
+/// - code blocks left open are closed
+/// - punctuation escaped twice is escaped once
+/// - `<br>` and `&nbsp;` outside code are markdown
+/// - blank lines outside code don't repeat, and there are none around it
+pub fn normalize_markdown(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut lines: Vec<String> = Vec::new();
+    let mut fence: Option<Fence> = None;
+    for line in text.split('\n') {
+        match fence.as_ref() {
+            Some(open) => {
+                if open.is_closed_by(line) {
+                    lines.push(open.marker.clone());
+                    fence = None;
+                } else if !(open.language == "rust" && is_hidden_rust_line(line)) {
+                    lines.push(unhide_rust_line(&open.language, line).to_string());
+                }
+            }
+            None => {
+                if let Some(open) = Fence::open(line) {
+                    lines.push(format!("{}{}", open.marker, open.language));
+                    fence = Some(open);
+                } else if line.trim().is_empty() {
+                    if lines.last().map(|l| !l.is_empty()).unwrap_or(false) {
+                        lines.push(String::new());
+                    }
+                } else {
+                    lines.push(normalize_text(line));
+                }
+            }
+        }
+    }
+    if let Some(open) = fence {
+        lines.push(open.marker);
+    }
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// A code block being read
+struct Fence {
+    /// The backticks or tildes it was opened with
+    marker: String,
+    language: String,
+}
+
+impl Fence {
+    fn open(line: &str) -> Option<Fence> {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        if indent > 3 {
+            return None;
+        }
+        let line = &line[indent..];
+        let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+        let len = line.len() - line.trim_start_matches(c).len();
+        if len < 3 {
+            return None;
+        }
+        let info = &line[len..];
+        if c == '`' && info.contains('`') {
+            return None;
+        }
+        Some(Fence {
+            marker: line[..len].to_string(),
+            language: fence_language(info),
+        })
+    }
+
+    fn is_closed_by(&self, line: &str) -> bool {
+        let line = line.trim();
+        let c = self.marker.chars().next().unwrap_or('`');
+        line.len() >= self.marker.len() && line.chars().all(|l| l == c)
+    }
+}
+
+/// The language of a code block from its info string, like `rust` from
+/// rustdoc's `rust,no_run` or pandoc's `{.python}`.
+fn fence_language(info: &str) -> String {
+    let mut attributes = false;
+    let language = info
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|token| token.trim_matches(|c| c == '{' || c == '}' || c == '.'))
+        .filter(|token| !token.is_empty())
+        .find(|token| {
+            let attribute =
+                RUSTDOC_ATTRIBUTES.contains(token) || token.starts_with("edition");
+            attributes |= attribute;
+            !attribute
+        });
+    match language {
+        Some(language) => language.to_lowercase(),
+        None if attributes => "rust".to_string(),
+        None => String::new(),
+    }
+}
+
+/// Whether rustdoc hides `line` of a Rust example, which it does for the
+/// lines starting with `# ` and the ones that are only `#`.
+fn is_hidden_rust_line(line: &str) -> bool {
+    let line = line.trim_start();
+    line == "#" || line.starts_with("# ")
+}
+
+/// `line` as rustdoc shows it, with `##` standing for a `#` that doesn't
+/// hide the line.
+fn unhide_rust_line<'a>(language: &str, line: &'a str) -> &'a str {
+    let trimmed = line.trim_start();
+    if language == "rust" && trimmed.starts_with("##") {
+        let indent = line.len() - trimmed.len();
+        return &line[indent + 1..];
+    }
+    line
+}
+
+/// Normalizes a line of text outside code blocks, leaving its code spans
+/// as they are.
+fn normalize_text(line: &str) -> String {
+    let mut result = String::new();
+    // the parts between backticks are code
+    for (i, part) in line.split('`').enumerate() {
+        if i > 0 {
+            result.push('`');
+        }
+        if i % 2 == 1 {
+            result.push_str(part);
+            continue;
+        }
+        let part = part
+            .replace("&nbsp;", " ")
+            .replace("<br />", "\\\n")
+            .replace("<br/>", "\\\n")
+            .replace("<br>", "\\\n");
+        let mut chars = part.chars().peekable();
+        while let Some(c) = chars.next() {
+            result.push(c);
+            if c == '\\' && chars.peek() == Some(&'\\') {
+                let mut ahead = chars.clone();
+                ahead.next();
+                if ahead.peek().map(|c| DOUBLE_ESCAPABLE.contains(*c)) == Some(true)
+                {
+                    chars.next();
+                }
+            }
+        }
+    }
+    result.trim_end_matches("\\\n").to_string()
+}
+
+/// Makes plain text markdown rendering as the text, line breaks and
+/// indentation included.
+pub fn plaintext_to_markdown(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let lines = text.trim_end().split('\n').collect::<Vec<_>>();
+    let mut result = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start_matches(' ');
+        // spaces would make the line a code block
+        for _ in 0..line.len() - trimmed.len() {
+            result.push('\u{a0}');
+        }
+        let list_delimiter = ordered_list_delimiter(trimmed);
+        for (j, c) in trimmed.char_indices() {
+            let starts_block = (j == 0 && matches!(c, '#' | '-' | '+' | '='))
+                || Some(j) == list_delimiter;
+            if starts_block || "\\`*_[]<>|~".contains(c) {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+        if let Some(next) = lines.get(i + 1) {
+            if !line.trim().is_empty() && !next.trim().is_empty() {
+                result.push('\\');
+            }
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// Where the `.` or `)` of `line` is if it starts like an item of an
+/// ordered list, as `1.` or `1)`.
+fn ordered_list_delimiter(line: &str) -> Option<usize> {
+    let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+    let delimiter = line.len() - rest.len();
+    let is_item = delimiter > 0
+        && (rest.starts_with(". ")
+            || rest.starts_with(") ")
+            || rest == "."
+            || rest == ")");
+    if is_item {
+        Some(delimiter)
+    } else {
+        None
+    }
+}
+
+/// Markdown showing `marked`.
+fn marked_to_markdown(marked: MarkedString) -> String {
+    match marked {
+        MarkedString::String(markdown) => normalize_markdown(&markdown),
+        MarkedString::LanguageString(code) => {
+            // longer than any run of backticks in the code
+            let longest = code
+                .value
+                .split(|c| c != '`')
+                .map(|run| run.len())
+                .max()
+                .unwrap_or(0);
+            let marker = "`".repeat(longest.max(2) + 1);
+            format!(
+                "{marker}{}\n{}\n{marker}",
+                fence_language(&code.language),
+                code.value.trim_end_matches('\n')
+            )
+        }
+    }
+}
+
+/// Normalizes the contents of a hover response, making them a single
+/// markdown document. A response that isn't a hover is left alone.
+pub fn normalize_hover(response: &mut Value) {
+    let hover: Hover = match serde_json::from_value(response.clone()) {
+        Ok(hover) => hover,
+        Err(_) => return,
+    };
+    let contents = match hover.contents {
+        HoverContents::Markup(content) => normalize_markup(content),
+        HoverContents::Scalar(marked) => MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: marked_to_markdown(marked),
+        },
+        HoverContents::Array(marked) => MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: marked
+                .into_iter()
+                .map(marked_to_markdown)
+                .filter(|section| !section.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n\n---\n\n"),
+        },
+    };
+    response["contents"] = json!(contents);
+}
+
+/// Normalizes the documentation of a completion item.
+pub fn normalize_completion_item(item: &mut Value) {
+    let documentation = match item.get("documentation") {
+        Some(documentation) => documentation,
+        None => return,
+    };
+    let content = match serde_json::from_value(documentation.clone()) {
+        Ok(Documentation::String(text)) => MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: plaintext_to_markdown(&text),
+        },
+        Ok(Documentation::MarkupContent(content)) => normalize_markup(content),
+        Err(_) => return,
+    };
+    item["documentation"] = json!(content);
+}
+
+/// Normalizes the documentation of the items of a completion response,
+/// which is a list of items or holds one.
+pub fn normalize_completion(response: &mut Value) {
+    let items = match response {
+        Value::Array(items) => items,
+        Value::Object(list) => match list.get_mut("items") {
+            Some(Value::Array(items)) => items,
+            _ => return,
+        },
+        _ => return,
+    };
+    for item in items {
+        normalize_completion_item(item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_markdown() {
+        // a rust-analyzer hover, with the example of the item's docs
+        let hover = "\r\n```rust\r\ncore::option::Option\r\n```\r\n\r\n```rust\r\n\
+            pub fn unwrap(self) -> T\r\n```\r\n\r\n---\r\n\r\n\r\n\
+            Returns the contained [`Some`] value.\r\n\r\n\
+            ```rust,should_panic\r\n# fn main() {\r\nlet x: Option<&str> = None;\r\n\
+            ## not hidden\r\nx.unwrap();\r\n#\r\n# }\r\n```\r\n";
+        assert_eq!(
+            normalize_markdown(hover),
+            "```rust\ncore::option::Option\n```\n\n```rust\npub fn unwrap(self) -> T\n\
+             ```\n\n---\n\nReturns the contained [`Some`] value.\n\n```rust\n\
+             let x: Option<&str> = None;\n# not hidden\nx.unwrap();\n```"
+        );
+
+        // rustdoc attributes alone mean Rust, and other languages keep
+        // their hidden lines
+        assert_eq!(
+            normalize_markdown("```no_run\n# hidden\n```"),
+            "```rust\n```"
+        );
+        assert_eq!(
+            normalize_markdown("~~~{.python}\n# comment\n~~~"),
+            "~~~python\n# comment\n~~~"
+        );
+        // an unfinished block is closed
+        assert_eq!(
+            normalize_markdown("````ts\nconst a = 1;"),
+            "````ts\nconst a = 1;\n````"
+        );
+
+        // double escapes and html, but not in code
+        assert_eq!(
+            normalize_markdown(
+                "\\\\_\\\\_init\\\\_\\\\_(self)&nbsp;`a\\\\_b`<br>C:\\\\Users"
+            ),
+            "\\_\\_init\\_\\_(self) `a\\\\_b`\\\nC:\\\\Users"
+        );
+    }
+
+    #[test]
+    fn test_plaintext_to_markdown() {
+        // a pyright docstring
+        let text = "Return the sum of a and b.\n\n  1. a *must* be an int\n\
+            # not a heading\nsnake_case_name <T>\n";
+        assert_eq!(
+            plaintext_to_markdown(text),
+            "Return the sum of a and b.\n\n\u{a0}\u{a0}1\\. a \\*must\\* be an \
+             int\\\n\\# not a heading\\\nsnake\\_case\\_name \\<T\\>"
+        );
+    }
+
+    #[test]
+    fn test_normalize_responses() {
+        // a hover of the old marked strings
+        let mut hover = json!({
+            "contents": [
+                { "language": "python", "value": "def f(x: int) -> int" },
+                "Doubles *x*.",
+            ],
+            "range": {
+                "start": { "line": 0, "character": 4 },
+                "end": { "line": 0, "character": 5 },
+            },
+        });
+        normalize_hover(&mut hover);
+        assert_eq!(
+            hover["contents"],
+            json!({
+                "kind": "markdown",
+                "value": "```python\ndef f(x: int) -> int\n```\n\n---\n\nDoubles *x*.",
+            })
+        );
+        assert_eq!(hover["range"]["start"]["character"], 4);
+
+        let mut completion = json!({
+            "isIncomplete": false,
+            "items": [
+                { "label": "len", "documentation": "len(s)\nReturn the length." },
+                { "label": "list" },
+            ],
+        });
+        normalize_completion(&mut completion);
+        assert_eq!(
+            completion["items"][0]["documentation"],
+            json!({ "kind": "markdown", "value": "len(s)\\\nReturn the length." })
+        );
+        assert_eq!(completion["items"][1], json!({ "label": "list" }));
+    }
+}
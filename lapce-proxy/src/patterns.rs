@@ -0,0 +1,156 @@
+//! The glob patterns paths are matched against, compiled once for the whole
+//! proxy. Servers starting again, and registering their document filters
+//! and watched files again, would otherwise compile the same patterns each
+//! time.
+
+use std::collections::HashMap;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use parking_lot::{const_mutex, Mutex};
+
+/// How many compiled sets of patterns are kept, the least recently used
+/// being dropped first
+const PATTERN_CACHE_SIZE: usize = 256;
+
+static CACHE: Mutex<Option<PatternCache>> = const_mutex(None);
+
+/// The compiled globs of `patterns`, matching a path that matches any of
+/// them.
+pub fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet, globset::Error> {
+    let key = patterns
+        .iter()
+        .map(|pattern| pattern.as_ref().to_string())
+        .collect::<Vec<_>>();
+    if let Some(set) = CACHE.lock().get_or_insert_with(PatternCache::new).get(&key) {
+        return Ok(set);
+    }
+
+    // compiled without holding the lock, as it's what takes long
+    let set = compile(&key)?;
+    CACHE
+        .lock()
+        .get_or_insert_with(PatternCache::new)
+        .insert(key, set.clone());
+    Ok(set)
+}
+
+fn compile(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+struct PatternCache {
+    /// Each set by its pattern strings, with when it was last used
+    sets: HashMap<Vec<String>, (GlobSet, u64)>,
+    /// Counts the uses of the cache, to tell which set was used last
+    clock: u64,
+}
+
+impl PatternCache {
+    fn new() -> PatternCache {
+        PatternCache {
+            sets: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, patterns: &[String]) -> Option<GlobSet> {
+        self.clock += 1;
+        let (set, used) = self.sets.get_mut(patterns)?;
+        *used = self.clock;
+        Some(set.clone())
+    }
+
+    fn insert(&mut self, patterns: Vec<String>, set: GlobSet) {
+        self.clock += 1;
+        if self.sets.len() >= PATTERN_CACHE_SIZE
+            && !self.sets.contains_key(&patterns)
+        {
+            let least_recent = self
+                .sets
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(patterns, _)| patterns.clone());
+            if let Some(least_recent) = least_recent {
+                self.sets.remove(&least_recent);
+            }
+        }
+        self.sets.insert(patterns, (set, self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Instant};
+
+    use super::*;
+    use crate::{lsp::activation_globs, watcher::EventFilter};
+
+    #[test]
+    fn test_pattern_cache() {
+        let set = glob_set(&["**/*.rs", "Cargo.toml"]).unwrap();
+        assert!(set.is_match(Path::new("src/main.rs")));
+        assert!(set.is_match(Path::new("Cargo.toml")));
+        assert!(!set.is_match(Path::new("README.md")));
+        assert!(glob_set(&["a[b"]).is_err());
+
+        let mut cache = PatternCache::new();
+        let key = |i: usize| vec![format!("*.{i}")];
+        for i in 0..PATTERN_CACHE_SIZE {
+            cache.insert(key(i), compile(&key(i)).unwrap());
+        }
+        // the first set is kept for being used, the second dropped instead
+        assert!(cache.get(&key(0)).is_some());
+        let new = key(PATTERN_CACHE_SIZE);
+        cache.insert(new.clone(), compile(&new).unwrap());
+        assert_eq!(cache.sets.len(), PATTERN_CACHE_SIZE);
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&new).is_some());
+    }
+
+    /// Compares what servers starting again and registering their watched
+    /// files again cost, with the globs compiled each time and taken from
+    /// the cache. Run it with
+    /// `cargo test -p lapce-proxy --release bench_glob_set -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_glob_set() {
+        let activation = [
+            "**/Cargo.toml",
+            "**/*.rs",
+            "**/rust-project.json",
+            "**/*.{ts,tsx,js,jsx}",
+        ]
+        .map(String::from);
+        let watched = ["**/*.rs", "**/Cargo.{toml,lock}", "**/node_modules/**"];
+        let runs = 2_000;
+
+        let start = Instant::now();
+        for _ in 0..runs {
+            assert!(compile(&activation).unwrap().is_match("src/lib.rs"));
+            let watched = watched.map(String::from);
+            assert!(compile(&watched).unwrap().is_match("src/lib.rs"));
+        }
+        let compiled = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..runs {
+            assert!(activation_globs(&activation)
+                .unwrap()
+                .is_match("src/lib.rs"));
+            let filter = EventFilter::new(Vec::<String>::new(), watched).unwrap();
+            assert!(filter.matches(Path::new("src/lib.rs")));
+        }
+        let cached = start.elapsed();
+
+        assert!(
+            cached * 10 < compiled,
+            "{runs} server starts: {compiled:?} compiling the globs each time, \
+             {cached:?} with the cache"
+        );
+    }
+}
@@ -1,38 +1,179 @@
 use anyhow::{anyhow, Result};
+use crossbeam_channel::RecvTimeoutError;
+use directories::ProjectDirs;
 use home::home_dir;
 use hotwatch::Hotwatch;
+use lapce_rpc::core::CoreNotification;
 use lapce_rpc::counter::Counter;
-use lapce_rpc::plugin::{PluginDescription, PluginId, PluginInfo};
+use lapce_rpc::plugin::{
+    is_icon_url, CommandDefinition, GrammarFormat, PluginDescription, PluginId,
+    PluginInfo,
+};
+use lapce_rpc::proxy::{PluginReport, PluginState, PluginStats};
+use lsp_types::PublishDiagnosticsParams;
+use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use toml;
 use wasmer::ChainableNamedResolver;
 use wasmer::ImportObject;
 use wasmer::Store;
 use wasmer::WasmerEnv;
+use wasmer_wasi::FsError;
 use wasmer_wasi::Pipe;
+use wasmer_wasi::VirtualFile;
 use wasmer_wasi::WasiEnv;
 use wasmer_wasi::WasiState;
 
+use crate::diagnostics::{range_within, DiagnosticSource};
 use crate::dispatch::Dispatcher;
+use crate::formatter::FormatterSpec;
+use crate::instance::{instance_lock_path, InstanceLock};
+use crate::lsp::{activation_globs, LspServerSpec, MessageTooLarge};
+use crate::registry;
+use crate::watchdog;
 
 pub type PluginName = String;
 
+const PLUGIN_INITIALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a plugin that was interrupted may take to stop, which it only
+/// doesn't if it's stuck in a call to the proxy
+const PLUGIN_INTERRUPT_TIMEOUT: Duration = Duration::from_secs(1);
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const COMPLETION_TRANSFORM_TIMEOUT: Duration = Duration::from_millis(500);
+/// How much of a plugin's unparsable output is quoted in the error
+const INVALID_OUTPUT_SNIPPET_LEN: usize = 200;
+/// A plugin whose servers crash this many times within
+/// `PLUGIN_CRASH_WINDOW` is disabled
+const MAX_PLUGIN_CRASHES: usize = 3;
+const PLUGIN_CRASH_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How a plugin's `wasm` starts when it holds the module itself
+const WASM_DATA_URL_PREFIX: &str = "data:";
+/// Plugin icons larger than this aren't sent to core
+const MAX_PLUGIN_ICON_SIZE: u64 = 1024 * 1024;
+/// How much of a line of a plugin's debug output is kept before it's sent
+/// to core unfinished
+const MAX_DEBUG_LINE_LEN: usize = 16 * 1024;
+/// How often a process whose output ended is checked for having exited
+const PROCESS_REAP_INTERVAL: Duration = Duration::from_millis(100);
+/// How many messages can wait for a plugin's thread, past which their
+/// senders wait or give up
+const PLUGIN_QUEUE_CAPACITY: usize = 256;
+
+/// The manifest file names a plugin directory can use, in order of
+/// preference. TOML is the canonical format and is what gets written
+/// when a plugin is installed.
+const PLUGIN_MANIFESTS: [&str; 4] =
+    ["plugin.toml", "plugin.json", "plugin.yaml", "plugin.yml"];
+
 #[derive(WasmerEnv, Clone)]
 pub(crate) struct PluginEnv {
     wasi_env: WasiEnv,
     desc: PluginDescription,
     dispatcher: Dispatcher,
+    usage: Arc<PluginUsage>,
+}
+
+impl PluginEnv {
+    /// Writes `object` to the plugin's stdin, for it to read as a message.
+    fn write_object(&self, object: &(impl Serialize + ?Sized)) {
+        self.usage.messages_sent.fetch_add(1, Ordering::Relaxed);
+        wasi_write_object(&self.wasi_env, object);
+    }
+
+    fn write_string(&self, buf: &str) {
+        self.usage.messages_sent.fetch_add(1, Ordering::Relaxed);
+        wasi_write_string(&self.wasi_env, buf);
+    }
+
+    /// Reads the message the plugin wrote to its stdout. A plugin talking
+    /// garbage, or flooding the proxy with a message over the limit, can't
+    /// be trusted to be in a sane state, so it's unloaded rather than left
+    /// half-working. The error is left for the caller to report.
+    fn read_object<T: DeserializeOwned>(&self) -> Result<T> {
+        self.usage.messages_received.fetch_add(1, Ordering::Relaxed);
+        let max_size = self.dispatcher.max_message_size.load(Ordering::Relaxed);
+        let result = wasi_read_object(&self.wasi_env, max_size);
+        if result.is_err() {
+            self.dispatcher
+                .plugins
+                .lock()
+                .remove_plugin_instance(&self.desc.name);
+        }
+        result
+    }
+}
+
+/// What a plugin has used so far, besides its memory.
+#[derive(Default)]
+pub(crate) struct PluginUsage {
+    calls: AtomicU64,
+    call_time_us: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    /// The message the plugin is handling, if it's handling one
+    handling: Mutex<Option<Handling>>,
+}
+
+/// A message a plugin started handling, which it may get stuck in.
+struct Handling {
+    /// The export handling it
+    export: &'static str,
+    since: Instant,
+    /// Whether the plugin was interrupted for taking too long with it
+    interrupted: bool,
+}
+
+impl PluginUsage {
+    /// Handles a message with the plugin's `export`, with `handle`, keeping
+    /// track of it for the plugin to be interrupted if it gets stuck.
+    fn handle(
+        &self,
+        export: &'static str,
+        handle: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        *self.handling.lock() = Some(Handling {
+            export,
+            since: Instant::now(),
+            interrupted: false,
+        });
+        let result = handle();
+        let interrupted = self
+            .handling
+            .lock()
+            .take()
+            .map(|handling| handling.interrupted)
+            .unwrap_or(false);
+        match result {
+            Err(_) if interrupted => {
+                Err(anyhow!("{export} got stuck, so it was stopped"))
+            }
+            result => result,
+        }
+    }
+
+    /// Makes `call` into one of the plugin's exports, counting it along
+    /// with the time it takes.
+    fn time<T>(&self, call: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = call();
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.call_time_us
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        result
+    }
 }
 
 #[derive(Clone)]
@@ -52,15 +193,94 @@ pub struct PluginCatalog {
     pub items: HashMap<PluginName, PluginDescription>,
     plugins: HashMap<PluginName, Plugin>,
     pub disabled: HashMap<PluginName, PluginDescription>,
-    store: Store,
-    senders: HashMap<PluginName, Sender<PluginTransmissionMessage>>,
+    senders: HashMap<PluginName, SyncSender<PluginTransmissionMessage>>,
+    /// The plugin that owns each registered command id
+    commands: HashMap<String, PluginName>,
+    /// Plugins subscribed to post-process completion responses, with
+    /// the priority they run at
+    completion_transformers: HashMap<PluginName, i32>,
+    /// Plugins not loaded because one of the same name was found in a
+    /// root taking precedence
+    pub shadowed: Vec<ShadowedPlugin>,
+    /// When the servers of each plugin exited on their own within the last
+    /// `PLUGIN_CRASH_WINDOW`
+    crashes: HashMap<PluginName, Vec<Instant>>,
+    /// The plugins disabled for crashing, which stay disabled when the
+    /// plugins are loaded again, until they're enabled
+    auto_disabled: HashSet<PluginName>,
+    /// The plugins `plugins.toml` was last read or written with as
+    /// disabled, which edits of the file are compared to
+    config_disabled: HashSet<PluginName>,
+    process_counter: Counter,
+    /// The processes each plugin spawned, by the handle it knows them by
+    processes: HashMap<PluginName, HashMap<u64, Child>>,
+    /// The plugins whose `initialize` hasn't returned yet, which may still
+    /// start language servers
+    starting: HashSet<PluginName>,
+}
+
+/// What was done to make the plugins match an edit of `plugins.toml`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PluginConfigChanges {
+    pub enabled: Vec<PluginName>,
+    pub disabled: Vec<PluginName>,
+}
+
+/// A plugin hidden by another one of the same name.
+pub struct ShadowedPlugin {
+    pub name: PluginName,
+    /// The directory of the plugin that's loaded
+    pub active: PathBuf,
+    /// The directory of the plugin that isn't
+    pub shadowed: PathBuf,
 }
 
 enum PluginTransmissionMessage {
     Initialize,
+    ExecuteCommand(String),
+    ProcessEvent(ProcessEvent),
+    TransformCompletion(CompletionTransform),
     Stop,
 }
 
+/// A completion response for a plugin's `transform_completion` export,
+/// which its thread answers on `reply`.
+struct CompletionTransform {
+    language_id: String,
+    response: Value,
+    state: Arc<Mutex<TransformState>>,
+    reply: crossbeam_channel::Sender<Result<Value>>,
+}
+
+/// How far a plugin's thread got with a `CompletionTransform`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransformState {
+    Waiting,
+    Running,
+    /// Given up on before the plugin got to it, so it never does
+    Abandoned,
+    Done,
+}
+
+/// What happened to a process a plugin spawned, which is given to its
+/// `handle_process_event` export.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "event")]
+pub enum ProcessEvent {
+    Stdout { handle: u64, line: String },
+    Stderr { handle: u64, line: String },
+    Exited { handle: u64, code: Option<i32> },
+}
+
+/// Whether a process that's done with its output was reaped.
+enum ProcessState {
+    Running,
+    Exited(Option<i32>),
+    /// It isn't tracked anymore, as it was killed with its plugin
+    Gone,
+}
+
 impl PluginCatalog {
     pub fn new() -> PluginCatalog {
         PluginCatalog {
@@ -68,21 +288,46 @@ impl PluginCatalog {
             items: HashMap::new(),
             plugins: HashMap::new(),
             disabled: HashMap::new(),
-            store: Store::default(),
             senders: HashMap::new(),
+            commands: HashMap::new(),
+            completion_transformers: HashMap::new(),
+            shadowed: Vec::new(),
+            crashes: HashMap::new(),
+            auto_disabled: HashSet::new(),
+            config_disabled: HashSet::new(),
+            process_counter: Counter::new(),
+            processes: HashMap::new(),
+            starting: HashSet::new(),
         }
     }
 
     pub fn stop(&mut self) {
+        self.kill_all_processes();
         self.items.clear();
         self.plugins.clear();
+        self.commands.clear();
+        self.completion_transformers.clear();
+        self.starting.clear();
     }
 
     pub fn reload(&mut self) {
+        self.kill_all_processes();
+        for name in self.plugins.keys() {
+            self.unregister(name);
+        }
         self.items.clear();
         self.plugins.clear();
         self.disabled.clear();
+        self.commands.clear();
+        self.completion_transformers.clear();
+        self.shadowed.clear();
+        self.starting.clear();
         let _ = self.load();
+        for name in self.auto_disabled.iter() {
+            if let Some(plugin) = self.items.get(name) {
+                self.disabled.insert(name.clone(), plugin.clone());
+            }
+        }
     }
 
     pub fn load(&mut self) -> Result<()> {
@@ -91,13 +336,21 @@ impl PluginCatalog {
             match load_plugin(plugin_path) {
                 Err(_e) => (),
                 Ok(plugin) => {
+                    // the roots are searched in order of precedence, so the
+                    // plugin already loaded wins
+                    if let Some(active) = self.items.get(&plugin.name) {
+                        self.shadowed.push(ShadowedPlugin {
+                            name: plugin.name.clone(),
+                            active: active.dir.clone().unwrap_or_default(),
+                            shadowed: plugin.dir.clone().unwrap_or_default(),
+                        });
+                        continue;
+                    }
                     self.items.insert(plugin.name.clone(), plugin.clone());
                 }
             }
         }
-        let home = home_dir().unwrap();
-        let path = home.join(".lapce").join("config").join("plugins.toml");
-        let mut file = fs::File::open(path)?;
+        let mut file = fs::File::open(plugin_config_path())?;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
         let plugin_config: PluginConfig = toml::from_str(&content)?;
@@ -108,75 +361,106 @@ impl PluginCatalog {
             }
         }
         self.disabled = disabled;
+        self.config_disabled = plugin_config.disabled.into_iter().collect();
         Ok(())
     }
 
+    /// Enables and disables the plugins to match `plugins.toml` after it
+    /// was edited, as far as it changed since it was last read or written.
+    /// A file that doesn't parse, like one that's being written, is left
+    /// for the next edit.
+    pub fn reload_config(
+        &mut self,
+        dispatcher: Dispatcher,
+    ) -> Result<PluginConfigChanges> {
+        let content = fs::read_to_string(plugin_config_path())?;
+        self.apply_config(dispatcher, &content)
+    }
+
+    fn apply_config(
+        &mut self,
+        dispatcher: Dispatcher,
+        content: &str,
+    ) -> Result<PluginConfigChanges> {
+        let plugin_config: PluginConfig = toml::from_str(content)?;
+        let _lock = self.lock_instance(&dispatcher, "plugins.toml")?;
+        let listed: HashSet<PluginName> =
+            plugin_config.disabled.into_iter().collect();
+        let mut to_disable: Vec<PluginName> =
+            listed.difference(&self.config_disabled).cloned().collect();
+        to_disable.sort();
+        let mut to_enable: Vec<PluginName> =
+            self.config_disabled.difference(&listed).cloned().collect();
+        to_enable.sort();
+
+        let mut changes = PluginConfigChanges::default();
+        for name in to_disable {
+            if let Some(plugin) = self.items.get(&name).cloned() {
+                self.stop_plugin(plugin);
+                changes.disabled.push(name);
+            }
+        }
+        for name in to_enable {
+            let plugin = match self.disabled.get(&name) {
+                Some(plugin) => plugin.clone(),
+                None => continue,
+            };
+            match self.start_enabled_plugin(dispatcher.clone(), plugin) {
+                Ok(()) => changes.enabled.push(name),
+                Err(e) => log::warn!("can't enable plugin {name}: {e}"),
+            }
+        }
+        self.config_disabled = listed;
+        Ok(changes)
+    }
+
+    /// Downloads and starts `plugin`. Fails with [`InstallCancelled`] if
+    /// `cancel` is set before it's done, leaving nothing of it behind. The
+    /// version of it already installed is only replaced once the new one is
+    /// downloaded.
     pub fn install_plugin(
         &mut self,
         dispatcher: Dispatcher,
         plugin: PluginDescription,
+        cancel: &AtomicBool,
     ) -> Result<()> {
+        let _lock = self.lock_instance(&dispatcher, &plugin.name)?;
         let home = home_dir().unwrap();
         let path = home.join(".lapce").join("plugins").join(&plugin.name);
-        let _ = fs::remove_dir_all(&path);
+        // out of the plugins directory, so that it isn't loaded half done
+        let download_dir = home.join(".lapce").join("downloads").join(&plugin.name);
+        let _ = fs::remove_dir_all(&download_dir);
 
-        fs::create_dir_all(&path)?;
-
-        {
-            let mut file = fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(path.join("plugin.toml"))?;
-            file.write_all(&toml::to_vec(&plugin)?)?;
+        let has_icon = match download_plugin_files(&plugin, &download_dir, cancel) {
+            Ok(has_icon) => has_icon,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&download_dir);
+                return Err(e);
+            }
+        };
+        let _ = fs::remove_dir_all(&path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
         }
+        fs::rename(&download_dir, &path)?;
 
         let mut plugin = plugin;
+        if let Some(icon) = plugin.icon.clone().filter(|icon| !is_icon_url(icon)) {
+            plugin.icon = path
+                .join(icon)
+                .to_str()
+                .filter(|_| has_icon)
+                .map(|icon| icon.to_string());
+        }
         if let Some(wasm) = plugin.wasm.clone() {
-            {
-                let url = format!(
-                    "https://raw.githubusercontent.com/{}/master/{}",
-                    plugin.repository, wasm
-                );
-                let mut resp = reqwest::blocking::get(url)?;
-                let mut file = fs::OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(path.join(&wasm))?;
-                std::io::copy(&mut resp, &mut file)?;
-            }
-
             plugin.dir = Some(path.clone());
-            plugin.wasm = Some(
-                path.join(&wasm)
-                    .to_str()
-                    .ok_or_else(|| anyhow!("path can't to string"))?
-                    .to_string(),
-            );
+            plugin.wasm = Some(wasm_path(&path, &wasm)?);
 
             if let Ok((p, tx)) = self.start_plugin(dispatcher, plugin.clone()) {
                 self.plugins.insert(plugin.name.clone(), p);
                 self.senders.insert(plugin.name.clone(), tx);
             }
         }
-        if let Some(themes) = plugin.themes.as_ref() {
-            for theme in themes {
-                {
-                    let url = format!(
-                        "https://raw.githubusercontent.com/{}/master/{}",
-                        plugin.repository, theme
-                    );
-                    let mut resp = reqwest::blocking::get(url)?;
-                    let mut file = fs::OpenOptions::new()
-                        .create(true)
-                        .truncate(true)
-                        .write(true)
-                        .open(path.join(theme))?;
-                    std::io::copy(&mut resp, &mut file)?;
-                }
-            }
-        }
         self.items.insert(plugin.name.clone(), plugin);
         Ok(())
     }
@@ -186,7 +470,9 @@ impl PluginCatalog {
         dispatcher: Dispatcher,
         plugin: PluginDescription,
     ) -> Result<()> {
-        self.disable_plugin(dispatcher, plugin.clone())?;
+        let _lock = self.lock_instance(&dispatcher, &plugin.name)?;
+        self.stop_plugin(plugin.clone());
+        self.save_config()?;
         let home = home_dir().unwrap();
         let path = home.join(".lapce").join("plugins").join(&plugin.name);
         fs::remove_dir_all(&path)?;
@@ -194,16 +480,19 @@ impl PluginCatalog {
         let _ = self.items.remove(&plugin.name);
         let _ = self.plugins.remove(&plugin.name);
         let _ = self.disabled.remove(&plugin.name);
+        self.unregister_commands(&plugin.name);
+        self.completion_transformers.remove(&plugin.name);
         Ok(())
     }
 
     pub fn start_all(&mut self, dispatcher: Dispatcher) {
         for (_, plugin) in self.items.clone().iter() {
             if !self.disabled.contains_key(&plugin.name) {
-                if let Ok((p, _tx)) =
+                if let Ok((p, tx)) =
                     self.start_plugin(dispatcher.clone(), plugin.clone())
                 {
                     self.plugins.insert(plugin.name.clone(), p);
+                    self.senders.insert(plugin.name.clone(), tx);
                 }
             }
         }
@@ -213,31 +502,51 @@ impl PluginCatalog {
         &mut self,
         dispatcher: Dispatcher,
         plugin_desc: PluginDescription,
-    ) -> Result<(Plugin, Sender<PluginTransmissionMessage>)> {
-        let module = wasmer::Module::from_file(
-            &self.store,
-            plugin_desc
-                .wasm
-                .as_ref()
-                .ok_or_else(|| anyhow!("no wasm in plugin"))?,
-        )?;
+    ) -> Result<(Plugin, SyncSender<PluginTransmissionMessage>)> {
+        // the plugin's files are its root, so it can't run without them
+        let dir = match plugin_desc.dir.clone().filter(|dir| dir.is_dir()) {
+            Some(dir) => dir,
+            None => return Err(no_valid_dir(&dispatcher, &plugin_desc.name)),
+        };
+        let wasm = plugin_desc
+            .wasm
+            .as_ref()
+            .ok_or_else(|| anyhow!("no wasm in plugin"))?;
+        let store = watchdog::store();
+        let module = if is_inline_wasm(wasm) {
+            wasmer::Module::new(&store, decode_inline_wasm(wasm)?)?
+        } else {
+            wasmer::Module::from_file(&store, wasm)?
+        };
         let output = Pipe::new();
         let input = Pipe::new();
         let env = plugin_desc.get_plugin_env()?;
-        let mut wasi_env = WasiState::new("Lapce")
-            .map_dir("/", plugin_desc.dir.clone().unwrap())?
+        let mut wasi_state = WasiState::new("Lapce");
+        wasi_state
+            .map_dir("/", &dir)
+            .map_err(|e| {
+                log::warn!("can't map the directory {dir:?}: {e}");
+                no_valid_dir(&dispatcher, &plugin_desc.name)
+            })?
             .stdin(Box::new(input))
             .stdout(Box::new(output))
-            .envs(env)
-            .finalize()?;
+            .envs(env);
+        if plugin_desc.debug_output {
+            wasi_state.stderr(Box::new(DebugOutput::new(
+                &plugin_desc.name,
+                dispatcher.clone(),
+            )));
+        }
+        let mut wasi_env = wasi_state.finalize()?;
         let wasi = wasi_env.import_object(&module)?;
 
         let plugin_env = PluginEnv {
             wasi_env,
             desc: plugin_desc.clone(),
             dispatcher,
+            usage: Arc::default(),
         };
-        let lapce = lapce_exports(&self.store, &plugin_env);
+        let lapce = lapce_exports(&store, &plugin_env);
         let instance = wasmer::Instance::new(&module, &lapce.chain_back(wasi))?;
         let plugin = Plugin {
             instance,
@@ -245,30 +554,85 @@ impl PluginCatalog {
         };
 
         let local_plugin = plugin.clone();
-        let (tx, rx) = mpsc::channel();
+        let (tx, rx) = mpsc::sync_channel(PLUGIN_QUEUE_CAPACITY);
 
         thread::spawn(move || loop {
-            match rx.try_recv() {
+            match rx.recv() {
                 Ok(PluginTransmissionMessage::Initialize) => {
-                    let initialize = local_plugin
-                        .instance
-                        .exports
-                        .get_function("initialize")
-                        .unwrap();
-                    wasi_write_object(
-                        &local_plugin.env.wasi_env,
-                        &PluginInfo {
-                            os: std::env::consts::OS.to_string(),
-                            arch: std::env::consts::ARCH.to_string(),
-                            configuration: plugin_desc.clone().configuration,
-                        },
+                    let timeout = plugin_desc
+                        .initialize_timeout
+                        .map(Duration::from_secs)
+                        .unwrap_or(PLUGIN_INITIALIZE_TIMEOUT);
+                    let result = local_plugin.initialize(timeout);
+                    let dispatcher = &local_plugin.env.dispatcher;
+                    if let Err(e) = &result {
+                        dispatcher
+                            .plugins
+                            .lock()
+                            .remove_plugin_instance(&plugin_desc.name);
+                        dispatcher.send_rpc_notification(
+                            CoreNotification::PluginError {
+                                plugin: plugin_desc.name.clone(),
+                                error: e.to_string(),
+                            },
+                        );
+                    }
+                    dispatcher.plugin_started(&plugin_desc.name);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                Ok(PluginTransmissionMessage::ExecuteCommand(command)) => {
+                    let result =
+                        local_plugin.env.usage.handle("execute_command", || {
+                            local_plugin.execute_command(&command)
+                        });
+                    if let Err(e) = result {
+                        local_plugin.env.dispatcher.send_rpc_notification(
+                            CoreNotification::PluginError {
+                                plugin: plugin_desc.name.clone(),
+                                error: e.to_string(),
+                            },
+                        );
+                    }
+                }
+                Ok(PluginTransmissionMessage::ProcessEvent(event)) => {
+                    let result = local_plugin
+                        .env
+                        .usage
+                        .handle("handle_process_event", || {
+                            local_plugin.handle_process_event(&event)
+                        });
+                    if let Err(e) = result {
+                        local_plugin.env.dispatcher.send_rpc_notification(
+                            CoreNotification::PluginError {
+                                plugin: plugin_desc.name.clone(),
+                                error: e.to_string(),
+                            },
+                        );
+                    }
+                }
+                Ok(PluginTransmissionMessage::TransformCompletion(transform)) => {
+                    {
+                        let mut state = transform.state.lock();
+                        if *state == TransformState::Abandoned {
+                            continue;
+                        }
+                        *state = TransformState::Running;
+                    }
+                    let result = local_plugin.transform_completion(
+                        &transform.language_id,
+                        &transform.response,
                     );
-                    initialize.call(&[]).unwrap();
+                    *transform.state.lock() = TransformState::Done;
+                    let _ = transform.reply.send(result);
                 }
+                Err(mpsc::RecvError) => break,
                 Ok(PluginTransmissionMessage::Stop) => {
                     let stop = local_plugin.instance.exports.get_function("stop");
                     if let Ok(stop_func) = stop {
-                        stop_func.call(&[]).unwrap();
+                        let _ = watchdog::refuel(&local_plugin.instance);
+                        local_plugin.env.usage.time(|| stop_func.call(&[])).unwrap();
                     } else if let Some(Value::Object(conf)) =
                         &plugin_desc.configuration
                     {
@@ -283,18 +647,35 @@ impl PluginCatalog {
                     }
                     break;
                 }
-                _ => {}
             }
         });
+        self.starting.insert(plugin.env.desc.name.clone());
         tx.send(PluginTransmissionMessage::Initialize)?;
+        if let Err(e) = self.register_commands(&plugin.env.desc) {
+            plugin.env.dispatcher.send_rpc_notification(
+                CoreNotification::PluginError {
+                    plugin: plugin.env.desc.name.clone(),
+                    error: e.to_string(),
+                },
+            );
+        }
         Ok((plugin, tx))
     }
 
     pub fn disable_plugin(
         &mut self,
-        _dispatcher: Dispatcher,
+        dispatcher: Dispatcher,
         plugin_desc: PluginDescription,
     ) -> Result<()> {
+        let _lock = self.lock_instance(&dispatcher, &plugin_desc.name)?;
+        // disabled for good now, even if it was for crashing
+        self.auto_disabled.remove(&plugin_desc.name);
+        self.stop_plugin(plugin_desc);
+        self.save_config()
+    }
+
+    /// Stops the plugin and keeps it as disabled, without saving it.
+    fn stop_plugin(&mut self, plugin_desc: PluginDescription) {
         let plugin_tx = self.senders.get(&plugin_desc.name);
         if let Some(tx) = plugin_tx {
             let local_tx = tx.clone();
@@ -303,26 +684,12 @@ impl PluginCatalog {
             });
         }
         self.senders.remove(&plugin_desc.name);
+        self.unregister_commands(&plugin_desc.name);
+        self.completion_transformers.remove(&plugin_desc.name);
+        self.kill_processes(&plugin_desc.name);
+        self.unregister(&plugin_desc.name);
         let plugin = plugin_desc.clone();
         self.disabled.insert(plugin_desc.name.clone(), plugin);
-        let disabled_plugin_list =
-            self.disabled.clone().into_keys().collect::<Vec<String>>();
-        let plugin_config = PluginConfig {
-            disabled: disabled_plugin_list,
-        };
-        let home = home_dir().unwrap();
-        let path = home.join(".lapce").join("config");
-        fs::create_dir_all(&path)?;
-        {
-            let mut file = fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(path.join("plugins.toml"))?;
-            file.write_all(&toml::to_vec(&plugin_config)?)?;
-        }
-
-        Ok(())
     }
 
     pub fn enable_plugin(
@@ -330,220 +697,1373 @@ impl PluginCatalog {
         dispatcher: Dispatcher,
         plugin_desc: PluginDescription,
     ) -> Result<()> {
+        let _lock = self.lock_instance(&dispatcher, &plugin_desc.name)?;
         let mut plugin = plugin_desc.clone();
         let home = home_dir().unwrap();
         let path = home.join(".lapce").join("plugins").join(&plugin.name);
         plugin.dir = Some(path.clone());
         if let Some(wasm) = plugin.wasm {
-            plugin.wasm = Some(
-                path.join(&wasm)
-                    .to_str()
-                    .ok_or_else(|| anyhow!("path can't to string"))?
-                    .to_string(),
-            );
-            self.start_plugin(dispatcher, plugin.clone())?;
-            self.disabled.remove(&plugin_desc.name);
-            let config_path = home.join(".lapce").join("config");
-            let disabled_plugin_list =
-                self.disabled.clone().into_keys().collect::<Vec<String>>();
-            let plugin_config = PluginConfig {
-                disabled: disabled_plugin_list,
-            };
-            {
-                let mut file = fs::OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(config_path.join("plugins.toml"))?;
-                file.write_all(&toml::to_vec(&plugin_config)?)?;
-            }
-            Ok(())
+            plugin.wasm = Some(wasm_path(&path, &wasm)?);
+            self.start_enabled_plugin(dispatcher, plugin)?;
+            self.save_config()
         } else {
             Err(anyhow!("no wasm in plugin"))
         }
     }
 
+    /// Starts the plugin and takes it out of the disabled ones, without
+    /// saving it.
+    fn start_enabled_plugin(
+        &mut self,
+        dispatcher: Dispatcher,
+        plugin: PluginDescription,
+    ) -> Result<()> {
+        let (p, tx) = self.start_plugin(dispatcher, plugin.clone())?;
+        self.plugins.insert(plugin.name.clone(), p);
+        self.senders.insert(plugin.name.clone(), tx);
+        self.disabled.remove(&plugin.name);
+        self.auto_disabled.remove(&plugin.name);
+        Ok(())
+    }
+
+    /// Takes the lock on the plugins the proxies of all windows share
+    /// before `plugin` is changed, telling core if another proxy has it.
+    /// It's held until the returned guard is dropped, once the change is
+    /// made.
+    fn lock_instance(
+        &self,
+        dispatcher: &Dispatcher,
+        plugin: &str,
+    ) -> Result<InstanceLock> {
+        match InstanceLock::acquire(&instance_lock_path()) {
+            Ok(lock) => Ok(lock),
+            Err(e) => {
+                dispatcher.send_rpc_notification(CoreNotification::PluginError {
+                    plugin: plugin.to_string(),
+                    error: format!("can't be changed: {e}"),
+                });
+                Err(e)
+            }
+        }
+    }
+
+    /// The config saved to `plugins.toml`, which leaves out the plugins
+    /// disabled for crashing, as they're only disabled until the editor
+    /// is restarted.
+    fn config(&self) -> PluginConfig {
+        let mut disabled = self
+            .disabled
+            .keys()
+            .filter(|name| !self.auto_disabled.contains(*name))
+            .cloned()
+            .collect::<Vec<String>>();
+        disabled.sort();
+        PluginConfig { disabled }
+    }
+
+    /// Writes the disabled plugins to `plugins.toml`.
+    fn save_config(&mut self) -> Result<()> {
+        let plugin_config = self.config();
+        let path = plugin_config_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // written next to it and moved over it, so that the proxies of other
+        // windows never read it half written
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        fs::write(&part, toml::to_vec(&plugin_config)?)?;
+        if let Err(e) = fs::rename(&part, &path) {
+            let _ = fs::remove_file(&part);
+            return Err(e.into());
+        }
+        self.config_disabled = plugin_config.disabled.into_iter().collect();
+        Ok(())
+    }
+
     pub fn next_plugin_id(&mut self) -> PluginId {
         PluginId(self.id_counter.next())
     }
-}
 
-impl Default for PluginCatalog {
-    fn default() -> Self {
-        Self::new()
+    /// Counts a crash of one of the servers of the plugin `name`, and
+    /// tells whether they crashed `MAX_PLUGIN_CRASHES` times within
+    /// `PLUGIN_CRASH_WINDOW`, so that it should be disabled.
+    pub fn record_crash(&mut self, name: &str) -> bool {
+        let now = Instant::now();
+        let crashes = self.crashes.entry(name.to_string()).or_default();
+        crashes.retain(|crash| now.duration_since(*crash) < PLUGIN_CRASH_WINDOW);
+        crashes.push(now);
+        crashes.len() >= MAX_PLUGIN_CRASHES
     }
-}
 
-pub(crate) fn lapce_exports(store: &Store, plugin_env: &PluginEnv) -> ImportObject {
-    macro_rules! lapce_export {
-        ($($host_function:ident),+ $(,)?) => {
-            wasmer::imports! {
-                "lapce" => {
-                    $(stringify!($host_function) =>
-                        wasmer::Function::new_native_with_env(store, plugin_env.clone(), $host_function),)+
+    /// Disables the plugin `name` until it's enabled again, without
+    /// saving it as disabled in `plugins.toml`.
+    pub fn auto_disable(&mut self, name: &str) {
+        self.remove_plugin_instance(name);
+        self.crashes.remove(name);
+        self.auto_disabled.insert(name.to_string());
+        if let Some(plugin) = self.items.get(name) {
+            self.disabled.insert(name.to_string(), plugin.clone());
+        }
+    }
+
+    /// The plugins disabled for crashing.
+    pub fn auto_disabled(&self) -> Vec<PluginName> {
+        let mut names = self.auto_disabled.iter().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Drops the running instance of a plugin without touching its
+    /// installed or disabled state, e.g. when it failed to initialize.
+    fn remove_plugin_instance(&mut self, name: &str) {
+        self.unregister(name);
+        self.plugins.remove(name);
+        self.senders.remove(name);
+        self.unregister_commands(name);
+        self.completion_transformers.remove(name);
+        self.kill_processes(name);
+    }
+
+    /// Drops what the plugin `name` left with the rest of the proxy, the
+    /// diagnostics it published and the formatters it registered.
+    fn unregister(&self, name: &str) {
+        if let Some(plugin) = self.plugins.get(name) {
+            let dispatcher = &plugin.env.dispatcher;
+            dispatcher.clear_diagnostics(DiagnosticSource::Plugin(name.to_string()));
+            dispatcher.lsp.lock().unregister_formatters(name);
+        }
+    }
+
+    /// Registers the commands a plugin contributes. Commands whose id is
+    /// already owned by another plugin are rejected, the rest still
+    /// get registered.
+    fn register_commands(&mut self, plugin: &PluginDescription) -> Result<()> {
+        let mut duplicates = Vec::new();
+        for command in plugin.commands.iter() {
+            match self.commands.get(&command.id) {
+                Some(owner) if owner != &plugin.name => {
+                    duplicates.push(format!(
+                        "command `{}` is already registered by plugin `{}`",
+                        command.id, owner
+                    ));
+                }
+                _ => {
+                    self.commands
+                        .insert(command.id.clone(), plugin.name.clone());
                 }
             }
         }
+        if duplicates.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(duplicates.join(", ")))
+        }
     }
 
-    lapce_export! {
-        host_handle_notification,
+    fn unregister_commands(&mut self, name: &str) {
+        self.commands.retain(|_, owner| owner != name);
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "method", content = "params")]
-pub enum PluginNotification {
-    StartLspServer {
-        exec_path: String,
-        language_id: String,
-        options: Option<Value>,
-        system_lsp: Option<bool>,
-    },
-    DownloadFile {
-        url: String,
-        path: PathBuf,
-    },
-    LockFile {
-        path: PathBuf,
-    },
-    MakeFileExecutable {
-        path: PathBuf,
-    },
-}
+    /// All the commands currently registered by running plugins.
+    pub fn commands(&self) -> Vec<CommandDefinition> {
+        let mut commands = self
+            .commands
+            .iter()
+            .filter_map(|(id, owner)| {
+                self.items
+                    .get(owner)?
+                    .commands
+                    .iter()
+                    .find(|c| &c.id == id)
+                    .cloned()
+            })
+            .collect::<Vec<CommandDefinition>>();
+        commands.sort_by(|a, b| a.id.cmp(&b.id));
+        commands
+    }
 
-fn host_handle_notification(plugin_env: &PluginEnv) {
-    let notification: Result<PluginNotification> =
-        wasi_read_object(&plugin_env.wasi_env);
-    if let Ok(notification) = notification {
-        match notification {
-            PluginNotification::StartLspServer {
-                exec_path,
-                language_id,
-                options,
-                system_lsp,
-            } => {
-                let exec_path = if system_lsp.unwrap_or(false) {
-                    // System LSP should be handled by PATH during
-                    // process creation, so we forbid anything that
-                    // is not just an executable name
-                    match PathBuf::from(&exec_path).file_name() {
-                        Some(v) => v.to_str().unwrap().to_string(),
-                        None => return,
-                    }
-                } else {
-                    plugin_env
-                        .desc
-                        .dir
-                        .clone()
-                        .unwrap()
-                        .join(&exec_path)
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                };
-                plugin_env.dispatcher.lsp.lock().start_server(
-                    &exec_path,
-                    &language_id,
-                    options,
-                );
-            }
-            PluginNotification::DownloadFile { url, path } => {
-                let mut resp = reqwest::blocking::get(url).expect("request failed");
-                let mut out = fs::File::create(
-                    plugin_env.desc.dir.clone().unwrap().join(path),
-                )
-                .expect("failed to create file");
-                std::io::copy(&mut resp, &mut out).expect("failed to copy content");
-            }
-            PluginNotification::LockFile { path } => {
-                let path = plugin_env.desc.dir.clone().unwrap().join(path);
-                let mut n = 0;
-                loop {
-                    if let Ok(_file) = fs::OpenOptions::new()
-                        .write(true)
-                        .create_new(true)
-                        .open(&path)
+    /// Subscribes a plugin to the completion pipeline. Transformers run
+    /// in ascending `priority` order, ties broken by plugin name.
+    fn register_completion_transformer(&mut self, name: &str, priority: i32) {
+        self.completion_transformers
+            .insert(name.to_string(), priority);
+    }
+
+    /// Marks the plugin as started, once its `initialize` returned.
+    pub fn started(&mut self, name: &str) {
+        self.starting.remove(name);
+    }
+
+    /// Whether a plugin is still starting.
+    pub fn is_starting(&self) -> bool {
+        !self.starting.is_empty()
+    }
+
+    /// Interrupts the plugins that have been handling a message for longer
+    /// than `timeout`, as each plugin handles its messages one at a time and
+    /// the ones after it would never be. A plugin stuck in a call to the
+    /// proxy can't be interrupted, which core is told about.
+    pub fn interrupt_stuck(&self, timeout: Duration) {
+        for (name, plugin) in self.plugins.iter() {
+            let export = {
+                let mut handling = plugin.env.usage.handling.lock();
+                match handling.as_mut() {
+                    Some(handling)
+                        if !handling.interrupted
+                            && handling.since.elapsed() > timeout =>
                     {
-                        return;
-                    }
-                    if n > 10 {
-                        return;
+                        handling.interrupted = true;
+                        handling.export
                     }
-                    n += 1;
-                    let mut hotwatch =
-                        Hotwatch::new().expect("hotwatch failed to initialize!");
-                    let (tx, rx) = crossbeam_channel::bounded(1);
-                    let _ = hotwatch.watch(&path, move |_event| {
-                        #[allow(deprecated)]
-                        let _ = tx.send(0);
-                    });
-                    let _ = rx.recv_timeout(Duration::from_secs(10));
+                    _ => continue,
                 }
-            }
-            PluginNotification::MakeFileExecutable { path } => {
-                let _ = Command::new("chmod")
-                    .arg("+x")
-                    .arg(&plugin_env.desc.dir.clone().unwrap().join(path))
-                    .output();
+            };
+            log::warn!(
+                "plugin {name} has been in {export} for over {} seconds, \
+                 interrupting it",
+                timeout.as_secs()
+            );
+            if let Err(e) = watchdog::interrupt(&plugin.instance) {
+                plugin.env.dispatcher.send_rpc_notification(
+                    CoreNotification::PluginError {
+                        plugin: name.clone(),
+                        error: format!(
+                            "{export} got stuck and can't be stopped: {e}"
+                        ),
+                    },
+                );
             }
         }
     }
-}
-
-pub fn wasi_read_string(wasi_env: &WasiEnv) -> Result<String> {
-    let mut state = wasi_env.state();
-    let wasi_file = state
-        .fs
-        .stdout_mut()?
-        .as_mut()
-        .ok_or_else(|| anyhow!("can't get stdout"))?;
-    let mut buf = String::new();
-    wasi_file.read_to_string(&mut buf)?;
-    Ok(buf)
-}
 
-pub fn wasi_read_object<T: DeserializeOwned>(wasi_env: &WasiEnv) -> Result<T> {
-    let json = wasi_read_string(wasi_env)?;
-    Ok(serde_json::from_str(&json)?)
-}
+    /// What each running plugin has used since it was started, by name.
+    pub fn plugin_stats(&self) -> HashMap<String, PluginStats> {
+        self.plugins
+            .iter()
+            .map(|(name, plugin)| (name.clone(), plugin.stats()))
+            .collect()
+    }
 
-pub fn wasi_write_string(wasi_env: &WasiEnv, buf: &str) {
-    let mut state = wasi_env.state();
-    let wasi_file = state.fs.stdin_mut().unwrap().as_mut().unwrap();
-    writeln!(wasi_file, "{}\r", buf).unwrap();
-}
+    /// Every installed plugin with its state, sorted by name.
+    pub fn plugin_reports(&self) -> Vec<PluginReport> {
+        let mut stats = self.plugin_stats();
+        let mut reports = self
+            .items
+            .values()
+            .filter(|desc| !self.disabled.contains_key(&desc.name))
+            .map(|desc| {
+                let state = if self.starting.contains(&desc.name) {
+                    PluginState::Starting
+                } else if self.plugins.contains_key(&desc.name) {
+                    PluginState::Running
+                } else {
+                    PluginState::Stopped
+                };
+                (desc, state)
+            })
+            .chain(self.disabled.values().map(|desc| {
+                let state = if self.auto_disabled.contains(&desc.name) {
+                    PluginState::AutoDisabled
+                } else {
+                    PluginState::Disabled
+                };
+                (desc, state)
+            }))
+            .map(|(desc, state)| PluginReport {
+                name: desc.name.clone(),
+                version: desc.version.clone(),
+                state,
+                stats: stats.remove(&desc.name),
+            })
+            .collect::<Vec<_>>();
+        reports.sort_by(|a, b| a.name.cmp(&b.name));
+        reports
+    }
 
-pub fn wasi_write_object(wasi_env: &WasiEnv, object: &(impl Serialize + ?Sized)) {
-    wasi_write_string(wasi_env, &serde_json::to_string(&object).unwrap());
-}
+    /// The running plugins subscribed to the completion pipeline, in the
+    /// order they should be given the response.
+    fn completion_transformers(
+        &self,
+    ) -> Vec<(Plugin, SyncSender<PluginTransmissionMessage>)> {
+        let mut transformers = self
+            .completion_transformers
+            .iter()
+            .filter_map(|(name, priority)| {
+                let plugin = self.plugins.get(name)?;
+                Some((*priority, name, plugin, self.senders.get(name)?))
+            })
+            .collect::<Vec<_>>();
+        transformers.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        transformers
+            .into_iter()
+            .map(|(_, _, plugin, tx)| (plugin.clone(), tx.clone()))
+            .collect()
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum PluginRequest {}
+    /// Sends a command to the plugin that registered it.
+    pub fn execute_command(&self, command: &str) -> Result<()> {
+        let owner = self
+            .commands
+            .get(command)
+            .ok_or_else(|| anyhow!("no plugin registered command `{command}`"))?;
+        let tx = self
+            .senders
+            .get(owner)
+            .ok_or_else(|| anyhow!("plugin `{owner}` isn't running"))?;
+        // the plugin's thread may be waiting on the catalog, which is held
+        // here, so a plugin that's busy is failed rather than waited for
+        tx.try_send(PluginTransmissionMessage::ExecuteCommand(
+            command.to_string(),
+        ))
+        .map_err(|e| match e {
+            TrySendError::Full(_) => anyhow!("plugin `{owner}` is busy"),
+            TrySendError::Disconnected(_) => {
+                anyhow!("plugin `{owner}` isn't running")
+            }
+        })
+    }
+
+    /// Runs a process for `plugin`, which is told of its output and exit,
+    /// and returns the handle the plugin can kill it with. A command that
+    /// isn't just a name is relative to the plugin's directory.
+    fn spawn_process(
+        &mut self,
+        dispatcher: Dispatcher,
+        plugin: &PluginDescription,
+        command: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+    ) -> Result<u64> {
+        let name = &plugin.name;
+        if !plugin.spawn_processes {
+            return Err(anyhow!(
+                "plugin {name} can't spawn processes without \
+                 `spawn-processes = true` in its manifest"
+            ));
+        }
+        let tx = self
+            .senders
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("plugin {name} isn't running"))?;
+        let dir = plugin
+            .dir
+            .clone()
+            .ok_or_else(|| anyhow!("plugin {name} has no directory"))?;
+        let program = if Path::new(command).components().count() > 1 {
+            dir.join(command)
+        } else {
+            PathBuf::from(command)
+        };
+
+        let mut child = Command::new(&program)
+            .args(args)
+            .envs(env)
+            .current_dir(&dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("can't spawn {command}: {e}"))?;
+        let handle = self.process_counter.next();
+
+        let stderr = child.stderr.take().map(|stderr| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                forward_lines(
+                    stderr,
+                    |line| ProcessEvent::Stderr { handle, line },
+                    &tx,
+                )
+            })
+        });
+        if let Some(stdout) = child.stdout.take() {
+            let name = name.clone();
+            thread::spawn(move || {
+                forward_lines(
+                    stdout,
+                    |line| ProcessEvent::Stdout { handle, line },
+                    &tx,
+                );
+                if let Some(stderr) = stderr {
+                    let _ = stderr.join();
+                }
+                loop {
+                    let state =
+                        dispatcher.plugins.lock().reap_process(&name, handle);
+                    match state {
+                        ProcessState::Running => {
+                            thread::sleep(PROCESS_REAP_INTERVAL)
+                        }
+                        ProcessState::Exited(code) => {
+                            let _ =
+                                tx.send(PluginTransmissionMessage::ProcessEvent(
+                                    ProcessEvent::Exited { handle, code },
+                                ));
+                            return;
+                        }
+                        ProcessState::Gone => return,
+                    }
+                }
+            });
+        }
+        self.processes
+            .entry(name.clone())
+            .or_default()
+            .insert(handle, child);
+        Ok(handle)
+    }
+
+    fn reap_process(&mut self, name: &str, handle: u64) -> ProcessState {
+        let processes = match self.processes.get_mut(name) {
+            Some(processes) => processes,
+            None => return ProcessState::Gone,
+        };
+        let status = match processes.get_mut(&handle) {
+            Some(child) => child.try_wait(),
+            None => return ProcessState::Gone,
+        };
+        match status {
+            Ok(None) => ProcessState::Running,
+            status => {
+                processes.remove(&handle);
+                ProcessState::Exited(status.ok().flatten().and_then(|s| s.code()))
+            }
+        }
+    }
+
+    /// Kills a process of the plugin `name`. It's reaped once its output
+    /// ends, and the plugin is told it exited then.
+    fn kill_process(&mut self, name: &str, handle: u64) -> Result<()> {
+        let child = self
+            .processes
+            .get_mut(name)
+            .and_then(|processes| processes.get_mut(&handle))
+            .ok_or_else(|| anyhow!("plugin {name} has no process {handle}"))?;
+        child.kill()?;
+        Ok(())
+    }
+
+    /// Kills and reaps all the processes of the plugin `name`, as it's
+    /// unloading.
+    fn kill_processes(&mut self, name: &str) {
+        for (_, mut child) in self.processes.remove(name).unwrap_or_default() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    fn kill_all_processes(&mut self) {
+        for name in self.processes.keys().cloned().collect::<Vec<_>>() {
+            self.kill_processes(&name);
+        }
+    }
+}
+
+/// Sends each line read from `output` to a plugin as the event `event`
+/// makes of it, until the output ends or the plugin is gone.
+fn forward_lines(
+    output: impl Read,
+    event: impl Fn(String) -> ProcessEvent,
+    tx: &SyncSender<PluginTransmissionMessage>,
+) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match output.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&line)
+                    .trim_end_matches(&['\n', '\r'][..])
+                    .to_string();
+                let event = PluginTransmissionMessage::ProcessEvent(event(line));
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Plugin {
+    fn stats(&self) -> PluginStats {
+        let usage = &self.env.usage;
+        PluginStats {
+            memory_pages: self
+                .instance
+                .exports
+                .get_memory("memory")
+                .map(|memory| memory.size().0)
+                .unwrap_or(0),
+            calls: usage.calls.load(Ordering::Relaxed),
+            call_time_us: usage.call_time_us.load(Ordering::Relaxed),
+            messages_sent: usage.messages_sent.load(Ordering::Relaxed),
+            messages_received: usage.messages_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Calls the plugin's `initialize` export on a separate thread, giving up
+    /// if it hasn't returned within `timeout`. A plugin that hangs is
+    /// interrupted, so that it doesn't keep that thread busy forever.
+    fn initialize(&self, timeout: Duration) -> Result<()> {
+        let initialize = self.instance.exports.get_function("initialize")?.clone();
+        self.env.write_object(&PluginInfo {
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            configuration: self.env.desc.configuration.clone(),
+        });
+
+        watchdog::refuel(&self.instance)?;
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let usage = self.env.usage.clone();
+        thread::spawn(move || {
+            let _ = tx.send(usage.time(|| initialize.call(&[])).map(|_| ()));
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => Ok(result?),
+            Err(_) => {
+                watchdog::interrupt(&self.instance)?;
+                let stopped = rx.recv_timeout(PLUGIN_INTERRUPT_TIMEOUT).is_ok();
+                Err(anyhow!(
+                    "initialize didn't finish within {} seconds, {}",
+                    timeout.as_secs(),
+                    if stopped {
+                        "so it was stopped"
+                    } else {
+                        "and it can't be stopped"
+                    }
+                ))
+            }
+        }
+    }
+
+    /// Runs one of the plugin's contributed commands through its
+    /// `execute_command` export, passing the command id on stdin.
+    fn execute_command(&self, command: &str) -> Result<()> {
+        let execute_command =
+            self.instance.exports.get_function("execute_command")?;
+        self.env.write_string(command);
+        watchdog::refuel(&self.instance)?;
+        self.env.usage.time(|| execute_command.call(&[]))?;
+        Ok(())
+    }
+
+    /// Tells the plugin of what happened to one of its processes through
+    /// its `handle_process_event` export, if it has one.
+    fn handle_process_event(&self, event: &ProcessEvent) -> Result<()> {
+        let exports = &self.instance.exports;
+        let handle_event = match exports.get_function("handle_process_event") {
+            Ok(handle_event) => handle_event,
+            Err(_) => return Ok(()),
+        };
+        self.env.write_object(event);
+        watchdog::refuel(&self.instance)?;
+        self.env.usage.time(|| handle_event.call(&[]))?;
+        Ok(())
+    }
+
+    /// Hands a completion response to the plugin's `transform_completion`
+    /// export and returns the response it writes back. Called on the
+    /// plugin's thread, by `request_transform`.
+    fn transform_completion(
+        &self,
+        language_id: &str,
+        response: &Value,
+    ) -> Result<Value> {
+        let transform =
+            self.instance.exports.get_function("transform_completion")?;
+        self.env.write_object(&json!({
+            "language_id": language_id,
+            "response": response,
+        }));
+
+        watchdog::refuel(&self.instance)?;
+        self.env
+            .usage
+            .time(|| transform.call(&[]))
+            .map_err(|e| anyhow!(e.to_string()))?;
+        self.env.read_object()
+    }
+}
+
+/// Has the thread of `plugin` transform a completion response after what
+/// it's doing already. A plugin that hasn't got to it within `timeout` is
+/// skipped, and one that's still running it is interrupted.
+fn request_transform(
+    plugin: &Plugin,
+    tx: &SyncSender<PluginTransmissionMessage>,
+    language_id: &str,
+    response: &Value,
+    timeout: Duration,
+) -> Result<Value> {
+    let state = Arc::new(Mutex::new(TransformState::Waiting));
+    let (reply, rx) = crossbeam_channel::bounded(1);
+    tx.try_send(PluginTransmissionMessage::TransformCompletion(
+        CompletionTransform {
+            language_id: language_id.to_string(),
+            response: response.clone(),
+            state: state.clone(),
+            reply,
+        },
+    ))
+    .map_err(|e| match e {
+        TrySendError::Full(_) => {
+            anyhow!("the plugin was busy, so transform_completion was skipped")
+        }
+        TrySendError::Disconnected(_) => anyhow!("the plugin isn't running"),
+    })?;
+    match rx.recv_timeout(timeout) {
+        Ok(result) => return result,
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(anyhow!("the plugin stopped"))
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+    }
+
+    {
+        // held so that the plugin's thread can't go on to its next message
+        // before it's interrupted
+        let mut state = state.lock();
+        match *state {
+            TransformState::Waiting => {
+                *state = TransformState::Abandoned;
+                return Err(anyhow!(
+                    "the plugin was busy for {} ms, so transform_completion was \
+                     skipped",
+                    timeout.as_millis()
+                ));
+            }
+            TransformState::Running => watchdog::interrupt(&plugin.instance)?,
+            TransformState::Abandoned | TransformState::Done => {}
+        }
+    }
+    let stopped = rx.recv_timeout(PLUGIN_INTERRUPT_TIMEOUT).is_ok();
+    Err(anyhow!(
+        "transform_completion didn't finish within {} ms, {}",
+        timeout.as_millis(),
+        if stopped {
+            "so it was stopped"
+        } else {
+            "and it can't be stopped"
+        }
+    ))
+}
+
+/// Passes a completion response through every plugin subscribed to the
+/// completion pipeline before it's forwarded to core. A plugin that fails
+/// or times out is reported and the response it was given is kept.
+pub(crate) fn transform_completion(
+    dispatcher: &Dispatcher,
+    language_id: &str,
+    mut response: Value,
+) -> Value {
+    let transformers = dispatcher.plugins.lock().completion_transformers();
+    for (plugin, tx) in transformers {
+        match request_transform(
+            &plugin,
+            &tx,
+            language_id,
+            &response,
+            COMPLETION_TRANSFORM_TIMEOUT,
+        ) {
+            Ok(transformed) => response = transformed,
+            Err(e) => {
+                dispatcher.send_rpc_notification(CoreNotification::PluginError {
+                    plugin: plugin.env.desc.name.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    response
+}
+
+impl Default for PluginCatalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn lapce_exports(store: &Store, plugin_env: &PluginEnv) -> ImportObject {
+    macro_rules! lapce_export {
+        ($($host_function:ident),+ $(,)?) => {
+            wasmer::imports! {
+                "lapce" => {
+                    $(stringify!($host_function) =>
+                        wasmer::Function::new_native_with_env(store, plugin_env.clone(), $host_function),)+
+                }
+            }
+        }
+    }
+
+    lapce_export! {
+        host_handle_notification,
+        host_workspace_root,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "method", content = "params")]
+pub enum PluginNotification {
+    StartLspServer(Box<LspServerSpec>),
+    DownloadFile {
+        url: String,
+        path: PathBuf,
+    },
+    LockFile {
+        path: PathBuf,
+    },
+    MakeFileExecutable {
+        path: PathBuf,
+    },
+    /// Subscribe to the completion pipeline, the plugin's
+    /// `transform_completion` export gets each response before core does.
+    RegisterCompletionTransformer {
+        priority: Option<i32>,
+    },
+    /// Format the documents of some languages with a command when their
+    /// servers can't
+    RegisterFormatter(FormatterSpec),
+    /// Show the diagnostics the plugin found in a document, in place of
+    /// those it published for it before. The ones of the language servers
+    /// are kept.
+    PublishDiagnostics(PublishDiagnosticsParams),
+    /// Run a process, if the plugin's manifest allows it. The plugin
+    /// reads `{"handle": ...}` or `{"error": ...}` back from its stdin,
+    /// and its `handle_process_event` export gets the process's output
+    /// and exit.
+    SpawnProcess {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+    },
+    KillProcess {
+        handle: u64,
+    },
+}
+
+fn host_handle_notification(plugin_env: &PluginEnv) {
+    let notification: Result<PluginNotification> = plugin_env.read_object();
+    if let Err(e) = &notification {
+        plugin_env
+            .dispatcher
+            .send_rpc_notification(CoreNotification::PluginError {
+                plugin: plugin_env.desc.name.clone(),
+                error: e.to_string(),
+            });
+    }
+    if let Ok(notification) = notification {
+        match notification {
+            PluginNotification::StartLspServer(mut spec) => {
+                let plugin_dir = plugin_env.desc.dir.clone().unwrap();
+                spec.plugin = Some(plugin_env.desc.name.clone());
+                spec.activation = plugin_env.desc.activation.clone();
+                spec.default_options =
+                    plugin_env.desc.default_server_options.clone();
+                match spec.resolve(&plugin_dir) {
+                    Ok(spec) => {
+                        let dispatcher = &plugin_env.dispatcher;
+                        {
+                            let buffers = dispatcher.buffers.lock();
+                            dispatcher.lsp.lock().add_server(spec, buffers.values());
+                        }
+                        dispatcher.activate_servers_in_workspace();
+                    }
+                    Err(e) => {
+                        plugin_env.dispatcher.send_rpc_notification(
+                            CoreNotification::PluginError {
+                                plugin: plugin_env.desc.name.clone(),
+                                error: format!("can't start language server: {e}"),
+                            },
+                        );
+                    }
+                }
+            }
+            PluginNotification::DownloadFile { url, path } => {
+                let mut resp = reqwest::blocking::get(url).expect("request failed");
+                let mut out = fs::File::create(
+                    plugin_env.desc.dir.clone().unwrap().join(path),
+                )
+                .expect("failed to create file");
+                std::io::copy(&mut resp, &mut out).expect("failed to copy content");
+            }
+            PluginNotification::LockFile { path } => {
+                let path = plugin_env.desc.dir.clone().unwrap().join(path);
+                let mut n = 0;
+                loop {
+                    if let Ok(_file) = fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&path)
+                    {
+                        return;
+                    }
+                    if n > 10 {
+                        return;
+                    }
+                    n += 1;
+                    let mut hotwatch =
+                        Hotwatch::new().expect("hotwatch failed to initialize!");
+                    let (tx, rx) = crossbeam_channel::bounded(1);
+                    let _ = hotwatch.watch(&path, move |_event| {
+                        #[allow(deprecated)]
+                        let _ = tx.send(0);
+                    });
+                    let _ = rx.recv_timeout(Duration::from_secs(10));
+                }
+            }
+            PluginNotification::MakeFileExecutable { path } => {
+                let _ = Command::new("chmod")
+                    .arg("+x")
+                    .arg(&plugin_env.desc.dir.clone().unwrap().join(path))
+                    .output();
+            }
+            PluginNotification::RegisterFormatter(mut spec) => {
+                let plugin_dir = plugin_env.desc.dir.clone().unwrap();
+                spec.plugin = Some(plugin_env.desc.name.clone());
+                match spec.resolve(&plugin_dir) {
+                    Ok(spec) => {
+                        plugin_env.dispatcher.lsp.lock().register_formatter(spec)
+                    }
+                    Err(e) => {
+                        plugin_env.dispatcher.send_rpc_notification(
+                            CoreNotification::PluginError {
+                                plugin: plugin_env.desc.name.clone(),
+                                error: format!("can't register formatter: {e}"),
+                            },
+                        );
+                    }
+                }
+            }
+            PluginNotification::RegisterCompletionTransformer { priority } => {
+                plugin_env
+                    .dispatcher
+                    .plugins
+                    .lock()
+                    .register_completion_transformer(
+                        &plugin_env.desc.name,
+                        priority.unwrap_or(0),
+                    );
+            }
+            PluginNotification::PublishDiagnostics(mut params) => {
+                let name = &plugin_env.desc.name;
+                {
+                    let buffer_id =
+                        params.uri.to_file_path().ok().and_then(|path| {
+                            plugin_env.dispatcher.open_buffer_id(&path)
+                        });
+                    let buffers = plugin_env.dispatcher.buffers.lock();
+                    let buffer =
+                        buffer_id.and_then(|buffer_id| buffers.get(&buffer_id));
+                    if let Some(buffer) = buffer {
+                        let published = params.diagnostics.len();
+                        params.diagnostics.retain(|diagnostic| {
+                            range_within(buffer, &diagnostic.range)
+                        });
+                        let dropped = published - params.diagnostics.len();
+                        if dropped > 0 {
+                            log::warn!(
+                                "{dropped} diagnostics of {name} are outside of {}",
+                                params.uri
+                            );
+                        }
+                    }
+                }
+                for diagnostic in params.diagnostics.iter_mut() {
+                    if diagnostic.source.is_none() {
+                        diagnostic.source = Some(name.clone());
+                    }
+                }
+                plugin_env.dispatcher.publish_diagnostics(
+                    DiagnosticSource::Plugin(name.clone()),
+                    params,
+                );
+            }
+            PluginNotification::SpawnProcess { command, args, env } => {
+                let dispatcher = &plugin_env.dispatcher;
+                let handle = dispatcher.plugins.lock().spawn_process(
+                    dispatcher.clone(),
+                    &plugin_env.desc,
+                    &command,
+                    &args,
+                    &env,
+                );
+                let response = match handle {
+                    Ok(handle) => json!({ "handle": handle }),
+                    Err(e) => json!({ "error": e.to_string() }),
+                };
+                plugin_env.write_object(&response);
+            }
+            PluginNotification::KillProcess { handle } => {
+                let name = &plugin_env.desc.name;
+                let killed = plugin_env
+                    .dispatcher
+                    .plugins
+                    .lock()
+                    .kill_process(name, handle);
+                if let Err(e) = killed {
+                    log::warn!("can't kill the process: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Writes the workspace to the plugin's stdin, as `{"root": ..., "roots":
+/// [...]}` with every root of a workspace of several, the workspace first.
+/// The root is `null` if no folder is open.
+fn host_workspace_root(plugin_env: &PluginEnv) {
+    plugin_env.write_object(&workspace_roots(&plugin_env.dispatcher));
+}
+
+fn workspace_roots(dispatcher: &Dispatcher) -> Value {
+    let roots = dispatcher.lsp.lock().workspace_roots().to_vec();
+    json!({
+        "root": roots.first(),
+        "roots": roots,
+    })
+}
+
+/// The stderr of a plugin asking for its debug output, which forwards
+/// each line written to it to core.
+struct DebugOutput {
+    plugin: PluginName,
+    dispatcher: Dispatcher,
+    /// What was written of the line that isn't finished yet
+    line: Vec<u8>,
+}
+
+impl DebugOutput {
+    fn new(plugin: &str, dispatcher: Dispatcher) -> DebugOutput {
+        DebugOutput {
+            plugin: plugin.to_string(),
+            dispatcher,
+            line: Vec::new(),
+        }
+    }
+
+    fn send_line(&mut self, end: usize) {
+        let line: Vec<u8> = self.line.drain(..end).collect();
+        let line = String::from_utf8_lossy(&line);
+        self.dispatcher
+            .send_rpc_notification(CoreNotification::PluginLog {
+                plugin: self.plugin.clone(),
+                line: line.trim_end_matches(&['\n', '\r'][..]).to_string(),
+            });
+    }
+}
+
+impl std::fmt::Debug for DebugOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebugOutput")
+            .field("plugin", &self.plugin)
+            .finish()
+    }
+}
+
+impl Write for DebugOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            self.send_line(end + 1);
+        }
+        // a line that never ends is sent in pieces
+        if self.line.len() >= MAX_DEBUG_LINE_LEN {
+            self.send_line(self.line.len());
+        }
+        Ok(buf.len())
+    }
+
+    // WASI flushes after every write, so the line is left to be finished
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DebugOutput {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.send_line(self.line.len());
+        }
+    }
+}
+
+impl Read for DebugOutput {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Seek for DebugOutput {
+    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "can't seek in the debug output",
+        ))
+    }
+}
+
+impl VirtualFile for DebugOutput {
+    fn last_accessed(&self) -> u64 {
+        0
+    }
+
+    fn last_modified(&self) -> u64 {
+        0
+    }
+
+    fn created_time(&self) -> u64 {
+        0
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn set_len(&mut self, _new_size: u64) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn unlink(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn bytes_available(&self) -> Result<usize, FsError> {
+        Ok(0)
+    }
+}
+
+/// Reads what the plugin wrote, failing with [`MessageTooLarge`] if it's
+/// more than `max_size` bytes. What it wrote is gone either way.
+pub fn wasi_read_string(wasi_env: &WasiEnv, max_size: usize) -> Result<String> {
+    let mut state = wasi_env.state();
+    let wasi_file = state
+        .fs
+        .stdout_mut()?
+        .as_mut()
+        .ok_or_else(|| anyhow!("can't get stdout"))?;
+    let mut buf = Vec::new();
+    Read::take(&mut *wasi_file, max_size as u64 + 1).read_to_end(&mut buf)?;
+    if buf.len() > max_size {
+        let rest = std::io::copy(wasi_file, &mut std::io::sink())?;
+        return Err(MessageTooLarge {
+            size: buf.len() + rest as usize,
+            limit: max_size,
+            id: None,
+        }
+        .into());
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+pub fn wasi_read_object<T: DeserializeOwned>(
+    wasi_env: &WasiEnv,
+    max_size: usize,
+) -> Result<T> {
+    let json = wasi_read_string(wasi_env, max_size)?;
+    serde_json::from_str(&json).map_err(|e| {
+        let mut snippet = json
+            .trim()
+            .chars()
+            .take(INVALID_OUTPUT_SNIPPET_LEN)
+            .collect::<String>();
+        if json.trim().chars().count() > INVALID_OUTPUT_SNIPPET_LEN {
+            snippet.push_str("...");
+        }
+        anyhow!("produced invalid output ({e}): {snippet}")
+    })
+}
+
+pub fn wasi_write_string(wasi_env: &WasiEnv, buf: &str) {
+    let mut state = wasi_env.state();
+    let wasi_file = state.fs.stdin_mut().unwrap().as_mut().unwrap();
+    writeln!(wasi_file, "{}\r", buf).unwrap();
+}
+
+pub fn wasi_write_object(wasi_env: &WasiEnv, object: &(impl Serialize + ?Sized)) {
+    wasi_write_string(wasi_env, &serde_json::to_string(&object).unwrap());
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PluginRequest {}
 
 pub struct PluginHandler {}
 
+/// The error of a plugin installation cancelled by the user.
+#[derive(Debug)]
+pub struct InstallCancelled;
+
+impl std::fmt::Display for InstallCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "installation cancelled")
+    }
+}
+
+impl std::error::Error for InstallCancelled {}
+
+/// Writes what `reader` gives to `path`, a chunk at a time so that setting
+/// `cancel` stops it. It goes to a `.part` file next to `path` first, which
+/// is removed if the download doesn't complete.
+fn download(reader: &mut impl Read, path: &Path, cancel: &AtomicBool) -> Result<()> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let result = fs::File::create(&part)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| copy_chunks(reader, &mut file, cancel));
+    match result {
+        Ok(()) => fs::rename(&part, path)?,
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn copy_chunks(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let mut buf = [0; DOWNLOAD_CHUNK_SIZE];
+    loop {
+        if cancel.load(Ordering::Acquire) {
+            return Err(InstallCancelled.into());
+        }
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..n])?;
+    }
+}
+
+/// The directories plugins are looked for in, the one taking precedence
+/// first: `LAPCE_PLUGIN_DIR` if set, then the home directory, then the
+/// data directory of the platform.
+fn plugin_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if let Some(dir) = std::env::var_os("LAPCE_PLUGIN_DIR") {
+        roots.push(PathBuf::from(dir));
+    }
+    if let Some(home) = home_dir() {
+        roots.push(home.join(".lapce").join("plugins"));
+    }
+    if let Some(dirs) = ProjectDirs::from("", "", "Lapce") {
+        roots.push(dirs.data_dir().join("plugins"));
+    }
+    roots
+}
+
 fn find_all_plugins() -> Vec<PathBuf> {
     let mut plugin_paths = Vec::new();
-    let home = home_dir().unwrap();
-    let path = home.join(".lapce").join("plugins");
-    let _ = path.read_dir().map(|dir| {
-        dir.flat_map(|item| item.map(|p| p.path()).ok())
-            .map(|dir| dir.join("plugin.toml"))
-            .filter(|f| f.exists())
-            .for_each(|f| plugin_paths.push(f))
-    });
+    for path in plugin_roots() {
+        let _ = path.read_dir().map(|dir| {
+            dir.flat_map(|item| item.map(|p| p.path()).ok())
+                .filter_map(|dir| {
+                    PLUGIN_MANIFESTS
+                        .iter()
+                        .map(|manifest| dir.join(manifest))
+                        .find(|f| f.exists())
+                })
+                .for_each(|f| plugin_paths.push(f))
+        });
+    }
     plugin_paths
 }
 
+/// Where `file`, a path relative to the plugin's directory `path`, is
+/// downloaded to, making the directories it's in. Fails for a path that
+/// leads out of the plugin's directory.
+fn plugin_file(path: &Path, file: &str) -> Result<PathBuf> {
+    if !Path::new(file)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("{file} isn't in the plugin's directory"));
+    }
+    let file_path = path.join(file);
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(file_path)
+}
+
+/// Downloads the files of `plugin` into `path`, giving whether its icon
+/// is among them. The plugin works without it, so failing to download it
+/// is only logged.
+fn download_plugin_files(
+    plugin: &PluginDescription,
+    path: &Path,
+    cancel: &AtomicBool,
+) -> Result<bool> {
+    fs::create_dir_all(path)?;
+
+    {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path.join("plugin.toml"))?;
+        file.write_all(&toml::to_vec(&plugin)?)?;
+    }
+
+    if let Some(wasm) = plugin.wasm.as_ref() {
+        if !is_inline_wasm(wasm) {
+            let url = format!(
+                "https://raw.githubusercontent.com/{}/master/{}",
+                plugin.repository, wasm
+            );
+            let mut resp = registry::get(&url)?;
+            download(&mut resp, &path.join(wasm), cancel)?;
+        }
+    }
+    if let Some(themes) = plugin.themes.as_ref() {
+        for theme in themes {
+            let url = format!(
+                "https://raw.githubusercontent.com/{}/master/{}",
+                plugin.repository, theme
+            );
+            let mut resp = registry::get(&url)?;
+            download(&mut resp, &path.join(theme), cancel)?;
+        }
+    }
+    for grammar in plugin.grammars.iter().flatten() {
+        let grammar_path = plugin_file(path, grammar)?;
+        let url = format!(
+            "https://raw.githubusercontent.com/{}/master/{}",
+            plugin.repository, grammar
+        );
+        let mut resp = registry::get(&url)?.error_for_status()?;
+        download(&mut resp, &grammar_path, cancel)?;
+    }
+    if let Some(icon) = plugin.icon.as_ref().filter(|icon| !is_icon_url(icon)) {
+        match download_icon(plugin, icon, path, cancel) {
+            Ok(()) => return Ok(true),
+            Err(e) if e.is::<InstallCancelled>() => return Err(e),
+            Err(e) => {
+                log::warn!("can't download the icon of {}: {e}", plugin.name);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Downloads the `icon` of `plugin` into its directory at `path`.
+fn download_icon(
+    plugin: &PluginDescription,
+    icon: &str,
+    path: &Path,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let icon_path = plugin_file(path, icon)?;
+    let url = plugin.icon_url().unwrap();
+    let mut resp = registry::get(&url)?.error_for_status()?;
+    download(&mut resp, &icon_path, cancel)
+}
+
+/// Reads the `icon` of an installed plugin, downloading it if it's a URL.
+pub fn read_plugin_icon(icon: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if is_icon_url(icon) {
+        registry::get(icon)?
+            .error_for_status()?
+            .take(MAX_PLUGIN_ICON_SIZE + 1)
+            .read_to_end(&mut data)?;
+    } else {
+        fs::File::open(icon)?
+            .take(MAX_PLUGIN_ICON_SIZE + 1)
+            .read_to_end(&mut data)?;
+    }
+    if data.len() as u64 > MAX_PLUGIN_ICON_SIZE {
+        return Err(anyhow!("the icon is over {MAX_PLUGIN_ICON_SIZE} bytes"));
+    }
+    Ok(data)
+}
+
+/// Whether the `wasm` of a plugin is the module itself rather than its
+/// path, as a data URL like `data:application/wasm;base64,AGFzbQEAAAA=`.
+fn is_inline_wasm(wasm: &str) -> bool {
+    wasm.starts_with(WASM_DATA_URL_PREFIX)
+}
+
+/// The module in the inline `wasm` of a plugin.
+fn decode_inline_wasm(wasm: &str) -> Result<Vec<u8>> {
+    let (media_type, data) = wasm[WASM_DATA_URL_PREFIX.len()..]
+        .split_once(',')
+        .ok_or_else(|| anyhow!("invalid wasm data url"))?;
+    if !media_type.ends_with(";base64") {
+        return Err(anyhow!("wasm data url isn't base64 encoded"));
+    }
+    Ok(base64::decode(data.trim())?)
+}
+
+/// The file with the plugins the user disabled, which is read again when
+/// it's edited.
+pub fn plugin_config_path() -> PathBuf {
+    home_dir()
+        .unwrap()
+        .join(".lapce")
+        .join("config")
+        .join("plugins.toml")
+}
+
+/// Tells core that `plugin` can't be started for want of a directory to
+/// run in, giving the error to fail with.
+fn no_valid_dir(dispatcher: &Dispatcher, plugin: &str) -> anyhow::Error {
+    let error = "has no valid directory";
+    dispatcher.send_rpc_notification(CoreNotification::PluginError {
+        plugin: plugin.to_string(),
+        error: error.to_string(),
+    });
+    anyhow!("plugin {plugin} {error}")
+}
+
+/// The path of the `wasm` of a plugin installed in `dir`, leaving inline
+/// modules as they are.
+fn wasm_path(dir: &Path, wasm: &str) -> Result<String> {
+    if is_inline_wasm(wasm) {
+        return Ok(wasm.to_string());
+    }
+    Ok(dir
+        .join(wasm)
+        .to_str()
+        .ok_or_else(|| anyhow!("path can't to string"))?
+        .to_string())
+}
+
 fn load_plugin(path: &Path) -> Result<PluginDescription> {
     let mut file = fs::File::open(&path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let mut plugin: PluginDescription = toml::from_str(&contents)?;
+    let mut plugin: PluginDescription =
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents)?,
+            _ => toml::from_str(&contents)?,
+        };
+    activation_globs(&plugin.activation)
+        .map_err(|err| anyhow!("invalid activation glob: {err}"))?;
+    if let Some(options) = plugin.default_server_options.as_ref() {
+        if !options.is_object() {
+            return Err(anyhow!("default-server-options isn't a table"));
+        }
+    }
     plugin.dir = Some(path.parent().unwrap().canonicalize()?);
     plugin.wasm = plugin.wasm.as_ref().and_then(|wasm| {
+        if is_inline_wasm(wasm) {
+            return Some(wasm.clone());
+        }
         Some(
             path.parent()?
                 .join(wasm)
@@ -568,5 +2088,795 @@ fn load_plugin(path: &Path) -> Result<PluginDescription> {
             })
             .collect()
     });
+    plugin.grammars = plugin
+        .grammars
+        .as_ref()
+        .map(|grammars| {
+            grammars
+                .iter()
+                .map(|grammar| grammar_path(path, grammar))
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
+    plugin.icon = plugin.icon.as_ref().and_then(|icon| {
+        if is_icon_url(icon) {
+            return Some(icon.clone());
+        }
+        Some(
+            path.parent()?
+                .join(icon)
+                .canonicalize()
+                .ok()?
+                .to_str()?
+                .to_string(),
+        )
+    });
     Ok(plugin)
 }
+
+/// The absolute path of a grammar of the plugin with the manifest at
+/// `manifest`, failing if it's missing or of a format that isn't known.
+fn grammar_path(manifest: &Path, grammar: &str) -> Result<String> {
+    let path = manifest
+        .parent()
+        .ok_or_else(|| anyhow!("the manifest has no directory"))?
+        .join(grammar)
+        .canonicalize()
+        .map_err(|err| anyhow!("grammar {grammar} can't be found: {err}"))?;
+    if !path.is_file() {
+        return Err(anyhow!("grammar {grammar} isn't a file"));
+    }
+    if GrammarFormat::from_path(&path).is_none() {
+        return Err(anyhow!("grammar {grammar} is of an unknown format"));
+    }
+    Ok(path
+        .to_str()
+        .ok_or_else(|| anyhow!("path can't to string"))?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::DEFAULT_MAX_MESSAGE_SIZE;
+
+    #[test]
+    fn test_download_cancelled() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plugin.wasm");
+
+        let cancel = AtomicBool::new(true);
+        let e = download(&mut &[0u8; 16][..], &path, &cancel).unwrap_err();
+        assert!(e.is::<InstallCancelled>());
+        assert!(!path.exists());
+        assert!(!dir.join("plugin.wasm.part").exists());
+
+        cancel.store(false, Ordering::Release);
+        download(&mut &[1u8; 16][..], &path, &cancel).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![1u8; 16]);
+        assert!(!dir.join("plugin.wasm.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_crash() {
+        let mut catalog = PluginCatalog::new();
+        assert!(!catalog.record_crash("crashy"));
+        assert!(!catalog.record_crash("other"));
+        assert!(!catalog.record_crash("crashy"));
+        assert!(catalog.record_crash("crashy"));
+
+        // crashes long ago don't count
+        let long_ago = Instant::now() - PLUGIN_CRASH_WINDOW;
+        catalog
+            .crashes
+            .insert("other".to_string(), vec![long_ago, long_ago]);
+        assert!(!catalog.record_crash("other"));
+
+        catalog.auto_disable("crashy");
+        assert!(!catalog.record_crash("crashy"));
+        assert_eq!(catalog.auto_disabled(), vec!["crashy".to_string()]);
+    }
+
+    #[test]
+    fn test_execute_command_when_busy() {
+        let mut catalog = PluginCatalog::new();
+        let (tx, rx) = mpsc::sync_channel(1);
+        catalog.senders.insert("slow".to_string(), tx);
+        catalog
+            .commands
+            .insert("slow.run".to_string(), "slow".to_string());
+
+        catalog.execute_command("slow.run").unwrap();
+        // the plugin hasn't taken the first one yet
+        let error = catalog.execute_command("slow.run").unwrap_err();
+        assert_eq!(error.to_string(), "plugin `slow` is busy");
+        drop(rx);
+        let error = catalog.execute_command("slow.run").unwrap_err();
+        assert_eq!(error.to_string(), "plugin `slow` isn't running");
+    }
+
+    #[test]
+    fn test_auto_disabled_not_saved() {
+        let mut catalog = PluginCatalog::new();
+        for name in ["crashy", "unwanted"] {
+            let plugin: PluginDescription = toml::from_str(&format!(
+                "name = \"{name}\"\nversion = \"0.1.0\"\n\
+                 display-name = \"{name}\"\nauthor = \"lapce\"\n\
+                 description = \"\"\nrepository = \"lapce/{name}\"\n\
+                 wasm = \"plugin.wasm\"\n"
+            ))
+            .unwrap();
+            catalog.items.insert(name.to_string(), plugin.clone());
+            catalog.disabled.insert(name.to_string(), plugin);
+        }
+        catalog.disabled.remove("crashy");
+        catalog.auto_disable("crashy");
+        assert!(catalog.disabled.contains_key("crashy"));
+        assert_eq!(catalog.config().disabled, vec!["unwanted".to_string()]);
+    }
+
+    #[test]
+    fn test_inline_wasm() {
+        // the smallest valid module, the magic number and the version
+        let wasm = "data:application/wasm;base64,AGFzbQEAAAA=";
+        assert!(is_inline_wasm(wasm));
+        assert_eq!(decode_inline_wasm(wasm).unwrap(), b"\0asm\x01\0\0\0");
+        assert_eq!(wasm_path(Path::new("/plugins/tiny"), wasm).unwrap(), wasm);
+        wasmer::Module::new(&Store::default(), decode_inline_wasm(wasm).unwrap())
+            .unwrap();
+
+        assert!(!is_inline_wasm("plugin.wasm"));
+        assert_eq!(
+            wasm_path(Path::new("/plugins/tiny"), "plugin.wasm").unwrap(),
+            "/plugins/tiny/plugin.wasm"
+        );
+        assert!(decode_inline_wasm("data:application/wasm,AGFzbQEAAAA=").is_err());
+    }
+
+    #[test]
+    fn test_plugin_icon() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-plugin-icon-{}", std::process::id()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/icon.png"), b"\x89PNG").unwrap();
+        let manifest = |icon: &str| {
+            format!(
+                "name = \"icon\"\nversion = \"0.1.0\"\ndisplay-name = \"Icon\"\n\
+                 author = \"lapce\"\ndescription = \"\"\n\
+                 repository = \"lapce/icon\"\nicon = \"{icon}\"\n"
+            )
+        };
+
+        fs::write(dir.join("plugin.toml"), manifest("assets/icon.png")).unwrap();
+        let plugin = load_plugin(&dir.join("plugin.toml")).unwrap();
+        let icon = plugin.icon.unwrap();
+        assert!(icon.ends_with("assets/icon.png"));
+        assert_eq!(read_plugin_icon(&icon).unwrap(), b"\x89PNG");
+
+        let url = "https://example.com/icon.png";
+        fs::write(dir.join("plugin.toml"), manifest(url)).unwrap();
+        let plugin = load_plugin(&dir.join("plugin.toml")).unwrap();
+        assert_eq!(plugin.icon.as_deref(), Some(url));
+        assert_eq!(plugin.icon_url().as_deref(), Some(url));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_plugin_grammars() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-plugin-grammars-{}", std::process::id()));
+        fs::create_dir_all(dir.join("syntaxes")).unwrap();
+        fs::write(dir.join("syntaxes/foo.tmLanguage.json"), "{}").unwrap();
+        fs::write(dir.join("syntaxes/foo.wasm"), b"\0asm").unwrap();
+        fs::write(dir.join("syntaxes/foo.txt"), "").unwrap();
+        let manifest = |grammars: &str| {
+            format!(
+                "name = \"foo\"\nversion = \"0.1.0\"\ndisplay-name = \"Foo\"\n\
+                 author = \"lapce\"\ndescription = \"\"\n\
+                 repository = \"lapce/foo\"\ngrammars = [{grammars}]\n"
+            )
+        };
+
+        fs::write(
+            dir.join("plugin.toml"),
+            manifest("\"syntaxes/foo.tmLanguage.json\", \"syntaxes/foo.wasm\""),
+        )
+        .unwrap();
+        let plugin = load_plugin(&dir.join("plugin.toml")).unwrap();
+        let grammars = plugin.grammar_files();
+        assert_eq!(grammars.len(), 2);
+        assert!(grammars[0].0.is_absolute());
+        assert_eq!(grammars[0].1, GrammarFormat::TextMate);
+        assert_eq!(grammars[1].1, GrammarFormat::TreeSitter);
+
+        for grammar in ["syntaxes/bar.wasm", "syntaxes/foo.txt", "syntaxes"] {
+            fs::write(dir.join("plugin.toml"), manifest(&format!("\"{grammar}\"")))
+                .unwrap();
+            assert!(load_plugin(&dir.join("plugin.toml")).is_err());
+        }
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_plugin_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-plugin-file-{}", std::process::id()));
+        let path = plugin_file(&dir, "syntaxes/nested/foo.wasm").unwrap();
+        assert_eq!(path, dir.join("syntaxes/nested/foo.wasm"));
+        assert!(dir.join("syntaxes/nested").is_dir());
+        for outside in ["../foo.wasm", "syntaxes/../../foo.wasm", "/foo.wasm"] {
+            assert!(plugin_file(&dir, outside).is_err());
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_wedged_plugin_is_interrupted() {
+        // a plugin whose `initialize` never returns
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "initialize")
+                    (loop $forever (br $forever))))"#,
+        )
+        .unwrap();
+        let plugin: PluginDescription = toml::from_str(&format!(
+            "name = \"wedged\"\nversion = \"0.1.0\"\ndisplay-name = \"Wedged\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/wedged\"\ninitialize-timeout = 1\n\
+             wasm = \"data:application/wasm;base64,{}\"\n",
+            base64::encode(wasm)
+        ))
+        .unwrap();
+        let plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            configuration: Some(json!({})),
+            ..plugin
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        {
+            let mut plugins = dispatcher.plugins.lock();
+            let (instance, tx) =
+                plugins.start_plugin(dispatcher.clone(), plugin).unwrap();
+            plugins.plugins.insert("wedged".to_string(), instance);
+            plugins.senders.insert("wedged".to_string(), tx);
+        }
+
+        let error = receiver
+            .iter()
+            .find(|msg| msg["method"] == "plugin_error")
+            .unwrap();
+        assert_eq!(
+            error["params"]["error"],
+            "initialize didn't finish within 1 seconds, so it was stopped"
+        );
+        assert!(!dispatcher.plugins.lock().plugins.contains_key("wedged"));
+    }
+
+    #[test]
+    fn test_stuck_plugin_is_interrupted() {
+        // a plugin whose `execute_command` never returns
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "initialize"))
+                (func (export "execute_command")
+                    (loop $forever (br $forever))))"#,
+        )
+        .unwrap();
+        let plugin: PluginDescription = toml::from_str(&format!(
+            "name = \"stuck\"\nversion = \"0.1.0\"\ndisplay-name = \"Stuck\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/stuck\"\n\
+             wasm = \"data:application/wasm;base64,{}\"\n",
+            base64::encode(wasm)
+        ))
+        .unwrap();
+        let plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            configuration: Some(json!({})),
+            ..plugin
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let usage = {
+            let mut plugins = dispatcher.plugins.lock();
+            let (instance, tx) =
+                plugins.start_plugin(dispatcher.clone(), plugin).unwrap();
+            tx.send(PluginTransmissionMessage::ExecuteCommand(
+                "stuck.loop".to_string(),
+            ))
+            .unwrap();
+            let usage = instance.env.usage.clone();
+            plugins.plugins.insert("stuck".to_string(), instance);
+            plugins.senders.insert("stuck".to_string(), tx);
+            usage
+        };
+
+        let start = Instant::now();
+        while usage.handling.lock().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // not stuck for long enough yet
+        dispatcher
+            .plugins
+            .lock()
+            .interrupt_stuck(Duration::from_secs(60));
+        assert!(usage.handling.lock().is_some());
+
+        thread::sleep(Duration::from_millis(100));
+        dispatcher
+            .plugins
+            .lock()
+            .interrupt_stuck(Duration::from_millis(50));
+        let error = receiver
+            .iter()
+            .find(|msg| msg["method"] == "plugin_error")
+            .unwrap();
+        assert_eq!(
+            error["params"]["error"],
+            "execute_command got stuck, so it was stopped"
+        );
+        assert!(usage.handling.lock().is_none());
+    }
+
+    #[test]
+    fn test_stuck_transform_is_interrupted() {
+        // a plugin whose `transform_completion` never returns
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "initialize"))
+                (func (export "transform_completion")
+                    (loop $forever (br $forever))))"#,
+        )
+        .unwrap();
+        let plugin: PluginDescription = toml::from_str(&format!(
+            "name = \"looping\"\nversion = \"0.1.0\"\ndisplay-name = \"Looping\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/looping\"\n\
+             wasm = \"data:application/wasm;base64,{}\"\n",
+            base64::encode(wasm)
+        ))
+        .unwrap();
+        let plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            configuration: Some(json!({})),
+            ..plugin
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        {
+            let mut plugins = dispatcher.plugins.lock();
+            let (instance, tx) =
+                plugins.start_plugin(dispatcher.clone(), plugin).unwrap();
+            plugins.plugins.insert("looping".to_string(), instance);
+            plugins.senders.insert("looping".to_string(), tx);
+            plugins
+                .completion_transformers
+                .insert("looping".to_string(), 0);
+        }
+
+        let response = json!({ "isIncomplete": false, "items": [] });
+        // the second one is transformed once the first one was stopped
+        for _ in 0..2 {
+            assert_eq!(
+                transform_completion(&dispatcher, "rust", response.clone()),
+                response
+            );
+            let error = receiver
+                .iter()
+                .find(|msg| msg["method"] == "plugin_error")
+                .unwrap();
+            assert_eq!(
+                error["params"]["error"],
+                "transform_completion didn't finish within 500 ms, so it was \
+                 stopped"
+            );
+        }
+    }
+
+    #[test]
+    fn test_plugin_without_dir() {
+        let plugin: PluginDescription = toml::from_str(
+            "name = \"homeless\"\nversion = \"0.1.0\"\n\
+             display-name = \"Homeless\"\nauthor = \"lapce\"\n\
+             description = \"\"\nrepository = \"lapce/homeless\"\n\
+             wasm = \"homeless.wasm\"\n",
+        )
+        .unwrap();
+        assert_eq!(plugin.dir, None);
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let result = dispatcher
+            .plugins
+            .lock()
+            .start_plugin(dispatcher.clone(), plugin);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "plugin homeless has no valid directory"
+        );
+        let error = receiver
+            .iter()
+            .find(|msg| msg["method"] == "plugin_error")
+            .unwrap();
+        assert_eq!(error["params"]["plugin"], "homeless");
+        assert_eq!(error["params"]["error"], "has no valid directory");
+    }
+
+    #[test]
+    fn test_plugin_debug_output() {
+        // a plugin writing a line and a half to its stderr
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "hello\nwor")
+                (func (export "initialize")
+                    (i32.store (i32.const 0) (i32.const 16))
+                    (i32.store (i32.const 4) (i32.const 9))
+                    (drop (call $fd_write (i32.const 2)
+                        (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        )
+        .unwrap();
+        let plugin: PluginDescription = toml::from_str(&format!(
+            "name = \"chatty\"\nversion = \"0.1.0\"\ndisplay-name = \"Chatty\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/chatty\"\ndebug-output = true\n\
+             wasm = \"data:application/wasm;base64,{}\"\n",
+            base64::encode(wasm)
+        ))
+        .unwrap();
+        let plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            configuration: Some(json!({})),
+            ..plugin
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let (_instance, _tx) = dispatcher
+            .plugins
+            .lock()
+            .start_plugin(dispatcher.clone(), plugin)
+            .unwrap();
+
+        let log = receiver
+            .iter()
+            .find(|msg| msg["method"] == "plugin_log")
+            .unwrap();
+        assert_eq!(
+            log["params"],
+            json!({ "plugin": "chatty", "line": "hello" })
+        );
+        // the rest of the line waits for its end
+        thread::sleep(Duration::from_millis(100));
+        assert!(receiver.try_iter().all(|msg| msg["method"] != "plugin_log"));
+    }
+
+    #[test]
+    fn test_plugin_stats() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+                (memory (export "memory") 2)
+                (func (export "initialize")))"#,
+        )
+        .unwrap();
+        let plugin: PluginDescription = toml::from_str(&format!(
+            "name = \"idle\"\nversion = \"0.1.0\"\ndisplay-name = \"Idle\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/idle\"\n\
+             wasm = \"data:application/wasm;base64,{}\"\n",
+            base64::encode(wasm)
+        ))
+        .unwrap();
+        let plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            configuration: Some(json!({})),
+            ..plugin
+        };
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let (instance, _tx) = dispatcher
+            .plugins
+            .lock()
+            .start_plugin(dispatcher.clone(), plugin)
+            .unwrap();
+
+        let start = Instant::now();
+        while instance.stats().calls == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = instance.stats();
+        assert_eq!(stats.memory_pages, 2);
+        assert_eq!(stats.calls, 1);
+        // the plugin was given its info, and said nothing
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.messages_received, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plugin_processes() {
+        let plugin: PluginDescription = toml::from_str(
+            "name = \"runner\"\nversion = \"0.1.0\"\ndisplay-name = \"\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/runner\"\n",
+        )
+        .unwrap();
+        let mut plugin = PluginDescription {
+            dir: Some(std::env::temp_dir()),
+            ..plugin
+        };
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let (tx, rx) = mpsc::sync_channel(PLUGIN_QUEUE_CAPACITY);
+        dispatcher
+            .plugins
+            .lock()
+            .senders
+            .insert("runner".to_string(), tx);
+        let spawn = |plugin: &PluginDescription, script: &str| {
+            dispatcher.plugins.lock().spawn_process(
+                dispatcher.clone(),
+                plugin,
+                "sh",
+                &["-c".to_string(), script.to_string()],
+                &HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+            )
+        };
+
+        assert!(spawn(&plugin, "true").is_err());
+        plugin.spawn_processes = true;
+
+        let handle =
+            spawn(&plugin, "echo $GREETING; echo oops >&2; exit 3").unwrap();
+        let mut events = rx
+            .iter()
+            .map(|msg| match msg {
+                PluginTransmissionMessage::ProcessEvent(event) => event,
+                _ => panic!("not a process event"),
+            })
+            .take(3)
+            .collect::<Vec<_>>();
+        // the exit comes last, the lines can come in any order
+        assert_eq!(
+            events.pop(),
+            Some(ProcessEvent::Exited {
+                handle,
+                code: Some(3)
+            })
+        );
+        assert!(events.contains(&ProcessEvent::Stdout {
+            handle,
+            line: "hello".to_string()
+        }));
+        assert!(events.contains(&ProcessEvent::Stderr {
+            handle,
+            line: "oops".to_string()
+        }));
+        assert!(dispatcher.plugins.lock().processes["runner"].is_empty());
+
+        // exec'd, as a child of the shell would keep its output open
+        let killed = spawn(&plugin, "exec sleep 30").unwrap();
+        dispatcher
+            .plugins
+            .lock()
+            .kill_process("runner", killed)
+            .unwrap();
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok(PluginTransmissionMessage::ProcessEvent(
+                ProcessEvent::Exited { code: None, .. }
+            ))
+        ));
+
+        // unloading the plugin kills what it left running
+        let handle = spawn(&plugin, "exec sleep 30").unwrap();
+        let mut plugins = dispatcher.plugins.lock();
+        assert!(plugins.kill_process("other", handle).is_err());
+        plugins.remove_plugin_instance("runner");
+        assert!(!plugins.processes.contains_key("runner"));
+    }
+
+    #[test]
+    fn test_apply_plugin_config() {
+        let wasm = wasmer::wat2wasm(
+            br#"(module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "initialize")))"#,
+        )
+        .unwrap();
+        let plugin = |name: &str, wasm: Option<String>| {
+            let plugin: PluginDescription = toml::from_str(&format!(
+                "name = \"{name}\"\nversion = \"0.1.0\"\ndisplay-name = \"\"\n\
+                 author = \"lapce\"\ndescription = \"\"\n\
+                 repository = \"lapce/{name}\"\n"
+            ))
+            .unwrap();
+            PluginDescription {
+                wasm,
+                dir: Some(std::env::temp_dir()),
+                configuration: Some(json!({})),
+                ..plugin
+            }
+        };
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let mut catalog = PluginCatalog::new();
+        let wasm = format!("data:application/wasm;base64,{}", base64::encode(wasm));
+        catalog
+            .items
+            .insert("quiet".to_string(), plugin("quiet", Some(wasm)));
+        catalog
+            .items
+            .insert("crashy".to_string(), plugin("crashy", None));
+
+        let changes = catalog
+            .apply_config(dispatcher.clone(), r#"disabled = ["quiet", "gone"]"#)
+            .unwrap();
+        assert_eq!(changes.disabled, vec!["quiet"]);
+        assert!(changes.enabled.is_empty());
+        assert!(catalog.disabled.contains_key("quiet"));
+
+        // a file in the middle of being written is left alone
+        assert!(catalog
+            .apply_config(dispatcher.clone(), "disabled = [")
+            .is_err());
+        assert!(catalog.disabled.contains_key("quiet"));
+
+        // a plugin disabled for crashing isn't in the file to begin with
+        catalog.auto_disable("crashy");
+        let changes = catalog
+            .apply_config(dispatcher.clone(), "disabled = []")
+            .unwrap();
+        assert_eq!(changes.enabled, vec!["quiet"]);
+        assert!(changes.disabled.is_empty());
+        assert!(catalog.plugins.contains_key("quiet"));
+        assert_eq!(catalog.disabled.keys().collect::<Vec<_>>(), vec!["crashy"]);
+        let reports = catalog.plugin_reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].name, "crashy");
+        assert_eq!(reports[0].state, PluginState::AutoDisabled);
+        assert!(reports[0].stats.is_none());
+        assert_eq!(reports[1].name, "quiet");
+        assert!(reports[1].stats.is_some());
+
+        let changes = catalog.apply_config(dispatcher, "disabled = []").unwrap();
+        assert_eq!(changes, PluginConfigChanges::default());
+    }
+
+    #[test]
+    fn test_workspace_roots() {
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        assert_eq!(
+            workspace_roots(&dispatcher),
+            json!({ "root": null, "roots": [] })
+        );
+
+        let workspace = Path::new("/work");
+        dispatcher.lsp.lock().set_workspace_roots(
+            workspace,
+            &[PathBuf::from("app"), PathBuf::from("/shared")],
+        );
+        assert_eq!(
+            workspace_roots(&dispatcher),
+            json!({
+                "root": "/work",
+                "roots": ["/work", "/work/app", "/shared"],
+            })
+        );
+    }
+
+    fn wasi_env_with_stdout(output: &str) -> WasiEnv {
+        let mut stdout = Pipe::new();
+        stdout.write_all(output.as_bytes()).unwrap();
+        WasiState::new("Lapce")
+            .stdin(Box::new(Pipe::new()))
+            .stdout(Box::new(stdout))
+            .finalize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_wasi_read_object_invalid_output() {
+        let wasi_env = wasi_env_with_stdout("thread 'main' panicked at 'oops'");
+        let err = wasi_read_object::<PluginNotification>(
+            &wasi_env,
+            DEFAULT_MAX_MESSAGE_SIZE,
+        )
+        .unwrap_err();
+        let err = err.to_string();
+        assert!(err.starts_with("produced invalid output"));
+        assert!(err.ends_with("thread 'main' panicked at 'oops'"));
+    }
+
+    #[test]
+    fn test_wasi_read_object_truncates_snippet() {
+        let garbage = "x".repeat(INVALID_OUTPUT_SNIPPET_LEN * 2);
+        let wasi_env = wasi_env_with_stdout(&garbage);
+        let err = wasi_read_object::<Value>(&wasi_env, DEFAULT_MAX_MESSAGE_SIZE)
+            .unwrap_err()
+            .to_string();
+        let snippet = format!("{}...", "x".repeat(INVALID_OUTPUT_SNIPPET_LEN));
+        assert!(err.ends_with(&format!(": {snippet}")));
+    }
+
+    #[test]
+    fn test_wasi_read_object_too_large() {
+        let wasi_env = wasi_env_with_stdout(&"x".repeat(64));
+        let err = wasi_read_object::<Value>(&wasi_env, 16).unwrap_err();
+        let err = err.downcast::<MessageTooLarge>().unwrap();
+        assert_eq!((err.size, err.limit), (64, 16));
+        // the rest of the output doesn't stay behind for the next read
+        assert_eq!(wasi_read_string(&wasi_env, 16).unwrap(), "");
+    }
+
+    #[test]
+    fn test_read_object_unloads_plugin() {
+        let desc: PluginDescription = toml::from_str(
+            "name = \"flood\"\nversion = \"0.1.0\"\ndisplay-name = \"\"\n\
+             author = \"lapce\"\ndescription = \"\"\n\
+             repository = \"lapce/flood\"\n",
+        )
+        .unwrap();
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        dispatcher.max_message_size.store(16, Ordering::Relaxed);
+        let (tx, _rx) = mpsc::sync_channel(PLUGIN_QUEUE_CAPACITY);
+        dispatcher
+            .plugins
+            .lock()
+            .senders
+            .insert("flood".to_string(), tx);
+        let env = PluginEnv {
+            wasi_env: wasi_env_with_stdout(&"x".repeat(64)),
+            desc,
+            dispatcher: dispatcher.clone(),
+            usage: Arc::new(PluginUsage::default()),
+        };
+
+        let err = env.read_object::<Value>().unwrap_err();
+        assert!(err.is::<MessageTooLarge>());
+        assert!(!dispatcher.plugins.lock().senders.contains_key("flood"));
+    }
+
+    #[test]
+    fn test_wasi_read_object_valid_output() {
+        let wasi_env = wasi_env_with_stdout(
+            r#"{"method":"lock_file","params":{"path":"lock"}}"#,
+        );
+        let notification = wasi_read_object::<PluginNotification>(
+            &wasi_env,
+            DEFAULT_MAX_MESSAGE_SIZE,
+        )
+        .unwrap();
+        assert!(matches!(notification, PluginNotification::LockFile { .. }));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use lapce_rpc::proxy::QueueStats;
+use parking_lot::{Condvar, Mutex};
+
+/// What's done with a message sent while the queue is full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// The sender waits for room.
+    Block,
+    /// The oldest queued message of the same key is dropped to make room,
+    /// as the new one makes it useless, like the full text of a document
+    /// does the text it had before. The sender waits for room if there's
+    /// no such message.
+    DropOldest(String),
+}
+
+/// A queue of messages to send, holding at most `capacity` of them so that
+/// a peer that doesn't keep up slows down the senders rather than making
+/// the queue grow without end.
+pub struct BoundedQueue<T> {
+    state: Mutex<QueueState<T>>,
+    changed: Condvar,
+    capacity: usize,
+}
+
+struct QueueState<T> {
+    /// The messages with the key they were sent with, if any
+    items: VecDeque<(Option<String>, T)>,
+    closed: bool,
+    stats: QueueStats,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> BoundedQueue<T> {
+        BoundedQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+                stats: QueueStats {
+                    capacity,
+                    ..Default::default()
+                },
+            }),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Adds `item` to the back of the queue, making room for it as
+    /// `overflow` says if the queue is full. Fails once the queue is closed.
+    pub fn push(&self, item: T, overflow: Overflow) -> Result<()> {
+        let mut state = self.state.lock();
+        let key = match overflow {
+            Overflow::Block => None,
+            Overflow::DropOldest(key) => Some(key),
+        };
+        if state.items.len() >= self.capacity && !state.closed {
+            let oldest = key.as_ref().and_then(|key| {
+                state
+                    .items
+                    .iter()
+                    .position(|(queued, _)| queued.as_ref() == Some(key))
+            });
+            if let Some(oldest) = oldest {
+                state.items.remove(oldest);
+                state.stats.dropped += 1;
+            } else {
+                state.stats.blocked += 1;
+                while state.items.len() >= self.capacity && !state.closed {
+                    self.changed.wait(&mut state);
+                }
+            }
+        }
+        if state.closed {
+            return Err(anyhow!("the queue is closed"));
+        }
+        state.items.push_back((key, item));
+        state.stats.depth = state.items.len();
+        state.stats.max_depth = state.stats.max_depth.max(state.stats.depth);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Takes the message at the front of the queue, waiting for one if the
+    /// queue is empty. Gives `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some((_, item)) = state.items.pop_front() {
+                state.stats.depth = state.items.len();
+                self.changed.notify_all();
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            self.changed.wait(&mut state);
+        }
+    }
+
+    /// Stops taking messages. The ones queued can still be taken, and the
+    /// senders waiting for room give up.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.state.lock().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_bounded_queue() {
+        let queue = BoundedQueue::new(2);
+        queue.push(1, Overflow::Block).unwrap();
+        queue
+            .push(2, Overflow::DropOldest("a".to_string()))
+            .unwrap();
+        // the queue is full, so the older message of the key is dropped
+        queue
+            .push(3, Overflow::DropOldest("a".to_string()))
+            .unwrap();
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                depth: 2,
+                max_depth: 2,
+                capacity: 2,
+                blocked: 0,
+                dropped: 1,
+            }
+        );
+
+        // a message of another key waits for room
+        let queue = Arc::new(queue);
+        let sender = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                queue
+                    .push(4, Overflow::DropOldest("b".to_string()))
+                    .unwrap()
+            })
+        };
+        while queue.stats().blocked == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(queue.pop(), Some(1));
+        sender.join().unwrap();
+
+        queue.close();
+        assert!(queue.push(5, Overflow::Block).is_err());
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+}
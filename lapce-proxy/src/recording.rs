@@ -0,0 +1,346 @@
+//! Recordings of the traffic of language servers, which are replayed in
+//! place of the servers to reproduce what users saw with the servers and
+//! projects they have.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use lapce_rpc::core::TraceDirection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    lsp::{prepare_lsp_json, read_message, HeaderWatch, ServerConnection},
+    pipe::{ChannelReader, ChannelWriter},
+};
+
+/// The environment variable naming a directory to record the traffic of
+/// each language server to, as `<server id>.jsonl`, so that the instances
+/// of a server for other languages or roots each have theirs. Unlike traces,
+/// the recordings hold the documents as they were sent.
+pub const RECORD_ENV: &str = "LAPCE_LSP_RECORD";
+/// The environment variable naming a directory of recordings, the servers
+/// with one being replayed from it rather than started.
+pub const REPLAY_ENV: &str = "LAPCE_LSP_REPLAY";
+
+/// A message exchanged with a server, a line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Milliseconds since the server was started
+    pub elapsed_ms: u64,
+    pub direction: TraceDirection,
+    pub message: Value,
+}
+
+/// Writes the messages exchanged with a server to its recording.
+pub struct LspRecorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl LspRecorder {
+    /// Starts recording `server` if `RECORD_ENV` is set.
+    pub fn from_env(server: &str) -> Option<LspRecorder> {
+        let dir = std::env::var_os(RECORD_ENV)?;
+        let path = recording_path(Path::new(&dir), server);
+        LspRecorder::create(&path)
+            .map_err(|err| log::error!("can't record {server} to {path:?}: {err}"))
+            .ok()
+    }
+
+    /// Starts a recording at `path`, in place of the one there.
+    pub fn create(path: &Path) -> Result<LspRecorder> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(LspRecorder {
+            file: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        direction: TraceDirection,
+        message: &Value,
+    ) -> Result<()> {
+        let recorded = RecordedMessage {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            direction,
+            message: message.clone(),
+        };
+        serde_json::to_writer(&mut self.file, &recorded)?;
+        self.file.write_all(b"\n")?;
+        // what led to a crash of the proxy is kept
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// The recording of the server with the id `server`, the characters a file
+/// name can't have, like the separators of its path, replaced by `_`.
+fn recording_path(dir: &Path, server: &str) -> PathBuf {
+    let name = server
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ',') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    dir.join(format!("{name}.jsonl"))
+}
+
+/// Reads the messages of the recording at `path`.
+pub fn load_recording(path: &Path) -> Result<Vec<RecordedMessage>> {
+    let mut messages = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            messages.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(messages)
+}
+
+/// A server sending what a recorded one did, once the client sent what it
+/// had been sent before. The client is expected to send the messages of
+/// the recording in the same order, what else it sends is skipped.
+pub struct ReplayServer {
+    messages: Vec<RecordedMessage>,
+    /// Take as long to answer as the recorded server did
+    realtime: bool,
+}
+
+impl ReplayServer {
+    pub fn new(messages: Vec<RecordedMessage>) -> ReplayServer {
+        ReplayServer {
+            messages,
+            realtime: true,
+        }
+    }
+
+    /// The replay of `server`, if there's a recording of it in the
+    /// directory of `REPLAY_ENV`.
+    pub fn from_env(server: &str) -> Option<ReplayServer> {
+        let dir = std::env::var_os(REPLAY_ENV)?;
+        let path = recording_path(Path::new(&dir), server);
+        if !path.exists() {
+            return None;
+        }
+        load_recording(&path)
+            .map(ReplayServer::new)
+            .map_err(|err| log::error!("can't replay {path:?}: {err}"))
+            .ok()
+    }
+
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Starts the replay, giving the connection a client talks to it over.
+    pub fn start(self) -> ServerConnection {
+        let (client_tx, server_rx) = crossbeam_channel::unbounded();
+        let (server_tx, client_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            self.replay(
+                BufReader::new(ChannelReader::new(server_rx)),
+                ChannelWriter(server_tx),
+            )
+        });
+        ServerConnection {
+            writer: Box::new(ChannelWriter(client_tx)),
+            reader: Box::new(ChannelReader::new(client_rx)),
+            stderr: None,
+            process: None,
+        }
+    }
+
+    fn replay(
+        self,
+        mut reader: BufReader<ChannelReader>,
+        mut writer: ChannelWriter,
+    ) {
+        let watch = HeaderWatch::default();
+        // the ids the client gave the recorded requests, by their recorded id
+        let mut ids = HashMap::new();
+        // when the recording had the client's last message, and when the
+        // client sent it now
+        let mut anchor = (0, Instant::now());
+        for recorded in self.messages {
+            let mut message = recorded.message;
+            let method = message["method"].as_str().map(|m| m.to_string());
+            match recorded.direction {
+                TraceDirection::Sent => {
+                    // the answers to the server's requests aren't waited for
+                    let method = match method {
+                        Some(method) => method,
+                        None => continue,
+                    };
+                    loop {
+                        let sent =
+                            match read_message(&mut reader, usize::MAX, &watch) {
+                                Ok(sent) => sent,
+                                Err(_) => return,
+                            };
+                        let sent: Value = match serde_json::from_str(&sent) {
+                            Ok(sent) => sent,
+                            Err(_) => continue,
+                        };
+                        if sent["method"] == method.as_str() {
+                            if let (Some(recorded_id), Some(id)) =
+                                (message.get("id"), sent.get("id"))
+                            {
+                                ids.insert(recorded_id.to_string(), id.clone());
+                            }
+                            break;
+                        }
+                    }
+                    anchor = (recorded.elapsed_ms, Instant::now());
+                }
+                TraceDirection::Received => {
+                    if method.is_none() {
+                        // a response, given the id of the client's request
+                        let id = message
+                            .get("id")
+                            .and_then(|id| ids.remove(&id.to_string()));
+                        match id {
+                            Some(id) => message["id"] = id,
+                            None => continue,
+                        }
+                    }
+                    if self.realtime {
+                        let after = recorded.elapsed_ms.saturating_sub(anchor.0);
+                        let due = anchor.1 + Duration::from_millis(after);
+                        if let Some(wait) =
+                            due.checked_duration_since(Instant::now())
+                        {
+                            thread::sleep(wait);
+                        }
+                    }
+                    let message = match prepare_lsp_json(&message) {
+                        Ok(message) => message,
+                        Err(_) => continue,
+                    };
+                    if writer.write_all(message.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        // past the end of the recording, the client isn't answered anymore
+        while read_message(&mut reader, usize::MAX, &watch).is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use lsp_types::{Position, Url};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        dispatch::Dispatcher,
+        lsp::{LspClient, LspServerSpec, ServerConfig},
+    };
+
+    #[test]
+    fn test_replay_recording() {
+        let uri = Url::from_file_path(std::env::temp_dir().join("main.rs")).unwrap();
+        let hover = json!({ "contents": "fn main()" });
+        // the ids the recorded client gave its requests aren't the ones the
+        // replayed client gives them
+        let session = [
+            (
+                TraceDirection::Sent,
+                json!({ "jsonrpc": "2.0", "id": 7, "method": "initialize" }),
+            ),
+            (
+                TraceDirection::Received,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 7,
+                    "result": { "capabilities": { "hoverProvider": true } },
+                }),
+            ),
+            (
+                TraceDirection::Sent,
+                json!({ "jsonrpc": "2.0", "method": "initialized" }),
+            ),
+            (
+                TraceDirection::Sent,
+                json!({ "jsonrpc": "2.0", "id": 8, "method": "textDocument/hover" }),
+            ),
+            (
+                TraceDirection::Received,
+                json!({ "jsonrpc": "2.0", "id": 8, "result": hover }),
+            ),
+        ];
+        let path = std::env::temp_dir()
+            .join(format!("lapce-recording-{}", std::process::id()))
+            .join("fake.jsonl");
+        let mut recorder = LspRecorder::create(&path).unwrap();
+        for (direction, message) in &session {
+            recorder.record(*direction, message).unwrap();
+        }
+        let messages = load_recording(&path).unwrap();
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| (m.direction, m.message.clone()))
+                .collect::<Vec<_>>(),
+            session.to_vec()
+        );
+
+        let (sender, _receiver) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        *dispatcher.workspace.lock() = Some(std::env::temp_dir());
+        let spec: LspServerSpec = serde_json::from_value(json!({
+            "exec_path": "fake",
+            "language_id": "rust",
+            "options": null,
+            "system_lsp": true,
+        }))
+        .unwrap();
+        let client = LspClient::start(
+            spec.resolve(Path::new("/")).unwrap(),
+            ServerConfig::default(),
+            dispatcher,
+            ReplayServer::new(messages).realtime(false).start(),
+        );
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        client.request_hover(uri, Position::new(0, 3), move |_, result| {
+            let _ = tx.send(result);
+        });
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap(),
+            hover
+        );
+    }
+
+    #[test]
+    fn test_recording_path() {
+        let dir = Path::new("recordings");
+        assert_eq!(
+            recording_path(dir, "rust:/usr/bin/rust-analyzer:/home/me/a"),
+            dir.join("rust__usr_bin_rust-analyzer__home_me_a.jsonl")
+        );
+        assert_ne!(
+            recording_path(dir, "rust:/usr/bin/rust-analyzer:/home/me/a"),
+            recording_path(dir, "rust:/usr/bin/rust-analyzer:/home/me/b")
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use reqwest::{
+    blocking::{Client, Response},
+    header::{HeaderMap, HeaderName, HeaderValue},
+    redirect::{Attempt, Policy},
+};
+
+/// The file in the config directory with the headers to send plugin
+/// registries, kept out of the settings and the plugins' manifests as it
+/// holds their tokens. Each table is named after the URL of a registry:
+///
+/// ```toml
+/// ["https://plugins.example.com/"]
+/// Authorization = "Bearer ..."
+/// ```
+pub const REGISTRIES_FILE: &str = "registries.toml";
+
+/// The headers sent with the requests to each plugin registry, by the URL
+/// the registry's URLs start with.
+#[derive(Default)]
+pub struct RegistryHeaders {
+    registries: Vec<(String, HeaderMap)>,
+}
+
+impl RegistryHeaders {
+    /// The headers of the registries in `REGISTRIES_FILE`. A file that
+    /// can't be used is logged and taken as empty, without its content.
+    pub fn load() -> RegistryHeaders {
+        let path = match registries_path() {
+            Some(path) if path.exists() => path,
+            _ => return RegistryHeaders::default(),
+        };
+        RegistryHeaders::read(&path).unwrap_or_else(|err| {
+            log::error!("can't use the plugin registries of {path:?}: {err}");
+            RegistryHeaders::default()
+        })
+    }
+
+    fn read(path: &Path) -> Result<RegistryHeaders> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+                return Err(anyhow!(
+                    "other users can access it, make it private with `chmod 600`"
+                ));
+            }
+        }
+        RegistryHeaders::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> Result<RegistryHeaders> {
+        let registries: HashMap<String, HashMap<String, String>> =
+            toml::from_str(content)?;
+        let mut registries = registries
+            .into_iter()
+            .map(|(url, headers)| {
+                let headers = headers
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let name = HeaderName::try_from(name.as_str())
+                            .map_err(|_| anyhow!("invalid header name {name}"))?;
+                        // the value is a secret, so it's left out of the error
+                        let mut value = HeaderValue::try_from(value)
+                            .map_err(|_| anyhow!("invalid value of {name}"))?;
+                        value.set_sensitive(true);
+                        Ok((name, value))
+                    })
+                    .collect::<Result<HeaderMap>>()?;
+                Ok((url, headers))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // the most specific URL is tried first
+        registries.sort_by_key(|(url, _)| Reverse(url.len()));
+        Ok(RegistryHeaders { registries })
+    }
+
+    /// The headers of the registry serving `url`, if any.
+    pub fn for_url(&self, url: &str) -> Option<&HeaderMap> {
+        self.registries
+            .iter()
+            .find(|(registry, _)| serves(registry, url))
+            .map(|(_, headers)| headers)
+    }
+}
+
+impl fmt::Debug for RegistryHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.registries
+                    .iter()
+                    .map(|(url, headers)| (url, headers.keys().collect::<Vec<_>>())),
+            )
+            .finish()
+    }
+}
+
+fn registries_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.config_dir().join(REGISTRIES_FILE))
+}
+
+/// Whether `url` is one of the registry of the URL `registry`, which it
+/// starts with, up to a path segment.
+fn serves(registry: &str, url: &str) -> bool {
+    match url.strip_prefix(registry) {
+        Some(rest) => {
+            registry.ends_with('/')
+                || rest.is_empty()
+                || rest.starts_with(&['/', '?', '#'][..])
+        }
+        None => false,
+    }
+}
+
+/// Gets `url` with the headers of its registry. The headers aren't sent
+/// along when the registry redirects to another host.
+pub fn get(url: &str) -> Result<Response> {
+    let headers = RegistryHeaders::load();
+    let client = match headers.for_url(url) {
+        Some(headers) => Client::builder()
+            .default_headers(headers.clone())
+            .redirect(Policy::custom(same_host))
+            .build()?,
+        None => Client::new(),
+    };
+    Ok(client.get(url).send()?)
+}
+
+fn same_host(attempt: Attempt) -> reqwest::redirect::Action {
+    let same = attempt
+        .previous()
+        .first()
+        .map(|first| {
+            first.scheme() == attempt.url().scheme()
+                && first.host_str() == attempt.url().host_str()
+                && first.port_or_known_default()
+                    == attempt.url().port_or_known_default()
+        })
+        .unwrap_or(false);
+    if !same {
+        let host = attempt.url().host_str().unwrap_or_default().to_string();
+        attempt.error(anyhow!("the registry redirected to another host, {host}"))
+    } else if attempt.previous().len() > 10 {
+        attempt.error(anyhow!("too many redirects"))
+    } else {
+        attempt.follow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_headers() {
+        let headers = RegistryHeaders::parse(
+            r#"
+            ["https://plugins.example.com"]
+            Authorization = "Bearer secret"
+
+            ["https://plugins.example.com/team/"]
+            Authorization = "Bearer team-secret"
+            "#,
+        )
+        .unwrap();
+
+        let authorization = |url: &str| {
+            headers
+                .for_url(url)
+                .map(|headers| headers["authorization"].to_str().unwrap())
+        };
+        assert_eq!(
+            authorization("https://plugins.example.com/a/plugin.toml"),
+            Some("Bearer secret")
+        );
+        assert_eq!(
+            authorization("https://plugins.example.com/team/plugin.toml"),
+            Some("Bearer team-secret")
+        );
+        assert_eq!(authorization("https://plugins.example.com.evil.org/"), None);
+        assert!(!format!("{headers:?}").contains("secret"));
+
+        assert!(RegistryHeaders::parse(
+            r#"["https://plugins.example.com"]
+            "bad header" = "secret""#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,434 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Receiver;
+use lapce_rpc::{core::CoreNotification, proxy::PrepareSaveResponse};
+use lsp_types::TextEdit;
+use serde_json::Value;
+use xi_rope::Rope;
+
+use crate::{
+    buffer::Buffer,
+    dispatch::Dispatcher,
+    formatter::FormatterSpec,
+    lsp::{LspCatalog, LspClient},
+};
+
+/// How long a stage of getting a document ready to be saved may take before
+/// it's skipped
+const SAVE_STAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Formatting {
+    Server(Arc<LspClient>),
+    External(FormatterSpec),
+}
+
+/// What a document goes through before it's saved: the edits the servers
+/// want made with `willSaveWaitUntil`, one server after the other, then
+/// formatting. Each stage works on the text the stages before it left, which
+/// the servers are given for the time of the pipeline. A stage that fails
+/// is skipped, as the document is saved either way.
+pub struct SavePipeline {
+    /// The document at the revision being saved
+    buffer: Buffer,
+    edit_servers: Vec<Arc<LspClient>>,
+    formatting: Option<Formatting>,
+}
+
+impl SavePipeline {
+    /// The pipeline of `buffer`, with the stages the user turned on for its
+    /// language. `format` is whether to format the documents of languages
+    /// the user didn't choose for.
+    pub fn new(lsp: &LspCatalog, buffer: &Buffer, format: bool) -> SavePipeline {
+        let stages = lsp
+            .save_stages
+            .get(&buffer.language_id)
+            .copied()
+            .unwrap_or_default();
+        let edit_servers = if stages.will_save_wait_until.unwrap_or(true) {
+            lsp.buffer_clients(buffer)
+                .into_iter()
+                .filter(|client| client.edits_before_save())
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let formatting = if stages.format.unwrap_or(format) {
+            lsp.formatting_server(buffer, None)
+                .map(|client| Formatting::Server(client.clone()))
+                .or_else(|| {
+                    lsp.formatter(&buffer.language_id).map(Formatting::External)
+                })
+        } else {
+            None
+        };
+        SavePipeline {
+            buffer: buffer.clone(),
+            edit_servers,
+            formatting,
+        }
+    }
+
+    /// Runs the stages, giving back the text to save if they changed it.
+    /// The servers are given the document as it's in the buffer again
+    /// afterwards.
+    pub fn run(self, dispatcher: &Dispatcher) -> Result<PrepareSaveResponse> {
+        let mut draft = Draft::new(self.buffer.clone());
+
+        for client in self.edit_servers.iter() {
+            draft.give_to(client);
+            let uri = client.get_uri(&draft.buffer);
+            let (tx, rx) = crossbeam_channel::bounded(1);
+            client.request_will_save_wait_until(uri, move |_, result| {
+                let _ = tx.send(result);
+            });
+            if let Err(err) =
+                wait_for_edits(&rx).and_then(|edits| draft.apply(edits))
+            {
+                log::warn!(
+                    "skipped the edits of {} before saving: {err}",
+                    client.name()
+                );
+            }
+        }
+
+        match self.formatting.as_ref() {
+            Some(Formatting::Server(client)) => {
+                draft.give_to(client);
+                let uri = client.get_uri(&draft.buffer);
+                let (tx, rx) = crossbeam_channel::bounded(1);
+                client.request_document_formatting(uri, move |_, result| {
+                    let _ = tx.send(result);
+                });
+                if let Err(err) =
+                    wait_for_edits(&rx).and_then(|edits| draft.apply(edits))
+                {
+                    log::warn!("skipped formatting before saving: {err}");
+                }
+            }
+            Some(Formatting::External(formatter)) => {
+                let workspace = dispatcher.workspace.lock().clone();
+                match formatter.format(
+                    workspace,
+                    &draft.buffer.path,
+                    draft.buffer.get_document(),
+                ) {
+                    Ok(formatted) => draft.replace(formatted),
+                    Err(err) => {
+                        dispatcher.send_rpc_notification(
+                            CoreNotification::FormatterFailed {
+                                path: draft.buffer.path.clone(),
+                                formatter: formatter.name(),
+                                error: err.to_string(),
+                            },
+                        );
+                    }
+                }
+            }
+            None => {}
+        }
+
+        let buffers = dispatcher.buffers.lock();
+        let buffer = buffers
+            .get(&self.buffer.id)
+            .ok_or_else(|| anyhow!("the document was closed"))?;
+        for client in draft.given_servers() {
+            client.end_draft(buffer);
+        }
+        if buffer.rev != self.buffer.rev {
+            return Err(anyhow!("the document changed while it was prepared"));
+        }
+
+        let text = draft.buffer.get_document();
+        let changed = draft.generation > 0 && text != self.buffer.get_document();
+        Ok(PrepareSaveResponse {
+            text: if changed { Some(text) } else { None },
+        })
+    }
+}
+
+/// The text of a document being prepared for saving.
+struct Draft {
+    buffer: Buffer,
+    /// Bumped each time the text changes
+    generation: u64,
+    /// The servers given the text, with the generation they were given
+    given: Vec<(Arc<LspClient>, u64)>,
+}
+
+impl Draft {
+    fn new(buffer: Buffer) -> Draft {
+        Draft {
+            buffer,
+            generation: 0,
+            given: Vec::new(),
+        }
+    }
+
+    /// Gives `client` the text, if it doesn't have it already.
+    fn give_to(&mut self, client: &Arc<LspClient>) {
+        let given = self
+            .given
+            .iter()
+            .find(|(given, _)| Arc::ptr_eq(given, client))
+            .map(|(_, generation)| *generation)
+            .unwrap_or(0);
+        if given == self.generation {
+            return;
+        }
+        client.send_draft(&self.buffer);
+        self.given.retain(|(given, _)| !Arc::ptr_eq(given, client));
+        self.given.push((client.clone(), self.generation));
+    }
+
+    fn apply(&mut self, edits: Vec<TextEdit>) -> Result<()> {
+        if !edits.is_empty() {
+            self.buffer.rope = apply_text_edits(&self.buffer, edits)?;
+            self.generation += 1;
+        }
+        Ok(())
+    }
+
+    fn replace(&mut self, text: String) {
+        self.buffer.rope = Rope::from(text);
+        self.generation += 1;
+    }
+
+    /// The servers given a text other than the one of the buffer.
+    fn given_servers(&self) -> impl Iterator<Item = &Arc<LspClient>> {
+        self.given
+            .iter()
+            .filter(|(_, generation)| *generation > 0)
+            .map(|(client, _)| client)
+    }
+}
+
+fn wait_for_edits(receiver: &Receiver<Result<Value>>) -> Result<Vec<TextEdit>> {
+    let result = receiver
+        .recv_timeout(SAVE_STAGE_TIMEOUT)
+        .map_err(|_| anyhow!("the server didn't answer in time"))??;
+    Ok(serde_json::from_value::<Option<Vec<TextEdit>>>(result)?.unwrap_or_default())
+}
+
+/// The text of `buffer` with `edits` made, which are all relative to it.
+/// Edits inserting at the same place are made in the order they're given.
+pub fn apply_text_edits(buffer: &Buffer, edits: Vec<TextEdit>) -> Result<Rope> {
+    let mut edits = edits
+        .into_iter()
+        .map(|edit| {
+            let start = buffer.offset_of_position(&edit.range.start);
+            let end = buffer.offset_of_position(&edit.range.end);
+            match (start, end) {
+                (Some(start), Some(end)) if start <= end => {
+                    Ok((start, end, edit.new_text))
+                }
+                _ => Err(anyhow!("invalid edit range {:?}", edit.range)),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    edits.sort_by_key(|(start, end, _)| (*start, *end));
+    if edits.windows(2).any(|edits| edits[0].1 > edits[1].0) {
+        return Err(anyhow!("overlapping edits"));
+    }
+
+    let mut rope = buffer.rope.clone();
+    for (start, end, text) in edits.into_iter().rev() {
+        rope.edit(start..end, text.as_str());
+    }
+    Ok(rope)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use lapce_rpc::buffer::BufferId;
+    use lsp_types::{
+        OneOf, Position, Range, ServerCapabilities, TextDocumentContentChangeEvent,
+        TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+        Url,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        fake_lsp::{fake_spec, FakeLspServer},
+        lsp::ServerConfig,
+    };
+
+    fn edit(start: (u32, u32), end: (u32, u32), text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(
+                Position::new(start.0, start.1),
+                Position::new(end.0, end.1),
+            ),
+            new_text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_text_edits() {
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main(){\nlet a=1;\n}\n"),
+            path: PathBuf::from("/main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+
+        let rope = apply_text_edits(
+            &buffer,
+            vec![
+                edit((1, 0), (1, 0), "    "),
+                edit((0, 9), (0, 9), " "),
+                edit((1, 5), (1, 6), " = "),
+                // inserted after the indent given first
+                edit((1, 0), (1, 0), "// "),
+            ],
+        )
+        .unwrap();
+        assert_eq!(rope.to_string(), "fn main() {\n    // let a = 1;\n}\n");
+
+        assert!(apply_text_edits(
+            &buffer,
+            vec![edit((0, 0), (0, 5), ""), edit((0, 3), (0, 7), "")],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_save_pipeline() {
+        let (connection, received) = FakeLspServer::new()
+            .capabilities(ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        will_save_wait_until: Some(true),
+                        ..Default::default()
+                    },
+                )),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                ..Default::default()
+            })
+            .respond(
+                "textDocument/willSaveWaitUntil",
+                json!([edit((0, 0), (0, 0), "// saved\n")]),
+            )
+            // made against the text the edits before saving left
+            .respond(
+                "textDocument/formatting",
+                json!([edit((1, 9), (1, 9), " ")]),
+            )
+            .start();
+        let (sender, _core) = crossbeam_channel::unbounded();
+        let dispatcher = Dispatcher::without_plugins(sender);
+        let workspace =
+            std::env::temp_dir().join(format!("lapce-save-{}", std::process::id()));
+        fs::create_dir_all(&workspace).unwrap();
+        *dispatcher.workspace.lock() = Some(workspace.clone());
+        let client = LspClient::start(
+            fake_spec(),
+            ServerConfig::default(),
+            dispatcher.clone(),
+            connection,
+        );
+        let messages = || {
+            std::iter::from_fn(|| {
+                received.recv_timeout(Duration::from_millis(200)).ok()
+            })
+            .filter(|msg| msg["method"] != "$/cancelRequest")
+            .collect::<Vec<_>>()
+        };
+        messages();
+        assert!(client.edits_before_save());
+
+        let mut buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn main(){}\n"),
+            path: workspace.join("main.rs"),
+            rev: 1,
+            mod_time: None,
+        };
+        let uri = Url::from_file_path(&buffer.path).unwrap();
+        dispatcher.buffers.lock().insert(buffer.id, buffer.clone());
+        dispatcher.lsp.lock().insert_client(client.clone());
+        client.send_did_open(&buffer.id, uri, "rust", buffer.get_document(), 1);
+
+        let pipeline = SavePipeline::new(&dispatcher.lsp.lock(), &buffer, true);
+        let text = pipeline.run(&dispatcher).unwrap().text.unwrap();
+        assert_eq!(text, "// saved\nfn main() {}\n");
+
+        // core puts the text in the buffer, then has it written
+        buffer.rope = Rope::from(&text);
+        buffer.rev = 2;
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+            range_length: None,
+            text: "// saved\n".to_string(),
+        };
+        client.update(&buffer, &change, 2);
+        buffer.save(2).unwrap();
+        dispatcher.lsp.lock().save_buffer(&buffer, &workspace);
+
+        let sent = messages();
+        let methods = sent
+            .iter()
+            .map(|msg| msg["method"].as_str().unwrap_or("response"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            [
+                "textDocument/didOpen",
+                "textDocument/willSaveWaitUntil",
+                "textDocument/didChange",
+                "textDocument/formatting",
+                "textDocument/didChange",
+                "textDocument/didChange",
+                "textDocument/didSave",
+            ]
+        );
+        let changes = sent
+            .iter()
+            .filter(|msg| msg["method"] == "textDocument/didChange")
+            .map(|msg| &msg["params"])
+            .collect::<Vec<_>>();
+        // the formatting server is given the draft, then the buffer back,
+        // before the edit core makes is sent against it
+        assert_eq!(
+            changes[0]["contentChanges"][0]["text"],
+            "// saved\nfn main(){}\n"
+        );
+        assert_eq!(changes[1]["contentChanges"][0]["text"], "fn main(){}\n");
+        assert_eq!(changes[2]["contentChanges"][0], json!(change));
+        let versions = changes
+            .iter()
+            .map(|change| change["textDocument"]["version"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(versions
+            .windows(2)
+            .all(|versions| versions[0] < versions[1]));
+        assert_eq!(fs::read_to_string(&buffer.path).unwrap(), text);
+
+        // an edit made while a server has a draft is sent whole, as the
+        // server doesn't have the text it's made against
+        client.send_draft(&buffer);
+        buffer.rope = Rope::from(format!("{text}\n"));
+        buffer.rev = 3;
+        client.update(&buffer, &change, 3);
+        let changes = messages();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[1]["params"]["contentChanges"][0]["range"],
+            json!(null)
+        );
+        client.end_draft(&buffer);
+        assert_eq!(messages().len(), 1);
+
+        dispatcher.lsp.lock().stop();
+        let _ = fs::remove_dir_all(&workspace);
+    }
+}
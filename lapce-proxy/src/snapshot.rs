@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use ignore::WalkBuilder;
+use lsp_types::{SymbolInformation, Url};
+use serde::{Deserialize, Serialize};
+
+/// Bumped when what's in a snapshot changes, as older ones can't be used
+const SNAPSHOT_VERSION: u32 = 2;
+/// Snapshots older than this are from too long ago to be trusted
+const MAX_SNAPSHOT_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What the proxy knew about a workspace when it was last persisted, for the
+/// next proxy of the workspace to start from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProxySnapshot {
+    version: u32,
+    workspace: PathBuf,
+    /// When the snapshot was taken, in milliseconds since the epoch
+    saved_at: u64,
+    /// The plugins disabled for crashing, which would crash again
+    pub auto_disabled: Vec<String>,
+    /// The symbols of the whole workspace each server gave, by server id
+    pub workspace_symbols: HashMap<String, Vec<SymbolInformation>>,
+    /// The last version each server was given of the documents open in it,
+    /// by server id, which the versions of the documents go on from
+    pub document_versions: HashMap<String, HashMap<Url, i32>>,
+    /// The servers shut down for being idle, by id, which are started once
+    /// they're needed rather than right away
+    pub idle_servers: Vec<String>,
+}
+
+impl ProxySnapshot {
+    /// An empty snapshot of `workspace`, taken now.
+    pub fn new(workspace: &Path) -> ProxySnapshot {
+        ProxySnapshot {
+            version: SNAPSHOT_VERSION,
+            workspace: workspace.to_path_buf(),
+            saved_at: millis_since_epoch(SystemTime::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Writes the snapshot to `path`, replacing the one there at once so
+    /// that a proxy killed halfway doesn't leave half of it.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(self)?)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Reads the snapshot of `workspace` at `path`, failing if it's of
+    /// another version or workspace, or too old.
+    pub fn read(path: &Path, workspace: &Path) -> Result<ProxySnapshot> {
+        let snapshot: ProxySnapshot = serde_json::from_slice(&fs::read(path)?)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow!("it's of version {}", snapshot.version));
+        }
+        if snapshot.workspace != workspace {
+            return Err(anyhow!("it's of {:?}", snapshot.workspace));
+        }
+        let age = millis_since_epoch(SystemTime::now())
+            .checked_sub(snapshot.saved_at)
+            .map(Duration::from_millis);
+        match age {
+            Some(age) if age <= MAX_SNAPSHOT_AGE => Ok(snapshot),
+            _ => Err(anyhow!("it's out of date")),
+        }
+    }
+
+    /// Whether no file was added to the workspace or removed from it since
+    /// the snapshot was taken, as the directories they're in would have
+    /// been modified. The files ignored by git aren't looked at.
+    pub fn workspace_unchanged(&self) -> bool {
+        WalkBuilder::new(&self.workspace)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .all(|entry| {
+                entry
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.modified().ok())
+                    .map(|modified| millis_since_epoch(modified) <= self.saved_at)
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Whether none of the files the `symbols` are in changed since the
+    /// snapshot was taken, so that they can be used as they are.
+    pub fn unchanged(&self, symbols: &[SymbolInformation]) -> bool {
+        let mut checked = HashMap::new();
+        symbols.iter().all(|symbol| {
+            *checked.entry(&symbol.location.uri).or_insert_with(|| {
+                symbol
+                    .location
+                    .uri
+                    .to_file_path()
+                    .ok()
+                    .and_then(|path| fs::metadata(path).ok()?.modified().ok())
+                    .map(|modified| millis_since_epoch(modified) <= self.saved_at)
+                    .unwrap_or(false)
+            })
+        })
+    }
+}
+
+/// Where the snapshot of `workspace` is kept.
+pub fn snapshot_path(workspace: &Path) -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce").map(|dirs| {
+        dirs.data_local_dir()
+            .join("proxy-state")
+            .join(format!("{:016x}.json", workspace_key(workspace)))
+    })
+}
+
+/// A hash of the path of `workspace` that stays the same across releases,
+/// unlike the hasher of the standard library.
+fn workspace_key(workspace: &Path) -> u64 {
+    // FNV-1a
+    workspace
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+pub(crate) fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Location, Range, SymbolKind};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-snapshot-test-{}", std::process::id()));
+        let workspace = dir.join("workspace");
+        fs::create_dir_all(&workspace).unwrap();
+        let file = workspace.join("main.rs");
+        fs::write(&file, "fn main() {}").unwrap();
+        #[allow(deprecated)]
+        let symbols = vec![SymbolInformation {
+            name: "main".to_string(),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            deprecated: None,
+            location: Location::new(
+                Url::from_file_path(&file).unwrap(),
+                Range::default(),
+            ),
+            container_name: None,
+        }];
+
+        let path = dir.join("state.json");
+        let mut snapshot = ProxySnapshot::new(&workspace);
+        snapshot.auto_disabled = vec!["crashy".to_string()];
+        snapshot
+            .workspace_symbols
+            .insert("rust:fake".to_string(), symbols.clone());
+        snapshot.document_versions.insert(
+            "rust:fake".to_string(),
+            HashMap::from([(Url::from_file_path(&file).unwrap(), 7)]),
+        );
+        snapshot.idle_servers = vec!["toml:taplo".to_string()];
+        snapshot.write(&path).unwrap();
+        let read = ProxySnapshot::read(&path, &workspace).unwrap();
+        assert_eq!(read, snapshot);
+        assert!(read.unchanged(&symbols));
+        assert!(read.workspace_unchanged());
+        assert!(ProxySnapshot::read(&path, &dir).is_err());
+
+        // nor can they be used once a file was added, which they'd miss
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(workspace.join("lib.rs"), "pub fn lib() {}").unwrap();
+        assert!(read.unchanged(&symbols));
+        assert!(!read.workspace_unchanged());
+
+        // a file changing makes its symbols unusable
+        let mut older = snapshot.clone();
+        older.saved_at -= 60_000;
+        assert!(!older.unchanged(&symbols));
+        older.saved_at -= MAX_SNAPSHOT_AGE.as_millis() as u64;
+        older.write(&path).unwrap();
+        assert!(ProxySnapshot::read(&path, &workspace).is_err());
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(ProxySnapshot::read(&path, &workspace).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -3,17 +3,22 @@ use std::{
     collections::VecDeque,
     io::{self, ErrorKind, Read, Write},
     path::PathBuf,
+    time::Duration,
 };
 
 use alacritty_terminal::{
     config::Program,
     event::OnResize,
     event_loop::Msg,
+    grid::Dimensions,
     term::SizeInfo,
     tty::{self, setup_env, EventedPty, EventedReadWrite},
 };
 use directories::BaseDirs;
-use lapce_rpc::terminal::TermId;
+use lapce_rpc::{
+    core::CoreNotification,
+    terminal::{TermExitStatus, TermId},
+};
 #[cfg(not(windows))]
 use mio::unix::UnixReady;
 #[allow(deprecated)]
@@ -26,6 +31,12 @@ use serde_json::json;
 use crate::dispatch::Dispatcher;
 
 const READ_BUFFER_SIZE: usize = 0x10_0000;
+/// How much unsent output a terminal keeps by default, in bytes
+const DEFAULT_OUTPUT_LIMIT: usize = 16 * 1024 * 1024;
+/// Output is held back while this many messages are waiting to reach core
+const MAX_QUEUED_MESSAGES: usize = 64;
+/// How often held back output is retried when the pty is quiet
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
 
 pub type TermConfig = alacritty_terminal::config::Config;
 
@@ -39,6 +50,8 @@ pub struct Terminal {
 
     #[allow(deprecated)]
     pub tx: Sender<Msg>,
+
+    output: OutputBuffer,
 }
 
 impl Terminal {
@@ -46,6 +59,7 @@ impl Terminal {
         term_id: TermId,
         cwd: Option<PathBuf>,
         shell: String,
+        output_limit: Option<usize>,
         width: usize,
         height: usize,
     ) -> Terminal {
@@ -87,6 +101,10 @@ impl Terminal {
             pty,
             tx,
             rx,
+            output: OutputBuffer::new(
+                output_limit.unwrap_or(DEFAULT_OUTPUT_LIMIT),
+                height,
+            ),
         }
     }
 
@@ -108,7 +126,10 @@ impl Terminal {
         let mut state = State::default();
 
         'event_loop: loop {
-            let _ = self.poll.poll(&mut events, None);
+            // poll again soon if there's output waiting for core to catch up
+            let timeout =
+                (!self.output.buf.is_empty()).then_some(OUTPUT_FLUSH_INTERVAL);
+            let _ = self.poll.poll(&mut events, timeout);
             for event in events.iter() {
                 match event.token() {
                     token if token == channel_token => {
@@ -118,9 +139,27 @@ impl Terminal {
                     }
 
                     token if token == self.pty.child_event_token() => {
-                        if let Some(tty::ChildEvent::Exited) =
-                            self.pty.next_child_event()
+                        // alacritty reaps the child without keeping its exit
+                        // status, so try to get to it first
+                        #[cfg(unix)]
+                        let status = reap_child(self.pty.child().id());
+                        #[cfg(not(unix))]
+                        let status = None;
+
+                        if status.is_some()
+                            || matches!(
+                                self.pty.next_child_event(),
+                                Some(tty::ChildEvent::Exited)
+                            )
                         {
+                            self.flush_output(&dispatcher, true);
+                            dispatcher.send_rpc_notification(
+                                CoreNotification::TerminalExited {
+                                    term_id: self.term_id,
+                                    status: status
+                                        .unwrap_or(TermExitStatus::Unknown),
+                                },
+                            );
                             dispatcher.send_notification(
                                 "close_terminal",
                                 json!({
@@ -142,15 +181,7 @@ impl Terminal {
 
                         if event.readiness().is_readable() {
                             match self.pty.reader().read(&mut buf) {
-                                Ok(n) => {
-                                    dispatcher.send_notification(
-                                        "update_terminal",
-                                        json!({
-                                            "term_id": self.term_id,
-                                            "content": base64::encode(&buf[..n]),
-                                        }),
-                                    );
-                                }
+                                Ok(n) => self.output.push(&buf[..n]),
                                 Err(_e) => (),
                             }
                         }
@@ -163,6 +194,7 @@ impl Terminal {
                     _ => (),
                 }
             }
+            self.flush_output(&dispatcher, false);
             // Register write interest if necessary.
             let mut interest = Ready::readable();
             if state.needs_write() {
@@ -186,7 +218,10 @@ impl Terminal {
             match msg {
                 Msg::Input(input) => state.write_list.push_back(input),
                 Msg::Shutdown => return false,
-                Msg::Resize(size) => self.pty.on_resize(&size),
+                Msg::Resize(size) => {
+                    self.output.rows = size.screen_lines();
+                    self.pty.on_resize(&size);
+                }
             }
         }
 
@@ -211,6 +246,36 @@ impl Terminal {
         true
     }
 
+    /// Sends the buffered output to core, unless core is still busy with
+    /// earlier messages and `force` isn't set. Holding it back lets the
+    /// buffer drop the oldest output of a runaway process instead of
+    /// queueing all of it in memory.
+    fn flush_output(&mut self, dispatcher: &Dispatcher, force: bool) {
+        if self.output.buf.is_empty()
+            || (!force && dispatcher.sender.len() >= MAX_QUEUED_MESSAGES)
+        {
+            return;
+        }
+
+        if self.output.dropped > 0 {
+            dispatcher.send_rpc_notification(
+                CoreNotification::TerminalOutputTruncated {
+                    term_id: self.term_id,
+                    dropped_bytes: self.output.dropped,
+                },
+            );
+            self.output.dropped = 0;
+        }
+        let content = self.output.buf.drain(..).collect::<Vec<u8>>();
+        dispatcher.send_notification(
+            "update_terminal",
+            json!({
+                "term_id": self.term_id,
+                "content": base64::encode(&content),
+            }),
+        );
+    }
+
     #[inline]
     fn pty_write(&mut self, state: &mut State) -> io::Result<()> {
         state.ensure_next();
@@ -246,6 +311,83 @@ impl Terminal {
     }
 }
 
+/// Reaps the child if it has exited, returning how it ended.
+#[cfg(unix)]
+fn reap_child(pid: u32) -> Option<TermExitStatus> {
+    let mut status = 0;
+    let pid =
+        unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+    if pid <= 0 {
+        return None;
+    }
+
+    if libc::WIFEXITED(status) {
+        Some(TermExitStatus::Code(libc::WEXITSTATUS(status)))
+    } else if libc::WIFSIGNALED(status) {
+        Some(TermExitStatus::Signal(libc::WTERMSIG(status)))
+    } else {
+        Some(TermExitStatus::Unknown)
+    }
+}
+
+/// Output read from the pty that hasn't been sent to core yet, capped at
+/// `limit` bytes by dropping the oldest lines.
+struct OutputBuffer {
+    buf: VecDeque<u8>,
+    limit: usize,
+    /// The height of the terminal. The last `rows` lines are what's on
+    /// screen and are never dropped.
+    rows: usize,
+    /// How many bytes were dropped since the last flush
+    dropped: usize,
+}
+
+impl OutputBuffer {
+    fn new(limit: usize, rows: usize) -> OutputBuffer {
+        OutputBuffer {
+            buf: VecDeque::new(),
+            limit,
+            rows,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        if self.buf.len() > self.limit {
+            self.trim();
+        }
+    }
+
+    fn trim(&mut self) {
+        let visible_start = self.visible_start();
+        let excess = (self.buf.len() - self.limit).min(visible_start);
+        // cut at a line start so what's left doesn't begin mid-line
+        let cut = self
+            .buf
+            .range(excess..visible_start)
+            .position(|b| *b == b'\n')
+            .map(|pos| excess + pos + 1)
+            .unwrap_or(visible_start);
+        self.buf.drain(..cut);
+        self.dropped += cut;
+    }
+
+    /// The offset of the first of the last `rows` lines.
+    fn visible_start(&self) -> usize {
+        let mut lines = 0;
+        for (i, b) in self.buf.iter().enumerate().rev() {
+            if *b == b'\n' {
+                lines += 1;
+                if lines > self.rows {
+                    return i + 1;
+                }
+            }
+        }
+        0
+    }
+}
+
 struct Writing {
     source: Cow<'static, [u8]>,
     written: usize,
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use lapce_rpc::core::{CoreNotification, TraceDirection};
+use lsp_types::TraceValue;
+use serde_json::Value;
+
+use crate::dispatch::Dispatcher;
+
+/// The environment variable turning on the trace of language servers when
+/// the proxy starts, as a list like `rust-analyzer=verbose,python`. An entry
+/// names a server or a language, and a bare level applies to every server.
+pub const TRACE_ENV: &str = "LAPCE_LSP_TRACE";
+/// The trace file of a server is rotated once it's over this size, keeping
+/// the previous one next to it.
+const MAX_TRACE_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// The fields holding document contents, which are left out of the trace
+/// below the verbose level.
+const REDACTED_FIELDS: &[&str] = &["text"];
+
+/// How the traffic of a server is traced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceSetting {
+    pub level: TraceValue,
+    /// Forward the messages to core rather than writing them to a file
+    pub to_core: bool,
+}
+
+impl TraceSetting {
+    /// The setting given to a server in `TRACE_ENV`, looked up by any of
+    /// `names`.
+    pub fn from_env(names: &[String]) -> Option<TraceSetting> {
+        let value = std::env::var(TRACE_ENV).ok()?;
+        parse_trace_env(&value, names).map(|level| TraceSetting {
+            level,
+            to_core: false,
+        })
+    }
+}
+
+fn parse_level(level: &str) -> Option<TraceValue> {
+    match level.trim() {
+        "off" => Some(TraceValue::Off),
+        "messages" => Some(TraceValue::Messages),
+        "verbose" => Some(TraceValue::Verbose),
+        _ => None,
+    }
+}
+
+/// The level `value` gives to the server known by `names`. An entry for
+/// the server wins over a bare level, and a name without a level traces
+/// messages.
+fn parse_trace_env(value: &str, names: &[String]) -> Option<TraceValue> {
+    let mut default = None;
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once('=') {
+            Some((name, level)) => {
+                if names.iter().any(|n| n == name.trim()) {
+                    return parse_level(level);
+                }
+            }
+            None => match parse_level(entry) {
+                Some(level) => default = Some(level),
+                None if names.iter().any(|n| n == entry) => {
+                    return Some(TraceValue::Messages);
+                }
+                None => {}
+            },
+        }
+    }
+    default
+}
+
+/// Where the traced messages go.
+pub enum TraceSink {
+    File(TraceFile),
+    Core(Dispatcher),
+}
+
+/// Records the JSON-RPC messages exchanged with a language server.
+pub struct LspTracer {
+    server: String,
+    level: TraceValue,
+    sink: TraceSink,
+    start: Instant,
+    /// The methods of the requests in flight in each direction, to tell
+    /// what the responses answer
+    methods: HashMap<(TraceDirection, String), String>,
+}
+
+impl LspTracer {
+    /// Starts tracing `server` as `setting` says, or gives back `None` if
+    /// the setting turns the trace off.
+    pub fn new(
+        server: &str,
+        setting: TraceSetting,
+        dispatcher: &Dispatcher,
+    ) -> Result<Option<LspTracer>> {
+        if setting.level == TraceValue::Off {
+            return Ok(None);
+        }
+        let sink = if setting.to_core {
+            TraceSink::Core(dispatcher.clone())
+        } else {
+            let dir = trace_directory()
+                .ok_or_else(|| anyhow!("can't find the trace directory"))?;
+            TraceSink::File(TraceFile::open(dir.join(format!("{server}.log")))?)
+        };
+        Ok(Some(LspTracer::with_sink(server, setting.level, sink)))
+    }
+
+    pub fn with_sink(server: &str, level: TraceValue, sink: TraceSink) -> Self {
+        LspTracer {
+            server: server.to_string(),
+            level,
+            sink,
+            start: Instant::now(),
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Records `message`, which went in `direction`.
+    pub fn record(&mut self, direction: TraceDirection, message: &Value) {
+        let id = message.get("id").map(|id| match id {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        });
+        let method = match (message.get("method"), &id) {
+            (Some(Value::String(method)), Some(id)) => {
+                self.methods.insert((direction, id.clone()), method.clone());
+                Some(method.clone())
+            }
+            (Some(Value::String(method)), None) => Some(method.clone()),
+            // a response, to a request that went the other way
+            (_, Some(id)) => self.methods.remove(&(direction.reverse(), id.clone())),
+            _ => None,
+        };
+
+        let mut message = message.clone();
+        if self.level != TraceValue::Verbose {
+            redact(&mut message);
+        }
+        let message = message.to_string();
+
+        match &mut self.sink {
+            TraceSink::File(file) => {
+                let arrow = match direction {
+                    TraceDirection::Sent => "-->",
+                    TraceDirection::Received => "<--",
+                };
+                let line = format!(
+                    "[{:>10.3}s] {arrow} {} #{}\n{message}\n",
+                    self.start.elapsed().as_secs_f64(),
+                    method.as_deref().unwrap_or("response"),
+                    id.as_deref().unwrap_or("-"),
+                );
+                if let Err(err) = file.write(&line) {
+                    log::error!("can't write the trace of {}: {err}", self.server);
+                }
+            }
+            TraceSink::Core(dispatcher) => {
+                dispatcher.send_rpc_notification(CoreNotification::LspTrace {
+                    server: self.server.clone(),
+                    direction,
+                    method,
+                    id,
+                    message,
+                });
+            }
+        }
+    }
+}
+
+/// Replaces the document contents in `value` by their size.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(s) if REDACTED_FIELDS.contains(&key.as_str()) => {
+                        *value = Value::String(format!("<{} bytes>", s.len()));
+                    }
+                    value => redact(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Where the trace files of the servers are written.
+pub fn trace_directory() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "Lapce")
+        .map(|dirs| dirs.data_local_dir().join("lsp-trace"))
+}
+
+/// A log file moved aside to `<path>.1` once it's over
+/// `MAX_TRACE_FILE_SIZE`.
+pub struct TraceFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl TraceFile {
+    pub fn open(path: PathBuf) -> Result<TraceFile> {
+        Self::with_max_size(path, MAX_TRACE_FILE_SIZE)
+    }
+
+    fn with_max_size(path: PathBuf, max_size: u64) -> Result<TraceFile> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(TraceFile {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write(&mut self, s: &str) -> Result<()> {
+        if self.size > 0 && self.size + s.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(s.as_bytes())?;
+        self.size += s.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_trace_env() {
+        let names = vec!["rust-analyzer".to_string(), "rust".to_string()];
+        assert_eq!(
+            parse_trace_env("verbose", &names),
+            Some(TraceValue::Verbose)
+        );
+        assert_eq!(
+            parse_trace_env("verbose, rust-analyzer=off", &names),
+            Some(TraceValue::Off)
+        );
+        assert_eq!(parse_trace_env("rust", &names), Some(TraceValue::Messages));
+        assert_eq!(parse_trace_env("python=verbose", &names), None);
+    }
+
+    #[test]
+    fn test_trace_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-trace-test-{}", std::process::id()));
+        let path = dir.join("cat.log");
+        let mut tracer = LspTracer::with_sink(
+            "cat",
+            TraceValue::Messages,
+            TraceSink::File(TraceFile::with_max_size(path.clone(), 256).unwrap()),
+        );
+
+        tracer.record(
+            TraceDirection::Sent,
+            &json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": { "textDocument": { "text": "fn main() {}" } },
+            }),
+        );
+        tracer.record(
+            TraceDirection::Sent,
+            &json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" }),
+        );
+        let trace = fs::read_to_string(&path).unwrap();
+        assert!(trace.contains("--> textDocument/didOpen #-"));
+        assert!(trace.contains(r#""text":"<12 bytes>""#));
+        assert!(!trace.contains("fn main"));
+
+        // over the size limit, the file is rotated
+        tracer.record(
+            TraceDirection::Received,
+            &json!({ "jsonrpc": "2.0", "id": 1, "result": null }),
+        );
+        let trace = fs::read_to_string(&path).unwrap();
+        assert!(trace.contains("<-- shutdown #1"));
+        assert!(fs::read_to_string(rotated_path(&path))
+            .unwrap()
+            .contains("--> shutdown #1"));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
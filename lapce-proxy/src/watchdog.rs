@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use loupe::MemoryUsage;
+use wasmer::{
+    wasmparser::{Operator, Type as BlockType, TypeOrFuncType},
+    CompilerConfig, Cranelift, FunctionMiddleware, Instance, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Store, Universal,
+};
+use wasmer_types::{
+    ExportIndex, GlobalInit, GlobalType, ModuleInfo, Mutability, Type,
+};
+
+/// The global of a plugin's module with the fuel it has left, which every
+/// function call and loop iteration burns one of. The module traps once it
+/// runs out.
+pub const FUEL_GLOBAL: &str = "lapce_fuel";
+/// The global of a plugin's module that makes it trap at the next function
+/// call or loop iteration once it's set. Only the proxy writes it, so that
+/// setting it can't be undone by the module burning fuel at the same time.
+pub const INTERRUPT_GLOBAL: &str = "lapce_interrupt";
+/// The fuel a plugin is given for each call into it, enough for any plugin
+/// that doesn't loop forever
+pub const PLUGIN_CALL_FUEL: i64 = 1 << 40;
+
+/// A store compiling modules that burn fuel and can be interrupted. Each
+/// module needs a store of its own, as the middleware remembers the globals
+/// of the one it compiled.
+pub fn store() -> Store {
+    let mut compiler = Cranelift::default();
+    compiler.push_middleware(Arc::new(Fuel::default()));
+    Store::new(&Universal::new(compiler).engine())
+}
+
+/// Gets the instance ready for a call into it, with `PLUGIN_CALL_FUEL` to
+/// run on.
+pub fn refuel(instance: &Instance) -> Result<()> {
+    instance
+        .exports
+        .get_global(INTERRUPT_GLOBAL)?
+        .set(wasmer::Value::I32(0))?;
+    instance
+        .exports
+        .get_global(FUEL_GLOBAL)?
+        .set(wasmer::Value::I64(PLUGIN_CALL_FUEL))?;
+    Ok(())
+}
+
+/// Makes whatever the instance runs trap, even on another thread.
+pub fn interrupt(instance: &Instance) -> Result<()> {
+    instance
+        .exports
+        .get_global(INTERRUPT_GLOBAL)?
+        .set(wasmer::Value::I32(1))?;
+    Ok(())
+}
+
+/// The indices of the globals added to a module
+#[derive(Debug, Clone, Copy, MemoryUsage)]
+struct Globals {
+    fuel: u32,
+    interrupt: u32,
+}
+
+/// Adds the globals to a module and checks them in its functions.
+#[derive(Debug, Default, MemoryUsage)]
+struct Fuel {
+    /// The globals, once the module is transformed
+    globals: Mutex<Option<Globals>>,
+}
+
+impl ModuleMiddleware for Fuel {
+    fn generate_function_middleware(
+        &self,
+        _: LocalFunctionIndex,
+    ) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionFuel {
+            globals: self
+                .globals
+                .lock()
+                .unwrap()
+                .expect("the module wasn't transformed"),
+            entered: false,
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let mut globals = self.globals.lock().unwrap();
+        assert!(
+            globals.is_none(),
+            "a fuel middleware can't compile two modules"
+        );
+        let mut add_global = |name: &str, ty: Type, init: GlobalInit| {
+            let index = module_info
+                .globals
+                .push(GlobalType::new(ty, Mutability::Var));
+            module_info.global_initializers.push(init);
+            module_info
+                .exports
+                .insert(name.to_string(), ExportIndex::Global(index));
+            index.as_u32()
+        };
+        *globals = Some(Globals {
+            fuel: add_global(
+                FUEL_GLOBAL,
+                Type::I64,
+                GlobalInit::I64Const(PLUGIN_CALL_FUEL),
+            ),
+            interrupt: add_global(
+                INTERRUPT_GLOBAL,
+                Type::I32,
+                GlobalInit::I32Const(0),
+            ),
+        });
+    }
+}
+
+#[derive(Debug)]
+struct FunctionFuel {
+    globals: Globals,
+    /// Whether the fuel of the call to the function was burnt yet
+    entered: bool,
+}
+
+impl FunctionFuel {
+    /// Burns one fuel, trapping if there's none left or the module was
+    /// interrupted.
+    fn burn<'a>(&self, state: &mut MiddlewareReaderState<'a>) {
+        let Globals { fuel, interrupt } = self.globals;
+        state.extend([
+            Operator::GlobalGet { global_index: fuel },
+            Operator::I64Const { value: 1 },
+            Operator::I64Sub,
+            Operator::GlobalSet { global_index: fuel },
+            Operator::GlobalGet { global_index: fuel },
+            Operator::I64Const { value: 0 },
+            Operator::I64LtS,
+            Operator::GlobalGet {
+                global_index: interrupt,
+            },
+            Operator::I32Or,
+            Operator::If {
+                ty: TypeOrFuncType::Type(BlockType::EmptyBlockType),
+            },
+            Operator::Unreachable,
+            Operator::End,
+        ]);
+    }
+}
+
+impl FunctionMiddleware for FunctionFuel {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        if !self.entered {
+            self.entered = true;
+            self.burn(state);
+        }
+        // burnt at the start of the loop's body, which each iteration runs
+        let is_loop = matches!(operator, Operator::Loop { .. });
+        state.push_operator(operator);
+        if is_loop {
+            self.burn(state);
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,231 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Weak},
+    thread,
+    time::{Duration, SystemTime},
 };
 
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{unbounded, Sender};
+use globset::GlobSet;
 use notify::{
-    event::{ModifyKind, RenameMode},
+    event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
     recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode,
     Watcher,
 };
 use parking_lot::Mutex;
 
+use crate::patterns::glob_set;
+
 /// Wrapper around a `notify::Watcher`. It runs the inner watcher
 /// in a separate thread, and communicates with it via a [crossbeam channel].
 /// [crossbeam channel]: https://docs.rs/crossbeam-channel
 pub struct FileWatcher {
-    inner: RecommendedWatcher,
+    inner: WatcherBackend,
     state: Arc<Mutex<WatcherState>>,
 }
 
+/// The mechanism used to detect file changes. Native events are
+/// unreliable on network and remote filesystems (NFS, SMB, sshfs...),
+/// so those fall back to periodically polling the watched trees.
+enum WatcherBackend {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl WatcherBackend {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) {
+        match self {
+            WatcherBackend::Native(watcher) => {
+                let _ = watcher.watch(path, mode);
+            }
+            WatcherBackend::Poll(watcher) => watcher.watch(path, mode),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) {
+        match self {
+            WatcherBackend::Native(watcher) => {
+                let _ = watcher.unwatch(path);
+            }
+            WatcherBackend::Poll(watcher) => watcher.unwatch(path),
+        }
+    }
+}
+
+/// Modification times of every file under a watched path.
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+/// A watcher that stats the watched paths every `interval` and
+/// synthesizes the same `notify::Event`s the native watcher would
+/// send for files that were created, removed or modified in between.
+struct PollWatcher {
+    roots: Arc<Mutex<HashMap<PathBuf, (bool, Snapshot)>>>,
+}
+
+impl PollWatcher {
+    fn new(tx_event: Sender<notify::Result<Event>>, interval: Duration) -> Self {
+        let roots = Arc::new(Mutex::new(HashMap::new()));
+        let weak_roots = Arc::downgrade(&roots);
+        thread::spawn(move || Self::poll(weak_roots, tx_event, interval));
+        PollWatcher { roots }
+    }
+
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) {
+        let recursive = mode == RecursiveMode::Recursive;
+        let snapshot = snapshot(path, recursive);
+        self.roots
+            .lock()
+            .insert(path.to_path_buf(), (recursive, snapshot));
+    }
+
+    fn unwatch(&mut self, path: &Path) {
+        self.roots.lock().remove(path);
+    }
+
+    /// Runs until the `PollWatcher` owning `roots` is dropped.
+    fn poll(
+        roots: Weak<Mutex<HashMap<PathBuf, (bool, Snapshot)>>>,
+        tx_event: Sender<notify::Result<Event>>,
+        interval: Duration,
+    ) {
+        loop {
+            thread::sleep(interval);
+            let roots = match roots.upgrade() {
+                Some(roots) => roots,
+                None => return,
+            };
+
+            let watched = roots
+                .lock()
+                .iter()
+                .map(|(path, (recursive, _))| (path.clone(), *recursive))
+                .collect::<Vec<_>>();
+            for (path, recursive) in watched {
+                // Scan without holding the lock, the tree could be large
+                // and the filesystem slow.
+                let new = snapshot(&path, recursive);
+                let events = {
+                    let mut roots = roots.lock();
+                    match roots.get_mut(&path) {
+                        Some((_, old)) => {
+                            let events = diff_snapshots(old, &new);
+                            *old = new;
+                            events
+                        }
+                        // unwatched while we were scanning
+                        None => continue,
+                    }
+                };
+                for event in events {
+                    if tx_event.send(Ok(event)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn snapshot(path: &Path, recursive: bool) -> Snapshot {
+    let mut snapshot = HashMap::new();
+    if let Ok(mtime) = fs::metadata(path).and_then(|m| m.modified()) {
+        snapshot.insert(path.to_path_buf(), mtime);
+    }
+    snapshot_dir(path, recursive, &mut snapshot);
+    snapshot
+}
+
+fn snapshot_dir(dir: &Path, recursive: bool, snapshot: &mut Snapshot) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if let Ok(mtime) = entry.metadata().and_then(|m| m.modified()) {
+            snapshot.insert(path.clone(), mtime);
+        }
+        // don't follow symlinks, they could loop back into the tree
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if recursive && is_dir {
+            snapshot_dir(&path, recursive, snapshot);
+        }
+    }
+}
+
+fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> Vec<Event> {
+    let mut events = Vec::new();
+    for (path, mtime) in new {
+        match old.get(path) {
+            None => events.push(
+                Event::new(EventKind::Create(CreateKind::Any))
+                    .add_path(path.clone()),
+            ),
+            Some(old_mtime) if old_mtime != mtime => events.push(
+                Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(path.clone()),
+            ),
+            Some(_) => {}
+        }
+    }
+    for path in old.keys() {
+        if !new.contains_key(path) {
+            events.push(
+                Event::new(EventKind::Remove(RemoveKind::Any))
+                    .add_path(path.clone()),
+            );
+        }
+    }
+    events
+}
+
+/// Whether `path` lives on a network or remote filesystem, where native
+/// change notifications can't be relied upon.
+#[cfg(target_os = "linux")]
+pub fn is_network_filesystem(path: &Path) -> bool {
+    const NETWORK_FILESYSTEMS: [&str; 9] = [
+        "nfs",
+        "nfs4",
+        "cifs",
+        "smbfs",
+        "smb3",
+        "9p",
+        "afs",
+        "fuse.sshfs",
+        "vboxsf",
+    ];
+
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mounts = match fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(_) => return false,
+    };
+
+    // the mount point closest to the path decides its filesystem
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?.replace("\\040", " ");
+            let fs_type = fields.next()?;
+            Some((PathBuf::from(mount_point), fs_type))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(_, fs_type)| NETWORK_FILESYSTEMS.contains(&fs_type))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_network_filesystem(_path: &Path) -> bool {
+    false
+}
+
 #[derive(Debug, Default)]
 struct WatcherState {
     events: EventQueue,
@@ -54,14 +260,92 @@ pub type EventQueue = VecDeque<(WatchToken, Event)>;
 
 pub type PathFilter = dyn Fn(&Path) -> bool + Send + 'static;
 
+/// The file extensions and globs a subscription is interested in.
+/// A path is delivered if it matches any of them; a filter with
+/// neither extensions nor globs lets everything through.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    extensions: HashSet<String>,
+    globs: Option<GlobSet>,
+}
+
+impl EventFilter {
+    pub fn new<E, G>(extensions: E, globs: G) -> Result<EventFilter, globset::Error>
+    where
+        E: IntoIterator,
+        E::Item: AsRef<str>,
+        G: IntoIterator,
+        G::Item: AsRef<str>,
+    {
+        let extensions = extensions
+            .into_iter()
+            .map(|ext| ext.as_ref().trim_start_matches('.').to_string())
+            .collect::<HashSet<String>>();
+
+        let globs = globs
+            .into_iter()
+            .map(|glob| glob.as_ref().to_string())
+            .collect::<Vec<_>>();
+        let globs = if globs.is_empty() {
+            None
+        } else {
+            Some(glob_set(&globs)?)
+        };
+
+        Ok(EventFilter { extensions, globs })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() && self.globs.is_none() {
+            return true;
+        }
+
+        let extension_match = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| self.extensions.contains(ext))
+            .unwrap_or(false);
+
+        extension_match
+            || self
+                .globs
+                .as_ref()
+                .map(|globs| globs.is_match(path))
+                .unwrap_or(false)
+    }
+}
+
 impl FileWatcher {
     pub fn new<T: Notify + 'static>(peer: T) -> Self {
+        Self::with_backend(peer, |tx_event| {
+            WatcherBackend::Native(
+                recommended_watcher(tx_event).expect("watcher should spawn"),
+            )
+        })
+    }
+
+    /// Create a `FileWatcher` that polls the watched paths for changes
+    /// every `interval` instead of relying on native notifications.
+    pub fn with_poll_interval<T: Notify + 'static>(
+        peer: T,
+        interval: Duration,
+    ) -> Self {
+        Self::with_backend(peer, |tx_event| {
+            WatcherBackend::Poll(PollWatcher::new(tx_event, interval))
+        })
+    }
+
+    fn with_backend<T, F>(peer: T, backend: F) -> Self
+    where
+        T: Notify + 'static,
+        F: FnOnce(Sender<notify::Result<Event>>) -> WatcherBackend,
+    {
         let (tx_event, rx_event) = unbounded();
 
         let state = Arc::new(Mutex::new(WatcherState::default()));
         let state_clone = state.clone();
 
-        let inner = recommended_watcher(tx_event).expect("watcher should spawn");
+        let inner = backend(tx_event);
 
         std::thread::spawn(move || {
             while let Ok(Ok(event)) = rx_event.recv() {
@@ -112,6 +396,22 @@ impl FileWatcher {
         self.watch_impl(path, recursive, token, Some(filter));
     }
 
+    /// Subscribe to events under `path` that match `filter`. Each
+    /// subscription is routed by its own `token`, so several consumers
+    /// can watch the same path with different filters while it is only
+    /// registered once with the underlying watcher.
+    pub fn subscribe(
+        &mut self,
+        path: &Path,
+        recursive: bool,
+        token: WatchToken,
+        filter: EventFilter,
+    ) {
+        self.watch_filtered(path, recursive, token, move |path| {
+            filter.matches(path)
+        });
+    }
+
     fn watch_impl(
         &mut self,
         path: &Path,
@@ -137,7 +437,7 @@ impl FileWatcher {
         let mode = mode_from_bool(w.recursive);
 
         if !state.watchees.iter().any(|w2| w.path == w2.path) {
-            let _ = self.inner.watch(&w.path, mode);
+            self.inner.watch(&w.path, mode);
         }
 
         state.watchees.push(w);
@@ -157,7 +457,7 @@ impl FileWatcher {
         if let Some(idx) = idx {
             let removed = state.watchees.remove(idx);
             if !state.watchees.iter().any(|w| w.path == removed.path) {
-                let _ = self.inner.unwatch(&removed.path);
+                self.inner.unwatch(&removed.path);
             }
             //TODO: Ideally we would be tracking what paths we're watching with
             // some prefix-tree-like structure, which would let us keep track
@@ -181,7 +481,7 @@ impl FileWatcher {
                     .collect::<Vec<_>>();
 
                 for (path, mode) in to_add {
-                    let _ = self.inner.watch(&path, mode);
+                    self.inner.watch(&path, mode);
                 }
             }
         }
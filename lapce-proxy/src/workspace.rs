@@ -0,0 +1,403 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use lapce_rpc::proxy::{LineEditPreview, WorkspaceEditOperation};
+use lsp_types::{
+    DocumentChangeOperation, DocumentChanges, OneOf, TextDocumentEdit, TextEdit,
+    Url, WorkspaceEdit,
+};
+
+use crate::buffer::Buffer;
+
+/// The largest file that isn't open an edit is previewed in
+const MAX_PREVIEW_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Turns `edit` into the operations to apply it with, in the order they're
+/// applied. The text edits of each operation are sorted from the end of the
+/// document to its start, so that applying one doesn't move the ones after
+/// it. `version_of` gives the version of the documents that are open, and
+/// the edit is rejected if any of them changed since the server made it, as
+/// applying it would put its text in the wrong places.
+pub fn normalize_workspace_edit(
+    edit: &WorkspaceEdit,
+    version_of: impl Fn(&Url) -> Option<i32>,
+) -> Result<Vec<WorkspaceEditOperation>> {
+    let mut operations = Vec::new();
+    let mut conflicts = Vec::new();
+
+    match edit.document_changes.as_ref() {
+        Some(DocumentChanges::Edits(edits)) => {
+            for edit in edits {
+                operations.push(document_edit(edit, &version_of, &mut conflicts)?);
+            }
+        }
+        Some(DocumentChanges::Operations(ops)) => {
+            for op in ops {
+                let operation = match op {
+                    DocumentChangeOperation::Edit(edit) => {
+                        document_edit(edit, &version_of, &mut conflicts)?
+                    }
+                    DocumentChangeOperation::Op(op) => {
+                        WorkspaceEditOperation::Resource(op.clone())
+                    }
+                };
+                operations.push(operation);
+            }
+        }
+        // `changes` is only used by servers that can't send `documentChanges`
+        None => {
+            let changes = edit
+                .changes
+                .iter()
+                .flatten()
+                .collect::<BTreeMap<&Url, &Vec<TextEdit>>>();
+            for (uri, edits) in changes {
+                operations.push(WorkspaceEditOperation::Edit {
+                    uri: uri.clone(),
+                    edits: sort_edits(uri, edits.clone())?,
+                });
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "document changed since the edit was made, please retry: {}",
+            conflicts.join(", ")
+        ));
+    }
+    Ok(operations)
+}
+
+fn document_edit(
+    edit: &TextDocumentEdit,
+    version_of: &impl Fn(&Url) -> Option<i32>,
+    conflicts: &mut Vec<String>,
+) -> Result<WorkspaceEditOperation> {
+    let uri = &edit.text_document.uri;
+    if let (Some(version), Some(current)) =
+        (edit.text_document.version, version_of(uri))
+    {
+        if version != current {
+            conflicts
+                .push(format!("{uri} (edited version {version}, now {current})"));
+        }
+    }
+
+    let edits = edit
+        .edits
+        .iter()
+        .map(|edit| match edit {
+            OneOf::Left(edit) => edit.clone(),
+            OneOf::Right(edit) => edit.text_edit.clone(),
+        })
+        .collect();
+    Ok(WorkspaceEditOperation::Edit {
+        uri: uri.clone(),
+        edits: sort_edits(uri, edits)?,
+    })
+}
+
+/// Sorts `edits` from the last to the first in the document. Inserts at the
+/// same position end up in the order they were given in once applied.
+fn sort_edits(uri: &Url, mut edits: Vec<TextEdit>) -> Result<Vec<TextEdit>> {
+    edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+    for pair in edits.windows(2) {
+        let (end, start) = (pair[0].range.end, pair[1].range.start);
+        if (end.line, end.character) > (start.line, start.character) {
+            return Err(anyhow!("overlapping edits in {uri}"));
+        }
+    }
+    edits.reverse();
+    Ok(edits)
+}
+
+/// The lines of `buffer` that `edits` change, as they are and as they'd be
+/// with the edits made. Edits on the same lines are shown together.
+pub fn preview_text_edits(
+    buffer: &Buffer,
+    edits: &[TextEdit],
+) -> Result<Vec<LineEditPreview>> {
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|edit| {
+        let (start, end) = (edit.range.start, edit.range.end);
+        (start.line, start.character, end.line, end.character)
+    });
+
+    let mut groups: Vec<(u32, u32, Vec<&TextEdit>)> = Vec::new();
+    for edit in edits {
+        let (start, end) = (edit.range.start.line, edit.range.end.line);
+        match groups.last_mut() {
+            Some((_, last, group)) if start <= *last => {
+                *last = (*last).max(end);
+                group.push(edit);
+            }
+            _ => groups.push((start, end, vec![edit])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(first, last, edits)| {
+            if last as usize > buffer.last_line() {
+                return Err(anyhow!(
+                    "edit past the end of the document at line {last}"
+                ));
+            }
+            let start = buffer.offset_of_line(first as usize);
+            let end = buffer.offset_of_line(last as usize + 1);
+            let before = buffer.slice_to_cow(start..end).to_string();
+            let mut after = before.clone();
+            // the start of the edit made last, which the next can't go past
+            let mut limit = end;
+            for edit in edits.iter().rev() {
+                let (edit_start, edit_end) = buffer
+                    .offset_of_position(&edit.range.start)
+                    .zip(buffer.offset_of_position(&edit.range.end))
+                    .filter(|(edit_start, edit_end)| {
+                        edit_start <= edit_end && *edit_end <= limit
+                    })
+                    .ok_or_else(|| anyhow!("invalid edit range {:?}", edit.range))?;
+                after.replace_range(
+                    edit_start - start..edit_end - start,
+                    &edit.new_text,
+                );
+                limit = edit_start;
+            }
+            Ok(LineEditPreview {
+                line: first,
+                before: without_line_ending(before),
+                after: without_line_ending(after),
+            })
+        })
+        .collect()
+}
+
+fn without_line_ending(mut text: String) -> String {
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    text
+}
+
+/// The path of the file at `uri` in `workspace`, for reading a file an edit
+/// touches that isn't open. Servers can send any uri, so only files within
+/// the workspace, once links are followed, are read, and only those small
+/// enough to preview.
+pub fn workspace_file(workspace: Option<&Path>, uri: &Url) -> Result<PathBuf> {
+    let workspace = workspace
+        .ok_or_else(|| anyhow!("can't read {uri} without a workspace"))?
+        .canonicalize()?;
+    let path = uri
+        .to_file_path()
+        .map_err(|_| anyhow!("{uri} isn't a local file"))?
+        .canonicalize()
+        .map_err(|err| anyhow!("can't read {uri}: {err}"))?;
+    if !path.starts_with(&workspace) {
+        return Err(anyhow!("{uri} is outside of the workspace"));
+    }
+    let metadata = path.metadata()?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{uri} isn't a file"));
+    }
+    if metadata.len() > MAX_PREVIEW_FILE_SIZE {
+        return Err(anyhow!("{uri} is too large to preview"));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lapce_rpc::buffer::BufferId;
+    use lsp_types::{
+        CreateFile, OptionalVersionedTextDocumentIdentifier, Position, Range,
+        ResourceOp,
+    };
+    use xi_rope::Rope;
+
+    use super::*;
+
+    fn text_edit(start: (u32, u32), end: (u32, u32), text: &str) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position::new(start.0, start.1),
+                end: Position::new(end.0, end.1),
+            },
+            new_text: text.to_string(),
+        }
+    }
+
+    fn new_texts(operation: &WorkspaceEditOperation) -> Vec<&str> {
+        match operation {
+            WorkspaceEditOperation::Edit { edits, .. } => {
+                edits.iter().map(|edit| edit.new_text.as_str()).collect()
+            }
+            WorkspaceEditOperation::Resource(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_workspace_edit() {
+        let uri = Url::parse("file:///src/lib.rs").unwrap();
+        let new_uri = Url::parse("file:///src/new.rs").unwrap();
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: new_uri.clone(),
+                    options: None,
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri.clone(),
+                        version: Some(3),
+                    },
+                    edits: vec![
+                        OneOf::Left(text_edit((0, 0), (0, 0), "first")),
+                        OneOf::Left(text_edit((4, 2), (4, 8), "last")),
+                        OneOf::Left(text_edit((0, 0), (0, 0), "second")),
+                        OneOf::Left(text_edit((2, 0), (3, 0), "middle")),
+                    ],
+                }),
+            ])),
+            ..Default::default()
+        };
+
+        let operations = normalize_workspace_edit(&edit, |_| Some(3)).unwrap();
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(
+            &operations[0],
+            WorkspaceEditOperation::Resource(ResourceOp::Create(create))
+                if create.uri == new_uri
+        ));
+        assert_eq!(
+            new_texts(&operations[1]),
+            vec!["last", "middle", "second", "first"]
+        );
+
+        // the server edited a version of the document core has since changed
+        let err = normalize_workspace_edit(&edit, |_| Some(4)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "document changed since the edit was made, please retry: \
+             file:///src/lib.rs (edited version 3, now 4)"
+        );
+        // documents that aren't open can't be checked
+        assert!(normalize_workspace_edit(&edit, |_| None).is_ok());
+    }
+
+    #[test]
+    fn test_normalize_overlapping_edits() {
+        let uri = Url::parse("file:///src/lib.rs").unwrap();
+        let edit = WorkspaceEdit {
+            changes: Some(
+                [(
+                    uri,
+                    vec![
+                        text_edit((1, 0), (1, 10), "a"),
+                        text_edit((1, 5), (2, 0), "b"),
+                    ],
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            ..Default::default()
+        };
+        assert!(normalize_workspace_edit(&edit, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_preview_text_edits() {
+        let buffer = Buffer {
+            language_id: "rust".to_string(),
+            id: BufferId(1),
+            rope: Rope::from("fn old() {}\r\nfn main() {\n    old();\n    old()\n}"),
+            path: PathBuf::from("/main.rs"),
+            rev: 0,
+            mod_time: None,
+        };
+        let line = |line: u32, before: &str, after: &str| LineEditPreview {
+            line,
+            before: before.to_string(),
+            after: after.to_string(),
+        };
+
+        let preview = preview_text_edits(
+            &buffer,
+            &[
+                text_edit((3, 4), (3, 7), "new"),
+                text_edit((0, 3), (0, 6), "new"),
+                text_edit((2, 4), (2, 7), "new"),
+                // the edit before it on the same line is shown with it
+                text_edit((3, 4), (3, 4), "self::"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            preview,
+            vec![
+                line(0, "fn old() {}", "fn new() {}"),
+                line(2, "    old();", "    new();"),
+                line(3, "    old()", "    self::new()"),
+            ]
+        );
+
+        // lines joined by an edit are shown together
+        let preview =
+            preview_text_edits(&buffer, &[text_edit((2, 10), (3, 4), " ")]).unwrap();
+        assert_eq!(
+            preview,
+            vec![line(2, "    old();\n    old()", "    old(); old()")]
+        );
+
+        assert!(
+            preview_text_edits(&buffer, &[text_edit((1, 4), (1, 2), "")]).is_err()
+        );
+        // past the last line
+        assert!(
+            preview_text_edits(&buffer, &[text_edit((4, 1), (5, 0), "")]).is_err()
+        );
+        assert!(
+            preview_text_edits(&buffer, &[text_edit((9, 0), (9, 0), "")]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_workspace_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("lapce-test-workspace-file-{}", std::process::id()));
+        let workspace = dir.join("workspace");
+        fs::create_dir_all(workspace.join("src")).unwrap();
+        fs::write(workspace.join("src/lib.rs"), "").unwrap();
+        fs::write(dir.join("secret"), "").unwrap();
+        let uri = |path: &Path| Url::from_file_path(path).unwrap();
+
+        let path =
+            workspace_file(Some(&workspace), &uri(&workspace.join("src/lib.rs")))
+                .unwrap();
+        assert_eq!(path, workspace.join("src/lib.rs").canonicalize().unwrap());
+
+        // leaving the workspace through `..`
+        let outside = workspace.join("src/../../secret");
+        assert!(workspace_file(Some(&workspace), &uri(&outside)).is_err());
+        assert!(
+            workspace_file(Some(&workspace), &uri(&workspace.join("src"))).is_err()
+        );
+        assert!(
+            workspace_file(Some(&workspace), &uri(&workspace.join("none"))).is_err()
+        );
+        assert!(workspace_file(None, &uri(&workspace.join("src/lib.rs"))).is_err());
+        let remote = Url::parse("https://example.com/lib.rs").unwrap();
+        assert!(workspace_file(Some(&workspace), &remote).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
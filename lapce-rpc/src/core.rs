@@ -1,17 +1,34 @@
-use lsp_types::{ProgressParams, PublishDiagnosticsParams};
+use lsp_types::{
+    CompletionItem, Position, ProgressParams, PublishDiagnosticsParams,
+    WorkDoneProgress,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::PathBuf};
 
 use crate::{
-    file::FileNodeItem, plugin::PluginDescription, source_control::DiffInfo,
-    terminal::TermId,
+    buffer::BufferId,
+    file::FileNodeItem,
+    handshake::Handshake,
+    parse::RequestId,
+    plugin::{CommandDefinition, PluginDescription},
+    source_control::DiffInfo,
+    terminal::{TermExitStatus, TermId},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "method", content = "params")]
 pub enum CoreNotification {
-    ProxyConnected {},
+    /// The proxy started, with what it tells about itself, which proxies
+    /// older than the handshake don't
+    ProxyConnected {
+        #[serde(default)]
+        handshake: Option<Handshake>,
+    },
+    /// The proxy can't work with core, and exits
+    ProxyIncompatible {
+        reason: String,
+    },
     OpenFileChanged {
         path: PathBuf,
         content: String,
@@ -21,6 +38,13 @@ pub enum CoreNotification {
         content: String,
         rev: u64,
     },
+    /// The proxy's text of the open file at `path`, at `rev`, isn't the
+    /// one core's edits are made against, so the edits are dropped until
+    /// core sends the whole text again
+    BufferOutOfSync {
+        path: PathBuf,
+        rev: u64,
+    },
     WorkspaceFileChange {},
     PublishDiagnostics {
         diagnostics: PublishDiagnosticsParams,
@@ -28,6 +52,33 @@ pub enum CoreNotification {
     WorkDoneProgress {
         progress: ProgressParams,
     },
+    /// A language server's `progress` on core's request `request_id`, like
+    /// finding the references of a symbol
+    RequestProgress {
+        request_id: RequestId,
+        progress: WorkDoneProgress,
+    },
+    /// The characters typing which in `buffer_id` should request completion
+    /// from `server`
+    CompletionTriggerCharacters {
+        buffer_id: BufferId,
+        server: String,
+        trigger_characters: Vec<String>,
+    },
+    /// The characters typing which in `buffer_id` should request formatting
+    /// from `server`
+    OnTypeFormattingTriggerCharacters {
+        buffer_id: BufferId,
+        server: String,
+        trigger_characters: Vec<String>,
+    },
+    /// Completion items a server streamed before answering the completion
+    /// request `request_id` at `position`
+    CompletionPartialResult {
+        request_id: usize,
+        position: Position,
+        items: Vec<CompletionItem>,
+    },
     HomeDir {
         path: PathBuf,
     },
@@ -53,6 +104,129 @@ pub enum CoreNotification {
     CloseTerminal {
         term_id: TermId,
     },
+    TerminalExited {
+        term_id: TermId,
+        status: TermExitStatus,
+    },
+    TerminalOutputTruncated {
+        term_id: TermId,
+        dropped_bytes: usize,
+    },
+    PluginError {
+        plugin: String,
+        error: String,
+    },
+    /// A `line` the plugin `plugin` wrote to its stderr, forwarded as it
+    /// asked for its debug output to be
+    PluginLog {
+        plugin: String,
+        line: String,
+    },
+    /// The plugin `plugin` crashed too often and was disabled for the
+    /// `reason`, until it's enabled again. It's still enabled in the
+    /// user's settings.
+    PluginAutoDisabled {
+        plugin: String,
+        reason: String,
+    },
+    /// `plugins.toml` was edited outside of the editor, and the plugins
+    /// were enabled and disabled to match it
+    PluginConfigReloaded {
+        enabled: Vec<String>,
+        disabled: Vec<String>,
+    },
+    PluginInstallCancelled {
+        plugin: String,
+    },
+    /// The plugin in `shadowed` isn't loaded because one of the same name
+    /// in `active` takes precedence
+    PluginShadowed {
+        plugin: String,
+        active: PathBuf,
+        shadowed: PathBuf,
+    },
+    PluginCommands {
+        commands: Vec<CommandDefinition>,
+    },
+    /// The file at `path` is `size` bytes, over the `limit` for opening it
+    /// in the language servers, so it's edited without them
+    DocumentTooLargeForLsp {
+        path: PathBuf,
+        size: usize,
+        limit: usize,
+    },
+    /// The file at `path` matches the user's globs of the files kept from
+    /// the language servers, so it's edited without them
+    DocumentIgnoredForLsp {
+        path: PathBuf,
+    },
+    /// The command a plugin registered to format the file at `path` failed
+    /// with `error`, and the file was left as it was
+    FormatterFailed {
+        path: PathBuf,
+        formatter: String,
+        error: String,
+    },
+    /// The file at `path` isn't given to the language server `server` for
+    /// the `reason` it's over the server's limits
+    DocumentTooLargeForServer {
+        path: PathBuf,
+        server: String,
+        reason: String,
+    },
+    /// The language server `server` was shut down for writing something
+    /// other than language server protocol messages
+    UnexpectedServerProtocol {
+        server: String,
+        error: String,
+    },
+    /// The language server `server` answered a request the user made with
+    /// the error `code` and `message`
+    LanguageServerRequestFailed {
+        server: String,
+        method: String,
+        code: i64,
+        message: String,
+    },
+    /// A language server's semantic tokens of the documents at `paths` are
+    /// stale, so they should be asked for again
+    SemanticTokensRefresh {
+        paths: Vec<PathBuf>,
+    },
+    /// Like `SemanticTokensRefresh`, for inlay hints
+    InlayHintsRefresh {
+        paths: Vec<PathBuf>,
+    },
+    /// Like `SemanticTokensRefresh`, for code lenses
+    CodeLensRefresh {
+        paths: Vec<PathBuf>,
+    },
+    /// A JSON-RPC `message` exchanged with the language server `server`,
+    /// forwarded while its traffic is traced
+    LspTrace {
+        server: String,
+        direction: TraceDirection,
+        method: Option<String>,
+        id: Option<String>,
+        message: String,
+    },
+}
+
+/// Which way a traced message went, seen from the editor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceDirection {
+    Sent,
+    Received,
+}
+
+impl TraceDirection {
+    pub fn reverse(self) -> TraceDirection {
+        match self {
+            TraceDirection::Sent => TraceDirection::Received,
+            TraceDirection::Received => TraceDirection::Sent,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// The version of the messages core and the proxy send each other, bumped
+/// when a change to them breaks the side that doesn't have it
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest version of the messages of the other side that this side can
+/// still work with
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// The requests added since the protocol's version was last bumped, which
+/// the other side may not know
+pub const FEATURES: &[&str] = &[
+    "prepare_save",
+    "rename_preview",
+    "queue_stats",
+    "prepare_call_hierarchy",
+    "servers_for_path",
+    "plugin_stats",
+    "refresh_diagnostics",
+    "diagnostics_bundle",
+];
+
+/// What core and the proxy tell each other about themselves first thing, to
+/// find out whether they can work together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    /// The version of the release
+    pub version: String,
+    pub protocol: u32,
+    pub min_protocol: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    /// The handshake of this side, of release `version`.
+    pub fn new(version: &str) -> Handshake {
+        Handshake {
+            version: version.to_string(),
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    /// Fails with why this side can't work with `other`, the `other_name`
+    /// side, if it can't.
+    pub fn check(&self, other: &Handshake, other_name: &str) -> Result<()> {
+        if other.protocol < self.min_protocol {
+            return Err(anyhow!(
+                "{other_name} v{} is too old for v{}, update {other_name} \
+                 (protocol {} is needed, it has {})",
+                other.version,
+                self.version,
+                self.min_protocol,
+                other.protocol,
+            ));
+        }
+        if self.protocol < other.min_protocol {
+            return Err(anyhow!(
+                "{other_name} v{} is too new for v{}, use the same version \
+                 of both (protocol {} is needed, this is {})",
+                other.version,
+                self.version,
+                other.min_protocol,
+                self.protocol,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the side of this handshake takes the request `feature`.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_handshake() {
+        let core = Handshake::new("0.2.0");
+        assert!(core.check(&Handshake::new("0.2.1"), "the proxy").is_ok());
+        assert!(core.supports("rename_preview"));
+
+        let old = Handshake {
+            protocol: MIN_PROTOCOL_VERSION - 1,
+            min_protocol: 0,
+            features: Vec::new(),
+            ..Handshake::new("0.1.0")
+        };
+        assert_eq!(
+            core.check(&old, "the proxy").unwrap_err().to_string(),
+            format!(
+                "the proxy v0.1.0 is too old for v0.2.0, update the proxy \
+                 (protocol {MIN_PROTOCOL_VERSION} is needed, it has {})",
+                MIN_PROTOCOL_VERSION - 1
+            )
+        );
+        assert!(!old.supports("rename_preview"));
+
+        let new = Handshake {
+            min_protocol: PROTOCOL_VERSION + 1,
+            ..Handshake::new("0.3.0")
+        };
+        assert!(core.check(&new, "the proxy").is_err());
+    }
+}
@@ -2,6 +2,7 @@ pub mod buffer;
 pub mod core;
 pub mod counter;
 pub mod file;
+pub mod handshake;
 mod parse;
 pub mod plugin;
 pub mod proxy;
@@ -13,7 +14,9 @@ pub mod terminal;
 use std::collections::HashMap;
 use std::io::stdin;
 use std::io::stdout;
+use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Write;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -31,11 +34,19 @@ use serde_json::Value;
 pub use stdio::stdio_transport;
 
 pub fn stdio() -> (Sender<Value>, Receiver<Value>) {
-    let stdout = stdout();
-    let stdin = BufReader::new(stdin());
+    transport(stdout(), BufReader::new(stdin()))
+}
+
+/// The channels to send messages through `writer` and receive them from
+/// `reader` with, such as both halves of a socket.
+pub fn transport<W, R>(writer: W, reader: R) -> (Sender<Value>, Receiver<Value>)
+where
+    W: 'static + Write + Send,
+    R: 'static + BufRead + Send,
+{
     let (writer_sender, writer_receiver) = crossbeam_channel::unbounded();
     let (reader_sender, reader_receiver) = crossbeam_channel::unbounded();
-    stdio::stdio_transport(stdout, writer_receiver, stdin, reader_sender);
+    stdio::stdio_transport(writer, writer_receiver, reader, reader_sender);
     (writer_sender, reader_receiver)
 }
 
@@ -1,4 +1,7 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use anyhow::{format_err, Error};
 use serde::{Deserialize, Serialize};
@@ -19,8 +22,88 @@ pub struct PluginDescription {
     pub enabled: Option<bool>,
     pub wasm: Option<String>,
     pub themes: Option<Vec<String>>,
+    /// Grammars highlighting the syntax of the languages the plugin adds,
+    /// as paths next to its manifest. Once the plugin is loaded they're the
+    /// absolute paths of the files, in one of the formats of
+    /// [`GrammarFormat`].
+    pub grammars: Option<Vec<String>>,
+    /// An image shown for the plugin, as a path next to its manifest or a
+    /// URL
+    pub icon: Option<String>,
     pub dir: Option<PathBuf>,
     pub configuration: Option<Value>,
+    /// The initialization options recommended for the plugin's language
+    /// servers. They're merged under everything else: the options the
+    /// plugin starts a server with, then the user's and the workspace's
+    /// settings for its languages, win over them.
+    #[serde(default)]
+    pub default_server_options: Option<Value>,
+    /// How many seconds the plugin's `initialize` may take before
+    /// it's considered failed
+    pub initialize_timeout: Option<u64>,
+    #[serde(default)]
+    pub commands: Vec<CommandDefinition>,
+    /// Globs of the files whose opening, or being in the workspace, starts
+    /// the plugin's language servers, like `**/Cargo.toml`. Without any,
+    /// they start with the plugin.
+    #[serde(default)]
+    pub activation: Vec<String>,
+    /// Forward what the plugin writes to its stderr to core, a line at a
+    /// time, as the plugin's debug log. The plugin talks to the proxy on
+    /// stdin and stdout, so its debug output goes to stderr rather than
+    /// in between the messages.
+    #[serde(default)]
+    pub debug_output: bool,
+    /// Let the plugin run processes of its own, like a debug adapter or a
+    /// build tool, with `spawn_process`. They're killed when it unloads.
+    #[serde(default)]
+    pub spawn_processes: bool,
+}
+
+/// A command a plugin contributes to the command palette.
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommandDefinition {
+    pub id: String,
+    pub title: String,
+    pub category: Option<String>,
+}
+
+impl CommandDefinition {
+    /// The text shown for the command in the palette, e.g. `Git: Blame`
+    pub fn palette_text(&self) -> String {
+        match &self.category {
+            Some(category) => format!("{category}: {}", self.title),
+            None => self.title.clone(),
+        }
+    }
+}
+
+/// The kinds of grammar file a plugin can contribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrammarFormat {
+    /// A TextMate grammar, as a plist or JSON
+    TextMate,
+    /// A tree-sitter parser, compiled to WebAssembly or to a library
+    TreeSitter,
+}
+
+impl GrammarFormat {
+    /// The format of the grammar at `path`, from its extension.
+    pub fn from_path(path: &Path) -> Option<GrammarFormat> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".tmlanguage")
+            || name.ends_with(".tmlanguage.json")
+            || name.ends_with(".tmgrammar.json")
+        {
+            return Some(GrammarFormat::TextMate);
+        }
+        match path.extension()?.to_str()? {
+            "wasm" | "so" | "dylib" | "dll" => Some(GrammarFormat::TreeSitter),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -30,7 +113,38 @@ pub struct PluginInfo {
     pub configuration: Option<Value>,
 }
 
+/// Whether the `icon` of a plugin is a URL rather than a path.
+pub fn is_icon_url(icon: &str) -> bool {
+    icon.starts_with("https://") || icon.starts_with("http://")
+}
+
 impl PluginDescription {
+    /// Where the icon of the plugin is downloaded from, from its repository
+    /// like its other files unless it's a URL.
+    pub fn icon_url(&self) -> Option<String> {
+        let icon = self.icon.as_ref()?;
+        if is_icon_url(icon) {
+            return Some(icon.clone());
+        }
+        Some(format!(
+            "https://raw.githubusercontent.com/{}/master/{}",
+            self.repository, icon
+        ))
+    }
+
+    /// The grammars of a loaded plugin, with their formats.
+    pub fn grammar_files(&self) -> Vec<(PathBuf, GrammarFormat)> {
+        self.grammars
+            .iter()
+            .flatten()
+            .filter_map(|grammar| {
+                let path = PathBuf::from(grammar);
+                let format = GrammarFormat::from_path(&path)?;
+                Some((path, format))
+            })
+            .collect()
+    }
+
     pub fn get_plugin_env(&self) -> Result<Vec<(String, String)>, Error> {
         let conf = match &self.configuration {
             Some(val) => val,
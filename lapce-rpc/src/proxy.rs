@@ -1,35 +1,196 @@
 use std::{collections::HashMap, path::PathBuf};
 
-use lsp_types::{CompletionItem, Position};
+use lsp_types::{
+    CallHierarchyItem, CodeActionKind, CodeLens, CompletionItem,
+    CompletionTriggerKind, FormattingOptions, InlayHint, Position, ResourceOp,
+    TextEdit, TraceValue, Url, WorkspaceEdit,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use xi_rope::RopeDelta;
 
 use crate::{
-    buffer::BufferId, file::FileNodeItem, plugin::PluginDescription,
-    source_control::FileDiff, terminal::TermId,
+    buffer::BufferId, file::FileNodeItem, handshake::Handshake,
+    plugin::PluginDescription, source_control::FileDiff, terminal::TermId,
 };
 
+/// The code of the error a language request fails with when no language
+/// server takes its document, where a server having nothing to answer
+/// gives an empty result
+pub const NO_LANGUAGE_SERVER: i64 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "method", content = "params")]
 pub enum ProxyNotification {
+    /// What core tells about itself, before anything else
+    Handshake {
+        handshake: Handshake,
+    },
     Initialize {
         workspace: PathBuf,
+        /// Poll the workspace for file changes every this many milliseconds
+        /// instead of relying on native filesystem notifications.
+        file_watcher_poll_interval: Option<u64>,
+        /// Shut down the least recently used language server when starting
+        /// one would exceed this many running servers.
+        max_language_servers: Option<usize>,
+        /// The user's changes to the completion trigger characters the
+        /// language servers advertise
+        completion_trigger_characters: TriggerCharacterOverride,
+        /// The user's settings for the language servers of each language id,
+        /// merged into their initialization options
+        lsp_settings: HashMap<String, Value>,
+        /// Files larger than this many bytes are opened without the language
+        /// servers
+        max_lsp_document_size: Option<usize>,
+        /// The user's limits on the documents given to each language server,
+        /// by server name
+        #[serde(default)]
+        lsp_document_limits: HashMap<String, DocumentLimits>,
+        /// The user's environment variables for each language server, by
+        /// server name, set over the ones of the server's plugin. An empty
+        /// value unsets the variable.
+        #[serde(default)]
+        lsp_env: HashMap<String, HashMap<String, String>>,
+        /// The user's commands to launch each language server through, by
+        /// server name, used instead of the ones of the server's plugin
+        #[serde(default)]
+        lsp_launch: HashMap<String, ServerLaunch>,
+        /// The user's limits on the requests each language server answers
+        /// at once, by server name. Requests over the limit wait for a
+        /// response.
+        #[serde(default)]
+        lsp_max_in_flight_requests: HashMap<String, usize>,
+        /// The user's choice of what documents of each language id go
+        /// through before they're saved
+        #[serde(default)]
+        save_stages: HashMap<String, SaveStages>,
+        /// Shut down language servers nothing was sent to for this many
+        /// seconds, starting them again when they're needed
+        lsp_idle_timeout: Option<u64>,
+        /// How the language servers of each language id are told to free
+        /// memory once they've been idle for a while
+        #[serde(default)]
+        lsp_memory_trim: HashMap<String, MemoryTrim>,
+        /// The error codes of language servers that read requests are sent
+        /// again once for, `ContentModified` if unset
+        #[serde(default)]
+        lsp_retry_error_codes: Option<Vec<i64>>,
+        /// The largest message in bytes language servers and plugins may
+        /// send
+        max_message_size: Option<usize>,
+        /// Filter and rank complete lists of completion items against the
+        /// word before the cursor before sending them
+        #[serde(default)]
+        filter_completion: bool,
+        /// Keep the symbols of the whole workspace to search them without
+        /// asking the language servers every time
+        #[serde(default)]
+        cache_workspace_symbols: bool,
+        /// When the documentation of completion items is resolved
+        #[serde(default)]
+        completion_documentation: CompletionDocumentation,
+        /// How many milliseconds after a completion item is accepted the
+        /// completions triggered by a character are ignored for
+        #[serde(default)]
+        completion_suppression: u64,
+        /// Globs of the files never given to the language servers, relative
+        /// to the workspace
+        #[serde(default)]
+        lsp_ignore: Vec<String>,
+        /// The roots of the other projects in the workspace, relative to it,
+        /// whose files language servers take as part of those projects
+        #[serde(default)]
+        workspace_roots: Vec<PathBuf>,
+        /// The language ids whose language servers run once for each
+        /// workspace root, rather than once for all of them
+        #[serde(default)]
+        per_root_language_servers: Vec<String>,
+        /// Whether the documents opened at startup wait for the plugins to
+        /// start their language servers
+        #[serde(default)]
+        lsp_startup_order: LspStartupOrder,
+        /// How many milliseconds a file has to stay open before it's given
+        /// to the language servers
+        #[serde(default)]
+        lsp_attach_delay: u32,
     },
     Shutdown {},
+    /// Writes what the proxy knows that's worth keeping across restarts to
+    /// its state file, as the editor may be suspended or killed next
+    PersistState {},
     Update {
         buffer_id: BufferId,
         delta: RopeDelta,
         rev: u64,
+        /// The revision the delta is made against, which the proxy has to
+        /// be at to apply it, `rev - 1` if unset
+        #[serde(default)]
+        base_rev: Option<u64>,
+    },
+    /// The whole text of a buffer at `rev`, for the proxy to start over
+    /// from after it told core its text is out of sync
+    SyncBuffer {
+        buffer_id: BufferId,
+        content: String,
+        rev: u64,
+    },
+    /// A completion item was accepted in the buffer, so that completions
+    /// its text triggers right after are ignored
+    CompletionAccepted {
+        buffer_id: BufferId,
+    },
+    /// The buffer was closed, so it's closed in the language servers too
+    CloseBuffer {
+        buffer_id: BufferId,
+    },
+    /// The open file at `path` turned out to be in another language, so
+    /// hand it over to the language servers of `language_id`.
+    ChangeDocumentLanguage {
+        path: PathBuf,
+        language_id: String,
+    },
+    /// Restarts the servers of `language_id` with `options` merged into
+    /// their initialization options, for settings servers only read when
+    /// they start
+    RestartLanguageServerWithOptions {
+        language_id: String,
+        options: Value,
+    },
+    /// Traces the JSON-RPC traffic of the servers of `language_id` at
+    /// `level`, to their trace file or forwarded to core
+    SetLspTrace {
+        language_id: String,
+        level: TraceValue,
+        to_core: bool,
+    },
+    /// Replaces the globs of the files never given to the language servers,
+    /// applying them to the files already open
+    SetLspIgnore {
+        patterns: Vec<String>,
+    },
+    /// Sets how much the servers of `language_id` log about themselves
+    /// with `$/logTrace`, from startup for the servers started later
+    SetServerTrace {
+        language_id: String,
+        value: TraceValue,
     },
     NewTerminal {
         term_id: TermId,
         cwd: Option<PathBuf>,
         shell: String,
+        /// How many bytes of output may pile up while core is busy
+        output_limit: Option<usize>,
     },
     InstallPlugin {
         plugin: PluginDescription,
     },
+    /// Stop installing the plugin named `name` and remove what's been
+    /// downloaded of it.
+    CancelPluginInstall {
+        name: String,
+    },
     DisablePlugin {
         plugin: PluginDescription,
     },
@@ -39,6 +200,9 @@ pub enum ProxyNotification {
     RemovePlugin {
         plugin: PluginDescription,
     },
+    ExecutePluginCommand {
+        command: String,
+    },
     GitCommit {
         message: String,
         diffs: Vec<FileDiff>,
@@ -84,6 +248,10 @@ pub enum ProxyRequest {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        trigger_kind: Option<CompletionTriggerKind>,
+        trigger_character: Option<String>,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GlobalSearch {
         pattern: String,
@@ -92,38 +260,102 @@ pub enum ProxyRequest {
         buffer_id: BufferId,
         completion_item: Box<CompletionItem>,
     },
+    /// The initialization options the server of a language is started with.
+    /// From lowest to highest precedence, they're merged from the defaults
+    /// in its plugin's manifest, the options the plugin starts it with, and
+    /// the user's and then the workspace's settings for its languages.
+    GetEffectiveServerConfig {
+        language_id: String,
+    },
     GetHover {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GetSignature {
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GetReferences {
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GetDefinition {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GetTypeDefinition {
         request_id: usize,
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
     },
     GetInlayHints {
         buffer_id: BufferId,
     },
+    /// Fills in the parts of a hint the server left out of `GetInlayHints`,
+    /// like its tooltip. The hint is given back as is when the server can't
+    /// resolve hints.
+    InlayHintResolve {
+        buffer_id: BufferId,
+        inlay_hint: Box<InlayHint>,
+    },
+    GetCodeLens {
+        buffer_id: BufferId,
+    },
+    /// Fills in the command of a lens the server left out of `GetCodeLens`.
+    /// The lens is given back as is when the server can't resolve lenses.
+    CodeLensResolve {
+        buffer_id: BufferId,
+        code_lens: Box<CodeLens>,
+    },
+    GetLinkedEditingRange {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    GetMoniker {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    /// The call hierarchy items at `position`, which the server is only
+    /// asked for again once the document changes
+    PrepareCallHierarchy {
+        buffer_id: BufferId,
+        position: Position,
+    },
+    /// The calls to `item`, one of the items prepared for `buffer_id`
+    GetIncomingCalls {
+        buffer_id: BufferId,
+        item: CallHierarchyItem,
+    },
+    /// The calls `item` makes, one of the items prepared for `buffer_id`
+    GetOutgoingCalls {
+        buffer_id: BufferId,
+        item: CallHierarchyItem,
+    },
     GetSemanticTokens {
         buffer_id: BufferId,
     },
     GetCodeActions {
         buffer_id: BufferId,
         position: Position,
+        #[serde(default)]
+        server_id: Option<String>,
+        /// The kinds of the actions wanted, like `source.organizeImports`,
+        /// which also take in their subkinds. All actions are wanted when
+        /// it's empty.
+        #[serde(default)]
+        only: Vec<CodeActionKind>,
     },
     GetDocumentSymbols {
         buffer_id: BufferId,
@@ -136,6 +368,21 @@ pub enum ProxyRequest {
     },
     GetDocumentFormatting {
         buffer_id: BufferId,
+        #[serde(default)]
+        server_id: Option<String>,
+    },
+    /// Formats what was typed before `position` after `ch`, one of the
+    /// characters the server formats after, was typed
+    OnTypeFormatting {
+        buffer_id: BufferId,
+        position: Position,
+        ch: String,
+        options: FormattingOptions,
+    },
+    GetDocumentDiagnostic {
+        buffer_id: BufferId,
+        /// The `resultId` of the report core last received for this buffer
+        previous_result_id: Option<String>,
     },
     GetFiles {
         path: String,
@@ -143,6 +390,15 @@ pub enum ProxyRequest {
     ReadDir {
         path: PathBuf,
     },
+    /// Runs the document through what it goes through before it's saved:
+    /// the edits the language servers want made with `willSaveWaitUntil`,
+    /// then formatting, which `format` turns on for the languages the user
+    /// didn't set it for. Answers with the text to save, if it changed.
+    PrepareSave {
+        rev: u64,
+        buffer_id: BufferId,
+        format: bool,
+    },
     Save {
         rev: u64,
         buffer_id: BufferId,
@@ -166,9 +422,412 @@ pub enum ProxyRequest {
         from: PathBuf,
         to: PathBuf,
     },
+    GetVersions {},
+    /// The icon of the installed plugin `name`
+    GetPluginIcon {
+        name: String,
+    },
+    /// The language servers running for `language_id`. The requests about
+    /// a buffer with a `server_id` go to that server alone, rather than to
+    /// the servers they usually go to.
+    GetLanguageServers {
+        language_id: String,
+    },
+    /// Orders a `WorkspaceEdit` into the operations to apply it with
+    NormalizeWorkspaceEdit {
+        edit: WorkspaceEdit,
+    },
+    /// The edit renaming the symbol at `position` in the open document at
+    /// `path` to `new_name`, with the lines it changes in each file, for
+    /// the user to review before it's applied
+    RenamePreview {
+        path: PathBuf,
+        position: Position,
+        new_name: String,
+    },
+    /// How full the queues of the messages to the language servers are
+    GetQueueStats {},
+    /// The language servers handling the open document at `path`, if any
+    GetServersForPath {
+        path: PathBuf,
+    },
+    /// What each running plugin has been using
+    GetPluginStats {},
+    /// Has the language servers of the open document at `path` compute its
+    /// diagnostics again, dropping the ones it has now
+    RefreshDiagnostics {
+        path: PathBuf,
+    },
+    /// Everything the proxy can tell about its plugins and language servers,
+    /// for the user to attach to a bug report
+    CollectDiagnosticsBundle {},
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReadDirResponse {
     pub items: HashMap<PathBuf, FileNodeItem>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionsResponse {
+    pub proxy: String,
+    pub wasmer: String,
+    /// The running language servers, by language id
+    pub servers: HashMap<String, ServerVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueStatsResponse {
+    /// The queue of each running language server, by the server's id
+    pub servers: HashMap<String, QueueStats>,
+}
+
+/// How a queue of messages to send has been coping.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// The messages waiting to be sent
+    pub depth: usize,
+    /// The most messages that have waited at once
+    pub max_depth: usize,
+    pub capacity: usize,
+    /// How often a sender had to wait for room
+    pub blocked: u64,
+    /// How many messages were dropped for a newer one that replaced them
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginStatsResponse {
+    /// The stats of each running plugin, by the plugin's name
+    pub plugins: HashMap<String, PluginStats>,
+}
+
+/// What a plugin has been using since it was started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginStats {
+    /// The 64 KiB pages of memory the plugin's module has
+    pub memory_pages: u32,
+    /// How often the proxy called into the plugin's exports
+    pub calls: u64,
+    /// How long the calls took in all, in microseconds
+    pub call_time_us: u64,
+    /// The messages the proxy wrote to the plugin
+    pub messages_sent: u64,
+    /// The messages the plugin wrote to the proxy
+    pub messages_received: u64,
+}
+
+/// The state of the proxy a bug report is made with. It has none of the
+/// contents of the user's files, and the values of the environment variables
+/// that may be secrets are replaced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagnosticsBundleResponse {
+    pub versions: VersionsResponse,
+    /// The operating system and architecture the proxy runs on
+    pub platform: String,
+    pub plugins: Vec<PluginReport>,
+    pub servers: Vec<ServerReport>,
+    /// The last lines the proxy logged
+    pub log: Vec<String>,
+}
+
+/// An installed plugin, as it's put in a bug report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginReport {
+    pub name: String,
+    pub version: String,
+    pub state: PluginState,
+    /// What it used, if it's running
+    pub stats: Option<PluginStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginState {
+    /// Its `initialize` hasn't returned yet
+    Starting,
+    Running,
+    /// Enabled, but not running, for having no module or failing to load
+    Stopped,
+    Disabled,
+    /// Disabled by the proxy for crashing too often
+    AutoDisabled,
+}
+
+/// A running language server, as it's put in a bug report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerReport {
+    #[serde(flatten)]
+    pub server: ServerDescriptor,
+    /// What the server reported about itself, once initialized
+    pub server_info: Option<ServerVersion>,
+    pub queue: QueueStats,
+    /// The capabilities the server advertised, by their field in
+    /// `ServerCapabilities`
+    pub capabilities: Vec<String>,
+    pub env: HashMap<String, String>,
+    /// The last lines the server wrote to its stderr
+    pub stderr: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceEditResponse {
+    pub operations: Vec<WorkspaceEditOperation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenamePreviewResponse {
+    /// The edit to apply once reviewed, which may also create, rename or
+    /// delete files
+    pub edit: WorkspaceEdit,
+    pub files: Vec<FileEditPreview>,
+}
+
+/// The lines an edit changes in a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEditPreview {
+    pub path: PathBuf,
+    pub changes: Vec<LineEditPreview>,
+}
+
+/// The lines from `line` on that an edit changes, as they are and as they
+/// would be, without their last line ending.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineEditPreview {
+    pub line: u32,
+    pub before: String,
+    pub after: String,
+}
+
+/// One step of applying a `WorkspaceEdit`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceEditOperation {
+    /// Edits to the document at `uri`, from its end to its start so that each
+    /// applies at the position it was made at
+    Edit { uri: Url, edits: Vec<TextEdit> },
+    /// Creating, renaming or deleting a file
+    Resource(ResourceOp),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginIconResponse {
+    /// The image file, base64 encoded
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrepareSaveResponse {
+    pub text: Option<String>,
+}
+
+/// Which stages of getting ready to be saved the documents of a language go
+/// through. Those left unset are on, except for formatting, which follows
+/// the editor's format on save.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SaveStages {
+    #[serde(default, alias = "will-save-wait-until")]
+    pub will_save_wait_until: Option<bool>,
+    #[serde(default)]
+    pub format: Option<bool>,
+}
+
+/// The command a language's servers free memory with, sent to them once
+/// they've been idle for a while, as a lighter alternative to shutting them
+/// down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryTrim {
+    /// Sent with `workspace/executeCommand`, to the servers advertising it
+    pub command: String,
+    /// How many seconds nothing must have been sent to a server for
+    #[serde(alias = "idle-timeout")]
+    pub idle_timeout: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LanguageServersResponse {
+    pub servers: Vec<LanguageServerInfo>,
+}
+
+/// A running language server, as core can pick it to answer a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanguageServerInfo {
+    /// Stays the same across restarts, made from the server's executable
+    /// and languages
+    pub id: String,
+    pub name: String,
+    pub language_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshDiagnosticsResponse {
+    /// The ids of the servers asked for the diagnostics again, none if
+    /// the document isn't open in any
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServersForPathResponse {
+    pub servers: Vec<ServerDescriptor>,
+}
+
+/// A language server handling a document, and how far along it is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerDescriptor {
+    pub id: String,
+    pub name: String,
+    pub language_ids: Vec<String>,
+    pub state: ServerState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    /// Started and not initialized yet, so it doesn't answer requests
+    Starting,
+    Ready,
+    ShuttingDown,
+    /// Exited or crashed, and not started again yet
+    Stopped,
+}
+
+/// What a language server reported about itself in its `serverInfo`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerVersion {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+/// Characters to add to or remove from the completion trigger characters a
+/// language server advertises.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerCharacterOverride {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+impl TriggerCharacterOverride {
+    /// The trigger characters of a server advertising `characters`, with
+    /// the override applied.
+    pub fn apply(&self, characters: &[String]) -> Vec<String> {
+        let mut effective = characters
+            .iter()
+            .chain(self.add.iter())
+            .filter(|c| !self.remove.contains(c))
+            .cloned()
+            .collect::<Vec<_>>();
+        effective.sort();
+        effective.dedup();
+        effective
+    }
+}
+
+/// When the documentation of completion items that servers leave out of
+/// their lists is resolved with `completionItem/resolve`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionDocumentation {
+    /// For the items of each list before it's sent to core, a few at a time
+    Eager,
+    /// Never, and the items are sent to core without any
+    Off,
+    /// For the item core asks about, once it's picked
+    #[default]
+    #[serde(other)]
+    Lazy,
+}
+
+/// When the documents core opens while the proxy starts are handed to the
+/// language servers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LspStartupOrder {
+    /// As they're opened, before the servers the plugins start are there
+    /// to take them
+    Concurrent,
+    /// Once the plugins are started, along with the servers they start
+    #[default]
+    #[serde(other)]
+    PluginsFirst,
+}
+
+/// How a language server's command is run, when it isn't run directly, like
+/// through `nix-shell --run` or a version manager's shim.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerLaunch {
+    /// The program the server's command is given to, with its arguments
+    /// before the command
+    #[serde(default)]
+    pub wrapper: Vec<String>,
+    /// Whether the command is given as one line for a shell, as
+    /// `nix-shell --run` takes it, rather than as separate arguments.
+    /// Without a wrapper, the line is run by the system's shell.
+    #[serde(default)]
+    pub shell: bool,
+}
+
+/// How large a document a language server is given. Larger documents are
+/// edited without the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentLimits {
+    /// The size in bytes
+    #[serde(default, alias = "max-size")]
+    pub max_size: Option<usize>,
+    /// The number of lines, not counting the empty one after a final line
+    /// break
+    #[serde(default, alias = "max-lines")]
+    pub max_lines: Option<usize>,
+}
+
+impl DocumentLimits {
+    /// These limits with the ones `overlay` sets in their place.
+    pub fn overlay(self, overlay: DocumentLimits) -> DocumentLimits {
+        DocumentLimits {
+            max_size: overlay.max_size.or(self.max_size),
+            max_lines: overlay.max_lines.or(self.max_lines),
+        }
+    }
+
+    /// Why a document of `size` bytes and `lines` lines is over the limits,
+    /// if it is.
+    pub fn exceeded_by(&self, size: usize, lines: usize) -> Option<String> {
+        if let Some(max) = self.max_size.filter(|max| size > *max) {
+            return Some(format!("{size} bytes, over the limit of {max}"));
+        }
+        if let Some(max) = self.max_lines.filter(|max| lines > *max) {
+            return Some(format!("{lines} lines, over the limit of {max}"));
+        }
+        None
+    }
+}
+
+/// What the proxy puts in the `data` of each completion item it forwards,
+/// so that the server the item came from is known, both to show it and to
+/// resolve the item with the same server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionItemData {
+    /// The name of the language server that gave the item
+    pub source: String,
+    /// The `data` the server set on the item
+    pub data: Option<Value>,
+    /// How the item matched what was typed, when the proxy filtered the
+    /// items
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched: Option<CompletionMatch>,
+}
+
+/// How a completion item matched the word typed before the cursor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletionMatch {
+    pub score: i64,
+    /// The indices of the matched characters in the item's filter text, or
+    /// its label if it has none
+    pub indices: Vec<usize>,
+}
+
+impl CompletionItemData {
+    /// The source tag of `item`, if the proxy set one.
+    pub fn of(item: &CompletionItem) -> Option<CompletionItemData> {
+        serde_json::from_value(item.data.clone()?).ok()
+    }
+}
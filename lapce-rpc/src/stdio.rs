@@ -2,10 +2,22 @@ use anyhow::Result;
 use crossbeam_channel::{Receiver, Sender};
 use serde_json::Value;
 use std::{
-    io::{self, BufRead, Write},
+    io::{self, BufRead, ErrorKind, Write},
     thread,
+    time::Duration,
 };
 
+/// How many times in a row reading or writing a message is tried again
+/// after an error the channel can recover from, before it's given up on
+const MAX_RETRIES: u32 = 5;
+/// How long to wait before trying again the first time, doubled each time
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Moves messages between the channels and the other end of `writer` and
+/// `reader`, one JSON object per line. Errors a pipe can recover from are
+/// retried a few times with a growing delay. Once the other end is gone,
+/// or the errors go on, the channels are closed: `reader_sender` is dropped,
+/// which is how the receiving side learns of it.
 pub fn stdio_transport<W, R>(
     mut writer: W,
     writer_receiver: Receiver<Value>,
@@ -16,35 +28,145 @@ pub fn stdio_transport<W, R>(
     R: 'static + BufRead + Send,
 {
     thread::spawn(move || -> Result<()> {
-        writer_receiver
-            .into_iter()
-            .try_for_each(|it| write_msg(&mut writer, &it))?;
+        let mut retries = Retries::default();
+        for msg in writer_receiver {
+            let msg = format!("{}\n", serde_json::to_string(&msg)?);
+            // what's written of the message already isn't written again
+            let mut written = 0;
+            while written < msg.len() {
+                match writer.write(&msg.as_bytes()[written..]) {
+                    Ok(0) => {
+                        return Err(io::Error::from(ErrorKind::WriteZero).into())
+                    }
+                    Ok(n) => {
+                        written += n;
+                        retries.reset();
+                    }
+                    Err(err) => retries.wait(err)?,
+                }
+            }
+            while let Err(err) = writer.flush() {
+                retries.wait(err)?;
+            }
+        }
         Ok(())
     });
     thread::spawn(move || -> Result<()> {
+        let mut retries = Retries::default();
+        let mut buf = Vec::new();
         loop {
-            let msg = read_msg(&mut reader)?;
-            reader_sender.send(msg)?;
+            let read = buf.len();
+            match read_msg(&mut reader, &mut buf) {
+                Ok(Some(msg)) => {
+                    retries.reset();
+                    reader_sender.send(msg)?;
+                }
+                // the other end closed the pipe
+                Ok(None) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::InvalidData => {
+                    eprintln!("skipped a message that isn't valid: {err}");
+                }
+                Err(err) => {
+                    if buf.len() > read {
+                        retries.reset();
+                    }
+                    retries.wait(err)?;
+                }
+            }
         }
     });
 }
 
-fn write_msg<W>(out: &mut W, msg: &Value) -> io::Result<()>
-where
-    W: Write,
-{
-    let msg = format!("{}\n", serde_json::to_string(msg)?);
-    out.write_all(msg.as_bytes())?;
-    out.flush()?;
-    Ok(())
+/// The errors a pipe can recover from in a row.
+#[derive(Default)]
+struct Retries {
+    count: u32,
+}
+
+impl Retries {
+    /// Waits to try again after `err`, or gives it back if the pipe can't
+    /// recover from it or has been given enough tries.
+    fn wait(&mut self, err: io::Error) -> io::Result<()> {
+        if !is_recoverable(&err) || self.count >= MAX_RETRIES {
+            return Err(err);
+        }
+        thread::sleep(RETRY_DELAY * 2u32.pow(self.count));
+        self.count += 1;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+}
+
+/// Whether `err` may go away by trying again, rather than meaning the other
+/// end of the pipe is gone.
+fn is_recoverable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
 }
 
-fn read_msg<R>(inp: &mut R) -> io::Result<Value>
+/// Reads the next message into `buf`, where what's read of it is kept if
+/// reading fails halfway through. Gives `None` once the pipe is closed.
+fn read_msg<R>(inp: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<Value>>
 where
     R: BufRead,
 {
-    let mut buf = String::new();
-    let _s = inp.read_line(&mut buf)?;
-    let value: Value = serde_json::from_str(&buf)?;
-    Ok(value)
+    let read = inp.read_until(b'\n', buf)?;
+    if read == 0 && buf.is_empty() {
+        return Ok(None);
+    }
+    let line = std::mem::take(buf);
+    let value: Value = serde_json::from_slice(&line)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Gives `data` a few bytes at a time, failing the way a pipe that
+    /// isn't ready does in between.
+    struct FlakyReader {
+        data: Vec<u8>,
+        pos: usize,
+        fail: bool,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            if self.fail {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(4).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_through_recoverable_errors() {
+        let reader = BufReader::new(FlakyReader {
+            data: b"{\"id\":1}\nnot json\n\n{\"id\":2}\n".to_vec(),
+            pos: 0,
+            fail: false,
+        });
+        let (_writer_sender, writer_receiver) = crossbeam_channel::unbounded();
+        let (reader_sender, reader_receiver) = crossbeam_channel::unbounded();
+        stdio_transport(io::sink(), writer_receiver, reader, reader_sender);
+
+        // the channel is closed once the data ends
+        let messages = reader_receiver.iter().collect::<Vec<_>>();
+        assert_eq!(messages, vec![json!({"id": 1}), json!({"id": 2})]);
+    }
 }
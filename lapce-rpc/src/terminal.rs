@@ -11,3 +11,15 @@ impl TermId {
         Self(TERMINAL_ID_COUNTER.next())
     }
 }
+
+/// How the process running in a terminal ended.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TermExitStatus {
+    /// It exited normally with this code
+    Code(i32),
+    /// It was terminated by this signal
+    Signal(i32),
+    /// The platform doesn't tell how it ended
+    Unknown,
+}
@@ -13,6 +13,7 @@ use lapce_data::{
     db::{TabsInfo, WindowInfo},
     proxy::VERSION,
 };
+use lapce_proxy::log_tail::TailLogger;
 
 use crate::logging::override_log_levels;
 use crate::window::LapceWindow;
@@ -75,6 +76,12 @@ pub fn launch() {
     }
 
     log_dispatch = override_log_levels(log_dispatch);
+    // The proxy keeps what it logged for bug reports, whatever gets printed.
+    let log_dispatch = fern::Dispatch::new().chain(log_dispatch).chain(
+        fern::Dispatch::new()
+            .level(log::LevelFilter::Info)
+            .chain(Box::new(TailLogger) as Box<dyn log::Log>),
+    );
     match log_dispatch.apply() {
         Ok(()) => (),
         Err(e) => eprintln!("Initialising logging failed {e:?}"),
@@ -207,6 +214,11 @@ impl AppDelegate<LapceData> for LapceAppDelegate {
         //FIXME: no event::aplicationWillTerminate is sent.
         if let Event::ApplicationWillTerminate = event {
             let _ = data.db.save_app(data);
+            for (_, window) in data.windows.iter() {
+                for (_, tab) in window.tabs.iter() {
+                    tab.proxy.persist_state();
+                }
+            }
             return None;
         }
         Some(event)
@@ -222,6 +234,7 @@ impl AppDelegate<LapceData> for LapceAppDelegate {
         if let Some(window) = data.windows.remove(&id) {
             for (_, tab) in window.tabs.iter() {
                 let _ = data.db.save_workspace(tab);
+                tab.proxy.persist_state();
             }
             data.db.save_last_window(&window);
         }
@@ -298,6 +298,15 @@ impl Widget<LapceTabData> for CompletionContainer {
                             resp.to_owned(),
                         );
                     }
+                    LapceUICommand::CompletionUnavailable(request_id, reason) => {
+                        if data.completion.status != CompletionStatus::Inactive
+                            && data.completion.request_id == *request_id
+                            && data.completion.explicit
+                        {
+                            let completion = Arc::make_mut(&mut data.completion);
+                            completion.unavailable = Some(reason.to_owned());
+                        }
+                    }
                     LapceUICommand::CancelCompletion(request_id) => {
                         if data.completion.request_id == *request_id {
                             let completion = Arc::make_mut(&mut data.completion);
@@ -355,6 +364,7 @@ impl Widget<LapceTabData> for CompletionContainer {
         if old_data.completion.input != data.completion.input
             || old_data.completion.request_id != data.completion.request_id
             || old_data.completion.status != data.completion.status
+            || old_data.completion.unavailable != data.completion.unavailable
             || !old_data
                 .completion
                 .current_items()
@@ -431,7 +441,7 @@ impl Widget<LapceTabData> for CompletionContainer {
 
     fn paint(&mut self, ctx: &mut PaintCtx, data: &LapceTabData, env: &Env) {
         if data.completion.status != CompletionStatus::Inactive
-            && data.completion.len() > 0
+            && (data.completion.len() > 0 || data.completion.unavailable.is_some())
         {
             let rect = self.completion_content_size.to_rect();
             let shadow_width = data.config.ui.drop_shadow_width() as f64;
@@ -505,7 +515,11 @@ impl Widget<LapceTabData> for Completion {
         _env: &Env,
     ) -> Size {
         let line_height = data.config.editor.line_height as f64;
-        let height = data.completion.len();
+        let height = if data.completion.is_empty() {
+            data.completion.unavailable.iter().count()
+        } else {
+            data.completion.len()
+        };
         let height = height as f64 * line_height;
         Size::new(bc.max().width, height)
     }
@@ -527,6 +541,31 @@ impl Widget<LapceTabData> for Completion {
                 .get_color_unchecked(LapceTheme::COMPLETION_BACKGROUND),
         );
 
+        if items.is_empty() {
+            let reason = match data.completion.unavailable.as_ref() {
+                Some(reason) => reason,
+                None => return,
+            };
+            let text_layout = ctx
+                .text()
+                .new_text_layout(reason.clone())
+                .font(
+                    FontFamily::new_unchecked(
+                        data.config.editor.font_family.clone(),
+                    ),
+                    data.config.editor.font_size as f64,
+                )
+                .text_color(
+                    data.config
+                        .get_color_unchecked(LapceTheme::EDITOR_DIM)
+                        .clone(),
+                )
+                .build()
+                .unwrap();
+            ctx.draw_text(&text_layout, Point::new(5.0, 5.0));
+            return;
+        }
+
         let start_line = (rect.y0 / line_height).floor() as usize;
         let end_line = (rect.y1 / line_height).ceil() as usize;
 
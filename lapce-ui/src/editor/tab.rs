@@ -54,11 +54,16 @@ impl LapceEditorTab {
         self.children.clear();
         ctx.children_changed();
 
-        let editor_tab = data.main_split.editor_tabs.get(&self.widget_id).unwrap();
+        let editor_tab = data
+            .main_split
+            .editor_tabs
+            .get(&self.widget_id)
+            .unwrap()
+            .clone();
         for child in editor_tab.children.iter() {
             match child {
                 EditorTabChild::Editor(view_id, _, _) => {
-                    data.main_split.editors.remove(view_id);
+                    data.main_split.remove_editor(view_id);
                 }
                 EditorTabChild::Settings(_, _) => {}
             }
@@ -126,7 +131,7 @@ impl LapceEditorTab {
         if delete {
             match removed_child {
                 EditorTabChild::Editor(view_id, _, _) => {
-                    data.main_split.editors.remove(&view_id);
+                    data.main_split.remove_editor(&view_id);
                 }
                 EditorTabChild::Settings(_, _) => {}
             }
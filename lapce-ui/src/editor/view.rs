@@ -212,7 +212,9 @@ impl LapceEditorView {
                 }
                 let offset = data.editor.cursor.offset();
                 let line = data.doc.buffer().line_of_offset(offset);
-                let _ = data.apply_completion_item(item);
+                if let Err(err) = data.apply_completion_item(item) {
+                    log::error!("Failed to apply completion item: {err}");
+                }
                 let new_offset = data.editor.cursor.offset();
                 let new_line = data.doc.buffer().line_of_offset(new_offset);
                 if line != new_line {
@@ -636,6 +636,13 @@ impl PaletteContent {
                     "".to_string(),
                     vec![],
                 ),
+                PaletteItemContent::PluginCommand(command) => (
+                    None,
+                    command.palette_text(),
+                    indices.to_vec(),
+                    "".to_string(),
+                    vec![],
+                ),
                 PaletteItemContent::Theme(theme) => (
                     None,
                     theme.to_string(),
@@ -381,7 +381,7 @@ impl LapceSplit {
             ));
         }
         let view_id = self.children[index].widget.id();
-        data.main_split.editors.remove(&view_id);
+        data.main_split.remove_editor(&view_id);
         self.children.remove(index);
         self.children_ids.remove(index);
 
@@ -24,7 +24,7 @@ use lapce_data::{
     config::{Config, LapceTheme},
     data::{
         DragContent, EditorDiagnostic, FocusArea, LapceData, LapceTabData,
-        LapceWorkspace, LapceWorkspaceType, WorkProgress,
+        LapceWorkspace, LapceWorkspaceType,
     },
     document::{BufferContent, LocalBufferKind},
     editor::EditorLocation,
@@ -739,7 +739,12 @@ impl LapceTab {
                         locations,
                         edits,
                     } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            // closed before its content was loaded
+                            None => return,
+                        };
                         let doc = Arc::make_mut(doc);
                         doc.init_content(content.to_owned());
 
@@ -760,7 +765,6 @@ impl LapceTab {
                                 &data.config,
                             );
                         }
-                        ctx.set_handled();
                     }
                     LapceUICommand::InitBufferContentLsp {
                         path,
@@ -768,7 +772,11 @@ impl LapceTab {
                         locations,
                         edits,
                     } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         let doc = Arc::make_mut(doc);
                         doc.init_content(content.to_owned());
 
@@ -789,7 +797,6 @@ impl LapceTab {
                                 &data.config,
                             );
                         }
-                        ctx.set_handled();
                     }
                     LapceUICommand::InitPaletteInput(pattern) => {
                         let doc = data
@@ -884,10 +891,13 @@ impl LapceTab {
                         version,
                         content,
                     } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         let doc = Arc::make_mut(doc);
                         doc.load_history(version, content.clone());
-                        ctx.set_handled();
                     }
                     LapceUICommand::UpdateTerminalTitle(term_id, title) => {
                         let terminal_panel = Arc::make_mut(&mut data.terminal);
@@ -942,6 +952,13 @@ impl LapceTab {
                     LapceUICommand::UpdateDisabledPlugins(plugins) => {
                         data.disabled_plugins = Arc::new(plugins.to_owned());
                     }
+                    LapceUICommand::UpdatePluginCommands(commands) => {
+                        data.plugin_commands = Arc::new(commands.to_owned());
+                    }
+                    LapceUICommand::ExecutePluginCommand(command) => {
+                        data.proxy.execute_plugin_command(command);
+                        ctx.set_handled();
+                    }
                     LapceUICommand::UpdatePluginInstallationChange(plugins) => {
                         let local_plugins = plugins.clone();
                         let handle = std::thread::spawn(move || {
@@ -1003,47 +1020,38 @@ impl LapceTab {
                         }
                         ctx.set_handled();
                     }
+                    LapceUICommand::UpdateCompletionPartial(
+                        request_id,
+                        position,
+                        items,
+                    ) => {
+                        let completion = Arc::make_mut(&mut data.completion);
+                        completion.receive_partial(*request_id, position, items);
+                        ctx.set_handled();
+                    }
                     LapceUICommand::WorkDoneProgress(params) => {
                         match &params.value {
                             lsp_types::ProgressParamsValue::WorkDone(progress) => {
-                                match progress {
-                                    lsp_types::WorkDoneProgress::Begin(begin) => {
-                                        data.progresses.push_back(WorkProgress {
-                                            token: params.token.clone(),
-                                            title: begin.title.clone(),
-                                            message: begin.message.clone(),
-                                            percentage: begin.percentage,
-                                        });
-                                    }
-                                    lsp_types::WorkDoneProgress::Report(report) => {
-                                        for p in data.progresses.iter_mut() {
-                                            if p.token == params.token {
-                                                p.message = report.message.clone();
-                                                p.percentage = report.percentage;
-                                            }
-                                        }
-                                    }
-                                    lsp_types::WorkDoneProgress::End(_end) => {
-                                        for view_id in data.main_split.editors.keys()
-                                        {
-                                            let editor_data =
-                                                data.editor_view_content(*view_id);
-                                            editor_data.doc.get_inlay_hints();
-                                        }
-                                        for i in data
-                                            .progresses
-                                            .iter()
-                                            .positions(|p| p.token == params.token)
-                                            .sorted()
-                                            .rev()
-                                        {
-                                            data.progresses.remove(i);
-                                        }
+                                if let lsp_types::WorkDoneProgress::End(_) = progress
+                                {
+                                    for view_id in data.main_split.editors.keys() {
+                                        let editor_data =
+                                            data.editor_view_content(*view_id);
+                                        editor_data.doc.get_inlay_hints();
                                     }
                                 }
+                                data.update_progress(&params.token, progress);
                             }
                         }
                     }
+                    LapceUICommand::RequestProgress(request_id, progress) => {
+                        // shown apart from the servers' own work, by a token
+                        // of the request
+                        let token = lsp_types::NumberOrString::String(format!(
+                            "lapce/request/{request_id}"
+                        ));
+                        data.update_progress(&token, progress);
+                    }
                     LapceUICommand::PublishDiagnostics(diagnostics) => {
                         let path = path_from_url(&diagnostics.uri);
                         let diagnostics = diagnostics
@@ -1134,7 +1142,11 @@ impl LapceTab {
                         ctx.set_handled();
                     }
                     LapceUICommand::BufferSave(path, rev, exit_widget_id) => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         if doc.rev() == *rev {
                             Arc::make_mut(doc).buffer_mut().set_pristine();
                             if let Some(widget_id) = exit_widget_id {
@@ -1150,7 +1162,6 @@ impl LapceTab {
                                 ));
                             }
                         }
-                        ctx.set_handled();
                     }
                     LapceUICommand::LoadBufferAndGoToPosition {
                         path,
@@ -1158,7 +1169,11 @@ impl LapceTab {
                         editor_view_id,
                         location,
                     } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         Arc::make_mut(doc).reload(Rope::from(content), true);
                         data.main_split.go_to_location(
                             ctx,
@@ -1166,7 +1181,6 @@ impl LapceTab {
                             location.clone(),
                             &data.config,
                         );
-                        ctx.set_handled();
                     }
                     LapceUICommand::UpdateSettingsFile(parent, key, value) => {
                         if let Ok(value) = toml::Value::deserialize(value) {
@@ -1341,6 +1355,54 @@ impl LapceTab {
                             }
                         }
                     }
+                    LapceUICommand::UpdateInlayHint {
+                        path,
+                        rev,
+                        offset,
+                        hint,
+                    } => {
+                        if let Some(doc) = data.main_split.open_docs.get_mut(path) {
+                            if doc.rev() == *rev {
+                                Arc::make_mut(doc)
+                                    .set_inlay_hint(*offset, hint.clone());
+                            }
+                        }
+                    }
+                    LapceUICommand::RefreshSemanticStyles(paths) => {
+                        for path in paths {
+                            if let Some(doc) = data.main_split.open_docs.get(path) {
+                                doc.get_semantic_styles();
+                            }
+                        }
+                    }
+                    LapceUICommand::RefreshInlayHints(paths) => {
+                        for path in paths {
+                            if let Some(doc) = data.main_split.open_docs.get(path) {
+                                doc.get_inlay_hints();
+                            }
+                        }
+                    }
+                    LapceUICommand::UpdateCodeLens { path, rev, lenses } => {
+                        if let Some(doc) = data.main_split.open_docs.get_mut(path) {
+                            if doc.rev() == *rev {
+                                Arc::make_mut(doc).set_code_lens(lenses.clone());
+                            }
+                        }
+                    }
+                    LapceUICommand::RequestCodeLens { path, rev } => {
+                        if let Some(doc) = data.main_split.open_docs.get(path) {
+                            if doc.rev() == *rev {
+                                doc.get_code_lens();
+                            }
+                        }
+                    }
+                    LapceUICommand::RefreshCodeLens(paths) => {
+                        for path in paths {
+                            if let Some(doc) = data.main_split.open_docs.get(path) {
+                                doc.get_code_lens();
+                            }
+                        }
+                    }
                     LapceUICommand::UpdateCodeActions(path, rev, offset, resp) => {
                         if let Some(doc) = data.main_split.open_docs.get_mut(path) {
                             if doc.rev() == *rev {
@@ -1388,12 +1450,29 @@ impl LapceTab {
                         ctx.set_handled();
                     }
                     LapceUICommand::OpenFileChanged { path, content } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         let doc = Arc::make_mut(doc);
                         doc.handle_file_changed(content.to_owned());
                     }
+                    LapceUICommand::SyncBuffer { path } => {
+                        if let Some(doc) = data.main_split.open_docs.get(path) {
+                            data.proxy.sync_buffer(
+                                doc.id(),
+                                doc.buffer().text().to_string(),
+                                doc.rev(),
+                            );
+                        }
+                        ctx.set_handled();
+                    }
                     LapceUICommand::ReloadBuffer { path, rev, content } => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         if doc.rev() + 1 == *rev {
                             let doc = Arc::make_mut(doc);
                             doc.reload(content.to_owned(), true);
@@ -1429,16 +1508,17 @@ impl LapceTab {
                                 }
                             }
                         }
-                        ctx.set_handled();
                     }
                     LapceUICommand::UpdateSemanticStyles(_id, path, rev, styles) => {
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        ctx.set_handled();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         if doc.rev() == *rev {
                             let doc = Arc::make_mut(doc);
                             doc.set_semantic_styles(Some(styles.clone()));
                         }
-
-                        ctx.set_handled();
                     }
                     LapceUICommand::Focus => {
                         let dir = data
@@ -1488,7 +1568,11 @@ impl LapceTab {
                         ctx.set_handled();
                         let doc = match content {
                             BufferContent::File(path) => {
-                                data.main_split.open_docs.get_mut(path).unwrap()
+                                // the file may be closed before it's parsed
+                                match data.main_split.open_docs.get_mut(path) {
+                                    Some(doc) => doc,
+                                    None => return,
+                                }
                             }
                             BufferContent::Local(kind) => {
                                 data.main_split.local_docs.get_mut(kind).unwrap()
@@ -1540,7 +1624,10 @@ impl LapceTab {
                         ..
                     } => {
                         ctx.set_handled();
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         Arc::make_mut(doc).update_history_changes(
                             *rev,
                             history,
@@ -1554,7 +1641,10 @@ impl LapceTab {
                         ..
                     } => {
                         ctx.set_handled();
-                        let doc = data.main_split.open_docs.get_mut(path).unwrap();
+                        let doc = match data.main_split.open_docs.get_mut(path) {
+                            Some(doc) => doc,
+                            None => return,
+                        };
                         Arc::make_mut(doc)
                             .update_history_styles(history, highlights.to_owned());
                     }
@@ -72,6 +72,7 @@ impl LapceWindow {
         if replace_current {
             let tab = data.tabs.get(&data.active_id).unwrap();
             let _ = tab.db.save_workspace(tab);
+            tab.proxy.persist_state();
         }
         let tab_id = WidgetId::next();
         let mut tab_data = LapceTabData::new(
@@ -292,6 +293,7 @@ impl Widget<LapceWindowData> for LapceWindow {
                                 Config::load(&tab.workspace.clone())
                                     .unwrap_or_default(),
                             );
+                            tab.proxy.set_lsp_ignore(&tab.config.lapce.lsp_ignore);
                         }
                         Arc::make_mut(&mut data.keypress)
                             .update_keymaps(&data.config);